@@ -1,7 +1,13 @@
 //! Heap allocator for JerichoOS
 //!
-//! Provides dynamic memory allocation using a linked list allocator
+//! Provides dynamic memory allocation using a linked list allocator,
+//! with a small static bump region serving allocations made before the
+//! heap is mapped.
 
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -11,7 +17,194 @@ use x86_64::{
 use linked_list_allocator::LockedHeap;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: BootAllocator = BootAllocator::new();
+
+/// Size of the static region used before init_heap
+pub const BOOT_HEAP_SIZE: usize = 4096;
+
+/// Heap usage in bytes (bump region not included)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Called with the free byte count when the heap runs low
+pub type LowMemoryCallback = fn(usize);
+
+/// Global allocator that bump-allocates until the real heap is ready
+///
+/// Lets early init code use Box/Vec before init_heap has mapped the heap.
+/// Bump memory is never reclaimed: once the heap is up, frees of bump
+/// pointers are ignored and everything else goes to the linked-list heap,
+/// so allocations made before the switch stay valid after it.
+pub struct BootAllocator {
+    heap: LockedHeap,
+    heap_ready: AtomicBool,
+    bump: UnsafeCell<[u8; BOOT_HEAP_SIZE]>,
+    /// Offset of the first free byte in `bump`
+    bump_next: AtomicUsize,
+    /// Free bytes below which `low_callback` fires (0 = disabled)
+    low_threshold: AtomicUsize,
+    /// LowMemoryCallback stored as a raw pointer (null = none)
+    low_callback: AtomicPtr<()>,
+    /// Flagged for this episode and free memory hasn't recovered since
+    low_fired: AtomicBool,
+    /// Set by the allocator, cleared by run_low_memory_callback
+    low_pending: AtomicBool,
+    /// Free bytes when low_pending was set
+    low_free: AtomicUsize,
+    /// Successful allocations since boot (bump and heap)
+    allocations: AtomicUsize,
+}
+
+// SAFETY: `bump` is only handed out in disjoint ranges claimed via `bump_next`
+unsafe impl Sync for BootAllocator {}
+
+impl BootAllocator {
+    /// Create an allocator in bump mode
+    pub const fn new() -> Self {
+        BootAllocator {
+            heap: LockedHeap::empty(),
+            heap_ready: AtomicBool::new(false),
+            bump: UnsafeCell::new([0; BOOT_HEAP_SIZE]),
+            bump_next: AtomicUsize::new(0),
+            low_threshold: AtomicUsize::new(0),
+            low_callback: AtomicPtr::new(ptr::null_mut()),
+            low_fired: AtomicBool::new(false),
+            low_pending: AtomicBool::new(false),
+            low_free: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Successful allocations made through this allocator
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// Flag low memory when an allocation leaves less than `bytes` free
+    ///
+    /// The allocator itself only sets a flag, once per episode: it is
+    /// re-armed after frees bring the heap back to `bytes` or more.
+    /// `callback` runs later from run_low_memory_callback, outside the
+    /// allocator, so it may take locks and free (or allocate) memory. A
+    /// threshold of 0 disables it.
+    pub fn set_low_memory_threshold(&self, bytes: usize, callback: LowMemoryCallback) {
+        self.low_callback.store(callback as *mut (), Ordering::Release);
+        self.low_fired.store(false, Ordering::Relaxed);
+        self.low_pending.store(false, Ordering::Relaxed);
+        self.low_threshold.store(bytes, Ordering::Release);
+    }
+
+    /// Flag or re-arm low memory for `free` bytes remaining
+    ///
+    /// Runs inside alloc/dealloc, where the caller may hold any lock, so
+    /// it only touches atomics.
+    fn check_low_memory(&self, free: usize) {
+        let threshold = self.low_threshold.load(Ordering::Acquire);
+        if free >= threshold {
+            self.low_fired.store(false, Ordering::Relaxed);
+            return;
+        }
+        if !self.low_fired.swap(true, Ordering::Relaxed) {
+            self.low_free.store(free, Ordering::Relaxed);
+            self.low_pending.store(true, Ordering::Release);
+        }
+    }
+
+    /// Run the low-memory callback if the allocator flagged low memory
+    ///
+    /// Call with no locks held. Returns whether the callback ran.
+    pub fn run_low_memory_callback(&self) -> bool {
+        if !self.low_pending.swap(false, Ordering::Acquire) {
+            return false;
+        }
+        let callback = self.low_callback.load(Ordering::Acquire);
+        if callback.is_null() {
+            return false;
+        }
+        // SAFETY: only ever stored from a LowMemoryCallback
+        let callback = unsafe { core::mem::transmute::<*mut (), LowMemoryCallback>(callback) };
+        callback(self.low_free.load(Ordering::Relaxed));
+        true
+    }
+
+    /// Hand the heap at `start..start + size` over and stop bump allocating
+    ///
+    /// # Safety
+    /// The region must be mapped, writable and unused, and this must be
+    /// called only once.
+    pub unsafe fn switch_to_heap(&self, start: *mut u8, size: usize) {
+        unsafe {
+            self.heap.lock().init(start, size);
+        }
+        self.heap_ready.store(true, Ordering::Release);
+    }
+
+    /// Heap usage, or None if the heap lock is held
+    pub fn try_stats(&self) -> Option<HeapStats> {
+        let heap = self.heap.try_lock()?;
+        Some(HeapStats {
+            size: heap.size(),
+            used: heap.used(),
+            free: heap.free(),
+        })
+    }
+
+    /// Whether `ptr` was handed out by the bump region
+    pub fn is_bump(&self, ptr: *const u8) -> bool {
+        let base = self.bump.get() as usize;
+        (base..base + BOOT_HEAP_SIZE).contains(&(ptr as usize))
+    }
+
+    fn bump_alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.bump.get() as usize;
+        let mut next = self.bump_next.load(Ordering::Relaxed);
+        loop {
+            let start = (base + next).next_multiple_of(layout.align());
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= base + BOOT_HEAP_SIZE => end,
+                _ => return ptr::null_mut(),
+            };
+            match self.bump_next.compare_exchange_weak(next, end - base, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return start as *mut u8,
+                Err(current) => next = current,
+            }
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for BootAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = if self.heap_ready.load(Ordering::Acquire) {
+            let (result, free) = {
+                let mut heap = self.heap.lock();
+                (heap.allocate_first_fit(layout), heap.free())
+            };
+            self.check_low_memory(free);
+            result.map_or(ptr::null_mut(), |block| block.as_ptr())
+        } else {
+            self.bump_alloc(layout)
+        };
+        if !ptr.is_null() {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !self.is_bump(ptr) {
+            let free = {
+                let mut heap = self.heap.lock();
+                unsafe { heap.deallocate(NonNull::new_unchecked(ptr), layout) };
+                heap.free()
+            };
+            self.check_low_memory(free);
+        }
+    }
+}
 
 /// Heap start address
 pub const HEAP_START: usize = 0x_4444_4444_0000;
@@ -55,14 +248,130 @@ pub fn init_heap(
 
     // Initialize the allocator
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        ALLOCATOR.switch_to_heap(HEAP_START as *mut u8, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// Current heap usage
+///
+/// Only try-locks the heap, so it returns None instead of deadlocking
+/// when called while an allocation is in progress (e.g. from an interrupt).
+pub fn stats() -> Option<HeapStats> {
+    ALLOCATOR.try_stats()
+}
+
+/// Number of successful kernel allocations since boot
+///
+/// Lets tests check that a path doesn't allocate at all.
+pub fn allocation_count() -> usize {
+    ALLOCATOR.allocation_count()
+}
+
+/// Call `callback` once whenever kernel heap free space drops below `bytes`
+///
+/// Lets subsystems shed load (drop queued messages, reap tasks) before
+/// an allocation actually fails. The callback runs from
+/// run_low_memory_callback, never inside the allocator. See
+/// BootAllocator::set_low_memory_threshold.
+pub fn set_low_memory_threshold(bytes: usize, callback: LowMemoryCallback) {
+    ALLOCATOR.set_low_memory_threshold(bytes, callback);
+}
+
+/// Run the low-memory callback if the heap ran low since the last call
+///
+/// The scheduler calls this on every voluntary yield, where no locks are
+/// held. Returns whether the callback ran.
+pub fn run_low_memory_callback() -> bool {
+    ALLOCATOR.run_low_memory_callback()
+}
+
 /// Dummy allocator for #[alloc_error_handler]
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
+
+/// Test the bump-to-heap handoff on a private allocator
+#[test_case]
+fn test_boot_allocator_handoff() {
+    static EARLY: BootAllocator = BootAllocator::new();
+    static mut TEST_HEAP: [u8; 4096] = [0; 4096];
+
+    serial_print!("test_boot_allocator_handoff...");
+    let layout = Layout::from_size_align(64, 16).unwrap();
+
+    let early = unsafe { EARLY.alloc(layout) };
+    assert!(!early.is_null());
+    assert!(EARLY.is_bump(early));
+    assert_eq!(early as usize % 16, 0);
+    unsafe { early.write_bytes(0xAB, 64) };
+
+    // Bump region runs out instead of overflowing
+    let too_big = Layout::from_size_align(BOOT_HEAP_SIZE, 1).unwrap();
+    assert!(unsafe { EARLY.alloc(too_big) }.is_null());
+
+    let heap_start = (&raw mut TEST_HEAP).cast::<u8>();
+    unsafe { EARLY.switch_to_heap(heap_start, 4096) };
+    let late = unsafe { EARLY.alloc(layout) };
+    assert!(!late.is_null());
+    assert!(!EARLY.is_bump(late));
+    assert!((heap_start as usize..heap_start as usize + 4096).contains(&(late as usize)));
+
+    // Early allocations survive the switch and can still be freed
+    assert_eq!(unsafe { *early.add(63) }, 0xAB);
+    unsafe {
+        EARLY.dealloc(early, layout);
+        EARLY.dealloc(late, layout);
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that the low-memory callback fires once, before the heap runs out
+#[test_case]
+fn test_low_memory_callback() {
+    static HEAP: BootAllocator = BootAllocator::new();
+    static mut TEST_HEAP: [u8; 4096] = [0; 4096];
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    static FREE_AT_FIRE: AtomicUsize = AtomicUsize::new(0);
+
+    fn on_low_memory(free: usize) {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+        FREE_AT_FIRE.store(free, Ordering::SeqCst);
+    }
+
+    serial_print!("test_low_memory_callback...");
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    unsafe { HEAP.switch_to_heap((&raw mut TEST_HEAP).cast::<u8>(), 4096) };
+    HEAP.set_low_memory_threshold(1024, on_low_memory);
+
+    let mut blocks = alloc::vec::Vec::new();
+    loop {
+        let block = unsafe { HEAP.alloc(layout) };
+        if block.is_null() {
+            break;
+        }
+        blocks.push(block);
+    }
+
+    // Only flagged inside the allocator; the callback runs when asked
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+    assert!(HEAP.run_low_memory_callback());
+    assert!(!HEAP.run_low_memory_callback());
+
+    // Fired once on the way down, while there was still room left
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    let free_at_fire = FREE_AT_FIRE.load(Ordering::SeqCst);
+    assert!(free_at_fire < 1024 && free_at_fire >= layout.size());
+
+    // Freeing everything re-arms it
+    for block in blocks.drain(..) {
+        unsafe { HEAP.dealloc(block, layout) };
+    }
+    let block = unsafe { HEAP.alloc(Layout::from_size_align(3584, 8).unwrap()) };
+    assert!(!block.is_null());
+    assert!(HEAP.run_low_memory_callback());
+    assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+    serial_println!("[ok]");
+}
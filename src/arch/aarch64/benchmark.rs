@@ -3,6 +3,30 @@
 //! Uses ARM Generic Timer counters for high-precision timing
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use core::sync::atomic::AtomicU64;
+
+/// Frequency assumed when CNTFRQ_EL0 reads 0 (62.5 MHz, as on RPi-class boards)
+pub const FALLBACK_COUNTER_FREQUENCY: u64 = 62_500_000;
+
+/// Set once the zero-frequency warning has been printed
+static ZERO_FREQUENCY_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Mock frequency switch (tests only)
+#[cfg(test)]
+static MOCK_FREQUENCY_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Frequency reported while the mock is enabled (tests only)
+#[cfg(test)]
+static MOCK_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Make counter_frequency() see `freq` instead of CNTFRQ_EL0 (None = real register)
+#[cfg(test)]
+pub fn set_mock_frequency(freq: Option<u64>) {
+    MOCK_FREQUENCY.store(freq.unwrap_or(0), Ordering::Relaxed);
+    MOCK_FREQUENCY_ENABLED.store(freq.is_some(), Ordering::Relaxed);
+}
 
 /// Read the virtual counter (CNTVCT_EL0) for high-precision timing
 ///
@@ -37,27 +61,49 @@ pub fn read_counter_frequency() -> u64 {
     freq
 }
 
+/// Counter frequency to convert with, never 0
+///
+/// Emulators and misconfigured firmware can leave CNTFRQ_EL0 at 0; the
+/// conversions then fall back to FALLBACK_COUNTER_FREQUENCY (warning
+/// once) instead of reporting every duration as 0.
+pub fn counter_frequency() -> u64 {
+    #[cfg(test)]
+    let freq = if MOCK_FREQUENCY_ENABLED.load(Ordering::Relaxed) {
+        MOCK_FREQUENCY.load(Ordering::Relaxed)
+    } else {
+        read_counter_frequency()
+    };
+    #[cfg(not(test))]
+    let freq = read_counter_frequency();
+
+    if freq != 0 {
+        return freq;
+    }
+    if !ZERO_FREQUENCY_WARNED.swap(true, Ordering::Relaxed) {
+        crate::uart_puts("[BENCH] WARNING: CNTFRQ_EL0 reads 0, assuming 62.5 MHz\n");
+    }
+    FALLBACK_COUNTER_FREQUENCY
+}
+
 /// Convert counter ticks to microseconds
 ///
 /// Uses the actual hardware counter frequency instead of assuming a fixed CPU speed
 pub fn ticks_to_us(ticks: u64) -> u64 {
-    let freq = read_counter_frequency();
-    // ticks / (freq / 1_000_000) = ticks * 1_000_000 / freq
-    (ticks * 1_000_000) / freq
+    // ticks * 1_000_000 / freq, widened so long spans don't wrap
+    crate::benchmark::scale_ticks(ticks, counter_frequency(), 1_000_000)
 }
 
 /// Convert counter ticks to nanoseconds
 ///
 /// Uses the actual hardware counter frequency instead of assuming a fixed CPU speed
 pub fn ticks_to_ns(ticks: u64) -> u64 {
-    let freq = read_counter_frequency();
-    // ticks / (freq / 1_000_000_000) = ticks * 1_000_000_000 / freq
-    (ticks * 1_000_000_000) / freq
+    // ticks * 1_000_000_000 / freq, widened so long spans don't wrap
+    crate::benchmark::scale_ticks(ticks, counter_frequency(), 1_000_000_000)
 }
 
 /// Get counter frequency in human-readable format
 pub fn get_counter_info() -> (u64, &'static str) {
-    let freq = read_counter_frequency();
+    let freq = counter_frequency();
     if freq >= 1_000_000_000 {
         (freq / 1_000_000_000, "GHz")
     } else if freq >= 1_000_000 {
@@ -102,4 +148,15 @@ mod tests {
         let us_ms = ticks_to_us(ticks_ms);
         assert_eq!(us_ms, 1_000, "1 millisecond should be 1000 microseconds");
     }
+
+    #[test]
+    fn test_zero_frequency_falls_back() {
+        set_mock_frequency(Some(0));
+        assert_eq!(counter_frequency(), FALLBACK_COUNTER_FREQUENCY);
+        // Conversions use the fallback instead of dividing by zero
+        assert_eq!(ticks_to_us(FALLBACK_COUNTER_FREQUENCY), 1_000_000);
+        assert_eq!(ticks_to_ns(1), 16);
+        assert_eq!(get_counter_info(), (62, "MHz"));
+        set_mock_frequency(None);
+    }
 }
@@ -4,6 +4,43 @@
 //! Architecture-aware: supports x86-64 TSC and ARM64 generic timer
 
 use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(test)]
+use core::sync::atomic::AtomicBool;
+
+/// Cycles per timer tick (100 Hz at the assumed 3 GHz)
+pub const CYCLES_PER_TICK: u64 = 30_000_000;
+
+/// Mock clock switch (tests only)
+#[cfg(test)]
+static MOCK_CLOCK: AtomicBool = AtomicBool::new(false);
+
+/// Mock cycle counter, advanced by hand (tests only)
+#[cfg(test)]
+static MOCK_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Make read_cycles()/timer_ticks() return a hand-driven clock
+///
+/// The mock restarts at 0 each time it is enabled. Only exists in test
+/// builds, so the production clock path has no extra check.
+#[cfg(test)]
+pub fn set_mock_clock(enabled: bool) {
+    MOCK_CYCLES.store(0, Ordering::Relaxed);
+    MOCK_CLOCK.store(enabled, Ordering::Relaxed);
+}
+
+/// Advance the mock clock by `cycles`
+#[cfg(test)]
+pub fn advance_mock(cycles: u64) {
+    MOCK_CYCLES.fetch_add(cycles, Ordering::Relaxed);
+}
+
+/// Timer ticks according to the mock clock (None if it's disabled)
+#[cfg(test)]
+pub fn mock_ticks() -> Option<u64> {
+    MOCK_CLOCK
+        .load(Ordering::Relaxed)
+        .then(|| MOCK_CYCLES.load(Ordering::Relaxed) / CYCLES_PER_TICK)
+}
 
 /// Read high-precision cycle counter (architecture-specific)
 ///
@@ -11,6 +48,11 @@ use core::sync::atomic::{AtomicU64, Ordering};
 /// ARM64: PMCCNTR_EL0 (Performance Monitor Cycle Counter)
 #[inline]
 pub fn read_cycles() -> u64 {
+    #[cfg(test)]
+    if MOCK_CLOCK.load(Ordering::Relaxed) {
+        return MOCK_CYCLES.load(Ordering::Relaxed);
+    }
+
     #[cfg(target_arch = "x86_64")]
     unsafe {
         core::arch::x86_64::_rdtsc()
@@ -34,6 +76,19 @@ pub fn rdtsc() -> u64 {
     read_cycles()
 }
 
+/// Convert `ticks` of a `freq` Hz counter to units of 1/`units_per_sec` s
+///
+/// `ticks * units_per_sec` overflows u64 after a few seconds of a GHz
+/// counter, so the product is taken in u128. Results beyond u64
+/// saturate; a zero frequency gives 0.
+pub fn scale_ticks(ticks: u64, freq: u64, units_per_sec: u64) -> u64 {
+    if freq == 0 {
+        return 0;
+    }
+    let scaled = ticks as u128 * units_per_sec as u128 / freq as u128;
+    u64::try_from(scaled).unwrap_or(u64::MAX)
+}
+
 /// Convert CPU cycles to microseconds (assuming 3 GHz CPU)
 pub fn cycles_to_us(cycles: u64) -> u64 {
     cycles / 3000  // 3 GHz = 3000 MHz = 3 cycles per nanosecond
@@ -64,6 +119,38 @@ pub fn get_context_switch_stats() -> (u64, u64, u64) {
     (count, total_cycles, avg_cycles)
 }
 
+/// TSC value captured at timer interrupt entry
+static TIMER_ENTRY_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupt latency statistics (from bench_interrupt_latency)
+static IRQ_LATENCY_SAMPLES: AtomicU64 = AtomicU64::new(0);
+static IRQ_LATENCY_TOTAL_CYCLES: AtomicU64 = AtomicU64::new(0);
+static IRQ_LATENCY_MAX_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Record the cycle counter on timer interrupt entry
+///
+/// Called first thing in the timer handler so the timestamp is as close
+/// to the hardware entry as possible.
+#[inline]
+pub fn record_timer_entry() {
+    TIMER_ENTRY_CYCLES.store(read_cycles(), Ordering::Relaxed);
+}
+
+/// Get interrupt latency statistics: (samples, avg_cycles, max_cycles)
+pub fn get_interrupt_latency_stats() -> (u64, u64, u64) {
+    let samples = IRQ_LATENCY_SAMPLES.load(Ordering::Relaxed);
+    let total = IRQ_LATENCY_TOTAL_CYCLES.load(Ordering::Relaxed);
+    let max = IRQ_LATENCY_MAX_CYCLES.load(Ordering::Relaxed);
+    let avg = total.checked_div(samples).unwrap_or(0);
+    (samples, avg, max)
+}
+
+/// Average IPC round-trip cycles (from bench_ipc_roundtrip)
+static IPC_ROUNDTRIP_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Average cycles per Wasm call (from bench_wasm_call)
+static WASM_CALL_CYCLES: AtomicU64 = AtomicU64::new(0);
+
 /// Benchmark results structure
 pub struct BenchmarkResults {
     pub boot_time_us: u64,
@@ -72,6 +159,10 @@ pub struct BenchmarkResults {
     pub avg_context_switch_ns: u64,
     pub timer_ticks: u64,
     pub uptime_ms: u64,
+    pub avg_interrupt_latency_ns: u64,
+    pub max_interrupt_latency_ns: u64,
+    pub ipc_roundtrip_ns: u64,
+    pub wasm_call_ns: u64,
 }
 
 impl BenchmarkResults {
@@ -93,6 +184,10 @@ impl BenchmarkResults {
         serial_println!("  Avg switch time:  {} ns ({} µs)", self.avg_context_switch_ns, self.avg_context_switch_ns / 1000);
         serial_println!("  Timer ticks:      {}", self.timer_ticks);
         serial_println!("  Uptime:           {} ms ({} s)", self.uptime_ms, self.uptime_ms / 1000);
+        serial_println!("  IRQ latency:      {} ns avg, {} ns max",
+            self.avg_interrupt_latency_ns, self.max_interrupt_latency_ns);
+        serial_println!("  IPC round-trip:   {} ns", self.ipc_roundtrip_ns);
+        serial_println!("  Wasm call:        {} ns", self.wasm_call_ns);
         serial_println!("");
 
         serial_println!("🎯 Success Criteria:");
@@ -118,6 +213,8 @@ pub fn collect_results(boot_cycles: u64) -> BenchmarkResults {
     let ticks = timer_ticks();
     let uptime_ms = ticks * 10;  // 10ms per tick at 100 Hz
 
+    let (_samples, avg_irq_cycles, max_irq_cycles) = get_interrupt_latency_stats();
+
     BenchmarkResults {
         boot_time_us,
         boot_time_cycles: boot_cycles,
@@ -125,6 +222,10 @@ pub fn collect_results(boot_cycles: u64) -> BenchmarkResults {
         avg_context_switch_ns,
         timer_ticks: ticks,
         uptime_ms,
+        avg_interrupt_latency_ns: cycles_to_ns(avg_irq_cycles),
+        max_interrupt_latency_ns: cycles_to_ns(max_irq_cycles),
+        ipc_roundtrip_ns: cycles_to_ns(IPC_ROUNDTRIP_CYCLES.load(Ordering::Relaxed)),
+        wasm_call_ns: cycles_to_ns(WASM_CALL_CYCLES.load(Ordering::Relaxed)),
     }
 }
 
@@ -156,6 +257,231 @@ pub fn benchmark_context_switches(iterations: u64) -> u64 {
     avg_cycles
 }
 
+/// Benchmark timer interrupt latency (x86-64 only)
+///
+/// Spins reading the TSC until the next timer tick, then compares the
+/// last timestamp taken before the tick with the one the handler recorded
+/// on entry. Returns (avg_cycles, max_cycles) over `ticks` samples and
+/// stores them for collect_results.
+///
+/// Interrupts must be enabled - this waits for real timer ticks.
+#[cfg(target_arch = "x86_64")]
+pub fn bench_interrupt_latency(ticks: u64) -> (u64, u64) {
+    use crate::interrupts::timer_ticks;  // x86 only
+
+    serial_println!("[BENCH] Running interrupt latency benchmark ({} ticks)...", ticks);
+
+    let mut total_cycles = 0u64;
+    let mut max_cycles = 0u64;
+
+    for _ in 0..ticks {
+        let start_tick = timer_ticks();
+        let mut last_before = read_cycles();
+
+        // Tight loop: keep the most recent timestamp taken before the tick
+        loop {
+            let now = read_cycles();
+            if timer_ticks() != start_tick {
+                let entry = TIMER_ENTRY_CYCLES.load(Ordering::Relaxed);
+                // `now` may have been read just before the interrupt fired
+                let before = if now <= entry { now } else { last_before };
+                let latency = entry.wrapping_sub(before);
+                total_cycles += latency;
+                max_cycles = max_cycles.max(latency);
+                break;
+            }
+            last_before = now;
+        }
+    }
+
+    let avg_cycles = total_cycles.checked_div(ticks).unwrap_or(0);
+
+    IRQ_LATENCY_SAMPLES.store(ticks, Ordering::Relaxed);
+    IRQ_LATENCY_TOTAL_CYCLES.store(total_cycles, Ordering::Relaxed);
+    IRQ_LATENCY_MAX_CYCLES.store(max_cycles, Ordering::Relaxed);
+
+    serial_println!("[BENCH] IRQ latency: avg {} cycles ({} ns), max {} cycles ({} ns)",
+        avg_cycles, cycles_to_ns(avg_cycles), max_cycles, cycles_to_ns(max_cycles));
+
+    (avg_cycles, max_cycles)
+}
+
+/// Endpoints used by the IPC round-trip benchmark
+#[cfg(target_arch = "x86_64")]
+const BENCH_PING_ENDPOINT: u64 = 200;
+#[cfg(target_arch = "x86_64")]
+const BENCH_PONG_ENDPOINT: u64 = 201;
+
+/// Echo task for bench_ipc_roundtrip: bounces every ping back as a pong
+///
+/// # Assumptions
+/// - TRUST: Task has cap 1 (READ ping endpoint) and cap 2 (WRITE pong endpoint)
+#[cfg(target_arch = "x86_64")]
+fn ipc_echo_main() -> ! {
+    use crate::capability::CapabilityId;
+    use crate::{ipc, scheduler};
+
+    let id = kensure!(scheduler::current_task_id(), "No current task");
+    let cspace = kensure!(scheduler::current_task_cspace(), "No CSpace for current task");
+
+    loop {
+        if let Ok(msg) = ipc::receive_message_blocking(id, &cspace, CapabilityId::new(1)) {
+            let _ = ipc::send_message(id, &cspace, CapabilityId::new(2), msg.data);
+        }
+    }
+}
+
+/// Benchmark IPC round-trip latency (x86-64 only)
+///
+/// Ping-pongs `iterations` messages with an echo task over two endpoints,
+/// so each sample covers capability checks, queueing, wake-up and two
+/// context switches. Returns average cycles per round trip, or None if
+/// not called from a task (blocking receive needs a current task).
+#[cfg(target_arch = "x86_64")]
+pub fn bench_ipc_roundtrip(iterations: u64) -> Option<u64> {
+    use crate::capability::{CSpace, Capability, CapabilityId, ResourceType, Rights};
+    use crate::task::{Priority, Privilege, Task};
+    use crate::{ipc, scheduler};
+    use core::sync::atomic::AtomicBool;
+
+    // The echo task outlives the benchmark (blocked on receive), start it once
+    static ECHO_STARTED: AtomicBool = AtomicBool::new(false);
+
+    serial_println!("[BENCH] Running IPC round-trip benchmark ({} messages)...", iterations);
+
+    let self_id = match scheduler::current_task_id() {
+        Some(id) => id,
+        None => {
+            serial_println!("[BENCH] IPC round-trip needs task context - skipped");
+            return None;
+        }
+    };
+
+    // Only marked started once the endpoints and echo task exist, so a
+    // failed setup is retried by the next run
+    if !ECHO_STARTED.load(Ordering::Acquire) {
+        ipc::create_endpoint(CapabilityId::new(BENCH_PING_ENDPOINT)).ok()?;
+        ipc::create_endpoint(CapabilityId::new(BENCH_PONG_ENDPOINT)).ok()?;
+
+        let mut echo = Task::try_new("ipc-echo", ipc_echo_main, Priority::Normal, Privilege::Kernel)?;
+        echo.cspace_mut().insert(Capability::new(
+            CapabilityId::new(1), ResourceType::Endpoint, BENCH_PING_ENDPOINT, Rights::READ));
+        echo.cspace_mut().insert(Capability::new(
+            CapabilityId::new(2), ResourceType::Endpoint, BENCH_PONG_ENDPOINT, Rights::READ_WRITE));
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            scheduler::SCHEDULER.lock().as_mut().map(|s| s.add_task(echo))
+        })?.ok()?;
+        ECHO_STARTED.store(true, Ordering::Release);
+    }
+
+    // Our side: send pings, receive pongs
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(
+        CapabilityId::new(1), ResourceType::Endpoint, BENCH_PING_ENDPOINT, Rights::READ_WRITE));
+    cspace.insert(Capability::new(
+        CapabilityId::new(2), ResourceType::Endpoint, BENCH_PONG_ENDPOINT, Rights::READ));
+
+    let mut total_cycles = 0u64;
+    for i in 0..iterations {
+        let start = read_cycles();
+        ipc::send_message(self_id, &cspace, CapabilityId::new(1), i.to_le_bytes().to_vec()).ok()?;
+        ipc::receive_message_blocking(self_id, &cspace, CapabilityId::new(2)).ok()?;
+        total_cycles += read_cycles().wrapping_sub(start);
+    }
+
+    let avg_cycles = total_cycles.checked_div(iterations)?;
+    IPC_ROUNDTRIP_CYCLES.store(avg_cycles, Ordering::Relaxed);
+
+    serial_println!("[BENCH] IPC round-trip: {} cycles ({} ns, {} µs)",
+        avg_cycles, cycles_to_ns(avg_cycles), cycles_to_us(avg_cycles));
+
+    Some(avg_cycles)
+}
+
+/// Ready tasks in the "many" half of the scheduling benchmark
+#[cfg(target_arch = "x86_64")]
+const BENCH_SCHED_TASKS: usize = 8;
+
+/// Benchmark per-tick scheduling overhead (x86-64 only)
+///
+/// Times `Scheduler::schedule()` on a private scheduler with one ready
+/// task (the fast path) and with several, without switching contexts.
+/// Returns (one_ready_cycles, many_ready_cycles) averaged per call, or
+/// None if the tasks can't be allocated.
+#[cfg(target_arch = "x86_64")]
+pub fn bench_schedule_overhead(iterations: u64) -> Option<(u64, u64)> {
+    use crate::scheduler::Scheduler;
+    use crate::task::{Priority, Privilege, Task};
+
+    fn idle_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    fn time_schedule(tasks: usize, iterations: u64) -> Option<u64> {
+        let mut scheduler = Scheduler::new();
+        for _ in 0..tasks {
+            scheduler.add_task(Task::try_new("bench-idle", idle_main, Priority::Normal, Privilege::Kernel)?).ok()?;
+        }
+        // Get a current task so every timed call is a steady-state tick
+        scheduler.schedule()?;
+
+        let start = read_cycles();
+        for _ in 0..iterations {
+            scheduler.schedule()?;
+        }
+        read_cycles().wrapping_sub(start).checked_div(iterations)
+    }
+
+    serial_println!("[BENCH] Running scheduling overhead benchmark ({} ticks)...", iterations);
+
+    let one = time_schedule(1, iterations)?;
+    let many = time_schedule(BENCH_SCHED_TASKS, iterations)?;
+
+    serial_println!("[BENCH] schedule() with 1 ready task: {} cycles ({} ns)",
+        one, cycles_to_ns(one));
+    serial_println!("[BENCH] schedule() with {} ready tasks: {} cycles ({} ns)",
+        BENCH_SCHED_TASKS, many, cycles_to_ns(many));
+
+    Some((one, many))
+}
+
+/// (module (func (export "nop")))
+const WASM_NOP: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60,
+    0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x6e, 0x6f,
+    0x70, 0x00, 0x00, 0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b,
+];
+
+/// Benchmark the host -> guest -> host round trip of a Wasm call
+///
+/// Times `call_function` on an exported no-op, so the result is the
+/// interpreter's fixed per-call cost (lookup, entry, exit) rather than
+/// any guest work. Returns average cycles per call, or None if the
+/// module fails to load or a call fails.
+pub fn bench_wasm_call(iterations: u64) -> Option<u64> {
+    use crate::wasm_runtime::WasmModule;
+
+    serial_println!("[BENCH] Running Wasm call benchmark ({} calls)...", iterations);
+
+    let mut module = WasmModule::from_bytes(WASM_NOP).ok()?;
+    // Warm up so the first timed call doesn't pay for lazy setup
+    module.call_function("nop", &[]).ok()?;
+
+    let start = read_cycles();
+    for _ in 0..iterations {
+        module.call_function("nop", &[]).ok()?;
+    }
+    let avg_cycles = read_cycles().wrapping_sub(start).checked_div(iterations)?;
+    WASM_CALL_CYCLES.store(avg_cycles, Ordering::Relaxed);
+
+    serial_println!("[BENCH] Wasm call: {} cycles ({} ns)", avg_cycles, cycles_to_ns(avg_cycles));
+
+    Some(avg_cycles)
+}
+
 /// Calculate memory footprint from kernel binary size
 pub fn estimate_memory_footprint() -> usize {
     // In a real implementation, we'd read this from the ELF headers
@@ -171,6 +497,33 @@ pub fn estimate_memory_footprint() -> usize {
     (100 + 8192 + (3 * 32)) * 1024  // Return in bytes
 }
 
+/// Count the capabilities in `cspace` by resource type
+///
+/// Entry `i` counts `ResourceType::ALL[i]`.
+pub fn capability_histogram(cspace: &crate::capability::CSpace) -> [usize; crate::capability::ResourceType::ALL.len()] {
+    let mut counts = [0; crate::capability::ResourceType::ALL.len()];
+    for cap in cspace.iter() {
+        counts[cap.resource_type().as_u32() as usize] += 1;
+    }
+    counts
+}
+
+/// Print the kernel CSpace's capabilities by resource type
+///
+/// Returns the total count.
+pub fn report_capabilities() -> usize {
+    use crate::capability::{kernel_cspace, ResourceType};
+
+    let counts = capability_histogram(&kernel_cspace().lock());
+    serial_println!("[BENCH] Kernel capabilities by resource type:");
+    for (resource_type, count) in ResourceType::ALL.iter().zip(counts) {
+        serial_println!("  {:<16}{}", resource_type.as_str(), count);
+    }
+    let total = counts.iter().sum();
+    serial_println!("  Total:          {}", total);
+    total
+}
+
 /// Benchmark syscall latency
 ///
 /// Measures round-trip time for a minimal syscall (capability validation)
@@ -302,3 +655,110 @@ pub fn run_benchmark_suite() {
     serial_println!("  Switch < 5µs:     {} ({} ns)", switch_pass, cycles_to_ns(avg_switch_cycles));
     serial_println!("");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that interrupt latency numbers are non-zero and bounded
+    #[test_case]
+    fn test_interrupt_latency_plausible() {
+        serial_print!("test_interrupt_latency_plausible...");
+        let (avg, max) = bench_interrupt_latency(5);
+        assert!(avg > 0);
+        assert!(max >= avg);
+        // anything near a full tick (10ms = 30M cycles @ 3GHz) means a missed sample
+        assert!(max < 30_000_000);
+
+        let (samples, stored_avg, stored_max) = get_interrupt_latency_stats();
+        assert_eq!((samples, stored_avg, stored_max), (5, avg, max));
+        serial_println!("[ok]");
+    }
+
+    /// Test that the IPC round-trip benchmark completes with a sane latency
+    #[test_case]
+    fn test_ipc_roundtrip_plausible() {
+        serial_print!("test_ipc_roundtrip_plausible...");
+        // Tests run in the test_runner task, so the no-task early return is never taken
+        assert!(crate::scheduler::current_task_id().is_some());
+        let avg = bench_ipc_roundtrip(16).expect("round-trip benchmark did not complete");
+        assert!(avg > 0);
+        // a round trip taking a whole timer tick (30M cycles) means we stalled
+        assert!(avg < 30_000_000);
+        assert_eq!(IPC_ROUNDTRIP_CYCLES.load(Ordering::Relaxed), avg);
+        serial_println!("[ok]");
+    }
+
+    /// Test that the scheduling overhead benchmark returns both samples
+    #[test_case]
+    fn test_schedule_overhead_plausible() {
+        serial_print!("test_schedule_overhead_plausible...");
+        let (one, many) = bench_schedule_overhead(32).expect("could not allocate benchmark tasks");
+        assert!(one > 0 && many > 0);
+        // a whole timer tick per schedule() call means something is badly wrong
+        assert!(one < CYCLES_PER_TICK && many < CYCLES_PER_TICK);
+        serial_println!("[ok]");
+    }
+
+    /// Test that the Wasm call benchmark runs and reports a sane average
+    #[test_case]
+    fn test_wasm_call_plausible() {
+        serial_print!("test_wasm_call_plausible...");
+        let avg = bench_wasm_call(64).expect("wasm call benchmark failed");
+        assert!(avg > 0);
+        // an interpreted no-op taking a whole timer tick means we stalled
+        assert!(avg < CYCLES_PER_TICK);
+        assert_eq!(WASM_CALL_CYCLES.load(Ordering::Relaxed), avg);
+        serial_println!("[ok]");
+    }
+
+    /// Test that the histogram counts each resource type
+    #[test_case]
+    fn test_capability_histogram() {
+        use crate::capability::{CSpace, ResourceType, Rights};
+
+        serial_print!("test_capability_histogram...");
+        let mut cspace = CSpace::new();
+        let mix = [
+            (ResourceType::Memory, 3),
+            (ResourceType::Endpoint, 2),
+            (ResourceType::Thread, 1),
+            (ResourceType::Admin, 1),
+        ];
+        for (resource_type, count) in mix {
+            for i in 0..count {
+                cspace.create(resource_type, i, Rights::READ);
+            }
+        }
+
+        let histogram = capability_histogram(&cspace);
+        for (i, resource_type) in ResourceType::ALL.iter().enumerate() {
+            let expected = mix.iter().find(|(t, _)| t == resource_type).map_or(0, |&(_, n)| n);
+            assert_eq!(histogram[i], expected as usize, "{}", resource_type);
+        }
+        assert_eq!(histogram.iter().sum::<usize>(), cspace.len());
+        serial_println!("[ok]");
+    }
+
+    /// Test that long measurements convert without wrapping
+    #[test_case]
+    fn test_scale_ticks_no_overflow() {
+        serial_print!("test_scale_ticks_no_overflow...");
+        const GHZ: u64 = 1_000_000_000;
+        // ~580 years at 1 GHz: ticks * 1e9 is far past u64
+        let ticks = u64::MAX / 2;
+        assert_eq!(scale_ticks(ticks, GHZ, 1_000_000_000), ticks);
+        assert_eq!(scale_ticks(ticks, GHZ, 1_000_000), ticks / 1000);
+        // 24 MHz (ARM generic timer): a wrapping u64 product would give 768_614_336_404
+        assert_eq!(scale_ticks(ticks, 24_000_000, 1_000_000), 384_307_168_202_282_325);
+
+        // An hour at 3 GHz, the case that used to wrap
+        let hour = 3 * GHZ * 3600;
+        assert_eq!(scale_ticks(hour, 3 * GHZ, 1_000_000_000), 3600 * GHZ);
+
+        // Out-of-range results saturate instead of wrapping
+        assert_eq!(scale_ticks(u64::MAX, 1, 1000), u64::MAX);
+        assert_eq!(scale_ticks(123, 0, 1000), 0);
+        serial_println!("[ok]");
+    }
+}
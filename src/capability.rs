@@ -2,6 +2,7 @@
 // capabilities are tokens that prove you can access something
 
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use spin::{Mutex, Once};
 
 /// Unique capability identifier
@@ -18,8 +19,27 @@ impl CapabilityId {
     pub fn value(&self) -> u64 {
         self.0
     }
+
+    /// Slot part of the ID (the whole value in monotonic mode)
+    pub fn slot(&self) -> u32 {
+        self.0 as u32
+    }
+
+    /// How many times the slot has been recycled (see CSpace::set_id_recycling)
+    pub fn generation(&self) -> u32 {
+        (self.0 >> ID_GENERATION_SHIFT) as u32
+    }
+
+    /// Same slot, next generation
+    fn next_generation(&self) -> CapabilityId {
+        let generation = self.generation().wrapping_add(1) as u64;
+        CapabilityId(self.slot() as u64 | generation << ID_GENERATION_SHIFT)
+    }
 }
 
+/// Bit position of the generation counter in a recycled capability ID
+const ID_GENERATION_SHIFT: u32 = 32;
+
 /// Capability rights/permissions
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]  // ARM64: C layout (4 bools = 4 bytes)
@@ -91,16 +111,85 @@ pub enum ResourceType {
     Thread,
     Endpoint,  // For IPC
     WasmModule,
+    Fuel,  // Wasm fuel refills (resource_id = max fuel per request)
+    Admin,  // Kernel monitoring/debug interfaces
+}
+
+impl ResourceType {
+    /// All variants, in discriminant order
+    pub const ALL: [ResourceType; 7] = [
+        ResourceType::Memory,
+        ResourceType::Interrupt,
+        ResourceType::Thread,
+        ResourceType::Endpoint,
+        ResourceType::WasmModule,
+        ResourceType::Fuel,
+        ResourceType::Admin,
+    ];
+
+    /// Decode a discriminant (None for an unknown value)
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(ResourceType::Memory),
+            1 => Some(ResourceType::Interrupt),
+            2 => Some(ResourceType::Thread),
+            3 => Some(ResourceType::Endpoint),
+            4 => Some(ResourceType::WasmModule),
+            5 => Some(ResourceType::Fuel),
+            6 => Some(ResourceType::Admin),
+            _ => None,
+        }
+    }
+
+    /// Stable numeric discriminant (syscall ABI and serialized CSpaces)
+    pub fn as_u32(&self) -> u32 {
+        *self as u32
+    }
+
+    /// Stable lowercase name for logs and shell output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceType::Memory => "memory",
+            ResourceType::Interrupt => "interrupt",
+            ResourceType::Thread => "thread",
+            ResourceType::Endpoint => "endpoint",
+            ResourceType::WasmModule => "wasm_module",
+            ResourceType::Fuel => "fuel",
+            ResourceType::Admin => "admin",
+        }
+    }
+}
+
+impl core::fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 /// A capability token - unforgeable reference to a resource
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(C)]  // ARM64: C layout (removed align(16) - conflicts with BTreeMap)
 pub struct Capability {
     id: CapabilityId,
     resource_type: ResourceType,
     resource_id: u64,  // Physical address, IRQ number, thread ID, etc.
     rights: Rights,
+    badge: Option<u64>,  // Endpoint badge set by mint (immutable)
+    expires_at: Option<u64>,  // Timer tick the capability stops working at
+}
+
+/// Current timer tick, the clock capability expiry is measured against
+fn now_ticks() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::interrupts::timer_ticks()
+    }
+
+    // ARM64: no tick counter wired up yet, so leases don't run out
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        0
+    }
 }
 
 impl Capability {
@@ -111,6 +200,8 @@ impl Capability {
             resource_type,
             resource_id,
             rights,
+            badge: None,
+            expires_at: None,
         }
     }
 
@@ -134,6 +225,34 @@ impl Capability {
         self.rights
     }
 
+    /// Get badge (None for unbadged capabilities)
+    pub fn badge(&self) -> Option<u64> {
+        self.badge
+    }
+
+    /// Tick at which this capability expires (None = never)
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Check whether the capability has expired as of tick `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Check whether both capabilities name the same resource (ids ignored)
+    pub fn refers_same_resource(&self, other: &Capability) -> bool {
+        self.resource_type == other.resource_type && self.resource_id == other.resource_id
+    }
+
+    /// Check whether this capability's rights cover all of `other`'s
+    ///
+    /// Only compares rights; combine with refers_same_resource to ask
+    /// whether `other` is redundant next to this one.
+    pub fn grants_at_least(&self, other: &Capability) -> bool {
+        self.rights.has(other.rights)
+    }
+
     /// Derive a new capability with reduced rights
     pub fn derive(&self, new_id: CapabilityId, new_rights: Rights) -> Option<Capability> {
         self.rights.derive(new_rights).map(|rights| {
@@ -142,22 +261,101 @@ impl Capability {
                 resource_type: self.resource_type,
                 resource_id: self.resource_id,
                 rights,
+                badge: self.badge,  // badges survive derivation
+                expires_at: self.expires_at,  // so does the lease: deriving can't extend it
             }
         })
     }
 }
 
+/// Serialized CSpace header: format version (u8), next_id (u64), has
+/// revocation endpoint (u8), revocation endpoint id (u64). All integers
+/// are little-endian.
+#[allow(dead_code, reason = "part of the checkpoint format, see CSpace::serialize")]
+pub const CSPACE_HEADER_SIZE: usize = 18;
+
+/// Layout version written by CSpace::serialize
+///
+/// 2 records expiry as ticks remaining instead of an absolute tick, so a
+/// lease keeps its length across a restore into a different clock.
+#[allow(dead_code, reason = "part of the checkpoint format, see CSpace::serialize")]
+pub const CSPACE_FORMAT_VERSION: u8 = 2;
+
+/// Serialized capability: id (u64), resource type (u8), resource id (u64),
+/// rights bits (u8: read, write, execute, grant), has badge (u8), badge (u64),
+/// has expiry (u8), ticks left before expiry (u64)
+#[allow(dead_code, reason = "part of the checkpoint format, see CSpace::serialize")]
+pub const CAP_RECORD_SIZE: usize = 36;
+
+/// Failed lookups after which a CSpace is reported as probing for IDs
+pub const PROBE_THRESHOLD: u32 = 32;
+
+/// A revocation message waiting to be sent (see CSpace::revoke_derived)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RevocationNotice {
+    /// Endpoint the holder registered to hear about revocations
+    pub endpoint: CapabilityId,
+    /// The revoked capability's ID in the holder's CSpace
+    pub cap_id: CapabilityId,
+    /// Resource the capability referred to
+    pub resource_id: u64,
+}
+
+/// Deliver revocation notices collected by revoke_tree/revoke_derived
+///
+/// Message payload: revoked capability ID (u64 LE) then resource ID (u64
+/// LE). Sending takes the IPC registry and scheduler locks, so call this
+/// only after dropping any lock the CSpaces were reached through.
+pub fn send_revocation_notices(notices: Vec<RevocationNotice>) {
+    #[cfg(target_arch = "x86_64")]
+    for notice in notices {
+        let mut data = Vec::with_capacity(16);
+        data.extend_from_slice(&notice.cap_id.value().to_le_bytes());
+        data.extend_from_slice(&notice.resource_id.to_le_bytes());
+        if let Err(e) = crate::ipc::send_kernel_notification(notice.endpoint, data) {
+            serial_println!("[CAP] Revocation notice for cap {} not delivered: {:?}",
+                notice.cap_id.value(), e);
+        }
+    }
+
+    // ARM64: no IPC subsystem yet, holders find out on next use
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = notices;
+}
+
+/// Errors from CSpace::deserialize
+#[allow(dead_code, reason = "returned by CSpace::deserialize, which has no kernel caller yet")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CSpaceDecodeError {
+    /// Not a header followed by whole capability records
+    InvalidLength,
+    /// Unknown resource type discriminant
+    UnknownResourceType(u8),
+    /// A flag or rights byte has bits outside its defined values
+    InvalidFlags,
+    /// Written in a format version this kernel doesn't read
+    UnsupportedVersion(u8),
+}
+
 /// Capability Space (CSpace) - stores all capabilities for an entity
 ///
 /// Clone is implemented to allow snapshot-based capability checking.
 /// This enables checking capabilities without holding scheduler lock.
 /// Note: Cloning creates a point-in-time snapshot; revocations after
 /// clone are not reflected in the snapshot.
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(C)]  // ARM64: Ensure consistent layout
 pub struct CSpace {
     capabilities: BTreeMap<CapabilityId, Capability>,  // Restored BTreeMap
     next_id: u64,
+    /// Endpoint capability that receives revocation notifications
+    revocation_endpoint: Option<CapabilityId>,
+    /// Hand out revoked slots again instead of always bumping next_id
+    recycle_ids: bool,
+    /// Revoked IDs with their generation already bumped, ready for reuse
+    free_ids: Vec<CapabilityId>,
+    /// Untrusted lookups (see lookup) that matched nothing
+    failed_lookups: u32,
 }
 
 impl CSpace {
@@ -166,6 +364,41 @@ impl CSpace {
         CSpace {
             capabilities: BTreeMap::new(),
             next_id: 1,
+            revocation_endpoint: None,
+            recycle_ids: false,
+            free_ids: Vec::new(),
+            failed_lookups: 0,
+        }
+    }
+
+    /// Turn capability ID recycling on or off (off by default)
+    ///
+    /// When on, create/derive/mint reuse the slots of revoked
+    /// capabilities. Each reuse bumps the generation stored in the upper
+    /// 32 bits of the ID, so a stale ID for the old capability never
+    /// matches the new one. Turning it off forgets the free list.
+    /// serialize doesn't record either, so a restored CSpace is monotonic.
+    pub fn set_id_recycling(&mut self, enabled: bool) {
+        self.recycle_ids = enabled;
+        if !enabled {
+            self.free_ids.clear();
+        }
+    }
+
+    /// Next ID for a new capability: a recycled slot if any, else next_id
+    fn allocate_id(&mut self) -> CapabilityId {
+        if let Some(id) = self.free_ids.pop() {
+            return id;
+        }
+        let id = CapabilityId::new(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// Queue a removed capability's slot for reuse, if recycling is on
+    fn release_id(&mut self, id: CapabilityId) {
+        if self.recycle_ids {
+            self.free_ids.push(id.next_generation());
         }
     }
 
@@ -176,39 +409,331 @@ impl CSpace {
         id
     }
 
+    /// Insert a capability received from another CSpace under a fresh ID
+    ///
+    /// Rights and badge are kept; only the ID changes, since the sender's
+    /// ID may already be taken here. IDs inserted by hand are skipped.
+    pub fn insert_transferred(&mut self, mut capability: Capability) -> CapabilityId {
+        while self.capabilities.contains_key(&CapabilityId::new(self.next_id)) {
+            self.next_id += 1;
+        }
+        capability.id = CapabilityId::new(self.next_id);
+        self.next_id += 1;
+        self.insert(capability)
+    }
+
     /// Get a capability by ID
+    ///
+    /// An expired capability is treated as absent, even before
+    /// sweep_expired removes it.
     pub fn get(&self, id: CapabilityId) -> Option<&Capability> {
-        self.capabilities.get(&id)
+        self.capabilities.get(&id).filter(|cap| !cap.is_expired(now_ticks()))
+    }
+
+    /// Get a capability by an ID that came from an untrusted caller
+    ///
+    /// Like get, but counts misses: guessing IDs is the only way to forge
+    /// a capability, so a run of misses is worth reporting. The warning is
+    /// logged once, when the count reaches PROBE_THRESHOLD.
+    pub fn lookup(&mut self, id: CapabilityId) -> Option<&Capability> {
+        if self.get(id).is_none() {
+            self.failed_lookups = self.failed_lookups.saturating_add(1);
+            if self.failed_lookups == PROBE_THRESHOLD {
+                serial_println!("[SECURITY] capability probing detected ({} failed lookups)", self.failed_lookups);
+            }
+            return None;
+        }
+        self.get(id)
+    }
+
+    /// Misses counted by lookup
+    pub fn failed_lookups(&self) -> u32 {
+        self.failed_lookups
+    }
+
+    /// Whether lookup misses have reached PROBE_THRESHOLD
+    pub fn is_probing(&self) -> bool {
+        self.failed_lookups >= PROBE_THRESHOLD
+    }
+
+    /// Iterate over the live capabilities in id order
+    ///
+    /// Skips expired capabilities, like get.
+    pub fn iter(&self) -> impl Iterator<Item = &Capability> {
+        let now = now_ticks();
+        self.capabilities.values().filter(move |cap| !cap.is_expired(now))
+    }
+
+    /// Remove every expired capability, returning how many were dropped
+    pub fn sweep_expired(&mut self) -> usize {
+        let now = now_ticks();
+        let expired: Vec<CapabilityId> = self.capabilities.values()
+            .filter(|cap| cap.is_expired(now))
+            .map(|cap| cap.id())
+            .collect();
+        for id in &expired {
+            self.revoke(*id);
+        }
+        expired.len()
     }
 
     /// Remove a capability (revoke)
     pub fn revoke(&mut self, id: CapabilityId) -> Option<Capability> {
-        self.capabilities.remove(&id)
+        let cap = self.capabilities.remove(&id)?;
+        self.release_id(id);
+        Some(cap)
     }
 
     /// Create a new capability in this CSpace
     pub fn create(&mut self, resource_type: ResourceType, resource_id: u64, rights: Rights) -> CapabilityId {
-        let id = CapabilityId::new(self.next_id);
-        self.next_id += 1;
+        let id = self.allocate_id();
 
         let cap = Capability::new(id, resource_type, resource_id, rights);
         self.insert(cap);
         id
     }
 
+    /// Create a capability that stops working at timer tick `expires_at`
+    ///
+    /// For leases: once the tick passes, lookups fail as if the
+    /// capability had been revoked.
+    pub fn create_expiring(
+        &mut self,
+        resource_type: ResourceType,
+        resource_id: u64,
+        rights: Rights,
+        expires_at: u64,
+    ) -> CapabilityId {
+        let id = self.create(resource_type, resource_id, rights);
+        if let Some(cap) = self.capabilities.get_mut(&id) {
+            cap.expires_at = Some(expires_at);
+        }
+        id
+    }
+
     /// Derive a new capability from an existing one (with reduced rights)
     pub fn derive(&mut self, source_id: CapabilityId, new_rights: Rights) -> Option<CapabilityId> {
         // TODO: should we audit derivations? could be useful for security analysis
         let source_cap = self.get(source_id)?.clone();
+        // Check up front so a refused derive doesn't use up a recycled ID
+        if !source_cap.rights().has(new_rights) {
+            return None;
+        }
 
-        let new_id = CapabilityId::new(self.next_id);
-        self.next_id += 1;
-
+        let new_id = self.allocate_id();
         let derived_cap = source_cap.derive(new_id, new_rights)?;
         self.insert(derived_cap);
         Some(new_id)
     }
 
+    /// Mint a badged copy of an endpoint capability (seL4-style)
+    ///
+    /// The badge is recorded in every message sent through the new
+    /// capability so a server can tell its clients apart. Only unbadged
+    /// endpoint capabilities can be minted, and rights can only be reduced.
+    pub fn mint(&mut self, source_id: CapabilityId, badge: u64, rights: Rights) -> Option<CapabilityId> {
+        let source_cap = self.get(source_id)?;
+        if source_cap.resource_type() != ResourceType::Endpoint || source_cap.badge().is_some() {
+            return None;
+        }
+        if !source_cap.rights().has(rights) {
+            return None;
+        }
+
+        let source_cap = source_cap.clone();
+        let new_id = self.allocate_id();
+        let mut minted = source_cap.derive(new_id, rights)?;
+        minted.badge = Some(badge);
+
+        self.insert(minted);
+        Some(new_id)
+    }
+
+    /// Clone a filtered subset of this CSpace for a child task
+    ///
+    /// The filter returns the rights the child should get for each
+    /// capability, or None to drop it. Capabilities keep their IDs so the
+    /// child can use the same handles as the parent. Rights can only be
+    /// reduced - a filter asking for more than the parent holds drops the cap.
+    pub fn clone_restricted(&self, filter: impl Fn(&Capability) -> Option<Rights>) -> CSpace {
+        let mut child = CSpace {
+            capabilities: BTreeMap::new(),
+            next_id: self.next_id,
+            revocation_endpoint: None,
+            recycle_ids: self.recycle_ids,
+            free_ids: Vec::new(),
+            failed_lookups: 0,
+        };
+
+        let now = now_ticks();
+        for cap in self.capabilities.values().filter(|cap| !cap.is_expired(now)) {
+            if let Some(derived) = filter(cap).and_then(|rights| cap.derive(cap.id(), rights)) {
+                child.insert(derived);
+            }
+        }
+        child
+    }
+
+    /// Register the endpoint that receives revocation notifications
+    ///
+    /// Returns false if `cap_id` isn't an endpoint capability in this CSpace.
+    pub fn set_revocation_endpoint(&mut self, cap_id: CapabilityId) -> bool {
+        match self.get(cap_id) {
+            Some(cap) if cap.resource_type() == ResourceType::Endpoint => {
+                self.revocation_endpoint = Some(cap_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Revoke a capability along with every copy derived from it in `holders`
+    ///
+    /// See revoke_derived for what counts as a derived copy. Notices for
+    /// holders with a revocation endpoint are appended to `notices`; send
+    /// them with send_revocation_notices once every lock is released.
+    /// Returns the number of capabilities revoked, root included.
+    pub fn revoke_tree(
+        &mut self,
+        id: CapabilityId,
+        holders: &mut [&mut CSpace],
+        notices: &mut Vec<RevocationNotice>,
+    ) -> usize {
+        let root = match self.revoke(id) {
+            Some(cap) => cap,
+            None => return 0,
+        };
+        1 + holders.iter_mut().map(|holder| holder.revoke_derived(&root, notices)).sum::<usize>()
+    }
+
+    /// Revoke the capabilities in this CSpace that could derive from `root`
+    ///
+    /// CSpaces don't record where a copy came from, so a copy is anything
+    /// naming the same resource with rights `root` covers (derivation
+    /// only narrows rights). Capabilities with rights `root` lacks can't
+    /// have come from it and are kept, as is the registered revocation
+    /// endpoint, so the holder still hears about the rest. A notice per
+    /// revoked capability is appended to `notices` if an endpoint is set.
+    pub fn revoke_derived(&mut self, root: &Capability, notices: &mut Vec<RevocationNotice>) -> usize {
+        let derived: Vec<CapabilityId> = self.capabilities.values()
+            .filter(|cap| cap.refers_same_resource(root)
+                && root.grants_at_least(cap)
+                && Some(cap.id()) != self.revocation_endpoint)
+            .map(|cap| cap.id())
+            .collect();
+
+        let endpoint = self.revocation_endpoint
+            .and_then(|ep| self.get(ep))
+            .filter(|cap| cap.resource_type() == ResourceType::Endpoint)
+            .map(|cap| CapabilityId::new(cap.resource_id()));
+
+        for &cap_id in &derived {
+            self.revoke(cap_id);
+            if let Some(endpoint) = endpoint {
+                notices.push(RevocationNotice { endpoint, cap_id, resource_id: root.resource_id() });
+            }
+        }
+        derived.len()
+    }
+
+    /// Encode this CSpace for checkpointing (see CSPACE_HEADER_SIZE for the layout)
+    #[allow(dead_code, reason = "nothing checkpoints tasks yet; kept with its round-trip tests")]
+    pub fn serialize(&self) -> Vec<u8> {
+        let now = now_ticks();
+        let mut out = Vec::with_capacity(CSPACE_HEADER_SIZE + self.len() * CAP_RECORD_SIZE);
+        out.push(CSPACE_FORMAT_VERSION);
+        out.extend_from_slice(&self.next_id.to_le_bytes());
+        out.push(self.revocation_endpoint.is_some() as u8);
+        out.extend_from_slice(&self.revocation_endpoint.map_or(0, |id| id.value()).to_le_bytes());
+
+        for cap in self.capabilities.values() {
+            let rights = cap.rights;
+            let rights_bits = rights.read as u8
+                | (rights.write as u8) << 1
+                | (rights.execute as u8) << 2
+                | (rights.grant as u8) << 3;
+
+            out.extend_from_slice(&cap.id.value().to_le_bytes());
+            out.push(cap.resource_type.as_u32() as u8);
+            out.extend_from_slice(&cap.resource_id.to_le_bytes());
+            out.push(rights_bits);
+            out.push(cap.badge.is_some() as u8);
+            out.extend_from_slice(&cap.badge.unwrap_or(0).to_le_bytes());
+            out.push(cap.expires_at.is_some() as u8);
+            let remaining = cap.expires_at.map_or(0, |deadline| deadline.saturating_sub(now));
+            out.extend_from_slice(&remaining.to_le_bytes());
+        }
+        out
+    }
+
+    /// Rebuild a CSpace from `serialize` output
+    ///
+    /// Leases restart from the current tick with the time they had left.
+    /// next_id is raised past every restored slot if the header's is
+    /// lower, so create can't hand out an ID that is already taken.
+    #[allow(dead_code, reason = "nothing restores a checkpoint yet; kept with its round-trip tests")]
+    pub fn deserialize(bytes: &[u8]) -> Result<CSpace, CSpaceDecodeError> {
+        if bytes.len() < CSPACE_HEADER_SIZE
+            || !(bytes.len() - CSPACE_HEADER_SIZE).is_multiple_of(CAP_RECORD_SIZE)
+        {
+            return Err(CSpaceDecodeError::InvalidLength);
+        }
+        if bytes[0] != CSPACE_FORMAT_VERSION {
+            return Err(CSpaceDecodeError::UnsupportedVersion(bytes[0]));
+        }
+
+        let u64_at = |offset: usize| {
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(raw)
+        };
+        let flag_at = |offset: usize| match bytes[offset] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(CSpaceDecodeError::InvalidFlags),
+        };
+
+        let now = now_ticks();
+        let mut cspace = CSpace::new();
+        cspace.next_id = u64_at(1);
+        if flag_at(9)? {
+            cspace.revocation_endpoint = Some(CapabilityId::new(u64_at(10)));
+        }
+
+        for record in (CSPACE_HEADER_SIZE..bytes.len()).step_by(CAP_RECORD_SIZE) {
+            let resource_type = ResourceType::from_u32(bytes[record + 8] as u32)
+                .ok_or(CSpaceDecodeError::UnknownResourceType(bytes[record + 8]))?;
+
+            let rights_bits = bytes[record + 17];
+            if rights_bits & !0x0F != 0 {
+                return Err(CSpaceDecodeError::InvalidFlags);
+            }
+            let rights = Rights {
+                read: rights_bits & 1 != 0,
+                write: rights_bits & 2 != 0,
+                execute: rights_bits & 4 != 0,
+                grant: rights_bits & 8 != 0,
+            };
+
+            let mut cap = Capability::new(
+                CapabilityId::new(u64_at(record)),
+                resource_type,
+                u64_at(record + 9),
+                rights,
+            );
+            if flag_at(record + 18)? {
+                cap.badge = Some(u64_at(record + 19));
+            }
+            if flag_at(record + 27)? {
+                cap.expires_at = Some(now.saturating_add(u64_at(record + 28)));
+            }
+            let slot = cap.id().slot() as u64;
+            cspace.next_id = cspace.next_id.max(slot + 1);
+            cspace.insert(cap);
+        }
+        Ok(cspace)
+    }
+
     /// Get number of capabilities
     pub fn len(&self) -> usize {
         self.capabilities.len()
@@ -243,3 +768,194 @@ pub fn kernel_cspace() -> &'static Mutex<CSpace> {
 pub fn create_user_cspace() -> CSpace {
     CSpace::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that clone_restricted downgrades and drops caps per the filter
+    #[test_case]
+    fn test_clone_restricted() {
+        serial_print!("test_clone_restricted...");
+        let mut parent = CSpace::new();
+        let mem = parent.create(ResourceType::Memory, 0x1000, Rights::READ_WRITE);
+        let irq = parent.create(ResourceType::Interrupt, 33, Rights::ALL);
+        let ep = parent.create(ResourceType::Endpoint, 7, Rights::READ_WRITE);
+
+        let child = parent.clone_restricted(|cap| match cap.resource_type() {
+            ResourceType::Memory => Some(Rights::READ),
+            ResourceType::Interrupt => None,
+            _ => Some(cap.rights()),
+        });
+
+        assert_eq!(child.len(), 2);
+        assert_eq!(child.get(mem).unwrap().rights(), Rights::READ);
+        assert!(child.get(irq).is_none());
+        assert_eq!(child.get(ep).unwrap().rights(), Rights::READ_WRITE);
+        assert_eq!(parent.len(), 3);
+        assert_eq!(parent.get(mem).unwrap().rights(), Rights::READ_WRITE);
+        serial_println!("[ok]");
+    }
+
+    /// Test resource equivalence ignoring ids, and rights superset checks
+    #[test_case]
+    fn test_same_resource_and_rights_superset() {
+        serial_print!("test_same_resource_and_rights_superset...");
+        let rw = Capability::new(CapabilityId::new(1), ResourceType::Memory, 0x2000, Rights::READ_WRITE);
+        let ro = Capability::new(CapabilityId::new(9), ResourceType::Memory, 0x2000, Rights::READ);
+        let other_addr = Capability::new(CapabilityId::new(1), ResourceType::Memory, 0x3000, Rights::READ_WRITE);
+        let other_type = Capability::new(CapabilityId::new(2), ResourceType::Interrupt, 0x2000, Rights::READ_WRITE);
+
+        assert!(rw.refers_same_resource(&ro));
+        assert!(ro.refers_same_resource(&rw));
+        assert!(!rw.refers_same_resource(&other_addr));
+        assert!(!rw.refers_same_resource(&other_type));
+
+        assert!(rw.grants_at_least(&ro));
+        assert!(!ro.grants_at_least(&rw));
+        assert!(rw.grants_at_least(&other_addr));
+        serial_println!("[ok]");
+    }
+
+    /// Test that a serialized CSpace deserializes to an equal one
+    #[test_case]
+    fn test_cspace_serialize_round_trip() {
+        serial_print!("test_cspace_serialize_round_trip...");
+        let mut cspace = CSpace::new();
+        cspace.create(ResourceType::Memory, 0x1000, Rights::READ_WRITE);
+        cspace.create(ResourceType::Interrupt, 33, Rights::ALL);
+        let ep = cspace.create(ResourceType::Endpoint, 7, Rights::READ_WRITE);
+        cspace.mint(ep, 0xBEEF, Rights::READ).unwrap();
+        cspace.create(ResourceType::Admin, 0, Rights::NONE);
+        assert!(cspace.set_revocation_endpoint(ep));
+
+        let bytes = cspace.serialize();
+        assert_eq!(bytes.len(), CSPACE_HEADER_SIZE + 5 * CAP_RECORD_SIZE);
+        assert_eq!(CSpace::deserialize(&bytes), Ok(cspace));
+
+        assert_eq!(CSpace::deserialize(&bytes[..bytes.len() - 1]), Err(CSpaceDecodeError::InvalidLength));
+        let mut corrupt = bytes.clone();
+        corrupt[CSPACE_HEADER_SIZE + 8] = 42;
+        assert_eq!(CSpace::deserialize(&corrupt), Err(CSpaceDecodeError::UnknownResourceType(42)));
+        let mut old = bytes.clone();
+        old[0] = 1;
+        assert_eq!(CSpace::deserialize(&old), Err(CSpaceDecodeError::UnsupportedVersion(1)));
+
+        // A header claiming next_id 1 can't make create reuse a restored ID
+        let mut stale = bytes.clone();
+        stale[1..9].copy_from_slice(&1u64.to_le_bytes());
+        let mut restored = CSpace::deserialize(&stale).unwrap();
+        let fresh = restored.create(ResourceType::Memory, 0x2000, Rights::READ);
+        assert_eq!(restored.len(), 6);
+        assert_eq!(restored.get(fresh).map(|cap| cap.resource_id()), Some(0x2000));
+        assert!(restored.get(CapabilityId::new(1)).is_some_and(|cap| cap.resource_id() == 0x1000));
+        serial_println!("[ok]");
+    }
+
+    /// Test numeric round trips and stable names for every resource type
+    #[test_case]
+    fn test_resource_type_conversions() {
+        use alloc::format;
+
+        serial_print!("test_resource_type_conversions...");
+        for (n, ty) in ResourceType::ALL.iter().enumerate() {
+            assert_eq!(ty.as_u32(), n as u32);
+            assert_eq!(ResourceType::from_u32(ty.as_u32()), Some(*ty));
+            assert_eq!(format!("{}", ty), ty.as_str());
+        }
+        assert_eq!(ResourceType::from_u32(ResourceType::ALL.len() as u32), None);
+        assert_eq!(ResourceType::from_u32(u32::MAX), None);
+
+        // Names appear in logs and shell output; don't change them
+        let names: alloc::vec::Vec<&str> = ResourceType::ALL.iter().map(|ty| ty.as_str()).collect();
+        assert_eq!(names, ["memory", "interrupt", "thread", "endpoint", "wasm_module", "fuel", "admin"]);
+        serial_println!("[ok]");
+    }
+
+    /// Test that an expiring capability works until its tick, then not
+    #[test_case]
+    fn test_capability_expires_at_tick() {
+        use crate::benchmark::{advance_mock, set_mock_clock, CYCLES_PER_TICK};
+
+        serial_print!("test_capability_expires_at_tick...");
+        set_mock_clock(true);
+        let mut cspace = CSpace::new();
+        let lease = cspace.create_expiring(ResourceType::Memory, 0x4000, Rights::READ_WRITE, 3);
+        let forever = cspace.create(ResourceType::Memory, 0x5000, Rights::READ);
+        let derived = cspace.derive(lease, Rights::READ).unwrap();
+
+        advance_mock(2 * CYCLES_PER_TICK);
+        assert_eq!(cspace.get(lease).and_then(|cap| cap.expires_at()), Some(3));
+        assert_eq!(cspace.get(derived).and_then(|cap| cap.expires_at()), Some(3));
+
+        // Past the deadline: looked up as absent, then swept for good
+        advance_mock(CYCLES_PER_TICK);
+        assert!(cspace.get(lease).is_none());
+        assert!(cspace.get(derived).is_none());
+        assert!(cspace.derive(lease, Rights::READ).is_none());
+        assert!(cspace.get(forever).is_some());
+        assert_eq!(cspace.sweep_expired(), 2);
+        assert_eq!(cspace.len(), 1);
+
+        set_mock_clock(false);
+        serial_println!("[ok]");
+    }
+
+    /// Test that a restored lease keeps the ticks it had left, not its deadline
+    #[test_case]
+    fn test_serialized_lease_keeps_remaining_ticks() {
+        use crate::benchmark::{advance_mock, set_mock_clock, CYCLES_PER_TICK};
+
+        serial_print!("test_serialized_lease_keeps_remaining_ticks...");
+        set_mock_clock(true);
+        let mut cspace = CSpace::new();
+        let lease = cspace.create_expiring(ResourceType::Memory, 0x4000, Rights::READ, now_ticks() + 5);
+        let bytes = cspace.serialize();
+
+        // Restored well after the original deadline passed
+        advance_mock(10 * CYCLES_PER_TICK);
+        let restored = CSpace::deserialize(&bytes).unwrap();
+        assert_eq!(restored.get(lease).and_then(|cap| cap.expires_at()), Some(now_ticks() + 5));
+
+        advance_mock(5 * CYCLES_PER_TICK);
+        assert!(restored.get(lease).is_none());
+
+        set_mock_clock(false);
+        serial_println!("[ok]");
+    }
+
+    /// Test that recycling reuses a revoked slot but rejects the stale ID
+    #[test_case]
+    fn test_id_recycling_bumps_generation() {
+        serial_print!("test_id_recycling_bumps_generation...");
+        // Monotonic by default: a revoked ID is never handed out again
+        let mut monotonic = CSpace::new();
+        let first = monotonic.create(ResourceType::Memory, 0x1000, Rights::READ);
+        monotonic.revoke(first);
+        assert_ne!(monotonic.create(ResourceType::Memory, 0x1000, Rights::READ), first);
+
+        let mut cspace = CSpace::new();
+        cspace.set_id_recycling(true);
+        let old = cspace.create(ResourceType::Memory, 0x1000, Rights::READ_WRITE);
+        let keep = cspace.create(ResourceType::Interrupt, 33, Rights::ALL);
+        assert!(cspace.revoke(old).is_some());
+
+        let new = cspace.create(ResourceType::Endpoint, 7, Rights::READ);
+        assert_eq!(new.slot(), old.slot());
+        assert_eq!(new.generation(), old.generation() + 1);
+        assert_ne!(new, old);
+
+        // The stale reference doesn't reach the capability now in its slot
+        assert!(cspace.get(old).is_none());
+        assert!(cspace.derive(old, Rights::READ).is_none());
+        assert!(cspace.revoke(old).is_none());
+        assert_eq!(cspace.get(new).unwrap().resource_type(), ResourceType::Endpoint);
+
+        // derive reuses slots too; a refused derive doesn't use one up
+        cspace.revoke(keep);
+        assert!(cspace.derive(new, Rights::ALL).is_none());
+        let derived = cspace.derive(new, Rights::READ).unwrap();
+        assert_eq!((derived.slot(), derived.generation()), (keep.slot(), 1));
+        serial_println!("[ok]");
+    }
+}
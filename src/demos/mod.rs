@@ -2,4 +2,122 @@
 
 mod wasm_tests;
 
-pub use wasm_tests::run_all_demos as run_demos;
+use core::ops::Range;
+use wasm_tests::{demo_01_add, demo_02_hello, demo_03_syscall, demo_04_mqtt, demo_05_security};
+
+/// A demo: (name, entry point)
+pub type Demo = (&'static str, fn());
+
+/// Every demo, in the order a full run uses (MQTT first: it needs a
+/// fresh broker)
+pub const DEMOS: &[Demo] = &[
+    ("mqtt", demo_04_mqtt),
+    ("add", demo_01_add),
+    ("hello", demo_02_hello),
+    ("syscall", demo_03_syscall),
+    ("security", demo_05_security),
+];
+
+/// Which demos to run
+pub enum DemoSelection {
+    /// The whole suite
+    All,
+    /// One demo, by its name in DEMOS
+    Named(&'static str),
+    /// Demos by position in DEMOS (clamped to the registry)
+    Range(Range<usize>),
+}
+
+/// What boot runs; narrow this (e.g. `Named("mqtt")`) while iterating on one demo
+pub const BOOT_DEMOS: DemoSelection = DemoSelection::All;
+
+/// Run the demos chosen at boot
+pub fn run_demos() {
+    run(BOOT_DEMOS);
+}
+
+/// Run the selected demos, returning how many ran
+pub fn run(selection: DemoSelection) -> usize {
+    serial_println!("\n╔════════════════════════════════════════════════════╗");
+    serial_println!("  JerichoOS WASM Demo Suite - Canonical Tests      ");
+    serial_println!("╚════════════════════════════════════════════════════╝");
+
+    // Start from an empty broker so a re-run doesn't see old subscribers
+    crate::wasm_runtime::reset_broker();
+
+    let ran = run_from(DEMOS, &selection);
+
+    serial_println!("╔════════════════════════════════════════════════════╗");
+    serial_println!("  WASM Demos Complete ({} run)                     ", ran);
+    serial_println!("╚════════════════════════════════════════════════════╝\n");
+    ran
+}
+
+/// Run the part of `registry` that `selection` picks
+fn run_from(registry: &[Demo], selection: &DemoSelection) -> usize {
+    let chosen: &[Demo] = match selection {
+        DemoSelection::All => registry,
+        DemoSelection::Named(name) => match registry.iter().position(|(n, _)| n == name) {
+            Some(i) => &registry[i..=i],
+            None => {
+                serial_println!("[DEMO] No demo named '{}'", name);
+                &[]
+            }
+        },
+        DemoSelection::Range(range) => {
+            let end = range.end.min(registry.len());
+            &registry[range.start.min(end)..end]
+        }
+    };
+
+    for (_, demo) in chosen {
+        demo();
+    }
+    chosen.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+    fn first() {
+        CALLS[0].fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn second() {
+        CALLS[1].fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn third() {
+        CALLS[2].fetch_add(1, Ordering::SeqCst);
+    }
+
+    const REGISTRY: &[Demo] = &[("first", first), ("second", second), ("third", third)];
+
+    fn calls() -> [usize; 3] {
+        core::array::from_fn(|i| CALLS[i].swap(0, Ordering::SeqCst))
+    }
+
+    /// Test that a named selection runs exactly that demo
+    #[test_case]
+    fn test_select_demo_by_name() {
+        serial_print!("test_select_demo_by_name...");
+        calls();
+
+        assert_eq!(run_from(REGISTRY, &DemoSelection::Named("second")), 1);
+        assert_eq!(calls(), [0, 1, 0]);
+
+        assert_eq!(run_from(REGISTRY, &DemoSelection::Named("missing")), 0);
+        assert_eq!(calls(), [0, 0, 0]);
+
+        assert_eq!(run_from(REGISTRY, &DemoSelection::Range(1..10)), 2);
+        assert_eq!(calls(), [0, 1, 1]);
+
+        assert_eq!(run_from(REGISTRY, &DemoSelection::All), 3);
+        assert_eq!(calls(), [1, 1, 1]);
+        serial_println!("[ok]");
+    }
+}
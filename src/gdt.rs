@@ -4,6 +4,7 @@
 //! - Code and data segments
 //! - TSS (Task State Segment) for interrupt handling
 
+use core::cell::UnsafeCell;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -12,9 +13,25 @@ use lazy_static::lazy_static;
 /// Double fault stack index in IST
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// Page fault stack index in IST
+///
+/// Task stacks grow on demand, so a fault on an unmapped stack page can't
+/// push its exception frame onto that same stack.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+/// Size of the boot ring 0 stack, used until a user task's own kernel
+/// stack is loaded (see set_kernel_stack)
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 5; // 20 KiB
+
+/// The TSS, writable so RSP0 can follow the running task
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Single core: only written with interrupts off (set_kernel_stack)
+unsafe impl Sync for Tss {}
+
 lazy_static! {
     /// Task State Segment
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = Tss(UnsafeCell::new({
         let mut tss = TaskStateSegment::new();
 
         // Set up the double fault stack
@@ -28,8 +45,22 @@ lazy_static! {
             stack_end // Stack grows downward
         };
 
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5; // 20 KiB
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
+        };
+
+        // Stack the CPU switches to on interrupts/exceptions from user tasks
+        tss.privilege_stack_table[0] = {
+            static mut STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
+            VirtAddr::from_ptr(&raw const STACK) + PRIVILEGE_STACK_SIZE as u64
+        };
+
         tss
-    };
+    }));
 }
 
 lazy_static! {
@@ -44,12 +75,19 @@ lazy_static! {
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
 
         // Add TSS segment
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        // SAFETY: TSS is never moved; later writes only touch RSP0
+        let tss_selector = gdt.append(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
+
+        // Add ring 3 segments (data before code, the order sysret expects)
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
 
         (gdt, Selectors {
             code_selector,
             data_selector,
-            tss_selector
+            tss_selector,
+            user_code_selector,
+            user_data_selector,
         })
     };
 }
@@ -59,6 +97,34 @@ struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+/// Kernel (ring 0) code and data selectors
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// User (ring 3) code and data selectors, RPL 3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Point RSP0 at the kernel stack of the task about to run
+///
+/// The CPU switches to RSP0 when an interrupt or exception arrives in
+/// ring 3, so every user task needs its own; the scheduler calls this on
+/// each switch to one. Call with interrupts disabled.
+pub fn set_kernel_stack(top: VirtAddr) {
+    // SAFETY: single core with interrupts off, and the CPU only reads
+    // RSP0 on a ring 3 -> ring 0 transition, which can't happen here
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] = top };
+}
+
+/// Current RSP0 (the stack ring 3 interrupts land on)
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] }
 }
 
 /// Initialize the GDT
@@ -80,3 +146,26 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Test that the GDT holds the ring 3 segments and the TSS has an RSP0
+#[test_case]
+fn test_user_segments_and_rsp0() {
+    use x86_64::PrivilegeLevel;
+    use x86_64::structures::gdt::DescriptorFlags;
+
+    serial_print!("test_user_segments_and_rsp0...");
+    let (user_code, user_data) = user_selectors();
+    assert_eq!(user_code.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(user_data.rpl(), PrivilegeLevel::Ring3);
+
+    let entries = GDT.0.entries();
+    assert_eq!(entries[user_code.index() as usize].raw(), DescriptorFlags::USER_CODE64.bits());
+    assert_eq!(entries[user_data.index() as usize].raw(), DescriptorFlags::USER_DATA.bits());
+    // sysret loads SS from the entry right before CS
+    assert_eq!(user_data.index() + 1, user_code.index());
+
+    let (kernel_code, _) = kernel_selectors();
+    assert_eq!(kernel_code.rpl(), PrivilegeLevel::Ring0);
+    assert_ne!(kernel_stack().as_u64(), 0);
+    serial_println!("[ok]");
+}
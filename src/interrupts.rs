@@ -7,6 +7,10 @@ use lazy_static::lazy_static;
 use crate::gdt;
 use pic8259::ChainedPics;
 use spin::Mutex;
+use alloc::vec::Vec;
+use crate::capability::{CapabilityId, CSpace, ResourceType};
+use crate::ipc::IpcError;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
 /// PIC interrupt offset
 /// We remap PIC interrupts to 32-47 (avoiding 0-31 which are CPU exceptions)
@@ -42,12 +46,18 @@ lazy_static! {
 
         // CPU Exception Handlers
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
         idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
 
         // Double fault handler with separate stack (from GDT/TSS)
         unsafe {
@@ -68,8 +78,19 @@ lazy_static! {
 
 /// Initialize the IDT and PICs
 pub fn init() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
     IDT.load();
 
+    // Report FP errors as #MF/#XM instead of the legacy IRQ 13 / #UD
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::NUMERIC_ERROR | Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
     // Initialize PICs
     unsafe {
         PICS.lock().initialize();
@@ -99,6 +120,15 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    // A task stack growing into a page it hasn't used yet
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if let Ok(addr) = Cr2::read() {
+            if crate::stack::handle_page_fault(addr) {
+                return;
+            }
+        }
+    }
+
     serial_println!("[EXCEPTION] PAGE FAULT");
     serial_println!("Accessed Address: {:?}", Cr2::read());
     serial_println!("Error Code: {:?}", error_code);
@@ -143,18 +173,110 @@ extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame)
     panic!("[EXCEPTION] DIVIDE ERROR (division by zero)\n{:#?}", stack_frame);
 }
 
+/// x87 floating-point exception handler (#MF)
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    serial_println!("[EXCEPTION] x87 FLOATING POINT\n{:#?}", stack_frame);
+    kill_faulting_task(stack_frame);
+}
+
+/// SIMD floating-point exception handler (#XM)
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let mut mxcsr = 0u32;
+    unsafe {
+        core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack));
+    }
+    serial_println!("[EXCEPTION] SIMD FLOATING POINT (MXCSR: {:#x})\n{:#?}", mxcsr, stack_frame);
+    kill_faulting_task(stack_frame);
+}
+
+/// Default MXCSR: all SIMD exceptions masked, round to nearest
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// Terminate the task that raised an FP exception instead of the kernel
+///
+/// Returning normally would re-run the faulting instruction, so the
+/// frame is rewritten to resume in scheduler::exit_current. Faults
+/// outside a task have nowhere to go and still panic.
+fn kill_faulting_task(mut stack_frame: InterruptStackFrame) {
+    // FP control state isn't saved per task: restore the masked defaults
+    // so whatever runs next doesn't inherit the unmasked exceptions
+    unsafe {
+        core::arch::asm!("fninit", "ldmxcsr [{}]", in(reg) &MXCSR_DEFAULT, options(nostack));
+    }
+
+    let task = crate::scheduler::SCHEDULER
+        .try_lock()
+        .and_then(|scheduler| scheduler.as_ref()?.current_task());
+    let task = match task {
+        Some(task) => task,
+        None => panic!("[EXCEPTION] floating-point exception outside a task"),
+    };
+    serial_println!("[EXCEPTION] Terminating task {}", task.value());
+
+    // Enter exit_current as if called: 16-byte aligned stack minus a return address
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = x86_64::VirtAddr::new(crate::scheduler::exit_current as *const () as u64);
+            frame.stack_pointer = x86_64::VirtAddr::new((frame.stack_pointer.as_u64() & !0xF) - 8);
+        });
+    }
+}
+
+/// Number of legacy PIC IRQ lines (two chained 8259s)
+pub const IRQ_LINES: usize = 16;
+
+/// Interrupts taken per IRQ line since boot (or the last reset)
+static IRQ_COUNTS: [core::sync::atomic::AtomicU64; IRQ_LINES] =
+    [const { core::sync::atomic::AtomicU64::new(0) }; IRQ_LINES];
+
+/// Count one interrupt on `irq` (called from each IRQ handler)
+fn count_irq(irq: u8) {
+    if let Some(counter) = IRQ_COUNTS.get(irq as usize) {
+        counter.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the per-IRQ counters, indexed by IRQ line
+pub fn irq_counts() -> [u64; IRQ_LINES] {
+    core::array::from_fn(|irq| IRQ_COUNTS[irq].load(core::sync::atomic::Ordering::Relaxed))
+}
+
+/// Zero the per-IRQ counters (timer_ticks is not affected)
+pub fn reset_irq_counts() {
+    for counter in &IRQ_COUNTS {
+        counter.store(0, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
 /// Timer tick counter
 static TIMER_TICKS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
 
 /// Get timer tick count
 pub fn timer_ticks() -> u64 {
+    #[cfg(test)]
+    if let Some(ticks) = crate::benchmark::mock_ticks() {
+        return ticks;
+    }
+
     TIMER_TICKS.load(core::sync::atomic::Ordering::Relaxed)
 }
 
+/// Timer interrupt rate set by init_timer (0 = not started)
+static TIMER_HZ: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// Timer ticks per second, or 0 before init_timer
+pub fn timer_frequency() -> u32 {
+    TIMER_HZ.load(core::sync::atomic::Ordering::Relaxed)
+}
+
 /// Timer interrupt handler (IRQ 0)
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Timestamp entry first for the interrupt latency benchmark
+    crate::benchmark::record_timer_entry();
+
     // Increment tick counter
     let ticks = TIMER_TICKS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    count_irq(TIMER_IRQ);
 
     // Verbose logging only in debug builds (reduces overhead)
     #[cfg(debug_assertions)]
@@ -165,10 +287,17 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
         }
     }
 
+    // Wake any tasks whose sleep/timeout deadline has passed, and
+    // receivers an IRQ couldn't wake itself
+    crate::scheduler::wake_sleepers(ticks + 1);
+    crate::ipc::wake_deferred();
+    crate::scheduler::record_load_tick();
+
     // Preemptive multitasking: yield to scheduler on every tick
     // This enables time-slice based task switching
-    if ticks > 0 {  // Skip first tick (timer setup)
-        crate::scheduler::task_yield();
+    // (deferred while the current task has preemption disabled)
+    if ticks > 0 && crate::scheduler::preempt_check() {  // Skip first tick (timer setup)
+        crate::scheduler::preempt_yield();
     }
 
     // Acknowledge interrupt
@@ -178,6 +307,159 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+/// Timer IRQ line (PIC input, not IDT vector)
+pub const TIMER_IRQ: u8 = 0;
+
+/// Keyboard IRQ line (PIC input, not IDT vector)
+pub const KEYBOARD_IRQ: u8 = 1;
+
+/// IRQ routes to task endpoints: (irq, endpoint id)
+static IRQ_ROUTES: Mutex<Vec<(u8, CapabilityId)>> = Mutex::new(Vec::new());
+
+/// Route an IRQ to an endpoint as IPC notifications
+///
+/// Each time the IRQ fires, the endpoint receives a kernel notification
+/// carrying the IRQ's data (the scancode for the keyboard), as an inline
+/// message read with ipc::try_receive_small.
+///
+/// # Security
+/// - `irq_cap` must be an Interrupt capability in `cspace` whose
+///   resource_id is the IRQ number, with READ rights
+/// - `endpoint_cap` must be an Endpoint capability in `cspace` with READ
+///   rights (the holder must be able to receive what it registers for)
+pub fn register_irq_handler(
+    cspace: &CSpace,
+    irq_cap: CapabilityId,
+    endpoint_cap: CapabilityId,
+) -> Result<u8, IpcError> {
+    let irq = cspace.get(irq_cap).ok_or(IpcError::PermissionDenied)?;
+    if irq.resource_type() != ResourceType::Interrupt || !irq.rights().read {
+        return Err(IpcError::PermissionDenied);
+    }
+    let irq_number = u8::try_from(irq.resource_id()).map_err(|_| IpcError::PermissionDenied)?;
+
+    let endpoint = cspace.get(endpoint_cap).ok_or(IpcError::PermissionDenied)?;
+    if endpoint.resource_type() != ResourceType::Endpoint || !endpoint.rights().read {
+        return Err(IpcError::PermissionDenied);
+    }
+    let endpoint_id = CapabilityId::new(endpoint.resource_id());
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut routes = IRQ_ROUTES.lock();
+        if !routes.contains(&(irq_number, endpoint_id)) {
+            routes.push((irq_number, endpoint_id));
+        }
+    });
+    Ok(irq_number)
+}
+
+/// Stop routing `irq` to an endpoint (see register_irq_handler)
+///
+/// Returns false if no such route was registered.
+pub fn unregister_irq_route(irq: u8, endpoint_id: CapabilityId) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut routes = IRQ_ROUTES.lock();
+        let before = routes.len();
+        routes.retain(|&route| route != (irq, endpoint_id));
+        routes.len() != before
+    })
+}
+
+/// Notify every endpoint registered for `irq` (interrupt context)
+///
+/// Notifications are inline messages, so nothing here allocates.
+fn dispatch_irq(irq: u8, data: &[u8]) {
+    let routes = match IRQ_ROUTES.try_lock() {
+        Some(routes) => routes,
+        None => return, // registration in progress on this core
+    };
+    for &(_, endpoint) in routes.iter().filter(|&&(line, _)| line == irq) {
+        // Dropped if the interrupted code holds the IPC locks or the queue is full
+        let _ = crate::ipc::send_small_from_interrupt(endpoint, data);
+    }
+}
+
+/// Scancode decoder; keeps modifier state between interrupts
+static KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+    Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore));
+
+/// Decoded characters kept for readers when no task has input focus
+pub const INPUT_BUFFER_SIZE: usize = 256;
+
+/// Fixed ring of decoded characters (no allocation in the ISR)
+struct InputBuffer {
+    chars: [char; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    /// Append a character, dropping it if the buffer is full
+    fn push(&mut self, ch: char) {
+        if self.len < INPUT_BUFFER_SIZE {
+            self.chars[(self.head + self.len) % INPUT_BUFFER_SIZE] = ch;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.len == 0 {
+            return None;
+        }
+        let ch = self.chars[self.head];
+        self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(ch)
+    }
+}
+
+static INPUT_BUFFER: Mutex<InputBuffer> = Mutex::new(InputBuffer {
+    chars: ['\0'; INPUT_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+});
+
+/// Take the oldest character typed while no task had input focus
+pub fn read_input() -> Option<char> {
+    x86_64::instructions::interrupts::without_interrupts(|| INPUT_BUFFER.lock().pop())
+}
+
+/// Feed a scancode to the decoder, returning the character it completes
+fn decode_scancode(scancode: u8) -> Option<char> {
+    let mut keyboard = KEYBOARD.try_lock()?;
+    let event = keyboard.add_byte(scancode).ok()??;
+    match keyboard.process_keyevent(event)? {
+        DecodedKey::Unicode(ch) => Some(ch),
+        DecodedKey::RawKey(_) => None,
+    }
+}
+
+/// Deliver a decoded character to the focused task, or buffer it
+fn route_input(ch: char) {
+    match crate::scheduler::focused_input_endpoint() {
+        Some(endpoint) => {
+            let mut utf8 = [0; 4];
+            // Dropped if the interrupted code holds the IPC locks or the queue is full
+            let _ = crate::ipc::send_small_from_interrupt(endpoint, ch.encode_utf8(&mut utf8).as_bytes());
+        }
+        None => {
+            if let Some(mut buffer) = INPUT_BUFFER.try_lock() {
+                buffer.push(ch);
+            }
+        }
+    }
+}
+
+/// Handle one keyboard scancode (split out of the ISR for testing)
+fn handle_keyboard_scancode(scancode: u8) {
+    count_irq(KEYBOARD_IRQ);
+    serial_println!("[KEYBOARD] Scancode: {:#x}", scancode);
+    dispatch_irq(KEYBOARD_IRQ, &[scancode]);
+    if let Some(ch) = decode_scancode(scancode) {
+        route_input(ch);
+    }
+}
+
 /// Keyboard interrupt handler (IRQ 1)
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
@@ -186,7 +468,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
-    serial_println!("[KEYBOARD] Scancode: {:#x}", scancode);
+    handle_keyboard_scancode(scancode);
 
     unsafe {
         PICS.lock()
@@ -222,6 +504,7 @@ pub fn init_timer(frequency_hz: u32) {
         data_port.write(((divisor >> 8) & 0xFF) as u8);
     }
 
+    TIMER_HZ.store(frequency_hz, core::sync::atomic::Ordering::Relaxed);
     serial_println!("[TIMER] PIT configured, enabling interrupts");
 
     // Enable interrupts globally
@@ -238,3 +521,151 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
     serial_println!("[ok]");
 }
+
+/// Test that a keyboard IRQ holder gets scancodes as IPC notifications
+#[test_case]
+fn test_keyboard_irq_notifies_holder() {
+    use crate::capability::{Capability, Rights};
+    use crate::task::TaskId;
+
+    serial_print!("test_keyboard_irq_notifies_holder...");
+    let endpoint_id = CapabilityId::new(7020);
+    crate::ipc::create_endpoint(endpoint_id).unwrap();
+
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(CapabilityId::new(1), ResourceType::Interrupt, KEYBOARD_IRQ as u64, Rights::READ));
+    cspace.insert(Capability::new(CapabilityId::new(2), ResourceType::Endpoint, endpoint_id.value(), Rights::READ));
+
+    // Registering needs an Interrupt cap, not just any cap
+    assert_eq!(register_irq_handler(&cspace, CapabilityId::new(2), CapabilityId::new(2)), Err(IpcError::PermissionDenied));
+    assert_eq!(register_irq_handler(&cspace, CapabilityId::new(1), CapabilityId::new(2)), Ok(KEYBOARD_IRQ));
+
+    // Inject a scancode as the ISR would
+    x86_64::instructions::interrupts::without_interrupts(|| handle_keyboard_scancode(0x1e));
+    assert!(unregister_irq_route(KEYBOARD_IRQ, endpoint_id));
+    assert!(!unregister_irq_route(KEYBOARD_IRQ, endpoint_id));
+
+    let msg = crate::ipc::try_receive_small(TaskId::new(9010), &cspace, CapabilityId::new(2))
+        .unwrap()
+        .expect("no IRQ notification delivered");
+    assert_eq!(msg.sender, crate::ipc::KERNEL_SENDER);
+    assert_eq!(msg.data(), [0x1e]);
+
+    // Unregistered: further scancodes don't reach the endpoint
+    x86_64::instructions::interrupts::without_interrupts(|| handle_keyboard_scancode(0x9e));
+    assert!(crate::ipc::try_receive_small(TaskId::new(9010), &cspace, CapabilityId::new(2)).unwrap().is_none());
+    serial_println!("[ok]");
+}
+
+/// Test that decoded keys go to the focused task's input endpoint
+#[test_case]
+fn test_keyboard_input_follows_focus() {
+    use crate::capability::{Capability, Rights};
+    use crate::scheduler;
+    use crate::task::TaskId;
+
+    serial_print!("test_keyboard_input_follows_focus...");
+    let endpoint_id = CapabilityId::new(7080);
+    crate::ipc::create_endpoint(endpoint_id).unwrap();
+    let task = TaskId::new(9017);
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, endpoint_id.value(), Rights::READ));
+    scheduler::register_input_endpoint(task, &cspace, CapabilityId::new(1)).unwrap();
+    scheduler::set_input_focus(task);
+    while read_input().is_some() {}
+
+    // "h" and "i", each pressed and released
+    let type_keys = |scancodes: &[u8]| x86_64::instructions::interrupts::without_interrupts(|| {
+        for &scancode in scancodes {
+            handle_keyboard_scancode(scancode);
+        }
+    });
+    type_keys(&[0x23, 0xa3, 0x17, 0x97]);
+    for expected in [b"h", b"i"] {
+        let msg = crate::ipc::try_receive_small(task, &cspace, CapabilityId::new(1))
+            .unwrap()
+            .expect("focused task got no input");
+        assert_eq!(msg.data(), expected);
+    }
+    assert_eq!(read_input(), None);
+
+    // Without focus, input waits in the global buffer
+    scheduler::clear_input_focus();
+    type_keys(&[0x1e, 0x9e]);
+    assert_eq!(read_input(), Some('a'));
+    assert!(crate::ipc::try_receive_small(task, &cspace, CapabilityId::new(1)).unwrap().is_none());
+    serial_println!("[ok]");
+}
+
+/// Test that keyboard interrupts are counted exactly, apart from the timer
+#[test_case]
+fn test_irq_counts_per_line() {
+    serial_print!("test_irq_counts_per_line...");
+    let (before, after) = x86_64::instructions::interrupts::without_interrupts(|| {
+        reset_irq_counts();
+        let before = irq_counts();
+        for scancode in [0x1e, 0x9e, 0x30, 0xb0, 0x2e] {
+            handle_keyboard_scancode(scancode);
+        }
+        (before, irq_counts())
+    });
+    assert_eq!(before[KEYBOARD_IRQ as usize], 0);
+    assert_eq!(after[KEYBOARD_IRQ as usize], 5);
+    // No timer interrupt could land with interrupts off
+    assert_eq!(after[TIMER_IRQ as usize], before[TIMER_IRQ as usize]);
+
+    // With the PIT running the timer line keeps counting on its own
+    if x86_64::instructions::interrupts::are_enabled() && TIMER_TICKS.load(core::sync::atomic::Ordering::Relaxed) > 0 {
+        let start = irq_counts()[TIMER_IRQ as usize];
+        while irq_counts()[TIMER_IRQ as usize] == start {
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(irq_counts()[KEYBOARD_IRQ as usize], 5);
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that a SIMD exception kills the faulting task, not the kernel
+#[test_case]
+fn test_simd_exception_terminates_task() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::scheduler;
+    use crate::task::TaskState;
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    static SURVIVED: AtomicBool = AtomicBool::new(false);
+
+    fn divide_by_zero_main() -> ! {
+        // Unmask divide-by-zero so divss raises #XM. The kernel is built
+        // without SSE, so nothing of ours lives in xmm0/xmm1.
+        let mxcsr: u32 = MXCSR_DEFAULT & !(1 << 9);
+        unsafe {
+            core::arch::asm!(
+                "ldmxcsr [{mxcsr}]",
+                "movd xmm0, {one:e}",
+                "xorps xmm1, xmm1",
+                "divss xmm0, xmm1",
+                mxcsr = in(reg) &mxcsr,
+                one = in(reg) 1.0f32.to_bits(),
+                options(nostack),
+            );
+        }
+        SURVIVED.store(true, Ordering::SeqCst);
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    serial_print!("test_simd_exception_terminates_task...");
+    let task = TestTask::spawn("simd-fault", divide_by_zero_main);
+    assert!(yield_until(100, || task.with(|t| t.state()) == TaskState::Terminated));
+    assert!(!SURVIVED.load(Ordering::SeqCst));
+
+    // FP control state was reset for everyone else (status flags aside)
+    let mut mxcsr = 0u32;
+    unsafe {
+        core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack));
+    }
+    assert_eq!(mxcsr & !0x3F, MXCSR_DEFAULT);
+    serial_println!("[ok]");
+}
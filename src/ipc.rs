@@ -2,13 +2,17 @@
 
 use alloc::collections::VecDeque;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
-use crate::capability::{CapabilityId, CSpace, ResourceType};
-use crate::task::TaskId;
+use crate::capability::{Capability, CapabilityId, CSpace, ResourceType};
+use crate::task::{BlockReason, Priority, TaskId};
 
 /// Maximum message size in bytes
 pub const MAX_MESSAGE_SIZE: usize = 4096;
 
+/// Sender ID used for kernel-originated messages (real task IDs start at 1)
+pub const KERNEL_SENDER: TaskId = TaskId::new(0);
+
 /// IPC Message
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -18,8 +22,15 @@ pub struct Message {
     /// Message data (up to MAX_MESSAGE_SIZE)
     pub data: Vec<u8>,
 
-    /// Optional capability being transferred
-    pub transferred_cap: Option<CapabilityId>,
+    /// Capabilities being transferred (IDs in the sender's CSpace)
+    pub transferred_caps: Vec<CapabilityId>,
+
+    /// The transferred capabilities, moved out of the sender's CSpace
+    /// until the receiver accepts them (see accept_capabilities)
+    caps_in_transit: Vec<Capability>,
+
+    /// Badge of the capability the message was sent through (see CSpace::mint)
+    pub badge: Option<u64>,
 }
 
 impl Message {
@@ -32,7 +43,9 @@ impl Message {
         Ok(Message {
             sender,
             data,
-            transferred_cap: None,
+            transferred_caps: Vec::new(),
+            caps_in_transit: Vec::new(),
+            badge: None,
         })
     }
 
@@ -42,15 +55,69 @@ impl Message {
         data: Vec<u8>,
         cap: CapabilityId,
     ) -> Result<Self, IpcError> {
-        if data.len() > MAX_MESSAGE_SIZE {
+        Self::with_capabilities(sender, data, alloc::vec![cap])
+    }
+
+    /// Create a message transferring several capabilities
+    pub fn with_capabilities(
+        sender: TaskId,
+        data: Vec<u8>,
+        caps: Vec<CapabilityId>,
+    ) -> Result<Self, IpcError> {
+        let mut message = Self::new(sender, data)?;
+        message.transferred_caps = caps;
+        Ok(message)
+    }
+
+    /// First transferred capability (single-capability messages)
+    pub fn transferred_cap(&self) -> Option<CapabilityId> {
+        self.transferred_caps.first().copied()
+    }
+
+    /// Move the capabilities carried by this message into `cspace`
+    ///
+    /// Each gets a fresh ID in the receiver's CSpace (rights and badge are
+    /// kept); returns the new IDs in transfer order. Capabilities of a
+    /// message that is dropped without being accepted are gone for good.
+    pub fn accept_capabilities(&mut self, cspace: &mut CSpace) -> Vec<CapabilityId> {
+        self.caps_in_transit
+            .drain(..)
+            .map(|cap| cspace.insert_transferred(cap))
+            .collect()
+    }
+}
+
+/// Largest payload an InlineMessage carries
+pub const INLINE_MESSAGE_SIZE: usize = 64;
+
+/// Small message stored inline, so sending it never touches the heap
+///
+/// Used by send_small/try_receive_small for control traffic; anything
+/// bigger goes through Message.
+#[derive(Debug, Clone, Copy)]
+pub struct InlineMessage {
+    /// Sender task ID
+    pub sender: TaskId,
+    /// Badge of the capability the message was sent through
+    pub badge: Option<u64>,
+    len: u8,
+    buf: [u8; INLINE_MESSAGE_SIZE],
+}
+
+impl InlineMessage {
+    /// Copy `data` into a new inline message
+    pub fn new(sender: TaskId, data: &[u8]) -> Result<Self, IpcError> {
+        if data.len() > INLINE_MESSAGE_SIZE {
             return Err(IpcError::MessageTooLarge);
         }
+        let mut buf = [0; INLINE_MESSAGE_SIZE];
+        buf[..data.len()].copy_from_slice(data);
+        Ok(InlineMessage { sender, badge: None, len: data.len() as u8, buf })
+    }
 
-        Ok(Message {
-            sender,
-            data,
-            transferred_cap: Some(cap),
-        })
+    /// Message payload
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
     }
 }
 
@@ -62,8 +129,20 @@ pub struct IpcEndpoint {
     /// Message queue
     messages: VecDeque<Message>,
 
-    /// Tasks waiting to receive messages
-    waiting_tasks: Vec<TaskId>,
+    /// Inline message queue, allocated up front for max_queue_size entries
+    small_messages: VecDeque<InlineMessage>,
+
+    /// Tasks waiting to receive messages, with their priority at wait time
+    ///
+    /// Kept in wake-up order: priority descending, then arrival ascending.
+    waiting_tasks: Vec<(TaskId, Priority)>,
+
+    /// Waiters woken by take_highest_waiter that haven't received yet
+    ///
+    /// If another receiver took the message first, such a task waits again
+    /// at the head of its priority instead of the tail, so it can't be
+    /// skipped forever. Holds at most max_queue_size entries.
+    woken: Vec<TaskId>,
 
     /// Maximum queue size
     max_queue_size: usize,
@@ -72,17 +151,25 @@ pub struct IpcEndpoint {
 impl IpcEndpoint {
     /// Create a new IPC endpoint
     pub fn new(id: CapabilityId) -> Self {
+        let max_queue_size = 16;  // Max 16 pending messages
         IpcEndpoint {
             id,
             messages: VecDeque::new(),
+            small_messages: VecDeque::with_capacity(max_queue_size),
             waiting_tasks: Vec::new(),
-            max_queue_size: 16,  // Max 16 pending messages
+            woken: Vec::new(),
+            max_queue_size,
         }
     }
 
+    /// Messages of both kinds waiting to be received
+    fn queued(&self) -> usize {
+        self.messages.len() + self.small_messages.len()
+    }
+
     /// Send a message to this endpoint
     pub fn send(&mut self, message: Message) -> Result<(), IpcError> {
-        if self.messages.len() >= self.max_queue_size {
+        if self.queued() >= self.max_queue_size {
             return Err(IpcError::QueueFull);
         }
 
@@ -101,21 +188,177 @@ impl IpcEndpoint {
         self.messages.pop_front()
     }
 
+    /// Queue an inline message (shares max_queue_size with send)
+    ///
+    /// Never allocates: the queue was sized for max_queue_size entries
+    /// when the endpoint was created.
+    pub fn send_small(&mut self, message: InlineMessage) -> Result<(), IpcError> {
+        if self.queued() >= self.max_queue_size {
+            return Err(IpcError::QueueFull);
+        }
+        self.small_messages.push_back(message);
+        Ok(())
+    }
+
+    /// Receive an inline message (non-blocking)
+    pub fn try_receive_small(&mut self) -> Option<InlineMessage> {
+        self.small_messages.pop_front()
+    }
+
     /// Check if there are pending messages
     pub fn has_messages(&self) -> bool {
         !self.messages.is_empty()
     }
 
     /// Add a task to the waiting list
-    pub fn add_waiter(&mut self, task: TaskId) {
-        if !self.waiting_tasks.contains(&task) {
-            self.waiting_tasks.push(task);
+    ///
+    /// Goes behind every waiter of the same or higher priority. A task
+    /// woken earlier that missed its message goes ahead of its equals
+    /// instead: it was the longest-waiting of them when it was woken.
+    pub fn add_waiter(&mut self, task: TaskId, priority: Priority) {
+        if self.waiting_tasks.iter().any(|&(t, _)| t == task) {
+            return;
         }
+        let rewaiting = self.woken.contains(&task);
+        self.woken.retain(|&t| t != task);
+
+        let pos = self.waiting_tasks.iter()
+            .position(|&(_, p)| p < priority || (rewaiting && p == priority))
+            .unwrap_or(self.waiting_tasks.len());
+        self.waiting_tasks.insert(pos, (task, priority));
+    }
+
+    /// Remove a task from the waiting list (e.g. after a timeout)
+    pub fn remove_waiter(&mut self, task: TaskId) {
+        self.waiting_tasks.retain(|&(t, _)| t != task);
+        self.woken.retain(|&t| t != task);
+    }
+
+    /// Remove and return the highest-priority waiter (FIFO among equals)
+    ///
+    /// Senders wake one waiter per message instead of the whole list, so
+    /// a low-priority task can't grab a message a high-priority one is
+    /// waiting for.
+    pub fn take_highest_waiter(&mut self) -> Option<TaskId> {
+        if self.waiting_tasks.is_empty() {
+            return None;
+        }
+        let (task, _) = self.waiting_tasks.remove(0);
+        if self.woken.len() >= self.max_queue_size {
+            // A woken task that never came back (e.g. it exited)
+            self.woken.remove(0);
+        }
+        self.woken.push(task);
+        Some(task)
     }
 
-    /// Get and clear all waiting tasks
-    pub fn take_waiters(&mut self) -> Vec<TaskId> {
-        core::mem::take(&mut self.waiting_tasks)
+    /// Note that `task` received a message, ending any pending wake-up
+    fn mark_served(&mut self, task: TaskId) {
+        self.woken.retain(|&t| t != task);
+    }
+
+    /// Get endpoint ID
+    pub fn id(&self) -> CapabilityId {
+        self.id
+    }
+
+    /// Debug snapshot of this endpoint
+    pub fn info(&self) -> EndpointInfo {
+        EndpointInfo {
+            id: self.id,
+            queued: self.queued(),
+            waiters: self.waiting_tasks.len(),
+            max_depth: self.max_queue_size,
+        }
+    }
+}
+
+/// Debug snapshot of one endpoint (see list_endpoints)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointInfo {
+    pub id: CapabilityId,
+    /// Messages waiting, inline ones included
+    pub queued: usize,
+    /// Tasks blocked receiving
+    pub waiters: usize,
+    /// Messages the queue holds before sends fail with QueueFull
+    pub max_depth: usize,
+}
+
+/// Pending messages kept per broadcast subscriber before new ones are dropped
+pub const MAX_BROADCAST_QUEUE: usize = 16;
+
+/// A broadcast subscriber's private queue
+struct Subscriber {
+    task: TaskId,
+    messages: VecDeque<Message>,
+    /// Messages dropped because the queue was full
+    dropped: u64,
+}
+
+/// Broadcast endpoint - every send is copied to each subscriber
+///
+/// Unlike IpcEndpoint, where each message goes to exactly one receiver,
+/// subscribers get their own bounded queue. A slow subscriber only loses
+/// its own copies, never blocks the sender or the other subscribers.
+pub struct BroadcastEndpoint {
+    id: CapabilityId,
+    subscribers: Vec<Subscriber>,
+}
+
+impl BroadcastEndpoint {
+    /// Create a broadcast endpoint with no subscribers
+    pub fn new(id: CapabilityId) -> Self {
+        BroadcastEndpoint {
+            id,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Register a subscriber (no-op if already subscribed)
+    pub fn subscribe(&mut self, task: TaskId) {
+        if !self.subscribers.iter().any(|s| s.task == task) {
+            self.subscribers.push(Subscriber {
+                task,
+                messages: VecDeque::new(),
+                dropped: 0,
+            });
+        }
+    }
+
+    /// Remove a subscriber and discard its pending messages
+    pub fn unsubscribe(&mut self, task: TaskId) {
+        self.subscribers.retain(|s| s.task != task);
+    }
+
+    /// Queue a copy of `message` for every subscriber
+    ///
+    /// Returns how many subscribers got it; full queues drop their copy.
+    pub fn send(&mut self, message: &Message) -> usize {
+        let mut delivered = 0;
+        for sub in self.subscribers.iter_mut() {
+            if sub.messages.len() >= MAX_BROADCAST_QUEUE {
+                sub.dropped += 1;
+            } else {
+                sub.messages.push_back(message.clone());
+                delivered += 1;
+            }
+        }
+        delivered
+    }
+
+    /// Take the oldest message queued for `task`
+    pub fn try_receive(&mut self, task: TaskId) -> Option<Message> {
+        self.subscribers.iter_mut()
+            .find(|s| s.task == task)?
+            .messages
+            .pop_front()
+    }
+
+    /// Messages dropped for `task` (None if it isn't subscribed)
+    #[cfg(test)]
+    pub fn dropped(&self, task: TaskId) -> Option<u64> {
+        self.subscribers.iter().find(|s| s.task == task).map(|s| s.dropped)
     }
 
     /// Get endpoint ID
@@ -130,6 +373,7 @@ static IPC_REGISTRY: Mutex<Option<IpcRegistry>> = Mutex::new(None);
 /// IPC Endpoint Registry
 pub struct IpcRegistry {
     endpoints: Vec<IpcEndpoint>,
+    broadcasts: Vec<BroadcastEndpoint>,
 }
 
 impl IpcRegistry {
@@ -137,6 +381,7 @@ impl IpcRegistry {
     pub fn new() -> Self {
         IpcRegistry {
             endpoints: Vec::new(),
+            broadcasts: Vec::new(),
         }
     }
 
@@ -161,6 +406,22 @@ impl IpcRegistry {
     fn get_endpoint(&self, cap_id: CapabilityId) -> Option<&IpcEndpoint> {
         self.endpoints.iter().find(|ep| ep.id() == cap_id)
     }
+
+    /// Debug snapshot of every endpoint, in creation order
+    pub fn endpoint_infos(&self) -> Vec<EndpointInfo> {
+        self.endpoints.iter().map(|ep| ep.info()).collect()
+    }
+
+    /// Create a new broadcast endpoint
+    pub fn create_broadcast_endpoint(&mut self, cap_id: CapabilityId) -> CapabilityId {
+        self.broadcasts.push(BroadcastEndpoint::new(cap_id));
+        cap_id
+    }
+
+    /// Get mutable reference to broadcast endpoint
+    fn get_broadcast_mut(&mut self, cap_id: CapabilityId) -> Option<&mut BroadcastEndpoint> {
+        self.broadcasts.iter_mut().find(|ep| ep.id() == cap_id)
+    }
 }
 
 /// IPC Error types
@@ -180,6 +441,15 @@ pub enum IpcError {
 
     /// No message available
     NoMessage,
+
+    /// IPC system not initialized (ipc::init() not called)
+    NotInitialized,
+
+    /// A lock is held by the interrupted code (interrupt context only)
+    WouldBlock,
+
+    /// A chunked transfer had a missing, repeated or malformed chunk
+    BadChunk,
 }
 
 /// Initialize the IPC system
@@ -191,17 +461,291 @@ pub fn init() {
 /// Create a new IPC endpoint
 pub fn create_endpoint(cap_id: CapabilityId) -> Result<CapabilityId, IpcError> {
     let mut registry = IPC_REGISTRY.lock();
-    let registry = registry.as_mut().ok_or(IpcError::EndpointNotFound)?;
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
     Ok(registry.create_endpoint(cap_id))
 }
 
+/// Create a new broadcast endpoint (see BroadcastEndpoint)
+pub fn create_broadcast_endpoint(cap_id: CapabilityId) -> Result<CapabilityId, IpcError> {
+    let mut registry = IPC_REGISTRY.lock();
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+    Ok(registry.create_broadcast_endpoint(cap_id))
+}
+
+/// List every endpoint with its queue depth and waiter count
+///
+/// Empty if IPC isn't initialized. Broadcast endpoints aren't included.
+pub fn list_endpoints() -> Vec<EndpointInfo> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IPC_REGISTRY.lock().as_ref().map(|r| r.endpoint_infos()).unwrap_or_default()
+    })
+}
+
+/// Resolve an endpoint capability, requiring read or write rights
+///
+/// Returns the target endpoint id and the capability's badge.
+fn check_endpoint_cap(
+    cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    write: bool,
+) -> Result<(CapabilityId, Option<u64>), IpcError> {
+    let cap = cspace.get(endpoint_cap).ok_or(IpcError::PermissionDenied)?;
+
+    if cap.resource_type() != ResourceType::Endpoint {
+        return Err(IpcError::PermissionDenied);
+    }
+
+    let allowed = if write { cap.rights().write } else { cap.rights().read };
+    if !allowed {
+        return Err(IpcError::PermissionDenied);
+    }
+
+    Ok((CapabilityId::new(cap.resource_id()), cap.badge()))
+}
+
+/// Subscribe a task to a broadcast endpoint - needs read permission
+pub fn subscribe_broadcast(
+    subscriber: TaskId,
+    subscriber_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+) -> Result<(), IpcError> {
+    let (endpoint_id, _) = check_endpoint_cap(subscriber_cspace, endpoint_cap, false)?;
+
+    let mut registry = IPC_REGISTRY.lock();
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+    registry.get_broadcast_mut(endpoint_id)
+        .ok_or(IpcError::EndpointNotFound)?
+        .subscribe(subscriber);
+    Ok(())
+}
+
+/// Unsubscribe a task from a broadcast endpoint, dropping its queue
+pub fn unsubscribe_broadcast(
+    subscriber: TaskId,
+    subscriber_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+) -> Result<(), IpcError> {
+    let (endpoint_id, _) = check_endpoint_cap(subscriber_cspace, endpoint_cap, false)?;
+
+    let mut registry = IPC_REGISTRY.lock();
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+    registry.get_broadcast_mut(endpoint_id)
+        .ok_or(IpcError::EndpointNotFound)?
+        .unsubscribe(subscriber);
+    Ok(())
+}
+
+/// Send to every subscriber of a broadcast endpoint - needs write permission
+///
+/// Returns the number of subscribers the message was queued for.
+/// Subscribers don't get woken; they poll with try_receive_broadcast.
+pub fn broadcast_message(
+    sender: TaskId,
+    sender_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    data: Vec<u8>,
+) -> Result<usize, IpcError> {
+    let (endpoint_id, badge) = check_endpoint_cap(sender_cspace, endpoint_cap, true)?;
+
+    // Same sender verification as send_message
+    let sender = match crate::scheduler::current_task_id() {
+        Some(real) if real != sender => return Err(IpcError::PermissionDenied),
+        Some(real) => real,
+        None => sender,
+    };
+
+    let mut message = Message::new(sender, data)?;
+    message.badge = badge;
+
+    let mut registry = IPC_REGISTRY.lock();
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+    let endpoint = registry.get_broadcast_mut(endpoint_id)
+        .ok_or(IpcError::EndpointNotFound)?;
+    Ok(endpoint.send(&message))
+}
+
+/// Take the next broadcast message queued for `receiver` (non-blocking)
+///
+/// The read capability is re-checked on every receive, so revoking it
+/// cuts the subscriber off even though it is still registered.
+pub fn try_receive_broadcast(
+    receiver: TaskId,
+    receiver_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+) -> Result<Option<Message>, IpcError> {
+    let (endpoint_id, _) = check_endpoint_cap(receiver_cspace, endpoint_cap, false)?;
+
+    let mut registry = IPC_REGISTRY.lock();
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+    let endpoint = registry.get_broadcast_mut(endpoint_id)
+        .ok_or(IpcError::EndpointNotFound)?;
+    Ok(endpoint.try_receive(receiver))
+}
+
+/// Send a payload of up to INLINE_MESSAGE_SIZE bytes without allocating
+///
+/// Same capability and sender checks as send_message, and like it wakes
+/// one blocked receiver. Inline messages queue separately from Message
+/// traffic on the same endpoint and are only seen by try_receive_small.
+pub fn send_small(
+    sender: TaskId,
+    sender_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    data: &[u8],
+) -> Result<(), IpcError> {
+    let (endpoint_id, badge) = check_endpoint_cap(sender_cspace, endpoint_cap, true)?;
+
+    // Same sender verification as send_message
+    let sender = match crate::scheduler::current_task_id() {
+        Some(real) if real != sender => return Err(IpcError::PermissionDenied),
+        Some(real) => real,
+        None => sender,
+    };
+
+    let mut message = InlineMessage::new(sender, data)?;
+    message.badge = badge;
+
+    let waiter = {
+        let mut registry = IPC_REGISTRY.lock();
+        let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+        let endpoint = registry.get_endpoint_mut(endpoint_id)
+            .ok_or(IpcError::EndpointNotFound)?;
+        endpoint.send_small(message)?;
+        endpoint.take_highest_waiter()
+    };
+    wake_waiter(waiter);
+    Ok(())
+}
+
+/// Take the next inline message queued on an endpoint (non-blocking)
+pub fn try_receive_small(
+    receiver: TaskId,
+    receiver_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+) -> Result<Option<InlineMessage>, IpcError> {
+    let (endpoint_id, _) = check_endpoint_cap(receiver_cspace, endpoint_cap, false)?;
+
+    let mut registry = IPC_REGISTRY.lock();
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+    let endpoint = registry.get_endpoint_mut(endpoint_id)
+        .ok_or(IpcError::EndpointNotFound)?;
+    let message = endpoint.try_receive_small();
+    if message.is_some() {
+        endpoint.mark_served(receiver);
+    }
+    Ok(message)
+}
+
 // send message to endpoint - checks capability write permission
+//
+// The sender id is verified against the scheduler's current task so a
+// task can't forge another task's id. With no current task (early boot,
+// kernel context) the supplied id is trusted.
 pub fn send_message(
     sender: TaskId,
     sender_cspace: &CSpace,
     endpoint_cap: CapabilityId,
     data: Vec<u8>,
 ) -> Result<(), IpcError> {
+    let current = crate::scheduler::current_task_id();
+    send_message_as(current, sender, sender_cspace, endpoint_cap, data)
+}
+
+/// Send with an explicitly verified current task (see send_message)
+fn send_message_as(
+    current: Option<TaskId>,
+    claimed_sender: TaskId,
+    sender_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    data: Vec<u8>,
+) -> Result<(), IpcError> {
+    let waiter = queue_as(current, claimed_sender, sender_cspace, endpoint_cap, data, Vec::new(), Vec::new())?;
+    wake_waiter(waiter);
+    Ok(())
+}
+
+/// Send a message that moves `caps` from the sender to the receiver
+///
+/// Every capability must be present in the sender task's CSpace and carry
+/// the grant right, or the send fails with PermissionDenied and nothing
+/// is moved. The same holds if the send itself fails (e.g. QueueFull):
+/// the sender only loses the capabilities once the message is queued.
+/// The receiver installs them with try_receive_with_caps.
+pub fn send_message_with_caps(
+    sender: TaskId,
+    endpoint_cap: CapabilityId,
+    data: Vec<u8>,
+    caps: &[CapabilityId],
+) -> Result<(), IpcError> {
+    // The sender's CSpace lives in the scheduler: check and move the caps
+    // under one borrow, then wake the receiver once the lock is released
+    let waiter = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = crate::scheduler::SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(IpcError::NotInitialized)?;
+        let current = scheduler.current_task();
+        let sender_cspace = scheduler.get_task_mut(sender)
+            .ok_or(IpcError::PermissionDenied)?
+            .cspace_mut();
+        queue_with_caps(current, sender, sender_cspace, endpoint_cap, data, caps)
+    })?;
+    wake_waiter(waiter);
+    Ok(())
+}
+
+/// Queue a message moving `caps` out of `sender_cspace`
+///
+/// Returns the waiter to wake; see send_message_with_caps.
+fn queue_with_caps(
+    current: Option<TaskId>,
+    claimed_sender: TaskId,
+    sender_cspace: &mut CSpace,
+    endpoint_cap: CapabilityId,
+    data: Vec<u8>,
+    caps: &[CapabilityId],
+) -> Result<Option<TaskId>, IpcError> {
+    // Check every capability before touching any of them
+    let mut moving = Vec::with_capacity(caps.len());
+    for (i, &id) in caps.iter().enumerate() {
+        let cap = sender_cspace.get(id).ok_or(IpcError::PermissionDenied)?;
+        if !cap.rights().grant || caps[..i].contains(&id) {
+            serial_println!("[IPC-DENIED] Capability {} can't be transferred", id.value());
+            return Err(IpcError::PermissionDenied);
+        }
+        moving.push(cap.clone());
+    }
+
+    let waiter = queue_as(current, claimed_sender, sender_cspace, endpoint_cap, data, caps.to_vec(), moving)?;
+
+    for &id in caps {
+        sender_cspace.revoke(id);
+    }
+    Ok(waiter)
+}
+
+/// Queue a message on an endpoint and return the waiter to wake
+///
+/// A claimed sender that doesn't match `current` is rejected; the message
+/// is always stamped with the verified id, never the caller-supplied one.
+/// Doesn't touch the scheduler, so callers may hold its lock.
+fn queue_as(
+    current: Option<TaskId>,
+    claimed_sender: TaskId,
+    sender_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    data: Vec<u8>,
+    transferred_caps: Vec<CapabilityId>,
+    caps_in_transit: Vec<Capability>,
+) -> Result<Option<TaskId>, IpcError> {
+    let sender = match current {
+        Some(real) if real != claimed_sender => {
+            serial_println!("[IPC-DENIED] Task {} tried to send as task {}",
+                real.value(), claimed_sender.value());
+            return Err(IpcError::PermissionDenied);
+        }
+        Some(real) => real,
+        None => claimed_sender,
+    };
+
     // verify caller has the capability they claim
     let cap = sender_cspace
         .get(endpoint_cap)
@@ -220,32 +764,120 @@ pub fn send_message(
     let target_endpoint_id = CapabilityId::new(cap.resource_id());
 
     let mut registry = IPC_REGISTRY.lock();
-    let registry = registry.as_mut().ok_or(IpcError::EndpointNotFound)?;
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
 
     let endpoint = registry.get_endpoint_mut(target_endpoint_id)
         .ok_or(IpcError::EndpointNotFound)?;
 
-    let message = Message::new(sender, data)?;
+    let mut message = Message::with_capabilities(sender, data, transferred_caps)?;
+    message.caps_in_transit = caps_in_transit;
+    message.badge = cap.badge();  // kernel-stamped, sender can't forge it
 
     endpoint.send(message)?;
 
-    // Wake up any waiting tasks
-    let waiters = endpoint.take_waiters();
-    let _ = registry;  // done with registry, drop it before touching scheduler
+    // Wake the highest-priority waiter (one message, one receiver)
+    Ok(endpoint.take_highest_waiter())
+}
 
-    for task_id in waiters {
-        crate::scheduler::SCHEDULER.lock()
-            .as_mut()
-            .unwrap()
-            .unblock_task(task_id);
+/// Unblock the waiter picked by queue_as (no locks may be held)
+fn wake_waiter(waiter: Option<TaskId>) {
+    if let Some(task_id) = waiter {
+        if let Some(scheduler) = crate::scheduler::SCHEDULER.lock().as_mut() {
+            scheduler.unblock_task(task_id);
+        }
+    }
+}
+
+/// Send a kernel-originated notification to an endpoint
+///
+/// Skips capability checks - only the kernel calls this (e.g. revocation
+/// notices). Messages carry KERNEL_SENDER as the sender.
+pub fn send_kernel_notification(endpoint_id: CapabilityId, data: Vec<u8>) -> Result<(), IpcError> {
+    let waiter = {
+        let mut registry = IPC_REGISTRY.lock();
+        let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+
+        let endpoint = registry.get_endpoint_mut(endpoint_id)
+            .ok_or(IpcError::EndpointNotFound)?;
+
+        endpoint.send(Message::new(KERNEL_SENDER, data)?)?;
+        endpoint.take_highest_waiter()
+    };
+
+    if let Some(task_id) = waiter {
+        if let Some(scheduler) = crate::scheduler::SCHEDULER.lock().as_mut() {
+            scheduler.unblock_task(task_id);
+        }
     }
 
     Ok(())
 }
 
+/// Send a small kernel notification from interrupt context
+///
+/// Queues an InlineMessage (read with try_receive_small) in the
+/// endpoint's preallocated inline queue, so the ISR never allocates.
+/// Only try-locks the registry. Wakes one blocked receiver, or defers
+/// the wake to the next timer tick if the interrupted code holds the
+/// scheduler (see wake_deferred).
+pub fn send_small_from_interrupt(endpoint_id: CapabilityId, data: &[u8]) -> Result<(), IpcError> {
+    let message = InlineMessage::new(KERNEL_SENDER, data)?;
+    let mut registry = IPC_REGISTRY.try_lock().ok_or(IpcError::WouldBlock)?;
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+
+    let endpoint = registry.get_endpoint_mut(endpoint_id)
+        .ok_or(IpcError::EndpointNotFound)?;
+    endpoint.send_small(message)?;
+
+    match crate::scheduler::SCHEDULER.try_lock() {
+        Some(mut scheduler) => {
+            if let Some(scheduler) = scheduler.as_mut() {
+                if let Some(task_id) = endpoint.take_highest_waiter() {
+                    scheduler.unblock_task(task_id);
+                }
+            }
+        }
+        // With every slot taken the receiver stays registered, and the
+        // next message wakes it instead
+        None => {
+            if let Some(slot) = DEFERRED_WAKES.iter().find(|slot| slot.load(Ordering::Acquire) == 0) {
+                if let Some(task_id) = endpoint.take_highest_waiter() {
+                    slot.store(task_id.value(), Ordering::Release);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Wakes send_small_from_interrupt couldn't deliver yet
+pub const MAX_DEFERRED_WAKES: usize = 8;
+
+/// Task ids waiting for wake_deferred (0 = free; real task ids start at 1)
+static DEFERRED_WAKES: [AtomicU64; MAX_DEFERRED_WAKES] = [const { AtomicU64::new(0) }; MAX_DEFERRED_WAKES];
+
+/// Deliver wakes deferred by send_small_from_interrupt (timer interrupt)
+///
+/// Uses try_lock like scheduler::wake_sleepers; if the scheduler is still
+/// locked the wakes wait for the next tick.
+pub fn wake_deferred() {
+    let Some(mut scheduler) = crate::scheduler::SCHEDULER.try_lock() else {
+        return;
+    };
+    let Some(scheduler) = scheduler.as_mut() else {
+        return;
+    };
+    for slot in DEFERRED_WAKES.iter() {
+        let task_id = slot.swap(0, Ordering::AcqRel);
+        if task_id != 0 {
+            scheduler.unblock_task(TaskId::new(task_id));
+        }
+    }
+}
+
 // try to receive message (non-blocking) - checks read permission
 pub fn try_receive_message(
-    _receiver: TaskId,
+    receiver: TaskId,
     receiver_cspace: &CSpace,
     endpoint_cap: CapabilityId,
 ) -> Result<Option<Message>, IpcError> {
@@ -265,12 +897,40 @@ pub fn try_receive_message(
     let target_endpoint_id = CapabilityId::new(cap.resource_id());
 
     let mut registry = IPC_REGISTRY.lock();
-    let registry = registry.as_mut().ok_or(IpcError::EndpointNotFound)?;
+    let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
 
     let endpoint = registry.get_endpoint_mut(target_endpoint_id)
         .ok_or(IpcError::EndpointNotFound)?;
 
-    Ok(endpoint.try_receive())
+    let message = endpoint.try_receive();
+    if message.is_some() {
+        endpoint.mark_served(receiver);
+    }
+    Ok(message)
+}
+
+/// Take the next message and install its capabilities (non-blocking)
+///
+/// Like try_receive_message, checked against the receiver task's own
+/// CSpace; the transferred capabilities land there too. Returns the
+/// message with their new IDs in transfer order.
+pub fn try_receive_with_caps(
+    receiver: TaskId,
+    endpoint_cap: CapabilityId,
+) -> Result<Option<(Message, Vec<CapabilityId>)>, IpcError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = crate::scheduler::SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(IpcError::NotInitialized)?;
+        let receiver_cspace = scheduler.get_task_mut(receiver)
+            .ok_or(IpcError::PermissionDenied)?
+            .cspace_mut();
+
+        let Some(mut message) = try_receive_message(receiver, receiver_cspace, endpoint_cap)? else {
+            return Ok(None);
+        };
+        let installed = message.accept_capabilities(receiver_cspace);
+        Ok(Some((message, installed)))
+    })
 }
 
 /// Receive a message from an endpoint (blocking)
@@ -298,34 +958,909 @@ pub fn receive_message_blocking(
     }
 
     let target_endpoint_id = CapabilityId::new(cap.resource_id());
+    let priority = crate::scheduler::task_priority(receiver).unwrap_or(Priority::Normal);
 
     loop {
-        // Try to receive non-blocking first (re-verify cap each iteration)
-        match try_receive_message(receiver, receiver_cspace, endpoint_cap)? {
+        // Check-and-block with interrupts off: on a single core no sender can
+        // run between seeing an empty queue and registering as a waiter, so
+        // the wake-up can't be lost
+        let received = x86_64::instructions::interrupts::without_interrupts(|| {
+            // Try to receive non-blocking first (re-verify cap each iteration)
+            if let Some(msg) = try_receive_message(receiver, receiver_cspace, endpoint_cap)? {
+                return Ok(Some(msg));
+            }
+
+            // No message available, register as waiter and block
+            {
+                let mut registry = IPC_REGISTRY.lock();
+                let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+
+                let endpoint = registry.get_endpoint_mut(target_endpoint_id)
+                    .ok_or(IpcError::EndpointNotFound)?;
+
+                endpoint.add_waiter(receiver, priority);
+            }
+
+            #[cfg(debug_assertions)]
+            serial_println!("[IPC] Task {} blocking on endpoint {}",
+                receiver.value(), endpoint_cap.value());
+
+            if let Some(scheduler) = crate::scheduler::SCHEDULER.lock().as_mut() {
+                scheduler.block_current(BlockReason::Ipc(target_endpoint_id));
+            }
+            Ok(None)
+        })?;
+
+        match received {
             Some(msg) => return Ok(msg),
-            None => {
-                // No message available, register as waiter and block
-                {
-                    let mut registry = IPC_REGISTRY.lock();
-                    let registry = registry.as_mut().ok_or(IpcError::EndpointNotFound)?;
+            // Switch away until a sender wakes us; capability is
+            // re-verified by try_receive_message on the next pass
+            None => crate::scheduler::task_yield(),
+        }
+    }
+}
 
-                    let endpoint = registry.get_endpoint_mut(target_endpoint_id)
-                        .ok_or(IpcError::EndpointNotFound)?;
+/// Receive a message from an endpoint, blocking for at most `timeout_ticks`
+///
+/// Returns `Ok(None)` if no message arrived before the timeout. A timed-out
+/// receiver is removed from the endpoint's waiter list.
+///
+/// # Security
+/// - Same capability checks as try_receive_message (re-verified each wake-up)
+///
+/// # Assumptions
+/// - INTERRUPTS: Must be enabled (timeout is measured in timer ticks)
+/// - LOCKS: No locks held by caller
+pub fn receive_message_timeout(
+    receiver: TaskId,
+    receiver_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    timeout_ticks: u64,
+) -> Result<Option<Message>, IpcError> {
+    use crate::interrupts::timer_ticks;
+    use x86_64::instructions::interrupts;
 
-                    endpoint.add_waiter(receiver);
-                }
+    let cap = receiver_cspace
+        .get(endpoint_cap)
+        .ok_or(IpcError::PermissionDenied)?;
 
-                // Block current task
-                serial_println!("[IPC] Task {} blocking on endpoint {}",
-                    receiver.value(), endpoint_cap.value());
+    if cap.resource_type() != ResourceType::Endpoint || !cap.rights().read {
+        return Err(IpcError::PermissionDenied);
+    }
 
-                crate::scheduler::SCHEDULER.lock()
-                    .as_mut()
-                    .unwrap()
-                    .block_current();
+    let target_endpoint_id = CapabilityId::new(cap.resource_id());
+    let priority = crate::scheduler::task_priority(receiver).unwrap_or(Priority::Normal);
+    let deadline = timer_ticks().saturating_add(timeout_ticks);
 
-                // When we wake up, capability is re-verified by try_receive_message
+    loop {
+        let received = try_receive_message(receiver, receiver_cspace, endpoint_cap)?;
+
+        if received.is_some() || timer_ticks() >= deadline {
+            // Done waiting either way - stop being a waiter
+            let mut registry = IPC_REGISTRY.lock();
+            if let Some(endpoint) = registry.as_mut()
+                .and_then(|r| r.get_endpoint_mut(target_endpoint_id))
+            {
+                endpoint.remove_waiter(receiver);
             }
+            return Ok(received);
         }
+
+        {
+            let mut registry = IPC_REGISTRY.lock();
+            let registry = registry.as_mut().ok_or(IpcError::NotInitialized)?;
+
+            let endpoint = registry.get_endpoint_mut(target_endpoint_id)
+                .ok_or(IpcError::EndpointNotFound)?;
+
+            endpoint.add_waiter(receiver, priority);
+        }
+
+        // Sleep until either a sender wakes us or the deadline passes
+        interrupts::without_interrupts(|| {
+            if let Some(scheduler) = crate::scheduler::SCHEDULER.lock().as_mut() {
+                scheduler.block_current_until(BlockReason::Ipc(target_endpoint_id), deadline);
+            }
+        });
+        crate::scheduler::task_yield();
     }
 }
+
+/// Header on every chunk of a send_large transfer: sequence number
+/// (u32 LE), then flags (u8, bit 0 = final chunk)
+pub const CHUNK_HEADER_SIZE: usize = 5;
+
+/// Payload bytes carried per chunk
+pub const CHUNK_PAYLOAD_SIZE: usize = MAX_MESSAGE_SIZE - CHUNK_HEADER_SIZE;
+
+/// Largest buffer send_large/recv_large move in one transfer
+pub const MAX_LARGE_MESSAGE_SIZE: usize = 256 * 1024;
+
+const CHUNK_FINAL: u8 = 1;
+
+/// Send a buffer larger than MAX_MESSAGE_SIZE as a run of chunks
+///
+/// Each chunk is an ordinary message (same capability checks as
+/// send_message) with a CHUNK_HEADER_SIZE header. Only one transfer
+/// should be in flight per endpoint, since chunks from two senders would
+/// interleave. The endpoint queue holds 16 messages, so anything over
+/// 16 chunks needs the receiver draining meanwhile; a QueueFull midway
+/// leaves a partial transfer that recv_large reports as BadChunk.
+pub fn send_large(
+    sender: TaskId,
+    sender_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+    data: &[u8],
+) -> Result<(), IpcError> {
+    if data.len() > MAX_LARGE_MESSAGE_SIZE {
+        return Err(IpcError::MessageTooLarge);
+    }
+
+    // An empty buffer still sends one (final) chunk
+    let chunks = data.len().div_ceil(CHUNK_PAYLOAD_SIZE).max(1);
+    for seq in 0..chunks {
+        let start = seq * CHUNK_PAYLOAD_SIZE;
+        let payload = &data[start..(start + CHUNK_PAYLOAD_SIZE).min(data.len())];
+
+        let mut chunk = Vec::with_capacity(CHUNK_HEADER_SIZE + payload.len());
+        chunk.extend_from_slice(&(seq as u32).to_le_bytes());
+        chunk.push(if seq + 1 == chunks { CHUNK_FINAL } else { 0 });
+        chunk.extend_from_slice(payload);
+        send_message(sender, sender_cspace, endpoint_cap, chunk)?;
+    }
+    Ok(())
+}
+
+/// Receive a send_large transfer and reassemble it
+///
+/// Blocks for each chunk like receive_message_blocking. A chunk with the
+/// wrong sequence number or from a different sender than the first, or
+/// one too short for its header, fails the transfer with BadChunk; the
+/// chunks already taken are dropped.
+pub fn recv_large(
+    receiver: TaskId,
+    receiver_cspace: &CSpace,
+    endpoint_cap: CapabilityId,
+) -> Result<Vec<u8>, IpcError> {
+    let mut data = Vec::new();
+    let mut sender = None;
+
+    for seq in 0u32.. {
+        let message = receive_message_blocking(receiver, receiver_cspace, endpoint_cap)?;
+        if message.data.len() < CHUNK_HEADER_SIZE || *sender.get_or_insert(message.sender) != message.sender {
+            return Err(IpcError::BadChunk);
+        }
+
+        let mut raw_seq = [0u8; 4];
+        raw_seq.copy_from_slice(&message.data[..4]);
+        let flags = message.data[4];
+        if u32::from_le_bytes(raw_seq) != seq || flags & !CHUNK_FINAL != 0 {
+            serial_println!("[IPC] Chunk {} out of sequence (expected {})",
+                u32::from_le_bytes(raw_seq), seq);
+            return Err(IpcError::BadChunk);
+        }
+
+        let payload = &message.data[CHUNK_HEADER_SIZE..];
+        if data.len() + payload.len() > MAX_LARGE_MESSAGE_SIZE {
+            return Err(IpcError::MessageTooLarge);
+        }
+        data.extend_from_slice(payload);
+
+        if flags & CHUNK_FINAL != 0 {
+            break;
+        }
+    }
+    Ok(data)
+}
+
+/// Test that a receive with no sender times out instead of hanging
+#[test_case]
+fn test_receive_timeout_without_sender() {
+    use crate::capability::{Capability, Rights};
+
+    serial_print!("test_receive_timeout_without_sender...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let endpoint_id = CapabilityId::new(7001);
+    create_endpoint(endpoint_id).unwrap();
+
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(
+        CapabilityId::new(1),
+        ResourceType::Endpoint,
+        endpoint_id.value(),
+        Rights::READ,
+    ));
+
+    let receiver = TaskId::new(9001);
+    let start = crate::interrupts::timer_ticks();
+    let result = receive_message_timeout(receiver, &cspace, CapabilityId::new(1), 3);
+
+    assert!(matches!(result, Ok(None)));
+    assert!(crate::interrupts::timer_ticks() >= start + 3);
+
+    let registry = IPC_REGISTRY.lock();
+    let endpoint = registry.as_ref().unwrap().get_endpoint(endpoint_id).unwrap();
+    assert!(endpoint.waiting_tasks.is_empty());
+    serial_println!("[ok]");
+}
+
+/// Test that a forged sender id is rejected and messages carry the real sender
+#[test_case]
+fn test_send_rejects_forged_sender() {
+    use crate::capability::{Capability, Rights};
+
+    serial_print!("test_send_rejects_forged_sender...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let endpoint_id = CapabilityId::new(7002);
+    create_endpoint(endpoint_id).unwrap();
+
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(
+        CapabilityId::new(1),
+        ResourceType::Endpoint,
+        endpoint_id.value(),
+        Rights::READ_WRITE,
+    ));
+
+    let real = TaskId::new(9002);
+    let forged = TaskId::new(1);
+    let cap = CapabilityId::new(1);
+
+    let result = send_message_as(Some(real), forged, &cspace, cap, b"spoof".to_vec());
+    assert!(matches!(result, Err(IpcError::PermissionDenied)));
+    assert!(try_receive_message(real, &cspace, cap).unwrap().is_none());
+
+    send_message_as(Some(real), real, &cspace, cap, b"hi".to_vec()).unwrap();
+    let msg = try_receive_message(real, &cspace, cap).unwrap().unwrap();
+    assert_eq!(msg.sender, real);
+    assert_eq!(msg.data, b"hi");
+    serial_println!("[ok]");
+}
+
+/// Test that revoking a root capability notifies a holder's revocation endpoint
+#[test_case]
+fn test_revoke_tree_notifies_holder() {
+    use crate::capability::{Capability, Rights};
+
+    serial_print!("test_revoke_tree_notifies_holder...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let notify_endpoint = CapabilityId::new(7003);
+    create_endpoint(notify_endpoint).unwrap();
+
+    let mut owner = CSpace::new();
+    let root = owner.create(ResourceType::Memory, 0x5000, Rights::READ_WRITE);
+
+    // Holder has a derived read-only copy plus its notification endpoint
+    let mut holder = CSpace::new();
+    let derived = owner.get(root).unwrap().derive(CapabilityId::new(10), Rights::READ).unwrap();
+    holder.insert(derived);
+    holder.insert(Capability::new(
+        CapabilityId::new(11),
+        ResourceType::Endpoint,
+        notify_endpoint.value(),
+        Rights::READ,
+    ));
+    assert!(holder.set_revocation_endpoint(CapabilityId::new(11)));
+    // More rights than the root: can't be a copy of it
+    holder.insert(Capability::new(CapabilityId::new(12), ResourceType::Memory, 0x5000, Rights::ALL));
+
+    let mut notices = Vec::new();
+    assert_eq!(owner.revoke_tree(root, &mut [&mut holder], &mut notices), 2);
+    assert!(holder.get(CapabilityId::new(10)).is_none());
+    assert!(holder.get(CapabilityId::new(12)).is_some());
+    assert_eq!(notices.len(), 1);
+
+    // Nothing is sent until the caller has dropped its locks
+    assert_eq!(IPC_REGISTRY.lock().as_ref().unwrap().get_endpoint(notify_endpoint).unwrap().queued(), 0);
+    crate::capability::send_revocation_notices(notices);
+
+    let msg = try_receive_message(TaskId::new(9003), &holder, CapabilityId::new(11))
+        .unwrap()
+        .expect("no revocation notice delivered");
+    assert_eq!(msg.sender, KERNEL_SENDER);
+    assert_eq!(&msg.data[..8], &10u64.to_le_bytes());
+    assert_eq!(&msg.data[8..], &0x5000u64.to_le_bytes());
+    serial_println!("[ok]");
+}
+
+/// Test that a single message goes to the highest-priority waiter
+#[test_case]
+fn test_wake_highest_priority_waiter() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use crate::capability::Rights;
+    use crate::scheduler;
+    use crate::task::{BlockReason, Privilege, Task, TaskState};
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    const ENDPOINT: u64 = 7004;
+    static RECEIVED_BY: AtomicU64 = AtomicU64::new(0);
+
+    fn receiver_main() -> ! {
+        let mut cspace = CSpace::new();
+        let inbox = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ);
+        let me = scheduler::current_task_id().unwrap();
+        let message = receive_message_blocking(me, &cspace, inbox).unwrap();
+        if message.data == b"one" {
+            RECEIVED_BY.store(me.value(), Ordering::SeqCst);
+        }
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    fn blocked(task: &TestTask) -> bool {
+        task.with(|t| t.state() == TaskState::Blocked
+            && t.block_reason() == Some(BlockReason::Ipc(CapabilityId::new(ENDPOINT))))
+    }
+
+    serial_print!("test_wake_highest_priority_waiter...");
+    create_endpoint(CapabilityId::new(ENDPOINT)).unwrap();
+
+    // Low starts waiting first, so arrival order alone would pick it
+    let spawn = |priority| {
+        let task = Task::try_new("ipc-waiter", receiver_main, priority, Privilege::Kernel).expect("out of memory creating task");
+        let task = TestTask::start(task);
+        assert!(yield_until(200, || blocked(&task)));
+        task
+    };
+    let low = spawn(Priority::Low);
+    let high = spawn(Priority::High);
+
+    let mut cspace = CSpace::new();
+    let outbox = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ_WRITE);
+    let me = scheduler::current_task_id().unwrap();
+    send_message(me, &cspace, outbox, b"one".to_vec()).unwrap();
+    assert!(yield_until(200, || RECEIVED_BY.load(Ordering::SeqCst) != 0));
+
+    // High took the message; Low is still waiting for one
+    assert_eq!(RECEIVED_BY.load(Ordering::SeqCst), high.id().value());
+    assert!(blocked(&low));
+    serial_println!("[ok]");
+}
+
+/// Test that messages sent through minted caps carry their badges
+#[test_case]
+fn test_badged_endpoint_caps() {
+    use crate::capability::Rights;
+
+    serial_print!("test_badged_endpoint_caps...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let endpoint_id = CapabilityId::new(7005);
+    create_endpoint(endpoint_id).unwrap();
+
+    let mut server = CSpace::new();
+    let root = server.create(ResourceType::Endpoint, endpoint_id.value(), Rights::ALL);
+    let client_a = server.mint(root, 0xA, Rights::READ_WRITE).unwrap();
+    let client_b = server.mint(root, 0xB, Rights::READ_WRITE).unwrap();
+
+    // Badged caps can't be re-minted with a different badge
+    assert!(server.mint(client_a, 0xC, Rights::READ_WRITE).is_none());
+
+    let sender = TaskId::new(9007);
+    send_message_as(None, sender, &server, client_a, b"from a".to_vec()).unwrap();
+    send_message_as(None, sender, &server, client_b, b"from b".to_vec()).unwrap();
+    send_message_as(None, sender, &server, root, b"unbadged".to_vec()).unwrap();
+
+    let badges: Vec<Option<u64>> = (0..3)
+        .map(|_| try_receive_message(sender, &server, root).unwrap().unwrap().badge)
+        .collect();
+    assert_eq!(badges, [Some(0xA), Some(0xB), None]);
+    serial_println!("[ok]");
+}
+
+/// Test that IPC calls before init() report NotInitialized
+#[test_case]
+fn test_send_before_init_not_initialized() {
+    use crate::capability::{Capability, Rights};
+
+    serial_print!("test_send_before_init_not_initialized...");
+    // Simulate an uninitialized system, restoring the registry afterwards
+    let saved = IPC_REGISTRY.lock().take();
+
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(
+        CapabilityId::new(1),
+        ResourceType::Endpoint,
+        7006,
+        Rights::READ_WRITE,
+    ));
+
+    let sender = crate::scheduler::current_task_id().unwrap_or(TaskId::new(9008));
+    let sent = send_message(sender, &cspace, CapabilityId::new(1), b"x".to_vec());
+    let created = create_endpoint(CapabilityId::new(7006));
+    let received = try_receive_message(sender, &cspace, CapabilityId::new(1));
+
+    *IPC_REGISTRY.lock() = saved;
+
+    assert!(matches!(sent, Err(IpcError::NotInitialized)));
+    assert!(matches!(created, Err(IpcError::NotInitialized)));
+    assert!(matches!(received, Err(IpcError::NotInitialized)));
+    serial_println!("[ok]");
+}
+
+/// Test that one broadcast reaches every subscriber and slow ones drop
+#[test_case]
+fn test_broadcast_fans_out_to_subscribers() {
+    use crate::capability::{Capability, Rights};
+
+    serial_print!("test_broadcast_fans_out_to_subscribers...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let endpoint_id = CapabilityId::new(7030);
+    create_broadcast_endpoint(endpoint_id).unwrap();
+    let cap = CapabilityId::new(1);
+
+    let mut reader = CSpace::new();
+    reader.insert(Capability::new(cap, ResourceType::Endpoint, endpoint_id.value(), Rights::READ));
+    let mut writer = CSpace::new();
+    writer.insert(Capability::new(cap, ResourceType::Endpoint, endpoint_id.value(), Rights { read: false, ..Rights::READ_WRITE }));
+
+    let subscribers = [TaskId::new(9011), TaskId::new(9012), TaskId::new(9013)];
+    for &task in &subscribers {
+        subscribe_broadcast(task, &reader, cap).unwrap();
+    }
+    // Subscribing needs read rights
+    assert_eq!(subscribe_broadcast(TaskId::new(9014), &writer, cap), Err(IpcError::PermissionDenied));
+
+    let sender = crate::scheduler::current_task_id().unwrap_or(KERNEL_SENDER);
+    assert_eq!(broadcast_message(sender, &writer, cap, b"hello".to_vec()), Ok(3));
+    for &task in &subscribers {
+        let msg = try_receive_broadcast(task, &reader, cap).unwrap().unwrap();
+        assert_eq!(msg.data, b"hello");
+        assert_eq!(msg.sender, sender);
+        assert!(try_receive_broadcast(task, &reader, cap).unwrap().is_none());
+    }
+
+    // Only the subscriber that never drains loses messages
+    for i in 0..=MAX_BROADCAST_QUEUE {
+        broadcast_message(sender, &writer, cap, alloc::vec![i as u8]).unwrap();
+        for &task in &subscribers[1..] {
+            try_receive_broadcast(task, &reader, cap).unwrap().unwrap();
+        }
+    }
+    let registry = IPC_REGISTRY.lock();
+    let endpoint = registry.as_ref().unwrap().broadcasts.iter().find(|ep| ep.id() == endpoint_id).unwrap();
+    assert_eq!(endpoint.dropped(subscribers[0]), Some(1));
+    assert_eq!(endpoint.dropped(subscribers[1]), Some(0));
+    serial_println!("[ok]");
+}
+
+/// Test that several capabilities move in one message, or none do
+#[test_case]
+fn test_transfer_multiple_caps() {
+    use crate::capability::Rights;
+
+    serial_print!("test_transfer_multiple_caps...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let endpoint_id = CapabilityId::new(7040);
+    create_endpoint(endpoint_id).unwrap();
+    let sender = TaskId::new(9015);
+    let grantable = Rights { grant: true, ..Rights::READ };
+
+    let mut sender_cspace = CSpace::new();
+    let endpoint = sender_cspace.create(ResourceType::Endpoint, endpoint_id.value(), Rights::READ_WRITE);
+    let caps: Vec<CapabilityId> = (0..3)
+        .map(|i| sender_cspace.create(ResourceType::Memory, 0x1000 * (i + 1), grantable))
+        .collect();
+    let mut receiver_cspace = CSpace::new();
+    let inbox = receiver_cspace.create(ResourceType::Endpoint, endpoint_id.value(), Rights::READ);
+
+    queue_with_caps(None, sender, &mut sender_cspace, endpoint, b"bundle".to_vec(), &caps).unwrap();
+    assert!(caps.iter().all(|&id| sender_cspace.get(id).is_none()));
+
+    let mut message = try_receive_message(sender, &receiver_cspace, inbox).unwrap().unwrap();
+    assert_eq!(message.transferred_caps, caps);
+    let received = message.accept_capabilities(&mut receiver_cspace);
+    let resources: Vec<u64> = received.iter()
+        .map(|&id| receiver_cspace.get(id).unwrap().resource_id())
+        .collect();
+    assert_eq!(resources, [0x1000, 0x2000, 0x3000]);
+
+    // One cap without grant: the send fails and every cap stays put
+    let caps = [
+        sender_cspace.create(ResourceType::Memory, 0x4000, grantable),
+        sender_cspace.create(ResourceType::Memory, 0x5000, Rights::READ),
+        sender_cspace.create(ResourceType::Memory, 0x6000, grantable),
+    ];
+    assert_eq!(
+        queue_with_caps(None, sender, &mut sender_cspace, endpoint, b"bundle".to_vec(), &caps),
+        Err(IpcError::PermissionDenied)
+    );
+    assert!(caps.iter().all(|&id| sender_cspace.get(id).is_some()));
+    assert!(try_receive_message(sender, &receiver_cspace, inbox).unwrap().is_none());
+    serial_println!("[ok]");
+}
+
+/// Test that transferred capabilities land in the receiving task's CSpace
+#[test_case]
+fn test_transfer_caps_between_tasks() {
+    use crate::capability::Rights;
+    use crate::scheduler::SCHEDULER;
+    use crate::task::{Privilege, Task};
+    use crate::wasm_runtime::tests::TestTask;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    fn idle_main() -> ! {
+        loop {
+            crate::scheduler::task_yield();
+        }
+    }
+
+    /// Run `f` on the test runner's own CSpace
+    fn with_my_cspace<R>(f: impl FnOnce(&mut CSpace) -> R) -> R {
+        let me = crate::scheduler::current_task_id().unwrap();
+        without_interrupts(|| f(SCHEDULER.lock().as_mut().unwrap().get_task_mut(me).unwrap().cspace_mut()))
+    }
+
+    serial_print!("test_transfer_caps_between_tasks...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+
+    let endpoint_id = CapabilityId::new(7120);
+    create_endpoint(endpoint_id).unwrap();
+    let grantable = Rights { grant: true, ..Rights::READ };
+
+    // The test runner sends: a claimed sender other than the running
+    // task would be refused as forged
+    let (outbox, caps) = with_my_cspace(|cspace| (
+        cspace.create(ResourceType::Endpoint, endpoint_id.value(), Rights::READ_WRITE),
+        [
+            cspace.create(ResourceType::Memory, 0x1000, grantable),
+            cspace.create(ResourceType::Memory, 0x2000, grantable),
+        ],
+    ));
+    let mut receiver = Task::try_new("cap-receiver", idle_main, Priority::Low, Privilege::Kernel).expect("out of memory creating task");
+    let inbox = receiver.cspace_mut().create(ResourceType::Endpoint, endpoint_id.value(), Rights::READ);
+    let receiver = TestTask::start(receiver);
+
+    let me = crate::scheduler::current_task_id().unwrap();
+    send_message_with_caps(me, outbox, b"bundle".to_vec(), &caps).unwrap();
+    let (message, installed) = try_receive_with_caps(receiver.id(), inbox).unwrap().unwrap();
+    assert_eq!(message.data, b"bundle");
+
+    assert!(with_my_cspace(|cspace| {
+        let moved = caps.iter().all(|&id| cspace.get(id).is_none());
+        cspace.revoke(outbox);
+        moved
+    }));
+    let receiver = receiver.remove();
+    let resources: Vec<u64> = installed.iter()
+        .map(|&id| receiver.cspace().get(id).unwrap().resource_id())
+        .collect();
+    assert_eq!(resources, [0x1000, 0x2000]);
+    serial_println!("[ok]");
+}
+
+/// Test that one send lets exactly one of three blocked receivers run
+#[test_case]
+fn test_single_send_wakes_one_of_three() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::capability::Rights;
+    use crate::scheduler;
+    use crate::task::{BlockReason, TaskState};
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    const ENDPOINT: u64 = 7060;
+    static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+    fn receiver_main() -> ! {
+        let mut cspace = CSpace::new();
+        let inbox = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ);
+        let me = scheduler::current_task_id().unwrap();
+        receive_message_blocking(me, &cspace, inbox).unwrap();
+        RECEIVED.fetch_add(1, Ordering::SeqCst);
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    serial_print!("test_single_send_wakes_one_of_three...");
+    create_endpoint(CapabilityId::new(ENDPOINT)).unwrap();
+
+    let receivers: [TestTask; 3] = core::array::from_fn(|_| TestTask::spawn("ipc-waiter", receiver_main));
+    let blocked_receivers = || receivers.iter()
+        .filter(|r| r.with(|t| t.state() == TaskState::Blocked
+            && t.block_reason() == Some(BlockReason::Ipc(CapabilityId::new(ENDPOINT)))))
+        .count();
+    assert!(yield_until(200, || blocked_receivers() == 3));
+
+    let mut cspace = CSpace::new();
+    let outbox = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ_WRITE);
+    let me = scheduler::current_task_id().unwrap();
+    send_message(me, &cspace, outbox, b"one".to_vec()).unwrap();
+
+    // Only one receiver was made runnable; the others never leave Blocked
+    assert_eq!(blocked_receivers(), 2);
+    assert!(yield_until(200, || RECEIVED.load(Ordering::SeqCst) == 1));
+    for _ in 0..10 {
+        scheduler::task_yield();
+    }
+    assert_eq!(RECEIVED.load(Ordering::SeqCst), 1);
+    assert_eq!(blocked_receivers(), 2);
+    serial_println!("[ok]");
+}
+
+/// Test that a small message round-trips without touching the heap
+#[test_case]
+fn test_inline_message_round_trip() {
+    use crate::capability::Rights;
+
+    serial_print!("test_inline_message_round_trip...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+    let endpoint_id = CapabilityId::new(7070);
+    create_endpoint(endpoint_id).unwrap();
+    let task = TaskId::new(9016);
+    let mut cspace = CSpace::new();
+    let cap = cspace.create(ResourceType::Endpoint, endpoint_id.value(), Rights::READ_WRITE);
+    let payload: [u8; 16] = *b"sixteen byte msg";
+
+    // Interrupts off so a preempting task can't allocate mid-measurement
+    let (allocations, received) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let before = crate::allocator::allocation_count();
+        send_small(task, &cspace, cap, &payload).unwrap();
+        let received = try_receive_small(task, &cspace, cap).unwrap();
+        (crate::allocator::allocation_count() - before, received)
+    });
+    assert_eq!(allocations, 0);
+    let received = received.expect("inline message lost");
+    assert_eq!(received.data(), &payload);
+    assert_eq!(received.sender, task);
+
+    // Anything over the inline limit is refused
+    assert_eq!(send_small(task, &cspace, cap, &[0; INLINE_MESSAGE_SIZE + 1]), Err(IpcError::MessageTooLarge));
+    serial_println!("[ok]");
+}
+
+/// Test that send_small wakes a blocked receiver and its message is taken
+#[test_case]
+fn test_send_small_wakes_receiver() {
+    use crate::capability::Rights;
+    use crate::scheduler;
+    use crate::task::{BlockReason, TaskState};
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    const ENDPOINT: u64 = 7081;
+
+    fn receiver_main() -> ! {
+        let mut cspace = CSpace::new();
+        let inbox = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ);
+        let me = scheduler::current_task_id().unwrap();
+        let _ = receive_message_blocking(me, &cspace, inbox);
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    serial_print!("test_send_small_wakes_receiver...");
+    let endpoint_id = CapabilityId::new(ENDPOINT);
+    create_endpoint(endpoint_id).unwrap();
+    let receiver = TestTask::spawn("small-receiver", receiver_main);
+    assert!(yield_until(200, || receiver.with(|t| t.state() == TaskState::Blocked
+        && t.block_reason() == Some(BlockReason::Ipc(endpoint_id)))));
+
+    let mut cspace = CSpace::new();
+    let cap = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ_WRITE);
+    let me = scheduler::current_task_id().unwrap();
+    send_small(me, &cspace, cap, b"wake").unwrap();
+    assert_eq!(receiver.with(|t| t.state()), TaskState::Ready);
+
+    // Taking the message ends the receiver's pending wake-up
+    let message = try_receive_small(receiver.id(), &cspace, cap).unwrap().expect("inline message lost");
+    assert_eq!(message.data(), b"wake");
+    let pending = IPC_REGISTRY.lock().as_mut().unwrap()
+        .get_endpoint_mut(endpoint_id).unwrap()
+        .woken.contains(&receiver.id());
+    assert!(!pending);
+    serial_println!("[ok]");
+}
+
+/// Test that an interrupt-context send wakes a blocked receiver, deferring
+/// to the next tick while the scheduler is locked
+#[test_case]
+fn test_interrupt_send_wakes_receiver() {
+    use crate::capability::Rights;
+    use crate::scheduler::{self, SCHEDULER};
+    use crate::task::{BlockReason, TaskState};
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    const ENDPOINT: u64 = 7080;
+
+    fn receiver_main() -> ! {
+        let mut cspace = CSpace::new();
+        let inbox = cspace.create(ResourceType::Endpoint, ENDPOINT, Rights::READ);
+        let me = scheduler::current_task_id().unwrap();
+        // Inline notifications aren't Messages, so this blocks again after each wake
+        let _ = receive_message_blocking(me, &cspace, inbox);
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    serial_print!("test_interrupt_send_wakes_receiver...");
+    let endpoint_id = CapabilityId::new(ENDPOINT);
+    create_endpoint(endpoint_id).unwrap();
+    let receiver = TestTask::spawn("irq-receiver", receiver_main);
+    let blocked = || receiver.with(|t| t.state() == TaskState::Blocked
+        && t.block_reason() == Some(BlockReason::Ipc(endpoint_id)));
+
+    // Scheduler free: woken on the spot
+    assert!(yield_until(200, blocked));
+    send_small_from_interrupt(endpoint_id, b"a").unwrap();
+    assert_eq!(receiver.with(|t| t.state()), TaskState::Ready);
+
+    // Scheduler held by the "interrupted" code: woken by the next tick
+    assert!(yield_until(200, blocked));
+    let deferred = without_interrupts(|| {
+        let held = SCHEDULER.lock();
+        send_small_from_interrupt(endpoint_id, b"b").unwrap();
+        drop(held);
+        let deferred = blocked();
+        wake_deferred();
+        deferred
+    });
+    assert!(deferred);
+    assert_eq!(receiver.with(|t| t.state()), TaskState::Ready);
+    serial_println!("[ok]");
+}
+
+/// Test that a 16KB buffer survives chunking, and a skipped chunk is caught
+#[test_case]
+fn test_large_message_chunked_round_trip() {
+    use crate::capability::Rights;
+
+    serial_print!("test_large_message_chunked_round_trip...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+    let endpoint_id = CapabilityId::new(7090);
+    create_endpoint(endpoint_id).unwrap();
+    let task = crate::scheduler::current_task_id().unwrap_or(TaskId::new(9018));
+    let mut cspace = CSpace::new();
+    let cap = cspace.create(ResourceType::Endpoint, endpoint_id.value(), Rights::READ_WRITE);
+
+    let data: Vec<u8> = (0..16 * 1024u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    send_large(task, &cspace, cap, &data).unwrap();
+    assert_eq!(recv_large(task, &cspace, cap).unwrap(), data);
+
+    // Chunk 1 never sent: chunk 2 arrives where 1 was expected
+    let chunk = |seq: u32, flags: u8| {
+        let mut raw = seq.to_le_bytes().to_vec();
+        raw.push(flags);
+        raw.extend_from_slice(b"part");
+        raw
+    };
+    send_message(task, &cspace, cap, chunk(0, 0)).unwrap();
+    send_message(task, &cspace, cap, chunk(2, CHUNK_FINAL)).unwrap();
+    assert_eq!(recv_large(task, &cspace, cap), Err(IpcError::BadChunk));
+    assert!(try_receive_message(task, &cspace, cap).unwrap().is_none());
+
+    assert_eq!(send_large(task, &cspace, cap, &alloc::vec![0; MAX_LARGE_MESSAGE_SIZE + 1]), Err(IpcError::MessageTooLarge));
+    serial_println!("[ok]");
+}
+
+/// Test that list_endpoints reports each endpoint's queue depth and waiters
+#[test_case]
+fn test_list_endpoints_reports_depths() {
+    use crate::capability::Rights;
+
+    serial_print!("test_list_endpoints_reports_depths...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+    let busy = create_endpoint(CapabilityId::new(7100)).unwrap();
+    let quiet = create_endpoint(CapabilityId::new(7101)).unwrap();
+    let task = crate::scheduler::current_task_id().unwrap_or(TaskId::new(9019));
+    let mut cspace = CSpace::new();
+    let cap = cspace.create(ResourceType::Endpoint, busy.value(), Rights::READ_WRITE);
+
+    for i in 0..3u8 {
+        send_message(task, &cspace, cap, alloc::vec![i]).unwrap();
+    }
+    send_small(task, &cspace, cap, b"inline").unwrap();
+    // A receiver parked on the quiet endpoint
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut registry = IPC_REGISTRY.lock();
+        registry.as_mut().unwrap().get_endpoint_mut(quiet).unwrap().add_waiter(TaskId::new(9020), Priority::Normal);
+    });
+
+    let info = |id: CapabilityId| list_endpoints().into_iter().find(|ep| ep.id == id).expect("endpoint not listed");
+    assert_eq!(info(busy), EndpointInfo { id: busy, queued: 4, waiters: 0, max_depth: 16 });
+    assert_eq!(info(quiet), EndpointInfo { id: quiet, queued: 0, waiters: 1, max_depth: 16 });
+
+    // Draining shows up in the next snapshot
+    while try_receive_message(task, &cspace, cap).unwrap().is_some() {}
+    assert_eq!(info(busy).queued, 1);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut registry = IPC_REGISTRY.lock();
+        registry.as_mut().unwrap().get_endpoint_mut(quiet).unwrap().remove_waiter(TaskId::new(9020));
+    });
+    serial_println!("[ok]");
+}
+
+/// Test that equal-priority waiters are served in arrival order
+#[test_case]
+fn test_waiters_served_in_arrival_order() {
+    use crate::capability::{Capability, Rights};
+
+    serial_print!("test_waiters_served_in_arrival_order...");
+    if IPC_REGISTRY.lock().is_none() {
+        init();
+    }
+    let endpoint_id = CapabilityId::new(7110);
+    create_endpoint(endpoint_id).unwrap();
+    let mut cspace = CSpace::new();
+    cspace.insert(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, endpoint_id.value(), Rights::READ_WRITE));
+    let cap = CapabilityId::new(1);
+
+    let with_endpoint = |f: &mut dyn FnMut(&mut IpcEndpoint)| x86_64::instructions::interrupts::without_interrupts(|| {
+        f(IPC_REGISTRY.lock().as_mut().unwrap().get_endpoint_mut(endpoint_id).unwrap())
+    });
+    let waiters = || {
+        let mut ids = Vec::new();
+        with_endpoint(&mut |ep| ids = ep.waiting_tasks.iter().map(|&(t, _)| t).collect());
+        ids
+    };
+    // Each send wakes the head of the list
+    let send = || {
+        let before = waiters();
+        send_message_as(None, TaskId::new(9025), &cspace, cap, b"turn".to_vec()).unwrap();
+        before[0]
+    };
+
+    let (a, b, c, high) = (TaskId::new(9021), TaskId::new(9022), TaskId::new(9023), TaskId::new(9024));
+    with_endpoint(&mut |ep| {
+        for task in [a, b, c] {
+            ep.add_waiter(task, Priority::Normal);
+        }
+        // A later, higher-priority arrival still goes first
+        ep.add_waiter(high, Priority::High);
+    });
+    assert_eq!(waiters(), [high, a, b, c]);
+    assert_eq!(send(), high);
+    try_receive_message(high, &cspace, cap).unwrap().unwrap();
+
+    // a is woken but another receiver takes the message: a waits again
+    // and keeps its place ahead of b and c
+    assert_eq!(send(), a);
+    try_receive_message(TaskId::new(9025), &cspace, cap).unwrap().unwrap();
+    with_endpoint(&mut |ep| ep.add_waiter(a, Priority::Normal));
+    assert_eq!(waiters(), [a, b, c]);
+
+    for expected in [a, b, c] {
+        assert_eq!(send(), expected);
+        try_receive_message(expected, &cspace, cap).unwrap().unwrap();
+    }
+
+    // Once served, waiting again means the back of the line
+    with_endpoint(&mut |ep| {
+        ep.add_waiter(b, Priority::Normal);
+        ep.add_waiter(a, Priority::Normal);
+    });
+    assert_eq!(waiters(), [b, a]);
+    with_endpoint(&mut |ep| {
+        ep.remove_waiter(a);
+        ep.remove_waiter(b);
+    });
+    serial_println!("[ok]");
+}
@@ -0,0 +1,138 @@
+//! Kernel invariant checks that stop a task instead of the kernel
+//!
+//! `kassert!` checks a condition and `kensure!` unwraps an Option or
+//! Result. A failure is logged with its file and line, and then only the
+//! current task is ended, through `scheduler::exit` with
+//! KASSERT_EXIT_CODE. With no task running (boot, or the scheduler isn't
+//! up) there is nothing smaller to stop, so they panic like `assert!`.
+//!
+//! Both take the scheduler lock on failure, so don't use them while
+//! holding it or from an interrupt handler; keep `panic!` for those.
+
+use core::fmt;
+
+/// Exit code of a task ended by a failed kassert!/kensure!
+pub const KASSERT_EXIT_CODE: i32 = 2;
+
+/// Values kensure! can unwrap
+pub trait Ensure {
+    type Output;
+
+    /// The value, or None if the check failed
+    fn ensure(self) -> Option<Self::Output>;
+}
+
+impl<T> Ensure for Option<T> {
+    type Output = T;
+
+    fn ensure(self) -> Option<T> {
+        self
+    }
+}
+
+impl<T, E> Ensure for Result<T, E> {
+    type Output = T;
+
+    fn ensure(self) -> Option<T> {
+        self.ok()
+    }
+}
+
+/// Report a failed check and end the current task (or panic outside one)
+#[cold]
+pub fn fail(condition: &str, file: &str, line: u32, detail: Option<fmt::Arguments>) -> ! {
+    match detail {
+        Some(detail) => serial_println!("[KASSERT] {} failed at {}:{}: {}", condition, file, line, detail),
+        None => serial_println!("[KASSERT] {} failed at {}:{}", condition, file, line),
+    }
+
+    if crate::scheduler::current_task_id().is_some() {
+        release_current_task();
+        crate::scheduler::exit(KASSERT_EXIT_CODE);
+    }
+    panic!("kernel invariant failed outside a task: {} at {}:{}", condition, file, line);
+}
+
+/// Drop what the scheduler holds for the failing task before it exits
+///
+/// exit doesn't unwind, so heap values owned by the task's own frames
+/// are leaked. Its capabilities go now; the stack and Task entry are
+/// freed when reap_terminated collects the terminated task.
+fn release_current_task() {
+    let cspace = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = crate::scheduler::SCHEDULER.lock();
+        let scheduler = scheduler.as_mut()?;
+        let task = scheduler.get_task_mut(scheduler.current_task()?)?;
+        Some(core::mem::replace(task.cspace_mut(), crate::capability::CSpace::new()))
+    });
+    drop(cspace);
+}
+
+/// Check a kernel invariant, ending the current task if it doesn't hold
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::kassert::fail(stringify!($cond), file!(), line!(), None)
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kassert::fail(stringify!($cond), file!(), line!(), Some(format_args!($($arg)+)))
+        }
+    };
+}
+
+/// Unwrap an Option or Result, ending the current task on None/Err
+#[macro_export]
+macro_rules! kensure {
+    ($value:expr $(,)?) => {
+        match $crate::kassert::Ensure::ensure($value) {
+            Some(value) => value,
+            None => $crate::kassert::fail(stringify!($value), file!(), line!(), None),
+        }
+    };
+    ($value:expr, $($arg:tt)+) => {
+        match $crate::kassert::Ensure::ensure($value) {
+            Some(value) => value,
+            None => $crate::kassert::fail(stringify!($value), file!(), line!(), Some(format_args!($($arg)+))),
+        }
+    };
+}
+
+/// Test that a failed kensure! ends only the task that hit it
+#[test_case]
+fn test_kensure_terminates_task() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::scheduler;
+    use crate::task::{Priority, Privilege, Task, TaskState};
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    static SURVIVED: AtomicBool = AtomicBool::new(false);
+
+    fn checker_main() -> ! {
+        let missing: Option<u32> = None;
+        let _value = kensure!(missing, "value {} missing", 7);
+        SURVIVED.store(true, Ordering::SeqCst);
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    serial_print!("test_kensure_terminates_task...");
+    // Passing checks are no-ops and hand back the value
+    let value: Result<u32, ()> = Ok(5);
+    kassert!(value.is_ok());
+    assert_eq!(kensure!(value), 5);
+
+    let mut task = Task::try_new("kensure-fail", checker_main, Priority::Normal, Privilege::Kernel).expect("out of memory creating task");
+    task.cspace_mut().create(crate::capability::ResourceType::Memory, 0x9000, crate::capability::Rights::READ);
+    let task = TestTask::start(task);
+    let outcome = || task.with(|t| (t.state(), t.exit_code(), t.cspace().is_empty()));
+    assert!(yield_until(100, || outcome().0 == TaskState::Terminated));
+
+    // The task is gone with its capabilities, and we (the kernel) are still running
+    assert_eq!(outcome(), (TaskState::Terminated, Some(KASSERT_EXIT_CODE), true));
+    assert!(!SURVIVED.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,164 @@
+//! Kernel log ring buffer for JerichoOS
+//!
+//! Keeps an in-memory history of serial output so recent lines can be
+//! read back after they scroll away (e.g. by a future `dmesg` command).
+//! Storage is fixed-size and static, so logging works before the heap
+//! is initialized and never allocates.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Number of lines kept in the ring buffer
+pub const KLOG_LINES: usize = 128;
+
+/// Maximum bytes stored per line (longer lines are truncated)
+pub const KLOG_LINE_LEN: usize = 128;
+
+/// A single buffered log line
+#[derive(Clone, Copy)]
+struct LogLine {
+    buf: [u8; KLOG_LINE_LEN],
+    len: usize,
+}
+
+impl LogLine {
+    const fn empty() -> Self {
+        LogLine {
+            buf: [0; KLOG_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < KLOG_LINE_LEN {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn as_string(&self) -> String {
+        String::from_utf8_lossy(&self.buf[..self.len]).into_owned()
+    }
+}
+
+/// Ring buffer of completed lines plus the line currently being written
+struct KernelLog {
+    lines: [LogLine; KLOG_LINES],
+    /// Next slot to overwrite
+    head: usize,
+    /// Number of valid lines (saturates at KLOG_LINES)
+    count: usize,
+    /// Line being assembled from partial `serial_print!` calls
+    partial: LogLine,
+}
+
+impl KernelLog {
+    const fn new() -> Self {
+        KernelLog {
+            lines: [LogLine::empty(); KLOG_LINES],
+            head: 0,
+            count: 0,
+            partial: LogLine::empty(),
+        }
+    }
+
+    /// Move the partial line into the ring, overwriting the oldest entry
+    fn commit(&mut self) {
+        self.lines[self.head] = self.partial;
+        self.head = (self.head + 1) % KLOG_LINES;
+        if self.count < KLOG_LINES {
+            self.count += 1;
+        }
+        self.partial.len = 0;
+    }
+}
+
+impl fmt::Write for KernelLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                b'\n' => self.commit(),
+                b'\r' => {}
+                _ => self.partial.push(byte),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Global kernel log
+static KLOG: Mutex<KernelLog> = Mutex::new(KernelLog::new());
+
+/// Writes skipped because the log was locked (e.g. print from an interrupt)
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Mirror formatted output into the ring buffer
+///
+/// Called from the serial print hook. Uses `try_lock` so a print from
+/// interrupt context while the log is held drops the write instead of
+/// deadlocking.
+pub fn record(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    match KLOG.try_lock() {
+        Some(mut log) => {
+            let _ = log.write_fmt(args);
+        }
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Get the last `n` completed lines, oldest first
+pub fn tail(n: usize) -> Vec<String> {
+    let log = KLOG.lock();
+    let n = n.min(log.count);
+
+    let mut lines = Vec::with_capacity(n);
+    for i in 0..n {
+        // head points one past the newest line
+        let idx = (log.head + KLOG_LINES - n + i) % KLOG_LINES;
+        lines.push(log.lines[idx].as_string());
+    }
+    lines
+}
+
+/// Commit a pending partial line so it shows up in tail()
+///
+/// Output from `serial_print!` without a trailing newline otherwise
+/// sits in the partial line until the next newline arrives.
+pub fn flush() {
+    let mut log = KLOG.lock();
+    if log.partial.len > 0 {
+        log.commit();
+    }
+}
+
+/// Number of writes dropped due to lock contention
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Test that tail() only returns the most recent lines, in order
+#[test_case]
+fn test_klog_tail_keeps_most_recent() {
+    use alloc::format;
+
+    serial_print!("test_klog_tail_keeps_most_recent...");
+    let total = KLOG_LINES + 10;
+    for i in 0..total {
+        serial_println!("klog-test {}", i);
+    }
+
+    let lines = tail(3);
+    assert_eq!(lines.len(), 3);
+    for (offset, line) in lines.iter().enumerate() {
+        assert_eq!(*line, format!("klog-test {}", total - 3 + offset));
+    }
+    assert_eq!(tail(KLOG_LINES * 2).len(), KLOG_LINES);
+    serial_println!("[ok]");
+}
@@ -19,9 +19,14 @@ use alloc::{boxed::Box, vec::Vec};
 
 #[macro_use]
 mod serial;
+#[macro_use]
+mod kassert;
+mod klog;
 mod gdt;
 mod interrupts;
+mod rtc;
 mod memory;
+mod stack;
 mod allocator;
 mod capability;
 mod syscall;
@@ -29,8 +34,11 @@ mod wasm_runtime;
 mod task;
 mod scheduler;
 mod ipc;
+mod sync;
+mod mpsc;
 mod benchmark;
 mod demos;
+mod shutdown;
 
 // Configure bootloader to map physical memory
 const BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
@@ -93,6 +101,8 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     if VERBOSE_BOOT { serial_println!("[INIT] Initializing heap allocator..."); }
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    memory::init_mapper(mapper);
     if VERBOSE_BOOT { serial_println!("[ OK ] Heap allocator initialized ({}KB)", allocator::HEAP_SIZE / 1024); }
 
     // Test heap allocation (only in debug builds)
@@ -166,6 +176,9 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     interrupts::init_timer(100);  // 100 Hz = 10ms intervals
     if VERBOSE_BOOT { serial_println!("[ OK ] Timer interrupts enabled"); }
 
+    rtc::init();
+    if VERBOSE_BOOT { serial_println!("[ OK ] RTC read, unix time {}", rtc::now()); }
+
     if VERBOSE_BOOT { serial_println!("[INFO] System running, timer ticking every 10ms..."); }
 
     // Initialize scheduler
@@ -173,15 +186,26 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     scheduler::init();
     if VERBOSE_BOOT { serial_println!("[ OK ] Task scheduler initialized"); }
 
+    // Entry points the TaskSpawn syscall can start (by table index)
+    let worker_entry = task::register_entry(task3_main);
+    if VERBOSE_BOOT { serial_println!("[ OK ] Registered spawnable entry {} (task3)", worker_entry); }
+
+    shutdown::register_cleanup(report_heap_on_shutdown);
+
     // Test scheduler (THIS CALL NEVER RETURNS - tasks run forever)
+    #[cfg(not(test))]
     test_scheduler();
 
+    // Tests get a task of their own so they can block, yield and spawn
+    // (never returns - test_runner shuts the kernel down)
     #[cfg(test)]
-    test_main();
+    run_tests_in_task();
+}
 
-    // Main idle loop - interrupts will fire asynchronously
-    loop {
-        x86_64::instructions::hlt();  // Halt until next interrupt
+/// Shutdown observer: log how much heap was still in use
+fn report_heap_on_shutdown() {
+    if let Some(stats) = allocator::stats() {
+        serial_println!("[SHUTDOWN] Heap: {} of {} bytes in use", stats.used, stats.size);
     }
 }
 
@@ -232,14 +256,11 @@ fn test_capability_system() {
     let rights_write = encode_rights(Rights::READ_WRITE);
     let result = ctx.syscall(1, cap2_id, rights_write, 0, 0);  // CapDerive
 
-    match result {
-        SyscallResult::Success(_) => {
-            serial_println!("[FAIL] Security breach! Escalated privileges!");
-        }
-        SyscallResult::Error(_) => {
-            serial_println!("[ OK ] Privilege escalation blocked (security works!)");
-        }
-    };
+    if result.is_ok() {
+        serial_println!("[FAIL] Security breach! Escalated privileges!");
+    } else {
+        serial_println!("[ OK ] Privilege escalation blocked (security works!)");
+    }
 
     // Test 4: Invoke the read-only capability
     serial_println!("[TEST] Invoking read-only capability...");
@@ -443,26 +464,34 @@ fn task3_main() -> ! {
     }
 }
 
+lazy_static::lazy_static! {
+    /// Holds the IPC sender back until the receiver's endpoint exists
+    static ref IPC_READY: sync::Barrier = sync::Barrier::new(2, capability::CapabilityId::new(101))
+        .expect("failed to create IPC barrier");
+}
+
+/// Broadcast endpoint the IPC sender announces it is done on
+#[cfg(not(test))]
+const IPC_DONE_BROADCAST: u64 = 102;
+
 /// Test IPC sender task - sends messages to receiver
 ///
 /// # Assumptions
 /// - TRUST: Task has been granted capability 1 (WRITE to endpoint 100)
+///   and capability 2 (WRITE to broadcast endpoint IPC_DONE_BROADCAST)
+#[cfg(not(test))]
 fn ipc_sender_main() -> ! {
     use alloc::vec;
     use capability::CapabilityId;
 
-    // Give receiver time to set up
-    for _ in 0..3 {
-        scheduler::task_yield();
-    }
+    // Wait for the receiver to create the endpoint
+    IPC_READY.wait();
 
     serial_println!("[IPC_SENDER] Starting message transmission");
 
     // Get current task ID and CSpace snapshot
-    let sender_id = scheduler::current_task_id()
-        .expect("No current task");
-    let sender_cspace = scheduler::current_task_cspace()
-        .expect("No CSpace for current task");
+    let sender_id = kensure!(scheduler::current_task_id(), "No current task");
+    let sender_cspace = kensure!(scheduler::current_task_cspace(), "No CSpace for current task");
 
     // Use capability ID 1 (granted at task setup with WRITE rights to endpoint 100)
     let endpoint_cap = CapabilityId::new(1);
@@ -480,6 +509,12 @@ fn ipc_sender_main() -> ! {
         scheduler::task_yield();
     }
 
+    // Fan the completion notice out to every subscriber
+    match ipc::broadcast_message(sender_id, &sender_cspace, CapabilityId::new(2), b"done".to_vec()) {
+        Ok(subscribers) => serial_println!("[IPC_SENDER] Broadcast done to {} subscriber(s)", subscribers),
+        Err(e) => serial_println!("[IPC_SENDER] Failed to broadcast: {:?}", e),
+    }
+
     serial_println!("[IPC_SENDER] All messages sent, going idle");
 
     loop {
@@ -491,21 +526,28 @@ fn ipc_sender_main() -> ! {
 ///
 /// # Assumptions
 /// - TRUST: Task has been granted capability 1 (READ to endpoint 100)
+///   and capability 2 (READ to broadcast endpoint IPC_DONE_BROADCAST)
+#[cfg(not(test))]
 fn ipc_receiver_main() -> ! {
     use capability::CapabilityId;
 
     serial_println!("[IPC_RECEIVER] Starting, creating endpoint");
 
     // Get current task ID and CSpace snapshot
-    let receiver_id = scheduler::current_task_id()
-        .expect("No current task");
-    let receiver_cspace = scheduler::current_task_cspace()
-        .expect("No CSpace for current task");
+    let receiver_id = kensure!(scheduler::current_task_id(), "No current task");
+    let receiver_cspace = kensure!(scheduler::current_task_cspace(), "No CSpace for current task");
 
     // Create IPC endpoint with ID 100 (the resource ID)
     let endpoint_id = CapabilityId::new(100);
-    match ipc::create_endpoint(endpoint_id) {
-        Ok(_) => serial_println!("[IPC_RECEIVER] Endpoint created successfully"),
+    let created = ipc::create_endpoint(endpoint_id)
+        .and_then(|_| ipc::create_broadcast_endpoint(CapabilityId::new(IPC_DONE_BROADCAST)))
+        // Subscribed before the sender starts, so the notice can't be missed
+        .and_then(|_| ipc::subscribe_broadcast(receiver_id, &receiver_cspace, CapabilityId::new(2)));
+    match created {
+        Ok(()) => {
+            serial_println!("[IPC_RECEIVER] Endpoint created successfully");
+            IPC_READY.wait();
+        }
         Err(e) => {
             serial_println!("[IPC_RECEIVER] Failed to create endpoint: {:?}", e);
             loop { scheduler::task_yield(); }
@@ -539,6 +581,22 @@ fn ipc_receiver_main() -> ! {
         }
     }
 
+    loop {
+        match ipc::try_receive_broadcast(receiver_id, &receiver_cspace, CapabilityId::new(2)) {
+            Ok(Some(notice)) => {
+                serial_println!("[IPC_RECEIVER] Broadcast from task {}: {:?}", notice.sender.value(), notice.data);
+                // Only one notice is ever sent; stop queueing copies for us
+                let _ = ipc::unsubscribe_broadcast(receiver_id, &receiver_cspace, CapabilityId::new(2));
+                break;
+            }
+            Ok(None) => scheduler::task_yield(),
+            Err(e) => {
+                serial_println!("[IPC_RECEIVER] Error receiving broadcast: {:?}", e);
+                break;
+            }
+        }
+    }
+
     serial_println!("[IPC_RECEIVER] All messages received, going idle");
 
     loop {
@@ -547,6 +605,7 @@ fn ipc_receiver_main() -> ! {
 }
 
 /// Benchmark task - measures context switch performance
+#[cfg(not(test))]
 fn benchmark_task() -> ! {
     // Wait for other tasks to start
     for _ in 0..2 {
@@ -586,6 +645,13 @@ fn benchmark_task() -> ! {
         serial_println!("");
         serial_println!("[BENCH] Collecting final benchmark results...");
 
+        // Interrupt latency over a few real timer ticks
+        benchmark::bench_interrupt_latency(10);
+        // IPC ping-pong with the echo task (needs this task's context)
+        benchmark::bench_ipc_roundtrip(10);
+        // Host -> guest -> host cost of a Wasm call
+        benchmark::bench_wasm_call(100);
+
         // Get boot cycles from global variable
         let boot_cycles = BOOT_CYCLES.load(core::sync::atomic::Ordering::Relaxed);
         let results = benchmark::collect_results(boot_cycles);
@@ -593,6 +659,7 @@ fn benchmark_task() -> ! {
 
         // Also print memory footprint
         benchmark::estimate_memory_footprint();
+        benchmark::report_capabilities();
     }
 
     serial_println!("");
@@ -605,18 +672,23 @@ fn benchmark_task() -> ! {
 }
 
 /// Test the task scheduler
-fn test_scheduler() {
-    use task::{Task, Priority, TaskContext};
+#[cfg(not(test))]
+fn test_scheduler() -> ! {
+    use task::{Task, Priority, Privilege};
     use capability::{Capability, CapabilityId, ResourceType, Rights};
 
     serial_println!("[TEST] Testing multitasking with IPC...");
 
     // Create tasks: IPC test + benchmark task
     serial_println!("[TEST] Creating tasks (IPC + benchmarks)...");
-    let mut receiver = Task::new("ipc_receiver", ipc_receiver_main, Priority::Normal);
-    let mut sender = Task::new("ipc_sender", ipc_sender_main, Priority::Normal);
-    let bencher = Task::new("benchmark", benchmark_task, Priority::Normal);
-    let task3 = Task::new("task3", task3_main, Priority::Normal);
+    let mut receiver = Task::try_new("ipc_receiver", ipc_receiver_main, Priority::Normal, Privilege::Kernel)
+        .expect("out of memory creating ipc_receiver");
+    let mut sender = Task::try_new("ipc_sender", ipc_sender_main, Priority::Normal, Privilege::Kernel)
+        .expect("out of memory creating ipc_sender");
+    let bencher = Task::try_new("benchmark", benchmark_task, Priority::Normal, Privilege::Kernel)
+        .expect("out of memory creating benchmark");
+    let task3 = Task::try_new("task3", task3_main, Priority::Normal, Privilege::Kernel)
+        .expect("out of memory creating task3");
 
     // Grant capabilities to IPC tasks BEFORE adding to scheduler
     // Endpoint resource ID is 100, capability ID in each task's CSpace is 1
@@ -629,6 +701,8 @@ fn test_scheduler() {
         Rights::READ,                    // READ rights for receiving
     );
     receiver.cspace_mut().insert(receiver_cap);
+    receiver.cspace_mut().insert(Capability::new(
+        CapabilityId::new(2), ResourceType::Endpoint, IPC_DONE_BROADCAST, Rights::READ));
     serial_println!("[TEST] Granted READ capability to receiver for endpoint 100");
 
     // Sender: WRITE rights to send messages
@@ -639,25 +713,34 @@ fn test_scheduler() {
         Rights { read: false, write: true, execute: false, grant: false },
     );
     sender.cspace_mut().insert(sender_cap);
+    sender.cspace_mut().insert(Capability::new(
+        CapabilityId::new(2), ResourceType::Endpoint, IPC_DONE_BROADCAST,
+        Rights { read: false, write: true, execute: false, grant: false }));
     serial_println!("[TEST] Granted WRITE capability to sender for endpoint 100");
 
     {
         let mut sched = scheduler::SCHEDULER.lock();
         let sched = sched.as_mut().expect("Scheduler not initialized");
 
-        let id_receiver = sched.add_task(receiver);
-        let id_sender = sched.add_task(sender);
-        let id_bench = sched.add_task(bencher);
-        let id3 = sched.add_task(task3);
+        let id_receiver = sched.add_task(receiver).expect("task limit reached");
+        let id_sender = sched.add_task(sender).expect("task limit reached");
+        let id_bench = sched.add_task(bencher).expect("task limit reached");
+        let id3 = sched.add_task(task3).expect("task limit reached");
 
         serial_println!("[ OK ] Created 4 tasks: {}, {}, {}, {}",
             id_receiver.value(), id_sender.value(), id_bench.value(), id3.value());
-
-        // Schedule first task
-        serial_println!("[TEST] Starting multitasking with IPC...");
-        sched.schedule();
     }
 
+    serial_println!("[TEST] Starting multitasking with IPC...");
+    start_first_task();
+}
+
+/// Schedule the first task and switch to it (never returns)
+fn start_first_task() -> ! {
+    use task::TaskContext;
+
+    scheduler::SCHEDULER.lock().as_mut().expect("Scheduler not initialized").schedule();
+
     serial_println!("[TEST] About to switch to first task...");
 
     // Create a dummy kernel context to save (we won't return here)
@@ -683,12 +766,36 @@ fn test_scheduler() {
     unreachable!("Returned from task execution");
 }
 
+/// Run the `#[test_case]`s from a kernel task
+#[cfg(test)]
+fn run_tests_in_task() -> ! {
+    use task::{Task, Priority, Privilege};
+
+    let runner = Task::try_new("test_runner", test_task_main, Priority::Normal, Privilege::Kernel)
+        .expect("out of memory creating test_runner");
+    scheduler::SCHEDULER.lock().as_mut().expect("Scheduler not initialized")
+        .add_task(runner).expect("task limit reached");
+    start_first_task();
+}
+
+/// Entry point of the test runner task
+#[cfg(test)]
+fn test_task_main() -> ! {
+    test_main();
+    unreachable!("test_runner shuts the kernel down");
+}
+
 /// Panic handler - called on kernel panic
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     // Only use serial output - VGA buffer may not be mapped yet
     serial_println!("[PANIC] {}", info);
 
+    // A failed test exits QEMU with a failure code instead of hanging
+    #[cfg(test)]
+    shutdown::shutdown(shutdown::QemuExitCode::Failed);
+
+    #[cfg(not(test))]
     loop {
         x86_64::instructions::hlt();
     }
@@ -700,5 +807,6 @@ fn test_runner(tests: &[&dyn Fn()]) {
     for test in tests {
         test();
     }
+    shutdown::shutdown(shutdown::QemuExitCode::Success);
 }
 
@@ -95,6 +95,7 @@ mod scheduler {
 // Architecture-independent modules (shared with x86-64)
 mod capability;
 mod syscall;
+mod mpsc;
 mod wasm_runtime;
 mod demos;
 mod benchmark;
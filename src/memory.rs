@@ -3,14 +3,25 @@
 //! Handles physical and virtual memory, page tables, and frame allocation
 
 use bootloader_api::info::{MemoryRegions, MemoryRegionKind};
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
-        FrameDeallocator,
+        frame::PhysFrameRange,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, FrameDeallocator,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Global frame allocator (installed after heap init)
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Active page table mapper (installed after heap init)
+///
+/// Like FRAME_ALLOCATOR, only locked with interrupts disabled, so the
+/// page fault handler can't find it held by a preempted task.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
 /// Initialize a new OffsetPageTable
 ///
 /// # Safety
@@ -79,6 +90,43 @@ impl BootInfoFrameAllocator {
         // Create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Allocate `count` physically contiguous frames (e.g. for DMA buffers)
+    ///
+    /// Scans the remaining usable frames for a large-enough run, so runs
+    /// may span adjacent memory regions. Frames skipped over to reach the
+    /// run are not reclaimed (we're still a bump allocator).
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut found = None;
+        let mut run_start: Option<PhysFrame> = None;
+        let mut run_len = 0;
+        let mut prev: Option<PhysFrame> = None;
+
+        for (idx, frame) in self.usable_frames().enumerate().skip(self.next) {
+            match (prev, run_start) {
+                (Some(p), Some(_)) if frame == p + 1 => run_len += 1,
+                _ => {
+                    // gap in physical memory - start a new run here
+                    run_start = Some(frame);
+                    run_len = 1;
+                }
+            }
+            prev = Some(frame);
+
+            if run_len == count {
+                found = run_start.map(|start| (idx, PhysFrame::range(start, frame + 1)));
+                break;
+            }
+        }
+
+        let (last_idx, range) = found?;
+        self.next = last_idx + 1;
+        Some(range)
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -90,8 +138,99 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 }
 
 impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
-    unsafe fn deallocate_frame(&mut self, _frame: PhysFrame) {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
         // TODO: Implement proper deallocation
-        // For now, we don't reuse frames (simple bump allocator)
+        // For now only the most recent frame can be handed back (simple
+        // bump allocator); anything older stays leaked
+        if self.next > 0 && self.usable_frames().nth(self.next - 1) == Some(frame) {
+            self.next -= 1;
+        }
+    }
+}
+
+/// Install the frame allocator for use after boot
+pub fn init_frame_allocator(allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Allocate `frames` physically contiguous frames from the global allocator
+///
+/// Returns None if the allocator isn't installed or no large-enough
+/// contiguous run remains.
+pub fn alloc_contiguous(frames: usize) -> Option<PhysFrameRange> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_contiguous(frames)
+    })
+}
+
+/// Install the page table mapper for use after boot
+pub fn init_mapper(mapper: OffsetPageTable<'static>) {
+    *MAPPER.lock() = Some(mapper);
+}
+
+/// Whether `page` is currently mapped
+pub fn is_mapped(page: Page<Size4KiB>) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        MAPPER.lock().as_ref().is_some_and(|mapper| mapper.translate_page(page).is_ok())
+    })
+}
+
+/// Back `page` with a fresh writable frame
+///
+/// Only try-locks the mapper and frame allocator, so it is safe to call
+/// from the page fault handler: it returns false instead of deadlocking
+/// if the fault hit while either was being used. Also false if they
+/// aren't installed, frames have run out, or the page is already mapped.
+pub fn try_map_fresh_page(page: Page<Size4KiB>) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let Some(mut mapper) = MAPPER.try_lock() else { return false };
+        let Some(mut frames) = FRAME_ALLOCATOR.try_lock() else { return false };
+        let (Some(mapper), Some(frames)) = (mapper.as_mut(), frames.as_mut()) else { return false };
+
+        let Some(frame) = frames.allocate_frame() else { return false };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frames) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                // Give the frame back; map_to doesn't take ownership on failure
+                unsafe { frames.deallocate_frame(frame) };
+                false
+            }
+        }
+    })
+}
+
+/// Test that a contiguous allocation really is physically adjacent
+#[test_case]
+fn test_alloc_contiguous_frames_adjacent() {
+    serial_print!("test_alloc_contiguous_frames_adjacent...");
+    let range = alloc_contiguous(8).expect("no contiguous run of 8 frames");
+
+    assert_eq!(range.count(), 8);
+    let mut expected = range.start.start_address().as_u64();
+    for frame in range {
+        assert_eq!(frame.start_address().as_u64(), expected);
+        expected += 4096;
     }
+    serial_println!("[ok]");
+}
+
+/// Test that a failed mapping hands its frame back to the allocator
+#[test_case]
+fn test_map_fresh_page_failure_returns_frame() {
+    serial_print!("test_map_fresh_page_failure_returns_frame...");
+    let next = || x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_ref().map(|frames| frames.next)
+    });
+
+    // The page holding this static is mapped, so map_to must refuse it
+    static ALREADY_MAPPED: u8 = 0;
+    let page = Page::containing_address(VirtAddr::new(&ALREADY_MAPPED as *const u8 as u64));
+    let before = next();
+    assert!(!try_map_fresh_page(page));
+    assert_eq!(next(), before);
+    serial_println!("[ok]");
 }
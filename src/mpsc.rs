@@ -0,0 +1,179 @@
+//! Bounded lock-free multi-producer single-consumer queue
+//!
+//! A fixed ring of slots, each tagged with a sequence number that says
+//! whether it is free for the producer at a given position or holds a
+//! value for the consumer there (Vyukov's bounded queue). Producers claim
+//! positions with a CAS on `tail`, so they never wait on each other or on
+//! the consumer; a full ring is reported instead of blocking.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+struct Slot<T> {
+    /// == position: free for the producer at `position`
+    /// == position + 1: holds the value pushed at `position`
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Lock-free ring of `N` slots
+pub struct MpscRing<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// Next position the consumer reads
+    head: AtomicUsize,
+    /// Next position a producer claims
+    tail: AtomicUsize,
+}
+
+// Values move between contexts, and slot access is arbitrated by `seq`
+unsafe impl<T: Send, const N: usize> Sync for MpscRing<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for MpscRing<T, N> {}
+
+impl<T, const N: usize> MpscRing<T, N> {
+    /// Create an empty ring (usable in statics)
+    pub const fn new() -> Self {
+        let mut slots = [const { Slot { seq: AtomicUsize::new(0), value: UnsafeCell::new(MaybeUninit::uninit()) } }; N];
+        let mut i = 0;
+        while i < N {
+            slots[i].seq = AtomicUsize::new(i);
+            i += 1;
+        }
+        MpscRing {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Push a value, handing it back if the ring is full
+    ///
+    /// Safe to call from any number of producers at once.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let lag = seq.wrapping_sub(pos) as isize;
+
+            if lag == 0 {
+                // Slot is free for this position: try to claim it
+                match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if lag < 0 {
+                // Still holds the value from one lap ago
+                return Err(value);
+            } else {
+                // Another producer claimed this position first
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pop the oldest value
+    ///
+    /// Returns None when empty, or when the oldest position is claimed but
+    /// its producer hasn't finished writing yet.
+    ///
+    /// # Safety
+    /// Only one consumer may pop at a time (callers serialize with a lock).
+    pub unsafe fn pop(&self) -> Option<T> {
+        let pos = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[pos % N];
+        if slot.seq.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return None;
+        }
+        let value = unsafe { (*slot.value.get()).assume_init_read() };
+        // Free the slot for the producer one lap ahead
+        slot.seq.store(pos.wrapping_add(N), Ordering::Release);
+        self.head.store(pos.wrapping_add(1), Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Approximate number of queued values (exact when quiescent)
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        tail.wrapping_sub(head).min(N)
+    }
+
+    /// Whether the ring looks empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for MpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscRing<T, N> {
+    fn drop(&mut self) {
+        // &mut self: no other consumer can exist
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Test interleaved producers across several laps of the ring
+    #[test_case]
+    fn test_mpsc_interleaved_fifo_per_producer() {
+        serial_print!("test_mpsc_interleaved_fifo_per_producer...");
+        const PRODUCERS: u32 = 3;
+        const PER_PRODUCER: u32 = 40;
+        let ring: MpscRing<(u32, u32), 8> = MpscRing::new();
+
+        let mut next = [0u32; PRODUCERS as usize];
+        let mut received: Vec<(u32, u32)> = Vec::new();
+        let mut round = 0u32;
+        while received.len() < (PRODUCERS * PER_PRODUCER) as usize {
+            // Producers take turns in a shifting order; a full ring makes
+            // them give up until the consumer catches up
+            for offset in 0..PRODUCERS {
+                let producer = ((round + offset) % PRODUCERS) as usize;
+                if next[producer] < PER_PRODUCER && ring.push((producer as u32, next[producer])).is_ok() {
+                    next[producer] += 1;
+                }
+            }
+            assert!(ring.len() <= ring.capacity());
+            // Consumer drains a little each round
+            for _ in 0..2 {
+                if let Some(item) = unsafe { ring.pop() } {
+                    received.push(item);
+                }
+            }
+            round += 1;
+        }
+
+        // Nothing lost, and each producer's messages arrive in order
+        assert!(ring.is_empty());
+        for producer in 0..PRODUCERS {
+            let seen: Vec<u32> = received.iter().filter(|m| m.0 == producer).map(|m| m.1).collect();
+            assert_eq!(seen, (0..PER_PRODUCER).collect::<Vec<_>>());
+        }
+
+        // A full ring hands the value back
+        for i in 0..8 {
+            assert!(ring.push((9, i)).is_ok());
+        }
+        assert_eq!(ring.push((9, 8)), Err((9, 8)));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,183 @@
+//! CMOS real-time clock for JerichoOS
+//!
+//! Reads the RTC once at boot to get a Unix timestamp, then advances it
+//! with the PIT tick counter. Reading the CMOS is slow and racy with its
+//! own update cycle, so `now()` never touches the hardware after `init()`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// CMOS register select port
+const CMOS_ADDRESS: u16 = 0x70;
+/// CMOS data port
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: update in progress
+const STATUS_A_UIP: u8 = 0x80;
+/// Status B: values are binary instead of BCD
+const STATUS_B_BINARY: u8 = 0x04;
+/// Status B: hours are 24-hour instead of 12-hour
+const STATUS_B_24H: u8 = 0x02;
+/// PM flag in the hours register when in 12-hour mode
+const HOUR_PM: u8 = 0x80;
+
+/// Timer frequency programmed by `interrupts::init_timer`
+pub const TICKS_PER_SECOND: u64 = 100;
+
+/// Unix time (seconds) read from the RTC at boot
+static BOOT_SECONDS: AtomicU64 = AtomicU64::new(0);
+/// Timer tick count when the RTC was read
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Raw register values as read from the CMOS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        address.write(reg);
+        data.read()
+    }
+}
+
+fn read_raw() -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UIP != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Convert raw registers to Unix seconds, honouring status register B
+fn decode(raw: RawTime, status_b: u8) -> u64 {
+    let pm = raw.hour & HOUR_PM != 0;
+    let raw = RawTime { hour: raw.hour & !HOUR_PM, ..raw };
+
+    let t = if status_b & STATUS_B_BINARY == 0 {
+        RawTime {
+            second: bcd_to_binary(raw.second),
+            minute: bcd_to_binary(raw.minute),
+            hour: bcd_to_binary(raw.hour),
+            day: bcd_to_binary(raw.day),
+            month: bcd_to_binary(raw.month),
+            year: bcd_to_binary(raw.year),
+        }
+    } else {
+        raw
+    };
+
+    let mut hour = t.hour;
+    if status_b & STATUS_B_24H == 0 {
+        // 12-hour mode: 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // The century register is not reliably present; assume 20xx
+    let year = 2000 + u64::from(t.year);
+    let days = days_from_civil(year, u64::from(t.month), u64::from(t.day));
+    days * 86_400 + u64::from(hour) * 3_600 + u64::from(t.minute) * 60 + u64::from(t.second)
+}
+
+/// Days since 1970-01-01 for a date in or after 1970
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Shift the year to start in March so the leap day is at the end
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Read the RTC and record the boot timestamp
+///
+/// Reads until two consecutive samples agree, so a read that straddles
+/// the RTC's once-per-second update is discarded.
+pub fn init() {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = read_register(REG_STATUS_B);
+
+    BOOT_SECONDS.store(decode(raw, status_b), Ordering::Relaxed);
+    BOOT_TICKS.store(crate::interrupts::timer_ticks(), Ordering::Relaxed);
+}
+
+/// Current Unix time in seconds
+pub fn now() -> u64 {
+    let elapsed = crate::interrupts::timer_ticks()
+        .saturating_sub(BOOT_TICKS.load(Ordering::Relaxed));
+    BOOT_SECONDS.load(Ordering::Relaxed) + elapsed / TICKS_PER_SECOND
+}
+
+/// Test register decoding and that now() advances one second per 100 ticks
+#[test_case]
+fn test_rtc_decode_and_advance() {
+    serial_print!("test_rtc_decode_and_advance...");
+
+    // 2024-03-01 13:45:30 in BCD, 12-hour mode (01 PM)
+    let raw = RawTime {
+        second: 0x30,
+        minute: 0x45,
+        hour: 0x01 | HOUR_PM,
+        day: 0x01,
+        month: 0x03,
+        year: 0x24,
+    };
+    assert_eq!(decode(raw, 0), 1_709_300_730);
+
+    // Same time in binary, 24-hour mode
+    let raw = RawTime { second: 30, minute: 45, hour: 13, day: 1, month: 3, year: 24 };
+    assert_eq!(decode(raw, STATUS_B_BINARY | STATUS_B_24H), 1_709_300_730);
+
+    // 12 AM is midnight
+    let raw = RawTime { second: 0, minute: 0, hour: 12, day: 1, month: 1, year: 0 };
+    assert_eq!(decode(raw, STATUS_B_BINARY), 946_684_800);
+
+    init();
+    let start_tick = crate::interrupts::timer_ticks();
+    let start = now();
+    while crate::interrupts::timer_ticks() < start_tick + TICKS_PER_SECOND {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = now() - start;
+    assert!((1..=2).contains(&elapsed));
+    serial_println!("[ok]");
+}
@@ -1,13 +1,120 @@
 // round robin scheduler
 // yeah it's not the most efficient, could use a better queue structure
 
-use crate::task::{Task, TaskId, TaskList, TaskState, TaskContext};
+use crate::task::{BlockReason, Priority, Task, TaskId, TaskList, TaskState, TaskContext};
 use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
+use crate::capability::{CapabilityId, CSpace, ResourceType};
+use crate::ipc::IpcError;
 
 /// Global scheduler instance
 pub static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
 
+/// Calls to `task_yield` (cooperative, including blocking waits)
+static VOLUNTARY_YIELDS: AtomicU64 = AtomicU64::new(0);
+/// Timer-forced yields
+static PREEMPTIONS: AtomicU64 = AtomicU64::new(0);
+/// Yields that actually switched to a different task
+static CONTEXT_SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Timer preemption switch (see set_preemption)
+static PREEMPTION_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Set while this CPU is picking the next task (see SwitchGuard)
+///
+/// The kernel runs on one core, so a single flag is the per-CPU state.
+static IN_CONTEXT_SWITCH: AtomicBool = AtomicBool::new(false);
+
+/// Task limit a new scheduler starts with (see Scheduler::set_max_tasks)
+///
+/// Each task holds a 64KB kernel stack, so this bounds the heap a spawn
+/// loop can eat without getting in the way of real workloads.
+pub const DEFAULT_MAX_TASKS: usize = 64;
+
+/// Consecutive turns each priority level gets in weighted mode
+///
+/// Indexed by `Priority as usize` (Low, Normal, High, Realtime). See
+/// Scheduler::set_weighted.
+pub const PRIORITY_WEIGHTS: [u32; 4] = [1, 2, 4, 8];
+
+/// Timer ticks utilization() looks back over (1 s at 100 Hz)
+///
+/// At most 127: the history is a u128 bitmask.
+pub const UTILIZATION_WINDOW: u32 = 100;
+
+/// Busy/idle history of the last UTILIZATION_WINDOW ticks, one bit each
+#[derive(Debug, Clone, Copy, Default)]
+struct LoadWindow {
+    /// Bit 0 is the newest tick; set = busy
+    busy: u128,
+    /// Ticks recorded so far, up to UTILIZATION_WINDOW
+    samples: u32,
+}
+
+impl LoadWindow {
+    fn record(&mut self, busy: bool) {
+        let mask = (1u128 << UTILIZATION_WINDOW) - 1;
+        self.busy = ((self.busy << 1) | busy as u128) & mask;
+        self.samples = (self.samples + 1).min(UTILIZATION_WINDOW);
+    }
+
+    /// Busy share of the recorded ticks, 0-100 (0 before any tick)
+    fn percent(&self) -> u8 {
+        if self.samples == 0 {
+            return 0;
+        }
+        (self.busy.count_ones() * 100 / self.samples) as u8
+    }
+}
+
+/// Scheduler errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedError {
+    /// Every slot up to the task limit holds a live task
+    TooManyTasks,
+}
+
+/// Scheduler activity counters since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedStats {
+    pub voluntary_yields: u64,
+    pub preemptions: u64,
+    pub context_switches: u64,
+}
+
+/// Debug snapshot of one task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub state: TaskState,
+    pub priority: Priority,
+    /// Set while the task is Blocked
+    pub block_reason: Option<BlockReason>,
+}
+
+impl core::fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "task {} ({}) ", self.id.value(), self.name)?;
+        match self.block_reason {
+            Some(reason) => write!(f, "blocked on {}", reason),
+            None => write!(f, "{:?}", self.state),
+        }
+    }
+}
+
+/// Snapshot of the scheduler counters
+pub fn stats() -> SchedStats {
+    SchedStats {
+        voluntary_yields: VOLUNTARY_YIELDS.load(Ordering::Relaxed),
+        preemptions: PREEMPTIONS.load(Ordering::Relaxed),
+        context_switches: CONTEXT_SWITCHES.load(Ordering::Relaxed),
+    }
+}
+
 /// Round-robin task scheduler
 pub struct Scheduler {
     /// All tasks in the system
@@ -18,6 +125,33 @@ pub struct Scheduler {
 
     /// Queue of ready tasks
     ready_queue: VecDeque<TaskId>,
+
+    /// Blocked tasks with a wake-up deadline (timer ticks)
+    sleepers: Vec<(TaskId, u64)>,
+
+    /// A timer preemption was skipped while preemption was disabled
+    preempt_pending: bool,
+
+    /// Consecutive picks made by the group hint (see take_group_peer)
+    group_streak: usize,
+
+    /// Most tasks (terminated but unreaped included) add_task accepts
+    max_tasks: usize,
+
+    /// Task whose ticks count as idle (see set_idle_task)
+    idle_task: Option<TaskId>,
+
+    /// Recent busy/idle ticks for utilization()
+    load: LoadWindow,
+
+    /// Weighted round-robin by priority (see set_weighted)
+    weighted: bool,
+
+    /// Level whose turns are being handed out in weighted mode
+    weighted_level: Priority,
+
+    /// Turns weighted_level has had in a row
+    level_turns: u32,
 }
 
 impl Scheduler {
@@ -27,16 +161,77 @@ impl Scheduler {
             tasks: TaskList::new(),
             current_task: None,
             ready_queue: VecDeque::new(),
+            sleepers: Vec::new(),
+            preempt_pending: false,
+            group_streak: 0,
+            max_tasks: DEFAULT_MAX_TASKS,
+            idle_task: None,
+            load: LoadWindow::default(),
+            weighted: false,
+            weighted_level: Priority::Realtime,
+            level_turns: 0,
         }
     }
 
     /// Add a task to the scheduler
-    pub fn add_task(&mut self, task: Task) -> TaskId {
+    ///
+    /// Fails once the scheduler holds max_tasks live tasks. Terminated
+    /// tasks keep their stacks and exit codes until a new task needs the
+    /// room: at the limit they are reaped before giving up.
+    pub fn add_task(&mut self, task: Task) -> Result<TaskId, SchedError> {
+        if self.tasks.len() >= self.max_tasks {
+            self.reap_terminated();
+        }
+        if self.tasks.len() >= self.max_tasks {
+            return Err(SchedError::TooManyTasks);
+        }
         let id = task.id();
         self.tasks.add(task);
         self.ready_queue.push_back(id);
         serial_println!("[SCHED] Added task {} to scheduler", id.value());
-        id
+        Ok(id)
+    }
+
+    /// Limit the number of tasks add_task accepts
+    ///
+    /// Lowering the limit below the current count doesn't remove any
+    /// tasks; it only refuses new ones until enough are reaped.
+    pub fn set_max_tasks(&mut self, max: usize) {
+        self.max_tasks = max;
+    }
+
+    /// Current task limit
+    pub fn max_tasks(&self) -> usize {
+        self.max_tasks
+    }
+
+    /// Drop terminated tasks, freeing their stacks
+    ///
+    /// Returns how many were reaped. A terminated task is never current
+    /// again, so its stack is no longer in use.
+    pub fn reap_terminated(&mut self) -> usize {
+        let dead: Vec<TaskId> = self.tasks.iter()
+            .filter(|task| task.state() == TaskState::Terminated && Some(task.id()) != self.current_task)
+            .map(|task| task.id())
+            .collect();
+        for &id in &dead {
+            self.tasks.remove(id);
+        }
+        dead.len()
+    }
+
+    /// Take a task out of the scheduler, whatever its state
+    ///
+    /// Also drops it from the run and sleep queues. Returns None for an
+    /// unknown task or the running one (its stack is in use).
+    pub fn remove_task(&mut self, id: TaskId) -> Option<Task> {
+        if Some(id) == self.current_task {
+            return None;
+        }
+        let task = self.tasks.remove(id)?;
+        self.ready_queue.retain(|&queued| queued != id);
+        self.sleepers.retain(|&(sleeper, _)| sleeper != id);
+        Some(task)
     }
 
     /// Get current running task ID
@@ -44,6 +239,42 @@ impl Scheduler {
         self.current_task
     }
 
+    /// Mark the task that runs when nothing else can
+    ///
+    /// Ticks spent in it, or with no task running at all, count as idle
+    /// for utilization.
+    pub fn set_idle_task(&mut self, id: Option<TaskId>) {
+        self.idle_task = id;
+    }
+
+    /// Whether the CPU is idle right now (no task, or the idle task)
+    pub fn is_idle(&self) -> bool {
+        self.current_task.is_none() || self.current_task == self.idle_task
+    }
+
+    /// Sample the current tick as busy or idle
+    pub fn record_tick(&mut self) {
+        let busy = !self.is_idle();
+        self.load.record(busy);
+    }
+
+    /// Percentage (0-100) of the last UTILIZATION_WINDOW ticks spent busy
+    pub fn utilization(&self) -> u8 {
+        self.load.percent()
+    }
+
+    /// Switch between flat and weighted round-robin
+    ///
+    /// In weighted mode each priority level gets PRIORITY_WEIGHTS turns in
+    /// a row (round-robin among its tasks) before the next lower level
+    /// runs, wrapping from Low back to Realtime. Higher levels get more CPU
+    /// but every level gets a turn each round. Levels with nothing ready
+    /// are skipped. The group hint is ignored while this is on.
+    pub fn set_weighted(&mut self, on: bool) {
+        self.weighted = on;
+        self.level_turns = 0;
+    }
+
     /// Get task count
     pub fn task_count(&self) -> usize {
         self.tasks.len()
@@ -59,12 +290,45 @@ impl Scheduler {
         self.tasks.get_mut(id)
     }
 
+    /// Peak stack usage of a task in bytes (see Task::stack_high_water)
+    pub fn stack_high_water(&self, id: TaskId) -> Option<usize> {
+        self.tasks.get(id).map(|task| task.stack_high_water())
+    }
+
     /// Schedule next task (round-robin)
     ///
-    /// Optimized for performance - minimal logging in hot path
+    /// Tasks sharing a group are preferred back-to-back (see
+    /// take_group_peer). Optimized for performance - minimal logging in
+    /// hot path
     pub fn schedule(&mut self) -> Option<TaskId> {
-        // Get next ready task from queue
-        if let Some(next_id) = self.ready_queue.pop_front() {
+        // Fast path: the running task is the only runnable one, so keep it
+        // without rotating the queue. Anything that wakes another task
+        // pushes it onto the queue, which takes us off this path.
+        if self.ready_queue.len() == 1 && self.ready_queue.front() == self.current_task.as_ref() {
+            if let Some(current_id) = self.current_task {
+                if self.tasks.get(current_id).map(|t| t.state()) == Some(TaskState::Running) {
+                    return Some(current_id);
+                }
+            }
+        }
+
+        // Get next ready task from queue, letting the group hint jump the line
+        let next = if self.weighted {
+            self.take_weighted()
+        } else {
+            match self.take_group_peer() {
+                Some(peer) => {
+                    self.group_streak += 1;
+                    Some(peer)
+                }
+                None => {
+                    self.group_streak = 0;
+                    self.ready_queue.pop_front()
+                }
+            }
+        };
+
+        if let Some(next_id) = next {
             // Mark previous task as ready (if any)
             if let Some(current_id) = self.current_task {
                 if let Some(current) = self.tasks.get_mut(current_id) {
@@ -96,6 +360,83 @@ impl Scheduler {
         None
     }
 
+    /// Remove and return a ready task from the current task's group
+    ///
+    /// A group gets at most one run of back-to-back picks per round: the
+    /// hint stops once every ready member has had a turn, so other tasks
+    /// are delayed by at most one group's worth of picks, never starved.
+    fn take_group_peer(&mut self) -> Option<TaskId> {
+        let current = self.current_task?;
+        let group = self.tasks.get(current)?.group()?;
+
+        let in_group = |id: &TaskId| {
+            self.tasks.get(*id).is_some_and(|t| t.group() == Some(group) && t.state() == TaskState::Ready)
+        };
+        let peers = self.ready_queue.iter().filter(|id| **id != current && in_group(id)).count();
+        if self.group_streak >= peers {
+            return None;
+        }
+
+        let pos = self.ready_queue.iter().position(|id| *id != current && in_group(id))?;
+        self.ready_queue.remove(pos)
+    }
+
+    /// Remove and return the next task in weighted mode (see set_weighted)
+    ///
+    /// Takes the first queued task of the current level while the level
+    /// has turns left, then moves down a level.
+    fn take_weighted(&mut self) -> Option<TaskId> {
+        let current = self.current_task;
+        let tasks = &self.tasks;
+        let runnable_at = |id: TaskId, level: Priority| tasks.get(id).is_some_and(|t| {
+            t.priority() == level
+                && (t.state() == TaskState::Ready || (Some(id) == current && t.state() == TaskState::Running))
+        });
+        let lower = |level: Priority| Priority::from_u64((level as u64 + 3) % 4).unwrap_or(Priority::Realtime);
+
+        let mut level = self.weighted_level;
+        let mut turns = self.level_turns;
+        if turns >= PRIORITY_WEIGHTS[level as usize] {
+            level = lower(level);
+            turns = 0;
+        }
+        for _ in 0..PRIORITY_WEIGHTS.len() {
+            if let Some(pos) = self.ready_queue.iter().position(|&id| runnable_at(id, level)) {
+                self.weighted_level = level;
+                self.level_turns = turns + 1;
+                return self.ready_queue.remove(pos);
+            }
+            // Nothing ready at this level: its turns pass to the next one
+            level = lower(level);
+            turns = 0;
+        }
+        None
+    }
+
+    /// Change a task's priority, repositioning its ready queue entry
+    ///
+    /// A queued task (Ready, or the running task's own entry) moves ahead
+    /// of the first queued task with a lower priority, or to the back if
+    /// there is none, so a promotion jumps the line and a demotion falls
+    /// behind its new peers. For the running task this takes effect at the
+    /// next schedule(). Returns false for an unknown task.
+    pub fn set_priority(&mut self, id: TaskId, priority: crate::task::Priority) -> bool {
+        match self.tasks.get_mut(id) {
+            Some(task) => task.set_priority(priority),
+            None => return false,
+        }
+
+        if let Some(pos) = self.ready_queue.iter().position(|&queued| queued == id) {
+            self.ready_queue.remove(pos);
+            let tasks = &self.tasks;
+            let ahead_of = self.ready_queue.iter()
+                .position(|&queued| tasks.get(queued).is_some_and(|t| t.priority() < priority))
+                .unwrap_or(self.ready_queue.len());
+            self.ready_queue.insert(ahead_of, id);
+        }
+        true
+    }
+
     /// Yield CPU to next task (cooperative multitasking)
     pub fn yield_cpu(&mut self) {
         if let Some(next_id) = self.schedule() {
@@ -105,33 +446,158 @@ impl Scheduler {
         }
     }
 
-    /// Block current task (for IPC wait)
-    pub fn block_current(&mut self) {
+    /// Disable preemption of the current task (nests)
+    pub fn preempt_disable(&mut self) {
+        if let Some(task) = self.current_task.and_then(|id| self.tasks.get_mut(id)) {
+            task.set_preempt_count(task.preempt_count() + 1);
+        }
+    }
+
+    /// Re-enable preemption of the current task
+    ///
+    /// Returns true when the count drops to zero and a timer preemption
+    /// was deferred meanwhile - the caller should yield now.
+    pub fn preempt_enable(&mut self) -> bool {
+        if let Some(task) = self.current_task.and_then(|id| self.tasks.get_mut(id)) {
+            let count = task.preempt_count().saturating_sub(1);
+            task.set_preempt_count(count);
+            if count == 0 && self.preempt_pending {
+                self.preempt_pending = false;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Check on a timer tick whether the current task may be preempted
+    ///
+    /// If preemption is disabled the tick is recorded as pending instead.
+    pub fn should_preempt(&mut self) -> bool {
+        let disabled = self.current_task
+            .and_then(|id| self.tasks.get(id))
+            .is_some_and(|task| task.preempt_count() > 0);
+
+        if disabled {
+            self.preempt_pending = true;
+        }
+        !disabled
+    }
+
+    /// Read a TLS slot of the current task
+    pub fn tls_get(&self, index: usize) -> Option<u64> {
+        self.tasks.get(self.current_task?)?.tls(index)
+    }
+
+    /// Write a TLS slot of the current task
+    ///
+    /// Returns false if there's no current task or the index is out of range.
+    pub fn tls_set(&mut self, index: usize, value: u64) -> bool {
+        match self.current_task.and_then(|id| self.tasks.get_mut(id)) {
+            Some(task) => task.set_tls(index, value),
+            None => false,
+        }
+    }
+
+    /// Block current task on `reason` (e.g. an IPC wait)
+    ///
+    /// Only updates scheduler state - the caller must `task_yield()`
+    /// afterwards to actually switch away.
+    pub fn block_current(&mut self, reason: BlockReason) {
         if let Some(current_id) = self.current_task {
             if let Some(task) = self.tasks.get_mut(current_id) {
-                task.set_state(TaskState::Blocked);
+                task.block(reason);
+
+                #[cfg(debug_assertions)]
                 serial_println!("[SCHED] Blocked task {}", current_id.value());
             }
 
             // Remove from ready queue
             self.ready_queue.retain(|&id| id != current_id);
+        }
+    }
 
-            // Schedule next task
-            self.schedule();
+    /// Put the current task to sleep until the given timer tick
+    ///
+    /// Only updates scheduler state - the caller must `task_yield()`
+    /// afterwards to actually switch away. The task is woken by
+    /// `wake_expired` once the deadline passes, or earlier by `unblock_task`.
+    pub fn sleep_current(&mut self, deadline: u64) {
+        self.block_current_until(BlockReason::Sleep(deadline), deadline);
+    }
+
+    /// Block the current task on `reason`, waking it at `deadline` at the latest
+    pub fn block_current_until(&mut self, reason: BlockReason, deadline: u64) {
+        if let Some(current_id) = self.current_task {
+            if let Some(task) = self.tasks.get_mut(current_id) {
+                task.block(reason);
+            }
+
+            self.ready_queue.retain(|&id| id != current_id);
+            self.sleepers.retain(|&(id, _)| id != current_id);
+            self.sleepers.push((current_id, deadline));
+        }
+    }
+
+    /// Wake all sleeping tasks whose deadline is at or before `now`
+    pub fn wake_expired(&mut self, now: u64) {
+        let mut i = 0;
+        while i < self.sleepers.len() {
+            let (task_id, deadline) = self.sleepers[i];
+            if deadline <= now {
+                // unblock_task removes the sleeper entry
+                self.unblock_task(task_id);
+            } else {
+                i += 1;
+            }
         }
     }
 
     /// Unblock a task (for IPC wake-up)
     pub fn unblock_task(&mut self, task_id: TaskId) {
+        self.sleepers.retain(|&(id, _)| id != task_id);
+
         if let Some(task) = self.tasks.get_mut(task_id) {
             if task.state() == TaskState::Blocked {
                 task.set_state(TaskState::Ready);
                 self.ready_queue.push_back(task_id);
+
+                #[cfg(debug_assertions)]
                 serial_println!("[SCHED] Unblocked task {}", task_id.value());
             }
         }
     }
 
+    /// Mark every task Terminated and empty the run queues
+    ///
+    /// Used on shutdown; returns how many tasks were still live. Stacks
+    /// stay allocated until reap_terminated.
+    pub fn terminate_all(&mut self) -> usize {
+        let mut terminated = 0;
+        for task in self.tasks.iter_mut() {
+            if task.state() != TaskState::Terminated {
+                task.set_state(TaskState::Terminated);
+                terminated += 1;
+            }
+        }
+        self.ready_queue.clear();
+        self.sleepers.clear();
+        self.current_task = None;
+        terminated
+    }
+
+    /// Debug snapshot of every task
+    pub fn task_infos(&self) -> Vec<TaskInfo> {
+        self.tasks.iter()
+            .map(|task| TaskInfo {
+                id: task.id(),
+                name: String::from(task.name()),
+                state: task.state(),
+                priority: task.priority(),
+                block_reason: task.block_reason(),
+            })
+            .collect()
+    }
+
     /// Terminate current task
     pub fn terminate_current(&mut self) {
         if let Some(current_id) = self.current_task {
@@ -184,6 +650,24 @@ pub fn current_task_cspace() -> Option<crate::capability::CSpace> {
     Some(task.cspace().clone())
 }
 
+/// Revoke every task's copies of `root` (see CSpace::revoke_derived)
+///
+/// The notices go out after the scheduler lock is released: delivering
+/// one wakes the receiver, which takes the lock again. Returns how many
+/// capabilities were revoked.
+pub fn revoke_derived(root: &crate::capability::Capability) -> usize {
+    let mut notices = Vec::new();
+    let revoked = x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().map_or(0, |scheduler| {
+            scheduler.tasks.iter_mut()
+                .map(|task| task.cspace_mut().revoke_derived(root, &mut notices))
+                .sum()
+        })
+    });
+    crate::capability::send_revocation_notices(notices);
+    revoked
+}
+
 /// Get the current task's ID
 ///
 /// # Assumptions
@@ -193,6 +677,209 @@ pub fn current_task_id() -> Option<TaskId> {
     SCHEDULER.lock().as_ref()?.current_task()
 }
 
+/// Disable preemption of the current task until preempt_enable
+///
+/// Calls nest; timer preemption is deferred until the count returns to
+/// zero. Voluntary task_yield() calls still switch.
+pub fn preempt_disable() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.preempt_disable();
+        }
+    });
+}
+
+/// Re-enable preemption, yielding if a preemption was deferred
+pub fn preempt_enable() {
+    let yield_now = x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().is_some_and(|s| s.preempt_enable())
+    });
+
+    if yield_now {
+        task_yield();
+    }
+}
+
+/// Read a task-local storage slot of the current task
+pub fn tls_get(index: usize) -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_ref()?.tls_get(index)
+    })
+}
+
+/// Write a task-local storage slot of the current task
+pub fn tls_set(index: usize, value: u64) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().is_some_and(|s| s.tls_set(index, value))
+    })
+}
+
+/// Turn timer preemption on or off system-wide (on at boot)
+///
+/// With preemption off the timer still ticks and wakes sleepers, but
+/// tasks only switch at explicit `task_yield` calls or when they block,
+/// giving reproducible interleavings for tests.
+pub fn set_preemption(enabled: bool) {
+    PREEMPTION_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Whether timer preemption is on
+pub fn preemption_enabled() -> bool {
+    PREEMPTION_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Task that receives keyboard input (None = the global input buffer)
+static INPUT_FOCUS: Mutex<Option<TaskId>> = Mutex::new(None);
+
+/// Endpoint each task wants its keyboard input on
+static INPUT_ENDPOINTS: Mutex<Vec<(TaskId, CapabilityId)>> = Mutex::new(Vec::new());
+
+/// Send decoded keyboard input to `task`
+///
+/// Characters go to the endpoint the task registered with
+/// register_input_endpoint; until it registers one they still land in
+/// the global input buffer.
+pub fn set_input_focus(task: TaskId) {
+    x86_64::instructions::interrupts::without_interrupts(|| *INPUT_FOCUS.lock() = Some(task));
+}
+
+/// Send keyboard input back to the global input buffer
+pub fn clear_input_focus() {
+    x86_64::instructions::interrupts::without_interrupts(|| *INPUT_FOCUS.lock() = None);
+}
+
+/// Task currently holding input focus
+pub fn input_focus() -> Option<TaskId> {
+    x86_64::instructions::interrupts::without_interrupts(|| *INPUT_FOCUS.lock())
+}
+
+/// Register the endpoint `task` receives keyboard input on
+///
+/// Each character arrives as an inline kernel notification holding its
+/// UTF-8 bytes (read with ipc::try_receive_small). Replaces any endpoint
+/// registered before.
+///
+/// # Security
+/// - `endpoint_cap` must be an Endpoint capability in `cspace` with READ
+///   rights (the holder must be able to receive what it registers for)
+pub fn register_input_endpoint(task: TaskId, cspace: &CSpace, endpoint_cap: CapabilityId) -> Result<(), IpcError> {
+    let endpoint = cspace.get(endpoint_cap).ok_or(IpcError::PermissionDenied)?;
+    if endpoint.resource_type() != ResourceType::Endpoint || !endpoint.rights().read {
+        return Err(IpcError::PermissionDenied);
+    }
+    let endpoint_id = CapabilityId::new(endpoint.resource_id());
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut endpoints = INPUT_ENDPOINTS.lock();
+        endpoints.retain(|&(t, _)| t != task);
+        endpoints.push((task, endpoint_id));
+    });
+    Ok(())
+}
+
+/// Input endpoint of the focused task (interrupt context)
+///
+/// None when nobody has focus, the focused task has no endpoint, or the
+/// interrupted code holds one of the locks.
+pub fn focused_input_endpoint() -> Option<CapabilityId> {
+    let focus = (*INPUT_FOCUS.try_lock()?)?;
+    INPUT_ENDPOINTS.try_lock()?
+        .iter()
+        .find(|&&(task, _)| task == focus)
+        .map(|&(_, endpoint)| endpoint)
+}
+
+/// Timer interrupt check: may the current task be preempted now?
+///
+/// Uses try_lock - if the scheduler is locked we skip this tick rather
+/// than deadlock.
+pub fn preempt_check() -> bool {
+    if !preemption_enabled() {
+        return false;
+    }
+    match SCHEDULER.try_lock() {
+        Some(mut guard) => guard.as_mut().is_some_and(|s| s.should_preempt()),
+        None => false,
+    }
+}
+
+/// Get a task's priority (None if no scheduler or unknown task)
+pub fn task_priority(id: TaskId) -> Option<crate::task::Priority> {
+    SCHEDULER.lock().as_ref()?.get_task(id).map(|task| task.priority())
+}
+
+/// Change a task's priority (false if no scheduler or unknown task)
+///
+/// See Scheduler::set_priority.
+pub fn set_priority(id: TaskId, priority: crate::task::Priority) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().is_some_and(|s| s.set_priority(id, priority))
+    })
+}
+
+/// List all tasks with their state and what blocked ones wait on
+pub fn list_tasks() -> Vec<TaskInfo> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_ref().map(|s| s.task_infos()).unwrap_or_default()
+    })
+}
+
+/// Sleep the current task for the given number of timer ticks
+///
+/// # Assumptions
+/// - INTERRUPTS: Must be enabled (the timer drives the wake-up)
+/// - LOCKS: No locks held by caller
+pub fn sleep_ticks(ticks: u64) {
+    use crate::interrupts::timer_ticks;
+
+    let deadline = timer_ticks().saturating_add(ticks);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.sleep_current(deadline);
+        }
+    });
+
+    // If no other task is runnable task_yield returns straight away,
+    // so keep checking the clock until the deadline passes
+    while timer_ticks() < deadline {
+        task_yield();
+    }
+}
+
+/// Wake sleepers whose deadline has passed (called from the timer interrupt)
+///
+/// Uses try_lock so an interrupt arriving while the scheduler is locked
+/// doesn't deadlock; the wake-up is simply retried on the next tick.
+pub fn wake_sleepers(now: u64) {
+    if let Some(mut guard) = SCHEDULER.try_lock() {
+        if let Some(scheduler) = guard.as_mut() {
+            scheduler.wake_expired(now);
+        }
+    }
+}
+
+/// Sample the tick for utilization (called from the timer interrupt)
+///
+/// Uses try_lock like wake_sleepers; a tick that finds the scheduler
+/// locked is left out of the window.
+pub fn record_load_tick() {
+    if let Some(mut guard) = SCHEDULER.try_lock() {
+        if let Some(scheduler) = guard.as_mut() {
+            scheduler.record_tick();
+        }
+    }
+}
+
+/// Percentage (0-100) of recent ticks spent running non-idle tasks
+///
+/// Covers the last UTILIZATION_WINDOW ticks. 0 if there's no scheduler.
+pub fn utilization() -> u8 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_ref().map_or(0, |s| s.utilization())
+    })
+}
+
 /// Context switch between tasks
 ///
 /// Saves current task's registers to old_context,
@@ -289,7 +976,7 @@ pub unsafe fn switch_context(old_context: &mut TaskContext, new_context: &TaskCo
 #[unsafe(naked)]
 pub extern "C" fn task_entry_wrapper() -> ! {
     core::arch::naked_asm!(
-        // RDI contains the entry point address (set up by Task::new)
+        // RDI contains the entry point address (set up by Task::try_new)
         // Call the task's entry point
         "call rdi",
 
@@ -306,18 +993,86 @@ pub extern "C" fn task_entry_wrapper() -> ! {
     )
 }
 
+/// Entry point for user (ring 3) tasks
+///
+/// Reached via `ret` in ring 0 like any new task, then builds an iret
+/// frame from registers set up by Task::try_new: RDI = entry point,
+/// RSI = user stack top, RDX = user code selector, RCX = user data
+/// selector. Interrupts are enabled in the new frame's RFLAGS.
+///
+/// # Safety
+/// This function never returns; the task can't return into ring 0.
+#[unsafe(naked)]
+pub extern "C" fn user_entry_trampoline() -> ! {
+    core::arch::naked_asm!(
+        "push rcx",      // SS
+        "push rsi",      // RSP
+        "push 0x200",    // RFLAGS (IF)
+        "push rdx",      // CS
+        "push rdi",      // RIP
+        "iretq",
+    )
+}
+
+/// Point RSP0 at `task`'s kernel stack before switching to it
+///
+/// Kernel tasks keep whatever is loaded: they never enter from ring 3.
+fn load_kernel_stack(task: &Task) {
+    if let Some(top) = task.kernel_stack_top() {
+        crate::gdt::set_kernel_stack(x86_64::VirtAddr::new(top));
+    }
+}
+
 /// Terminate the current task
 ///
 /// Called by task_entry_wrapper if a task unexpectedly returns
 extern "C" fn terminate_current_task() -> ! {
     serial_println!("[SCHED] Task returned unexpectedly, terminating...");
+    exit_current()
+}
 
-    // Lock scheduler and terminate current task
-    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+/// Terminate the current task with an exit code
+///
+/// The code stays readable through Task::exit_code until the task is
+/// reaped.
+pub fn exit(code: i32) -> ! {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            if let Some(task) = scheduler.current_task().and_then(|id| scheduler.get_task_mut(id)) {
+                task.set_exit_code(code);
+            }
+        }
+    });
+    exit_current()
+}
+
+/// Terminate the current task and switch to the next one for good
+///
+/// Does the switch itself instead of waiting for a timer tick, so the
+/// dead task's stack is never run on again. Also the resume point fault
+/// handlers redirect a misbehaving task to.
+pub extern "C" fn exit_current() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let next_ctx = SCHEDULER.lock().as_mut().and_then(|scheduler| {
+        // terminate_current picks the next task and marks it running
         scheduler.terminate_current();
+        let next = scheduler.get_task(scheduler.current_task()?)?;
+        load_kernel_stack(next);
+        Some(next.context() as *const TaskContext)
+    });
+
+    if let Some(next_ctx) = next_ctx {
+        CONTEXT_SWITCHES.fetch_add(1, Ordering::Relaxed);
+        // Never resumed, so the outgoing context can go anywhere
+        let mut discarded = TaskContext::new();
+        unsafe {
+            switch_context(&mut discarded, &*next_ctx);
+        }
     }
 
-    // Halt forever (scheduler should have switched to another task)
+    // Nothing else runnable
+    x86_64::instructions::interrupts::enable();
     loop {
         x86_64::instructions::hlt();
     }
@@ -335,6 +1090,54 @@ extern "C" fn terminate_current_task() -> ! {
 /// The switched-to task will have its own interrupt state restored from its saved RFLAGS.
 /// When this task resumes, interrupts are re-enabled if they were enabled on entry.
 pub fn task_yield() {
+    // A voluntary yield holds no locks: safe to let subsystems shed load
+    crate::allocator::run_low_memory_callback();
+    VOLUNTARY_YIELDS.fetch_add(1, Ordering::Relaxed);
+    switch_to_next();
+}
+
+/// Yield on behalf of the timer interrupt (counted as a preemption)
+pub fn preempt_yield() {
+    PREEMPTIONS.fetch_add(1, Ordering::Relaxed);
+    switch_to_next();
+}
+
+/// Marks the context switch path as entered; clears the flag on drop
+struct SwitchGuard;
+
+impl SwitchGuard {
+    /// Enter the switch path, or None if this CPU is already inside it
+    fn enter() -> Option<SwitchGuard> {
+        if IN_CONTEXT_SWITCH.swap(true, Ordering::Acquire) {
+            None
+        } else {
+            Some(SwitchGuard)
+        }
+    }
+}
+
+impl Drop for SwitchGuard {
+    fn drop(&mut self) {
+        IN_CONTEXT_SWITCH.store(false, Ordering::Release);
+    }
+}
+
+/// Stop the kernel on a nested yield before it corrupts scheduler state
+fn reentrant_switch() {
+    panic!("[SCHED] reentrant context switch");
+}
+
+/// Schedule and switch to the next task (shared by voluntary and timer yields)
+fn switch_to_next() {
+    switch_to_next_checked(reentrant_switch);
+}
+
+/// switch_to_next with the reentry handler split out so tests can observe it
+///
+/// `on_reentry` runs (with the caller's interrupt state restored) when a
+/// yield starts while another is still scheduling, e.g. from a log hook
+/// that yields. If it returns, the nested yield is dropped.
+fn switch_to_next_checked(on_reentry: fn()) {
     use x86_64::instructions::interrupts;
 
     // === PHASE 1: Disable interrupts ===
@@ -342,6 +1145,15 @@ pub fn task_yield() {
     let interrupts_enabled = interrupts::are_enabled();
     interrupts::disable();
 
+    // Checked with interrupts off, so a timer tick can't look like reentry
+    let Some(guard) = SwitchGuard::enter() else {
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+        on_reentry();
+        return;
+    };
+
     // === PHASE 2: Schedule under lock (interrupts disabled) ===
     let switch_info: Option<(*mut TaskContext, *const TaskContext)> = {
         let mut guard = SCHEDULER.lock();
@@ -395,10 +1207,9 @@ pub fn task_yield() {
             .unwrap()
             .context_mut() as *mut TaskContext;
 
-        let new_ctx_ptr = scheduler
-            .get_task(new_id)
-            .unwrap()
-            .context() as *const TaskContext;
+        let new_task = scheduler.get_task(new_id).unwrap();
+        load_kernel_stack(new_task);
+        let new_ctx_ptr = new_task.context() as *const TaskContext;
 
         #[cfg(debug_assertions)]
         serial_println!("[SCHED] Switching from task {} to task {}",
@@ -412,7 +1223,11 @@ pub fn task_yield() {
     // - Interrupts disabled: no timer, no nested task_yield
     // - Single-core: no concurrent execution possible
     // - Lock released: OK because nothing can run to mutate task list
+    // Cleared before the register swap: the task we switch to may be new
+    // and start at its entry point instead of returning through here
+    drop(guard);
     if let Some((old_ctx_ptr, new_ctx_ptr)) = switch_info {
+        CONTEXT_SWITCHES.fetch_add(1, Ordering::Relaxed);
         unsafe {
             switch_context(&mut *old_ctx_ptr, &*new_ctx_ptr);
         }
@@ -427,3 +1242,467 @@ pub fn task_yield() {
         interrupts::enable();
     }
 }
+
+/// Test that timer ticks don't preempt a task with preemption disabled
+#[test_case]
+fn test_preempt_disable_defers_switch() {
+    use crate::task::{Priority, Privilege};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_preempt_disable_defers_switch...");
+    let mut scheduler = Scheduler::new();
+    let a = scheduler.add_task(Task::try_new("preempt-a", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    scheduler.add_task(Task::try_new("preempt-b", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    assert_eq!(scheduler.schedule(), Some(a));
+
+    scheduler.preempt_disable();
+    scheduler.preempt_disable();  // nested
+
+    // Several ticks while pinned: no preemption, still on task A
+    for _ in 0..5 {
+        assert!(!scheduler.should_preempt());
+        assert_eq!(scheduler.current_task(), Some(a));
+    }
+
+    assert!(!scheduler.preempt_enable());  // still nested
+    assert!(scheduler.preempt_enable());   // deferred preemption now due
+    assert!(scheduler.should_preempt());
+    serial_println!("[ok]");
+}
+
+/// Test that TLS slots are private to each task across switches
+#[test_case]
+fn test_tls_isolated_between_tasks() {
+    use crate::task::{Priority, Privilege, TLS_SLOTS};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_tls_isolated_between_tasks...");
+    let mut scheduler = Scheduler::new();
+    let a = scheduler.add_task(Task::try_new("tls-a", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    let b = scheduler.add_task(Task::try_new("tls-b", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+
+    assert_eq!(scheduler.schedule(), Some(a));
+    assert!(scheduler.tls_set(0, 0xA));
+    assert!(!scheduler.tls_set(TLS_SLOTS, 1));  // out of range
+
+    assert_eq!(scheduler.schedule(), Some(b));
+    assert_eq!(scheduler.tls_get(0), Some(0));
+    assert!(scheduler.tls_set(0, 0xB));
+
+    // Interleave a few more rounds - each task keeps its own value
+    for _ in 0..3 {
+        assert_eq!(scheduler.schedule(), Some(a));
+        assert_eq!(scheduler.tls_get(0), Some(0xA));
+        assert_eq!(scheduler.schedule(), Some(b));
+        assert_eq!(scheduler.tls_get(0), Some(0xB));
+    }
+    assert_eq!(scheduler.tls_get(TLS_SLOTS), None);
+    serial_println!("[ok]");
+}
+
+/// Test that task_yield counts as voluntary and the timer's yield as a preemption
+#[test_case]
+fn test_stats_count_voluntary_yields() {
+    serial_print!("test_stats_count_voluntary_yields...");
+    // Interrupts off and the scheduler parked: no timer tick lands and no
+    // other task runs (and yields) in the middle, so the counts are exact
+    let (before, after) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let parked = SCHEDULER.lock().take();
+        let before = stats();
+        for _ in 0..10 {
+            task_yield();
+        }
+        for _ in 0..3 {
+            preempt_yield();
+        }
+        let after = stats();
+        *SCHEDULER.lock() = parked;
+        (before, after)
+    });
+    assert_eq!(after.voluntary_yields - before.voluntary_yields, 10);
+    assert_eq!(after.preemptions - before.preemptions, 3);
+    assert_eq!(after.context_switches, before.context_switches);
+    serial_println!("[ok]");
+}
+
+/// Test that a sleeping task wakes on exactly its deadline tick (mock clock)
+#[test_case]
+fn test_sleep_wakes_at_mock_tick() {
+    use crate::benchmark::{advance_mock, set_mock_clock, CYCLES_PER_TICK};
+    use crate::interrupts::timer_ticks;
+    use crate::task::{Priority, Privilege};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_sleep_wakes_at_mock_tick...");
+    set_mock_clock(true);
+    let mut scheduler = Scheduler::new();
+    let a = scheduler.add_task(Task::try_new("sleeper", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    assert_eq!(scheduler.schedule(), Some(a));
+
+    // Same deadline sleep_ticks(5) computes
+    scheduler.sleep_current(timer_ticks() + 5);
+    for _ in 0..4 {
+        advance_mock(CYCLES_PER_TICK);
+        scheduler.wake_expired(timer_ticks());
+        assert_eq!(scheduler.get_task(a).unwrap().state(), TaskState::Blocked);
+    }
+    advance_mock(CYCLES_PER_TICK);
+    scheduler.wake_expired(timer_ticks());
+    assert_eq!(timer_ticks(), 5);
+    assert_eq!(scheduler.get_task(a).unwrap().state(), TaskState::Ready);
+
+    set_mock_clock(false);
+    serial_println!("[ok]");
+}
+
+/// Test the single-ready-task fast path hands off once a second task is ready
+#[test_case]
+fn test_schedule_single_task_fast_path() {
+    use crate::task::{Priority, Privilege};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_schedule_single_task_fast_path...");
+    let mut scheduler = Scheduler::new();
+    let a = scheduler.add_task(Task::try_new("solo", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    for _ in 0..3 {
+        assert_eq!(scheduler.schedule(), Some(a));
+        assert_eq!(scheduler.ready_queue.len(), 1);
+        assert_eq!(scheduler.get_task(a).unwrap().state(), TaskState::Running);
+    }
+
+    let b = scheduler.add_task(Task::try_new("second", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    assert_eq!(scheduler.schedule(), Some(b));
+    assert_eq!(scheduler.get_task(a).unwrap().state(), TaskState::Ready);
+    assert_eq!(scheduler.schedule(), Some(a));
+    assert_eq!(scheduler.schedule(), Some(b));
+    serial_println!("[ok]");
+}
+
+/// Test that grouped tasks run back-to-back without starving other groups
+#[test_case]
+fn test_schedule_prefers_group_peers() {
+    use crate::task::{Priority, Privilege};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_schedule_prefers_group_peers...");
+    let mut scheduler = Scheduler::new();
+    // Interleaved so plain round-robin would alternate groups
+    let mut group_of = Vec::new();
+    for group in [1, 2, 1, 2] {
+        let mut task = Task::try_new("gang", worker_main, Priority::Normal, Privilege::Kernel).unwrap();
+        task.set_group(Some(group));
+        group_of.push((scheduler.add_task(task).unwrap(), group));
+    }
+    let group = |id: TaskId| group_of.iter().find(|&&(t, _)| t == id).unwrap().1;
+
+    let picks: Vec<TaskId> = (0..8).map(|_| scheduler.schedule().unwrap()).collect();
+    for pair in picks.chunks(2) {
+        assert_eq!(group(pair[0]), group(pair[1]));
+        assert_ne!(pair[0], pair[1]);
+    }
+    // Every task got its turns
+    for &(id, _) in &group_of {
+        assert_eq!(picks.iter().filter(|&&p| p == id).count(), 2);
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that add_task stops at max_tasks and reclaims terminated tasks
+#[test_case]
+fn test_max_tasks_limits_spawning() {
+    use crate::task::{Priority, Privilege};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_max_tasks_limits_spawning...");
+    let spawn = || Task::try_new("limited", worker_main, Priority::Normal, Privilege::Kernel).unwrap();
+    let mut scheduler = Scheduler::new();
+    assert_eq!(scheduler.max_tasks(), DEFAULT_MAX_TASKS);
+    scheduler.set_max_tasks(2);
+
+    let a = scheduler.add_task(spawn()).unwrap();
+    scheduler.add_task(spawn()).unwrap();
+    assert_eq!(scheduler.add_task(spawn()), Err(SchedError::TooManyTasks));
+
+    // A terminated task stays readable until its slot is needed
+    assert_eq!(scheduler.schedule(), Some(a));
+    scheduler.terminate_current();
+    assert!(scheduler.get_task(a).is_some());
+    assert!(scheduler.add_task(spawn()).is_ok());
+    assert!(scheduler.get_task(a).is_none());
+    assert_eq!(scheduler.add_task(spawn()), Err(SchedError::TooManyTasks));
+
+    // Spawn/exit cycles never run out of slots
+    for _ in 0..2 * DEFAULT_MAX_TASKS {
+        let id = scheduler.schedule().unwrap();
+        scheduler.terminate_current();
+        assert!(scheduler.get_task(id).is_some());
+        scheduler.add_task(spawn()).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that promoting a Low task gets it scheduled ahead of Normal ones
+#[test_case]
+fn test_set_priority_promotes_task() {
+    use crate::task::{Priority, Privilege};
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_set_priority_promotes_task...");
+    let mut scheduler = Scheduler::new();
+    let low = scheduler.add_task(Task::try_new("low", worker_main, Priority::Low, Privilege::Kernel).unwrap()).unwrap();
+    let a = scheduler.add_task(Task::try_new("normal-a", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    let b = scheduler.add_task(Task::try_new("normal-b", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    assert_eq!(scheduler.schedule(), Some(low));
+    assert_eq!(scheduler.schedule(), Some(a));
+
+    // Round-robin would run b next; the promoted task goes first
+    assert!(scheduler.set_priority(low, Priority::High));
+    assert_eq!(scheduler.get_task(low).map(|t| t.priority()), Some(Priority::High));
+    assert_eq!(scheduler.schedule(), Some(low));
+    assert_eq!(scheduler.schedule(), Some(b));
+
+    assert!(!scheduler.set_priority(TaskId::new(u64::MAX), Priority::Low));
+    serial_println!("[ok]");
+}
+
+/// Test that a task blocked on an endpoint reports it until woken
+#[test_case]
+fn test_block_reason_reported() {
+    use alloc::format;
+    use crate::task::Privilege;
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_block_reason_reported...");
+    let mut scheduler = Scheduler::new();
+    let a = scheduler.add_task(Task::try_new("waiter", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    assert_eq!(scheduler.schedule(), Some(a));
+
+    scheduler.block_current(BlockReason::Ipc(CapabilityId::new(100)));
+    let info = scheduler.task_infos().into_iter().find(|t| t.id == a).unwrap();
+    assert_eq!(info.state, TaskState::Blocked);
+    assert_eq!(info.block_reason, Some(BlockReason::Ipc(CapabilityId::new(100))));
+    assert_eq!(format!("{}", info), format!("task {} (waiter) blocked on endpoint 100", a.value()));
+
+    // Waking the task clears the reason
+    scheduler.unblock_task(a);
+    assert_eq!(scheduler.get_task(a).unwrap().block_reason(), None);
+    serial_println!("[ok]");
+}
+
+/// Test that with preemption off two yielding tasks interleave exactly
+#[test_case]
+fn test_cooperative_mode_is_deterministic() {
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    static TRACE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+    fn step(tag: u8) -> ! {
+        for i in 0..3 {
+            TRACE.lock().push(tag + i);
+            // Spin a while: a timer tick here must not switch tasks
+            for _ in 0..100_000 {
+                core::hint::spin_loop();
+            }
+            task_yield();
+        }
+        loop {
+            task_yield();
+        }
+    }
+
+    fn a_main() -> ! {
+        step(b'a')
+    }
+
+    fn b_main() -> ! {
+        step(b'A')
+    }
+
+    serial_print!("test_cooperative_mode_is_deterministic...");
+    set_preemption(false);
+    let tasks = [TestTask::spawn("coop-a", a_main), TestTask::spawn("coop-b", b_main)];
+    yield_until(500, || TRACE.lock().len() >= 6);
+    drop(tasks);
+    set_preemption(true);
+
+    assert_eq!(TRACE.lock().as_slice(), b"aAbBcC");
+    serial_println!("[ok]");
+}
+
+/// Test that a yield started mid-switch trips the reentry guard instead of recursing
+#[test_case]
+fn test_reentrant_yield_trips_guard() {
+    use core::sync::atomic::AtomicU32;
+
+    static REENTRIES: AtomicU32 = AtomicU32::new(0);
+
+    fn record_reentry() {
+        REENTRIES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    serial_print!("test_reentrant_yield_trips_guard...");
+    let switches = stats().context_switches;
+    {
+        // Stand in for an outer yield that is still scheduling
+        let _outer = SwitchGuard::enter().expect("switch flag left set");
+        assert!(SwitchGuard::enter().is_none());
+        switch_to_next_checked(record_reentry);
+        assert_eq!(REENTRIES.load(Ordering::SeqCst), 1);
+        assert_eq!(stats().context_switches, switches);
+    }
+
+    // The flag is clear again once the outer switch is done
+    assert!(!IN_CONTEXT_SWITCH.load(Ordering::SeqCst));
+    x86_64::instructions::interrupts::without_interrupts(|| switch_to_next_checked(record_reentry));
+    assert_eq!(REENTRIES.load(Ordering::SeqCst), 1);
+    serial_println!("[ok]");
+}
+
+/// Test that utilization stays low while idle and rises under load
+#[test_case]
+fn test_utilization_tracks_busy_ticks() {
+    use crate::task::{Priority, Privilege};
+
+    fn spin_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_utilization_tracks_busy_ticks...");
+    let mut scheduler = Scheduler::new();
+    assert_eq!(scheduler.utilization(), 0);
+    let idle = scheduler.add_task(Task::try_new("idle", spin_main, Priority::Low, Privilege::Kernel).unwrap()).unwrap();
+    scheduler.set_idle_task(Some(idle));
+
+    // Nothing to do but idle
+    for _ in 0..UTILIZATION_WINDOW {
+        assert_eq!(scheduler.schedule(), Some(idle));
+        scheduler.record_tick();
+    }
+    assert_eq!(scheduler.utilization(), 0);
+
+    // A worker that never blocks takes at least its round-robin share
+    let worker = scheduler.add_task(Task::try_new("worker", spin_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    let mut worker_ticks = 0;
+    for _ in 0..UTILIZATION_WINDOW {
+        if scheduler.schedule() == Some(worker) {
+            worker_ticks += 1;
+        }
+        scheduler.record_tick();
+    }
+    assert!(worker_ticks >= UTILIZATION_WINDOW / 2);
+    assert_eq!(scheduler.utilization() as u32, worker_ticks * 100 / UTILIZATION_WINDOW);
+    serial_println!("[ok]");
+}
+
+/// Test that weighted mode splits turns between levels by PRIORITY_WEIGHTS
+#[test_case]
+fn test_weighted_round_robin_by_priority() {
+    use crate::task::Privilege;
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_weighted_round_robin_by_priority...");
+    let mut scheduler = Scheduler::new();
+    let low = scheduler.add_task(Task::try_new("weighted-low", worker_main, Priority::Low, Privilege::Kernel).unwrap()).unwrap();
+    let normal = scheduler.add_task(Task::try_new("weighted-normal", worker_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    let high = scheduler.add_task(Task::try_new("weighted-high", worker_main, Priority::High, Privilege::Kernel).unwrap()).unwrap();
+    scheduler.set_weighted(true);
+
+    // One pick per tick, none of the tasks ever blocks
+    let mut turns = [0u32; 3];
+    for _ in 0..140 {
+        match scheduler.schedule() {
+            Some(id) if id == low => turns[0] += 1,
+            Some(id) if id == normal => turns[1] += 1,
+            Some(id) if id == high => turns[2] += 1,
+            other => panic!("unexpected pick {:?}", other),
+        }
+    }
+    let [low_turns, normal_turns, high_turns] = turns;
+    assert!(low_turns > 0);
+    assert!((3 * low_turns..=5 * low_turns).contains(&high_turns));
+    assert!(high_turns > normal_turns && normal_turns > low_turns);
+
+    // Back to flat round-robin: everyone gets an equal share
+    scheduler.set_weighted(false);
+    let mut flat = [0u32; 3];
+    for _ in 0..30 {
+        let id = scheduler.schedule().unwrap();
+        flat[[low, normal, high].iter().position(|&t| t == id).unwrap()] += 1;
+    }
+    assert_eq!(flat, [10, 10, 10]);
+    serial_println!("[ok]");
+}
+
+/// Test that switching to a user task loads its own kernel stack as RSP0
+#[test_case]
+fn test_switch_loads_user_kernel_stack() {
+    use crate::task::Privilege;
+
+    fn worker_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_switch_loads_user_kernel_stack...");
+    let user = Task::try_new("rsp0-user", worker_main, Priority::Normal, Privilege::User).unwrap();
+    let kernel = Task::try_new("rsp0-kernel", worker_main, Priority::Normal, Privilege::Kernel).unwrap();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let saved = crate::gdt::kernel_stack();
+        load_kernel_stack(&user);
+        assert_eq!(Some(crate::gdt::kernel_stack().as_u64()), user.kernel_stack_top());
+        // Kernel tasks leave RSP0 alone
+        load_kernel_stack(&kernel);
+        assert_eq!(Some(crate::gdt::kernel_stack().as_u64()), user.kernel_stack_top());
+        crate::gdt::set_kernel_stack(saved);
+    });
+    serial_println!("[ok]");
+}
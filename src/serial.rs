@@ -22,6 +22,9 @@ pub fn _print(args: ::core::fmt::Arguments) {
         .lock()
         .write_fmt(args)
         .expect("Printing to serial failed");
+
+    // Mirror into the in-memory kernel log (non-blocking)
+    crate::klog::record(args);
 }
 
 /// Print to serial port
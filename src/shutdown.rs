@@ -0,0 +1,134 @@
+//! Graceful shutdown for JerichoOS
+//!
+//! Stops every task, gives registered cleanup observers a chance to run,
+//! flushes the kernel log and then leaves QEMU through the ISA debug exit
+//! device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`). Without
+//! that device the port write does nothing and the CPU just halts.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::scheduler::{Scheduler, SCHEDULER};
+
+/// I/O port of QEMU's isa-debug-exit device
+const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// Most cleanup observers that can be registered
+pub const MAX_CLEANUP_OBSERVERS: usize = 16;
+
+/// Status reported to the host; QEMU exits with `(code << 1) | 1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Called once during shutdown, after tasks are stopped
+pub type CleanupObserver = fn();
+
+static OBSERVERS: Mutex<Vec<CleanupObserver>> = Mutex::new(Vec::new());
+
+/// Register a function to run on shutdown
+///
+/// Observers run in registration order. Returns false if the table is
+/// full.
+pub fn register_cleanup(observer: CleanupObserver) -> bool {
+    let mut observers = OBSERVERS.lock();
+    if observers.len() >= MAX_CLEANUP_OBSERVERS {
+        return false;
+    }
+    observers.push(observer);
+    true
+}
+
+/// Write `code` to the debug exit port
+pub fn exit_qemu(code: QemuExitCode) {
+    let mut port = Port::new(QEMU_EXIT_PORT);
+    unsafe { port.write(code as u32) };
+}
+
+/// Stop the kernel: terminate tasks, run cleanup, flush logs and exit
+///
+/// # Assumptions
+/// - CONTEXT: Any; interrupts are disabled for good, so the calling task
+///   never runs again
+pub fn shutdown(code: QemuExitCode) -> ! {
+    x86_64::instructions::interrupts::disable();
+    // A task interrupted mid-schedule may still hold the lock; skip the
+    // task bookkeeping rather than deadlock on the way out
+    let mut scheduler = SCHEDULER.try_lock();
+    let scheduler = scheduler.as_mut().and_then(|s| s.as_mut());
+    run_shutdown(scheduler, code, exit_qemu);
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Shutdown sequence up to and including the exit call
+///
+/// Split out of shutdown() so tests can supply their own scheduler and
+/// exit function.
+fn run_shutdown(scheduler: Option<&mut Scheduler>, code: QemuExitCode, exit: fn(QemuExitCode)) {
+    serial_println!("[SHUTDOWN] Stopping tasks...");
+    if let Some(scheduler) = scheduler {
+        let stopped = scheduler.terminate_all();
+        serial_println!("[SHUTDOWN] Terminated {} tasks", stopped);
+    }
+
+    // Copy out so an observer may register (or print) without deadlocking
+    let observers = OBSERVERS.lock().clone();
+    for observer in observers {
+        observer();
+    }
+
+    crate::klog::flush();
+    serial_println!("[SHUTDOWN] complete");
+    exit(code);
+}
+
+/// Test that shutdown stops tasks, runs observers and reaches the exit
+#[test_case]
+fn test_shutdown_runs_cleanup() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use crate::task::{Priority, Privilege, Task, TaskState};
+
+    static CLEANUPS: AtomicU32 = AtomicU32::new(0);
+    static EXIT_CODE: AtomicU32 = AtomicU32::new(0);
+
+    fn cleanup() {
+        CLEANUPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn record_exit(code: QemuExitCode) {
+        EXIT_CODE.store(code as u32, Ordering::SeqCst);
+    }
+
+    fn idle_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_shutdown_runs_cleanup...");
+    let mut scheduler = Scheduler::new();
+    let ids = [
+        scheduler.add_task(Task::try_new("a", idle_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap(),
+        scheduler.add_task(Task::try_new("b", idle_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap(),
+    ];
+    assert!(register_cleanup(cleanup));
+
+    run_shutdown(Some(&mut scheduler), QemuExitCode::Failed, record_exit);
+
+    for id in ids {
+        assert_eq!(scheduler.get_task(id).unwrap().state(), TaskState::Terminated);
+    }
+    assert!(scheduler.schedule().is_none());
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 1);
+    assert_eq!(EXIT_CODE.load(Ordering::SeqCst), QemuExitCode::Failed as u32);
+    assert_eq!(crate::klog::tail(1)[0], "[SHUTDOWN] complete");
+
+    OBSERVERS.lock().retain(|&observer| !core::ptr::fn_addr_eq(observer, cleanup as CleanupObserver));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,279 @@
+//! Lazily committed task stacks for JerichoOS
+//!
+//! Each task stack lives in its own fixed slot of a reserved virtual
+//! region. Only the top page is mapped up front; the page fault handler
+//! maps the rest on demand as the stack grows down. The lowest page of
+//! every slot is a guard that is never mapped, so running off the end
+//! still faults fatally instead of corrupting the neighbouring stack.
+//!
+//! Frames are never given back (the frame allocator can't reuse them
+//! yet). Instead a released slot keeps its mappings and is handed to the
+//! next task, so the memory a slot has committed is recycled.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Usable stack size per task (64 KB)
+pub const TASK_STACK_SIZE: usize = 64 * 1024;
+
+/// Fill pattern for fresh stack memory (used to measure peak stack usage)
+pub const STACK_SENTINEL: u8 = 0xAA;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Stack pages plus the guard page below them
+const SLOT_SIZE: usize = TASK_STACK_SIZE + PAGE_SIZE;
+
+/// Most task stacks that can exist at once
+pub const MAX_TASK_STACKS: usize = 256;
+
+/// Start of the virtual region holding the stack slots
+pub const STACK_REGION_START: usize = 0x_5555_0000_0000;
+
+/// Pages mapped at the top of each slot (0 = slot never used)
+static COMMITTED: [AtomicUsize; MAX_TASK_STACKS] = [const { AtomicUsize::new(0) }; MAX_TASK_STACKS];
+
+/// Slots currently owned by a TaskStack (faults in free slots stay fatal)
+static IN_USE: [AtomicBool; MAX_TASK_STACKS] = [const { AtomicBool::new(false) }; MAX_TASK_STACKS];
+
+/// Stack touched by probe_stack before the page tables are locked
+const STACK_PROBE_SIZE: usize = 2 * PAGE_SIZE;
+
+/// Slot bookkeeping: released slots are reused before fresh ones
+struct Slots {
+    free: Vec<usize>,
+    next: usize,
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots { free: Vec::new(), next: 0 });
+
+fn slot_top(slot: usize) -> usize {
+    STACK_REGION_START + (slot + 1) * SLOT_SIZE
+}
+
+/// Map pages from the slot's committed bottom down to (and including) `page`
+///
+/// Keeps the committed range contiguous so high-water scans never touch
+/// an unmapped page. Returns false if a page couldn't be mapped.
+fn commit_down_to(slot: usize, page: Page<Size4KiB>) -> bool {
+    let top = slot_top(slot);
+    let wanted = (top - page.start_address().as_u64() as usize) / PAGE_SIZE;
+    let committed = COMMITTED[slot].load(Ordering::Acquire);
+
+    for depth in committed + 1..=wanted {
+        let addr = top - depth * PAGE_SIZE;
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr as u64));
+        if !crate::memory::try_map_fresh_page(page) {
+            return false;
+        }
+        unsafe { core::ptr::write_bytes(addr as *mut u8, STACK_SENTINEL, PAGE_SIZE) };
+        COMMITTED[slot].store(depth, Ordering::Release);
+    }
+    true
+}
+
+/// Fault in the stack below the caller before mapping anything
+///
+/// commit_down_to runs with the mapper and frame allocator locked, and
+/// the page fault handler only try-locks them: a stack fault in there
+/// would be fatal. Touching the next pages first grows the caller's own
+/// stack (if it is a task stack) while the locks are still free. The
+/// fault handler runs on its own IST stack and doesn't need this.
+#[inline(never)]
+fn probe_stack() {
+    let probe = [0u8; STACK_PROBE_SIZE];
+    core::hint::black_box(&probe);
+}
+
+/// Page fault hook: grow a task stack if `addr` is inside one
+///
+/// Returns true if a page was mapped and the faulting access can be
+/// retried. Faults on a guard page, in a slot no stack owns, outside the
+/// region, or when the page tables are busy (the fault hit while they
+/// were being edited) return false and stay fatal.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64() as usize;
+    let Some(offset) = addr.checked_sub(STACK_REGION_START) else { return false };
+    let slot = offset / SLOT_SIZE;
+    if slot >= MAX_TASK_STACKS || offset % SLOT_SIZE < PAGE_SIZE || !IN_USE[slot].load(Ordering::Acquire) {
+        return false;
+    }
+    commit_down_to(slot, Page::containing_address(VirtAddr::new(addr as u64)))
+}
+
+/// A task's kernel stack, committed on demand
+pub struct TaskStack {
+    slot: usize,
+    /// Every page committed up front (see lock)
+    locked: bool,
+}
+
+impl TaskStack {
+    /// Reserve a stack slot and commit its top page
+    ///
+    /// Returns None if every slot is in use or the top page can't be
+    /// mapped. Already committed pages of a reused slot are refilled with
+    /// the sentinel so high_water starts from zero.
+    pub fn try_new() -> Option<Self> {
+        let slot = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.next < MAX_TASK_STACKS => {
+                    slots.next += 1;
+                    slots.next - 1
+                }
+                None => return None,
+            }
+        };
+        IN_USE[slot].store(true, Ordering::Release);
+        let stack = TaskStack { slot, locked: false };
+
+        probe_stack();
+        let top_page = Page::containing_address(VirtAddr::new((slot_top(slot) - PAGE_SIZE) as u64));
+        if !commit_down_to(slot, top_page) {
+            return None;
+        }
+        let committed = stack.committed_pages() * PAGE_SIZE;
+        unsafe { core::ptr::write_bytes((stack.top() as usize - committed) as *mut u8, STACK_SENTINEL, committed) };
+        Some(stack)
+    }
+
+    /// Commit every page of the stack now
+    ///
+    /// For tasks that can't afford a page fault mid-run. The new pages
+    /// get the sentinel like lazily faulted ones, so high_water still
+    /// works. The caller's own stack is probed first, so mapping never
+    /// faults with the page tables locked. Committed pages are never
+    /// unmapped, so once this succeeds the stack stays fault-free.
+    /// Returns false if a page couldn't be mapped (out of frames, or the
+    /// page tables are busy).
+    pub fn lock(&mut self) -> bool {
+        let bottom = slot_top(self.slot) - TASK_STACK_SIZE;
+        probe_stack();
+        if !commit_down_to(self.slot, Page::containing_address(VirtAddr::new(bottom as u64))) {
+            return false;
+        }
+        self.locked = true;
+        true
+    }
+
+    /// Whether lock has committed the whole stack
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Initial stack pointer (one past the highest byte)
+    pub fn top(&self) -> u64 {
+        slot_top(self.slot) as u64
+    }
+
+    /// Pages currently mapped, counted down from the top
+    pub fn committed_pages(&self) -> usize {
+        COMMITTED[self.slot].load(Ordering::Acquire)
+    }
+
+    /// Peak stack usage in bytes
+    ///
+    /// Scans up from the lowest committed page for the first byte that no
+    /// longer holds the sentinel. A value that happens to equal the
+    /// sentinel can under-report by a few bytes.
+    pub fn high_water(&self) -> usize {
+        let committed = self.committed_pages() * PAGE_SIZE;
+        let base = self.top() as usize - committed;
+        let bytes = unsafe { core::slice::from_raw_parts(base as *const u8, committed) };
+        let untouched = bytes.iter().position(|&b| b != STACK_SENTINEL).unwrap_or(committed);
+        committed - untouched
+    }
+}
+
+impl Drop for TaskStack {
+    fn drop(&mut self) {
+        IN_USE[self.slot].store(false, Ordering::Release);
+        SLOTS.lock().free.push(self.slot);
+    }
+}
+
+/// Test that a fault below the committed top maps pages down to it
+#[test_case]
+fn test_fault_commits_stack_pages() {
+    serial_print!("test_fault_commits_stack_pages...");
+    // Reused slots keep their pages: take stacks until one can still grow
+    let mut full = Vec::new();
+    let stack = loop {
+        let stack = TaskStack::try_new().expect("no stack slot");
+        if stack.committed_pages() + 2 <= TASK_STACK_SIZE / PAGE_SIZE {
+            break stack;
+        }
+        full.push(stack);
+    };
+    drop(full);
+
+    // Two pages below the committed bottom: both get mapped, in order
+    let before = stack.committed_pages();
+    let addr = stack.top() as usize - (before + 2) * PAGE_SIZE + 8;
+    assert!(handle_page_fault(VirtAddr::new(addr as u64)));
+    assert_eq!(stack.committed_pages(), before + 2);
+    assert_eq!(unsafe { core::ptr::read_volatile(addr as *const u8) }, STACK_SENTINEL);
+    assert_eq!(stack.high_water(), 0);
+
+    // Guard pages and slots no stack owns never grow
+    assert!(!handle_page_fault(VirtAddr::new((stack.top() as usize - SLOT_SIZE) as u64)));
+    let free = stack.slot;
+    drop(stack);
+    assert!(!handle_page_fault(VirtAddr::new((slot_top(free) - TASK_STACK_SIZE) as u64)));
+    serial_println!("[ok]");
+}
+
+/// Test that a task's own call chain faults its stack pages in
+#[test_case]
+fn test_stack_grows_under_a_running_task() {
+    use core::sync::atomic::AtomicBool;
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    static DONE: AtomicBool = AtomicBool::new(false);
+    static BEFORE: AtomicUsize = AtomicUsize::new(0);
+    static AFTER: AtomicUsize = AtomicUsize::new(0);
+    static LOWEST: AtomicUsize = AtomicUsize::new(0);
+
+    /// Recurse until a frame sits below `limit`; returns that frame's address
+    #[inline(never)]
+    fn grow_to(limit: usize) -> usize {
+        let frame = [0u8; 512];
+        let here = core::hint::black_box(&frame).as_ptr() as usize;
+        if here <= limit { here } else { core::hint::black_box(grow_to(limit)) }
+    }
+
+    fn grower_main() -> ! {
+        let here = core::hint::black_box(0u8);
+        let slot = (&here as *const u8 as usize - STACK_REGION_START) / SLOT_SIZE;
+        let top = slot_top(slot);
+
+        // Maps pages while this stack may have only its top page committed
+        drop(TaskStack::try_new());
+
+        let before = COMMITTED[slot].load(Ordering::Acquire);
+        let target = (before + 2).min(TASK_STACK_SIZE / PAGE_SIZE - 2);
+        LOWEST.store(grow_to(top - target * PAGE_SIZE), Ordering::SeqCst);
+        BEFORE.store(before, Ordering::SeqCst);
+        AFTER.store(COMMITTED[slot].load(Ordering::Acquire), Ordering::SeqCst);
+        DONE.store(true, Ordering::SeqCst);
+        crate::scheduler::exit(0)
+    }
+
+    serial_print!("test_stack_grows_under_a_running_task...");
+    let _grower = TestTask::spawn("stack-grower", grower_main);
+    assert!(yield_until(200, || DONE.load(Ordering::SeqCst)));
+
+    // Every page down to the deepest frame got committed, in order
+    let (before, after) = (BEFORE.load(Ordering::SeqCst), AFTER.load(Ordering::SeqCst));
+    assert!(after > before || before >= TASK_STACK_SIZE / PAGE_SIZE - 2);
+    let lowest = LOWEST.load(Ordering::SeqCst);
+    assert!(lowest >= STACK_REGION_START);
+    let slot = (lowest - STACK_REGION_START) / SLOT_SIZE;
+    assert!(slot_top(slot) - after * PAGE_SIZE <= lowest);
+    serial_println!("[ok]");
+}
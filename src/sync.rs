@@ -0,0 +1,381 @@
+//! Blocking synchronization primitives for kernel tasks
+//!
+//! Built on IPC endpoints: a blocked task waits in `receive_message_blocking`
+//! and is woken by a kernel notification, so waiting costs no CPU and
+//! wake-ups go through the scheduler's normal priority handling.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use crate::capability::{Capability, CapabilityId, CSpace, ResourceType, Rights};
+use crate::ipc::{self, IpcError, KERNEL_SENDER};
+
+/// Condition variable for producer/consumer style coordination
+///
+/// Each notification is a token message queued on a private endpoint, one
+/// per counted waiter. A waiter is counted while it still holds the mutex,
+/// so a notify that lands between releasing the mutex and blocking leaves
+/// its token queued and the wake-up isn't lost. As with any condition
+/// variable, callers should re-check their predicate in a loop.
+pub struct CondVar {
+    /// Endpoint waiters block on
+    endpoint: CapabilityId,
+    /// Holds the read capability used to receive tokens
+    cspace: CSpace,
+    cap: CapabilityId,
+    /// Waiters that haven't been sent a token yet
+    waiters: AtomicUsize,
+}
+
+impl CondVar {
+    /// Create a condition variable backed by a new endpoint `endpoint_id`
+    pub fn new(endpoint_id: CapabilityId) -> Result<Self, IpcError> {
+        let endpoint = ipc::create_endpoint(endpoint_id)?;
+        let cap = CapabilityId::new(1);
+        let mut cspace = CSpace::new();
+        cspace.insert(Capability::new(cap, ResourceType::Endpoint, endpoint.value(), Rights::READ));
+
+        Ok(CondVar {
+            endpoint,
+            cspace,
+            cap,
+            waiters: AtomicUsize::new(0),
+        })
+    }
+
+    /// Release `guard`, block until notified, then re-acquire `mutex`
+    ///
+    /// # Assumptions
+    /// - CONTEXT: Called from a task (blocking needs a current task)
+    /// - `guard` was obtained from `mutex`
+    pub fn wait<'a, T>(&self, mutex: &'a Mutex<T>, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // Count ourselves before letting notifiers in
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        drop(guard);
+
+        let receiver = crate::scheduler::current_task_id().unwrap_or(KERNEL_SENDER);
+        if let Err(e) = ipc::receive_message_blocking(receiver, &self.cspace, self.cap) {
+            serial_println!("[SYNC] CondVar wait failed: {:?}", e);
+        }
+
+        mutex.lock()
+    }
+
+    /// Wake one waiting task (no-op if none is waiting)
+    pub fn notify_one(&self) {
+        let claimed = self.waiters
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if claimed {
+            self.send_tokens(1);
+        }
+    }
+
+    /// Wake every waiting task
+    pub fn notify_all(&self) {
+        let count = self.waiters.swap(0, Ordering::SeqCst);
+        self.send_tokens(count);
+    }
+
+    /// Queue `count` wake-up tokens
+    ///
+    /// Tokens the endpoint has no room for are handed back to the waiter
+    /// count, so the next notify still reaches those tasks.
+    fn send_tokens(&self, count: usize) {
+        for sent in 0..count {
+            if ipc::send_kernel_notification(self.endpoint, alloc::vec::Vec::new()).is_err() {
+                self.waiters.fetch_add(count - sent, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+
+    /// Number of tasks waiting that haven't been notified yet
+    pub fn waiter_count(&self) -> usize {
+        self.waiters.load(Ordering::SeqCst)
+    }
+}
+
+/// Most tasks a Barrier can coordinate (one fewer wake-up tokens than
+/// parties must fit in an endpoint's queue)
+pub const MAX_BARRIER_PARTIES: usize = 17;
+
+/// Arrival bookkeeping for a Barrier
+struct BarrierState {
+    arrived: usize,
+    generation: usize,
+}
+
+/// Rendezvous point for a fixed number of tasks
+///
+/// Each task calling wait blocks until `parties` tasks have arrived; the
+/// last one releases the rest by queueing a token per waiter. Consecutive
+/// generations alternate between two endpoints, so a task racing ahead
+/// into the next phase can't take a token meant for a task still leaving
+/// the previous one.
+pub struct Barrier {
+    parties: usize,
+    /// Endpoints waiters block on, indexed by generation parity
+    endpoints: [CapabilityId; 2],
+    /// Holds the read capabilities used to receive tokens
+    cspace: CSpace,
+    caps: [CapabilityId; 2],
+    state: Mutex<BarrierState>,
+}
+
+impl Barrier {
+    /// Create a barrier for `parties` tasks, backed by new endpoints
+    /// `endpoint_id` and `endpoint_id + 1`
+    ///
+    /// # Panics
+    /// If `parties` is 0 or above MAX_BARRIER_PARTIES.
+    pub fn new(parties: usize, endpoint_id: CapabilityId) -> Result<Self, IpcError> {
+        assert!(parties > 0 && parties <= MAX_BARRIER_PARTIES, "unsupported barrier size {}", parties);
+        let endpoints = [
+            ipc::create_endpoint(endpoint_id)?,
+            ipc::create_endpoint(CapabilityId::new(endpoint_id.value() + 1))?,
+        ];
+        let caps = [CapabilityId::new(1), CapabilityId::new(2)];
+        let mut cspace = CSpace::new();
+        for (cap, endpoint) in caps.iter().zip(endpoints) {
+            cspace.insert(Capability::new(*cap, ResourceType::Endpoint, endpoint.value(), Rights::READ));
+        }
+
+        Ok(Barrier {
+            parties,
+            endpoints,
+            cspace,
+            caps,
+            state: Mutex::new(BarrierState { arrived: 0, generation: 0 }),
+        })
+    }
+
+    /// Block until all parties have called wait
+    ///
+    /// Returns true in exactly one task per generation: the last to
+    /// arrive, which released the others.
+    ///
+    /// # Assumptions
+    /// - CONTEXT: Called from a task (blocking needs a current task)
+    pub fn wait(&self) -> bool {
+        let (generation, last) = {
+            let mut state = self.state.lock();
+            let generation = state.generation;
+            state.arrived += 1;
+            let last = state.arrived == self.parties;
+            if last {
+                state.arrived = 0;
+                state.generation = generation.wrapping_add(1);
+            }
+            (generation, last)
+        };
+        let slot = generation % 2;
+
+        if last {
+            // Can't fail for lack of room: at most parties - 1 tokens are
+            // queued on this endpoint at a time
+            for _ in 1..self.parties {
+                if let Err(e) = ipc::send_kernel_notification(self.endpoints[slot], alloc::vec::Vec::new()) {
+                    serial_println!("[SYNC] Barrier release failed: {:?}", e);
+                }
+            }
+            return true;
+        }
+
+        let receiver = crate::scheduler::current_task_id().unwrap_or(KERNEL_SENDER);
+        if let Err(e) = ipc::receive_message_blocking(receiver, &self.cspace, self.caps[slot]) {
+            serial_println!("[SYNC] Barrier wait failed: {:?}", e);
+        }
+        false
+    }
+
+    /// Number of tasks the barrier waits for
+    pub fn parties(&self) -> usize {
+        self.parties
+    }
+}
+
+/// Count and take the wake-up tokens queued for `cap`
+#[cfg(test)]
+fn take_tokens(cspace: &CSpace, cap: CapabilityId) -> usize {
+    core::iter::from_fn(|| ipc::try_receive_message(KERNEL_SENDER, cspace, cap).unwrap()).count()
+}
+
+/// Test that notify_one and notify_all send one token per counted waiter
+#[test_case]
+fn test_condvar_notify_counts_waiters() {
+    serial_print!("test_condvar_notify_counts_waiters...");
+    let cv = CondVar::new(CapabilityId::new(7012)).unwrap();
+
+    // Nobody waiting: nothing to wake, and no token left lying around
+    cv.notify_one();
+    cv.notify_all();
+    assert_eq!(cv.waiter_count(), 0);
+    assert_eq!(take_tokens(&cv.cspace, cv.cap), 0);
+
+    // Three waiters counted by wait (still holding the mutex, not blocked yet)
+    cv.waiters.fetch_add(3, Ordering::SeqCst);
+    cv.notify_one();
+    assert_eq!(cv.waiter_count(), 2);
+    assert_eq!(take_tokens(&cv.cspace, cv.cap), 1);
+    cv.notify_all();
+    assert_eq!(cv.waiter_count(), 0);
+    assert_eq!(take_tokens(&cv.cspace, cv.cap), 2);
+
+    // A token queued before the waiter blocks is still there for it
+    cv.waiters.fetch_add(1, Ordering::SeqCst);
+    cv.notify_one();
+    let mutex = Mutex::new(());
+    drop(cv.wait(&mutex, mutex.lock()));
+    assert_eq!(take_tokens(&cv.cspace, cv.cap), 0);
+    serial_println!("[ok]");
+}
+
+/// Test a bounded buffer shared by two producers and two consumers
+#[test_case]
+fn test_condvar_bounded_buffer() {
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+    use crate::scheduler;
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+    use lazy_static::lazy_static;
+
+    const CAPACITY: usize = 4;
+    const PER_PRODUCER: u32 = 20;
+    const PRODUCERS: u32 = 2;
+
+    lazy_static! {
+        static ref NOT_EMPTY: CondVar = CondVar::new(CapabilityId::new(7010)).unwrap();
+        static ref NOT_FULL: CondVar = CondVar::new(CapabilityId::new(7011)).unwrap();
+    }
+    static BUFFER: Mutex<VecDeque<u32>> = Mutex::new(VecDeque::new());
+    static CONSUMED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    static NEXT_PRODUCER: AtomicUsize = AtomicUsize::new(0);
+
+    fn producer_main() -> ! {
+        let base = NEXT_PRODUCER.fetch_add(1, Ordering::SeqCst) as u32 * 1000;
+        for i in 0..PER_PRODUCER {
+            let mut buffer = BUFFER.lock();
+            while buffer.len() == CAPACITY {
+                buffer = NOT_FULL.wait(&BUFFER, buffer);
+            }
+            buffer.push_back(base + i);
+            drop(buffer);
+            NOT_EMPTY.notify_one();
+        }
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    fn consumer_main() -> ! {
+        loop {
+            let mut buffer = BUFFER.lock();
+            while buffer.is_empty() {
+                buffer = NOT_EMPTY.wait(&BUFFER, buffer);
+            }
+            let item = buffer.pop_front().unwrap();
+            drop(buffer);
+            NOT_FULL.notify_one();
+            CONSUMED.lock().push(item);
+        }
+    }
+
+    serial_print!("test_condvar_bounded_buffer...");
+    let _tasks = [
+        TestTask::spawn("cv-producer", producer_main),
+        TestTask::spawn("cv-producer", producer_main),
+        TestTask::spawn("cv-consumer", consumer_main),
+        TestTask::spawn("cv-consumer", consumer_main),
+    ];
+
+    let total = (PER_PRODUCER * PRODUCERS) as usize;
+    assert!(yield_until(500, || CONSUMED.lock().len() == total));
+
+    // Every item consumed exactly once
+    let mut consumed = CONSUMED.lock().clone();
+    consumed.sort_unstable();
+    let expected: Vec<u32> = (0..PRODUCERS)
+        .flat_map(|p| (0..PER_PRODUCER).map(move |i| p * 1000 + i))
+        .collect();
+    assert_eq!(consumed, expected);
+    serial_println!("[ok]");
+}
+
+/// Test that the last arrival releases the others on alternating endpoints
+#[test_case]
+fn test_barrier_counts_arrivals() {
+    serial_print!("test_barrier_counts_arrivals...");
+    let barrier = Barrier::new(3, CapabilityId::new(7052)).unwrap();
+    let tokens = |slot: usize| take_tokens(&barrier.cspace, barrier.caps[slot]);
+
+    // Two parties already waiting: the test's own wait is the last one
+    for generation in 0..4 {
+        barrier.state.lock().arrived = 2;
+        assert!(barrier.wait());
+        let state = barrier.state.lock();
+        assert_eq!((state.arrived, state.generation), (0, generation + 1));
+        drop(state);
+        // One token per waiter, on the endpoint of this generation only
+        assert_eq!(tokens(generation % 2), 2);
+        assert_eq!(tokens(1 - generation % 2), 0);
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that no task passes a barrier before all three have arrived
+#[test_case]
+fn test_barrier_releases_together() {
+    use alloc::vec::Vec;
+    use crate::scheduler;
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+    use lazy_static::lazy_static;
+
+    const PARTIES: usize = 3;
+    const PHASES: usize = 2;
+
+    lazy_static! {
+        static ref BARRIER: Barrier = Barrier::new(PARTIES, CapabilityId::new(7050)).unwrap();
+    }
+    static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+    static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+    /// Arrivals each task saw on leaving the barrier
+    static SEEN: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    static LEADERS: AtomicUsize = AtomicUsize::new(0);
+
+    fn worker_main() -> ! {
+        let worker = NEXT_WORKER.fetch_add(1, Ordering::SeqCst);
+        for _ in 0..PHASES {
+            // Uneven work so tasks reach the barrier at different times
+            for _ in 0..worker * 3 {
+                scheduler::task_yield();
+            }
+            ARRIVED.fetch_add(1, Ordering::SeqCst);
+            if BARRIER.wait() {
+                LEADERS.fetch_add(1, Ordering::SeqCst);
+            }
+            SEEN.lock().push(ARRIVED.load(Ordering::SeqCst));
+        }
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    serial_print!("test_barrier_releases_together...");
+    let _workers: [TestTask; PARTIES] = core::array::from_fn(|_| TestTask::spawn("barrier-worker", worker_main));
+
+    yield_until(500, || {
+        // Nobody leaves the first phase before everyone has arrived
+        if ARRIVED.load(Ordering::SeqCst) < PARTIES {
+            assert!(SEEN.lock().is_empty());
+        }
+        SEEN.lock().len() == PARTIES * PHASES
+    });
+
+    let seen = SEEN.lock().clone();
+    assert_eq!(seen.len(), PARTIES * PHASES);
+    // Each phase's tasks left only after all of that phase had arrived
+    assert!(seen[..PARTIES].iter().all(|&n| n >= PARTIES));
+    assert!(seen[PARTIES..].iter().all(|&n| n == PARTIES * PHASES));
+    assert_eq!(LEADERS.load(Ordering::SeqCst), PHASES);
+    serial_println!("[ok]");
+}
@@ -3,7 +3,8 @@
 //! Provides the interface between user code and kernel services
 //! All operations on capabilities go through syscalls
 
-use crate::capability::{CapabilityId, Rights, CSpace};
+use alloc::vec::Vec;
+use crate::capability::{Capability, CapabilityId, Rights, CSpace, ResourceType};
 
 /// Syscall numbers
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,6 +18,10 @@ pub enum SyscallNumber {
     CapRevoke = 2,
     /// Invoke a capability (use the resource it points to)
     CapInvoke = 3,
+    /// Spawn a task from a registered entry point
+    TaskSpawn = 4,
+    /// Read the timer tick count
+    GetTicks = 5,
     /// Print to serial (for testing)
     Print = 100,
 }
@@ -29,6 +34,8 @@ impl SyscallNumber {
             1 => Some(SyscallNumber::CapDerive),
             2 => Some(SyscallNumber::CapRevoke),
             3 => Some(SyscallNumber::CapInvoke),
+            4 => Some(SyscallNumber::TaskSpawn),
+            5 => Some(SyscallNumber::GetTicks),
             100 => Some(SyscallNumber::Print),
             _ => None,
         }
@@ -42,6 +49,32 @@ pub enum SyscallResult {
     Error(SyscallError),
 }
 
+impl SyscallResult {
+    /// Convert into a `Result` so callers can use `?` and combinators
+    pub fn ok(self) -> Result<u64, SyscallError> {
+        match self {
+            SyscallResult::Success(value) => Ok(value),
+            SyscallResult::Error(e) => Err(e),
+        }
+    }
+
+    /// Check whether the syscall succeeded
+    pub fn is_ok(&self) -> bool {
+        matches!(self, SyscallResult::Success(_))
+    }
+
+    /// Return the success value, or `default` on error
+    pub fn unwrap_or(self, default: u64) -> u64 {
+        self.ok().unwrap_or(default)
+    }
+}
+
+impl From<SyscallResult> for Result<u64, SyscallError> {
+    fn from(result: SyscallResult) -> Self {
+        result.ok()
+    }
+}
+
 /// Syscall errors
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SyscallError {
@@ -49,12 +82,67 @@ pub enum SyscallError {
     InvalidCapability,
     PermissionDenied,
     InvalidArgument,
+    OutOfMemory,
 }
 
+impl SyscallError {
+    /// All variants, in errno order
+    pub const ALL: [SyscallError; 5] = [
+        SyscallError::PermissionDenied,
+        SyscallError::InvalidArgument,
+        SyscallError::InvalidCapability,
+        SyscallError::OutOfMemory,
+        SyscallError::InvalidSyscall,
+    ];
+
+    /// Stable negative code for returning this error in a register
+    ///
+    /// Shares numbers with the Wasm host function errnos where the meaning
+    /// overlaps (-1 EACCES, -6 EINVAL, -7 ENOENT, -8 ENOMEM). Never
+    /// renumber: callers compare against these values. There's no trap
+    /// handler yet; the Wasm `syscall` shim returns these to guests.
+    pub fn as_errno(&self) -> i64 {
+        match self {
+            SyscallError::PermissionDenied => -1,
+            SyscallError::InvalidArgument => -6,
+            SyscallError::InvalidCapability => -7,
+            SyscallError::OutOfMemory => -8,
+            SyscallError::InvalidSyscall => -9, // ENOSYS
+        }
+    }
+
+    /// Decode an as_errno value (None for anything else)
+    pub fn from_errno(errno: i64) -> Option<Self> {
+        match errno {
+            -1 => Some(SyscallError::PermissionDenied),
+            -6 => Some(SyscallError::InvalidArgument),
+            -7 => Some(SyscallError::InvalidCapability),
+            -8 => Some(SyscallError::OutOfMemory),
+            -9 => Some(SyscallError::InvalidSyscall),
+            _ => None,
+        }
+    }
+}
+
+/// One capability as seen through the syscall interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapEntry {
+    pub id: u64,
+    pub resource_type: ResourceType,
+    pub resource_id: u64,
+    /// Rights in the encode_rights format
+    pub rights_bits: u64,
+}
+
+/// Contents of a CSpace in id order (see SyscallContext::snapshot)
+pub type CSpaceSnapshot = Vec<CapEntry>;
+
 /// Syscall context - simulates user process state
 pub struct SyscallContext {
     /// The CSpace of the calling "process"
     pub cspace: CSpace,
+    /// Slow down a caller flagged for capability probing (see resolve)
+    pub throttle_probing: bool,
 }
 
 impl SyscallContext {
@@ -62,6 +150,7 @@ impl SyscallContext {
     pub fn new() -> Self {
         SyscallContext {
             cspace: CSpace::new(),
+            throttle_probing: false,
         }
     }
 
@@ -84,10 +173,26 @@ impl SyscallContext {
             SyscallNumber::CapDerive => self.sys_cap_derive(arg1, arg2),
             SyscallNumber::CapRevoke => self.sys_cap_revoke(arg1),
             SyscallNumber::CapInvoke => self.sys_cap_invoke(arg1, arg2, arg3, arg4),
+            SyscallNumber::TaskSpawn => self.sys_task_spawn(arg1, arg2, arg3),
+            SyscallNumber::GetTicks => self.sys_get_ticks(),
             SyscallNumber::Print => self.sys_print(arg1),
         }
     }
 
+    /// Look up a capability ID passed in by the caller
+    ///
+    /// Misses count toward probing detection (see CSpace::lookup). With
+    /// throttle_probing set, a flagged caller also gives up the CPU on
+    /// every further miss, so guessing IDs gets slow.
+    fn resolve(&mut self, cap_id: u64) -> Option<Capability> {
+        let found = self.cspace.lookup(CapabilityId::new(cap_id)).cloned();
+        if found.is_none() && self.throttle_probing && self.cspace.is_probing() {
+            #[cfg(target_arch = "x86_64")]
+            crate::scheduler::task_yield();
+        }
+        found
+    }
+
     /// Create a new capability
     ///
     /// # Security
@@ -111,6 +216,9 @@ impl SyscallContext {
     /// arg2: new rights (encoded as bitflags)
     fn sys_cap_derive(&mut self, source_id: u64, rights_bits: u64) -> SyscallResult {
         let source_cap_id = CapabilityId::new(source_id);
+        if self.resolve(source_id).is_none() {
+            return SyscallResult::Error(SyscallError::PermissionDenied);
+        }
 
         let new_rights = Rights {
             read: (rights_bits & 0x1) != 0,
@@ -128,10 +236,19 @@ impl SyscallContext {
     /// Revoke a capability
     /// arg1: capability ID
     fn sys_cap_revoke(&mut self, cap_id: u64) -> SyscallResult {
-        let cap_id = CapabilityId::new(cap_id);
+        if self.resolve(cap_id).is_none() {
+            return SyscallResult::Error(SyscallError::InvalidCapability);
+        }
 
-        match self.cspace.revoke(cap_id) {
-            Some(_) => SyscallResult::Success(0),
+        match self.cspace.revoke(CapabilityId::new(cap_id)) {
+            Some(root) => {
+                // Copies handed to tasks go with it
+                #[cfg(target_arch = "x86_64")]
+                crate::scheduler::revoke_derived(&root);
+                #[cfg(not(target_arch = "x86_64"))]
+                let _ = root;
+                SyscallResult::Success(0)
+            }
             None => SyscallResult::Error(SyscallError::InvalidCapability),
         }
     }
@@ -140,9 +257,10 @@ impl SyscallContext {
     /// arg1: capability ID
     /// arg2-4: operation-specific arguments
     fn sys_cap_invoke(&mut self, cap_id: u64, _arg2: u64, _arg3: u64, _arg4: u64) -> SyscallResult {
-        let cap_id = CapabilityId::new(cap_id);
+        // Drop lapsed leases so an expired capability reads as missing
+        self.cspace.sweep_expired();
 
-        match self.cspace.get(cap_id) {
+        match self.resolve(cap_id) {
             Some(cap) => {
                 // In a real implementation, this would perform the actual operation
                 // For now, just verify the capability exists and has rights
@@ -154,6 +272,77 @@ impl SyscallContext {
         }
     }
 
+    /// Spawn a new task
+    /// arg1: Thread capability ID (needs EXECUTE rights)
+    /// arg2: index into the kernel task entry table
+    /// arg3: priority (0 = Low .. 3 = Realtime)
+    ///
+    /// # Security
+    /// Entry points are resolved from the kernel-registered table, so
+    /// callers can never make a task start at an arbitrary address. The
+    /// new task gets the caller's privilege, so a user task can't spawn
+    /// a kernel one; with no current task the caller is the kernel.
+    #[cfg(target_arch = "x86_64")]
+    fn sys_task_spawn(&mut self, cap_id: u64, entry_index: u64, priority: u64) -> SyscallResult {
+        use crate::task::{Priority, Privilege, SpawnError, Task};
+
+        let cap = match self.resolve(cap_id) {
+            Some(cap) => cap,
+            None => return SyscallResult::Error(SyscallError::InvalidCapability),
+        };
+
+        if cap.resource_type() != ResourceType::Thread || !cap.rights().execute {
+            return SyscallResult::Error(SyscallError::PermissionDenied);
+        }
+
+        let priority = match Priority::from_u64(priority) {
+            Some(p) => p,
+            None => return SyscallResult::Error(SyscallError::InvalidArgument),
+        };
+
+        let privilege = x86_64::instructions::interrupts::without_interrupts(|| {
+            let guard = crate::scheduler::SCHEDULER.lock();
+            let scheduler = guard.as_ref()?;
+            Some(scheduler.get_task(scheduler.current_task()?)?.privilege())
+        }).unwrap_or(Privilege::Kernel);
+
+        let new_task = match Task::new_by_index("spawned", entry_index as usize, priority, privilege) {
+            Ok(t) => t,
+            Err(SpawnError::InvalidEntry) => return SyscallResult::Error(SyscallError::InvalidArgument),
+            Err(SpawnError::OutOfMemory) => return SyscallResult::Error(SyscallError::OutOfMemory),
+        };
+
+        // Don't let the timer preempt us while holding the scheduler lock
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            match crate::scheduler::SCHEDULER.lock().as_mut() {
+                Some(sched) => match sched.add_task(new_task) {
+                    Ok(id) => SyscallResult::Success(id.value()),
+                    // Out of task slots is resource exhaustion to the caller
+                    Err(crate::scheduler::SchedError::TooManyTasks) => SyscallResult::Error(SyscallError::OutOfMemory),
+                },
+                None => SyscallResult::Error(SyscallError::InvalidSyscall),
+            }
+        })
+    }
+
+    /// Task spawning is not wired into the ARM64 scheduler yet
+    #[cfg(not(target_arch = "x86_64"))]
+    fn sys_task_spawn(&mut self, _cap_id: u64, _entry_index: u64, _priority: u64) -> SyscallResult {
+        SyscallResult::Error(SyscallError::InvalidSyscall)
+    }
+
+    /// Timer ticks since boot
+    #[cfg(target_arch = "x86_64")]
+    fn sys_get_ticks(&mut self) -> SyscallResult {
+        SyscallResult::Success(crate::interrupts::timer_ticks())
+    }
+
+    /// Timer ticks since boot
+    #[cfg(not(target_arch = "x86_64"))]
+    fn sys_get_ticks(&mut self) -> SyscallResult {
+        SyscallResult::Success(crate::arch::exceptions::get_timer_ticks())
+    }
+
     /// Print syscall (for testing)
     /// arg1: value to print
     fn sys_print(&mut self, value: u64) -> SyscallResult {
@@ -165,6 +354,18 @@ impl SyscallContext {
     pub fn capability_count(&self) -> usize {
         self.cspace.len()
     }
+
+    /// Capabilities currently held, for asserting on or listing
+    pub fn snapshot(&self) -> CSpaceSnapshot {
+        self.cspace.iter()
+            .map(|cap| CapEntry {
+                id: cap.id().value(),
+                resource_type: cap.resource_type(),
+                resource_id: cap.resource_id(),
+                rights_bits: encode_rights(cap.rights()),
+            })
+            .collect()
+    }
 }
 
 impl Default for SyscallContext {
@@ -182,3 +383,193 @@ pub fn encode_rights(rights: Rights) -> u64 {
     if rights.grant { bits |= 0x8; }
     bits
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SPAWNED_RAN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+    fn spawned_main() -> ! {
+        SPAWNED_RAN.store(true, core::sync::atomic::Ordering::SeqCst);
+        loop {
+            crate::scheduler::task_yield();
+        }
+    }
+
+    #[test_case]
+    fn test_task_spawn_syscall() {
+        use crate::scheduler::SCHEDULER;
+        use crate::task::{Privilege, TaskId};
+        use crate::wasm_runtime::tests::yield_until;
+        use core::sync::atomic::Ordering;
+
+        serial_print!("test_task_spawn_syscall...");
+        let mut ctx = SyscallContext::new();
+        ctx.cspace.insert(Capability::new(
+            CapabilityId::new(1),
+            ResourceType::Thread,
+            0,
+            Rights { read: false, write: false, execute: true, grant: false },
+        ));
+        let entry = crate::task::register_entry(spawned_main);
+
+        SPAWNED_RAN.store(false, Ordering::SeqCst);
+        let before = SCHEDULER.lock().as_ref().unwrap().task_count();
+        let id = TaskId::new(ctx.syscall(4, 1, entry as u64, 1, 0).ok().expect("spawn failed"));
+        assert_eq!(SCHEDULER.lock().as_ref().unwrap().task_count(), before + 1);
+        // Spawned from the (kernel) test runner, so it runs in ring 0 too
+        let privilege = SCHEDULER.lock().as_ref().unwrap().get_task(id).map(|t| t.privilege());
+        assert_eq!(privilege, Some(Privilege::Kernel));
+
+        // The registered entry point is what actually runs
+        assert!(yield_until(100, || SPAWNED_RAN.load(Ordering::SeqCst)));
+
+        // Without a Thread capability the spawn must be refused
+        assert_eq!(
+            ctx.syscall(4, 99, entry as u64, 1, 0),
+            SyscallResult::Error(SyscallError::InvalidCapability)
+        );
+
+        let spawned = x86_64::instructions::interrupts::without_interrupts(|| {
+            SCHEDULER.lock().as_mut().unwrap().remove_task(id)
+        });
+        assert!(spawned.is_some());
+        serial_println!("[ok]");
+    }
+
+    /// Test that revoking through the syscall also takes tasks' copies
+    #[test_case]
+    fn test_cap_revoke_reaches_task_copies() {
+        use crate::task::{Priority, Privilege, Task};
+        use crate::wasm_runtime::tests::TestTask;
+
+        fn holder_main() -> ! {
+            loop {
+                crate::scheduler::task_yield();
+            }
+        }
+
+        serial_print!("test_cap_revoke_reaches_task_copies...");
+        let mut ctx = SyscallContext::new();
+        let root = ctx.cspace.create(ResourceType::Memory, 0x7000, Rights::READ_WRITE);
+        let mut holder = Task::try_new("revoke-holder", holder_main, Priority::Low, Privilege::Kernel).expect("out of memory creating task");
+        let copy = holder.cspace_mut().insert(ctx.cspace.get(root).unwrap().derive(CapabilityId::new(5), Rights::READ).unwrap());
+        let unrelated = holder.cspace_mut().create(ResourceType::Memory, 0x8000, Rights::READ);
+        let holder = TestTask::start(holder);
+
+        assert!(ctx.syscall(SyscallNumber::CapRevoke as u64, root.value(), 0, 0, 0).is_ok());
+        let holder = holder.remove();
+        assert!(holder.cspace().get(copy).is_none());
+        assert!(holder.cspace().get(unrelated).is_some());
+        serial_println!("[ok]");
+    }
+
+    /// Test that GetTicks reads a clock that never goes backwards
+    #[test_case]
+    fn test_get_ticks_syscall() {
+        serial_print!("test_get_ticks_syscall...");
+        let mut ctx = SyscallContext::new();
+        let first = ctx.syscall(SyscallNumber::GetTicks as u64, 0, 0, 0, 0).ok().expect("GetTicks failed");
+
+        // Let a few ticks pass when the PIT is running
+        if x86_64::instructions::interrupts::are_enabled() && first > 0 {
+            while crate::interrupts::timer_ticks() < first + 3 {
+                x86_64::instructions::hlt();
+            }
+        }
+
+        let second = ctx.syscall(SyscallNumber::GetTicks as u64, 0, 0, 0, 0).ok().expect("GetTicks failed");
+        assert!(second >= first);
+        serial_println!("[ok]");
+    }
+
+    /// Test that the snapshot lists exactly the created and derived caps
+    #[test_case]
+    fn test_cspace_snapshot() {
+        serial_print!("test_cspace_snapshot...");
+        let mut ctx = SyscallContext::new();
+        // User space can't create capabilities, so the kernel hands one out
+        let source = ctx.cspace.create(ResourceType::Memory, 0x1000, Rights::ALL);
+        let derived = ctx.syscall(SyscallNumber::CapDerive as u64, source.value(), encode_rights(Rights::READ), 0, 0)
+            .ok()
+            .expect("derive failed");
+
+        assert_eq!(ctx.snapshot(), [
+            CapEntry { id: source.value(), resource_type: ResourceType::Memory, resource_id: 0x1000, rights_bits: 0xf },
+            CapEntry { id: derived, resource_type: ResourceType::Memory, resource_id: 0x1000, rights_bits: 0x1 },
+        ]);
+        serial_println!("[ok]");
+    }
+
+    /// Test the Result conversions for both variants
+    #[test_case]
+    fn test_syscall_result_conversions() {
+        serial_print!("test_syscall_result_conversions...");
+        let success = SyscallResult::Success(7);
+        let failure = SyscallResult::Error(SyscallError::PermissionDenied);
+
+        assert_eq!(success.ok(), Ok(7));
+        assert_eq!(failure.ok(), Err(SyscallError::PermissionDenied));
+        assert!(success.is_ok());
+        assert!(!failure.is_ok());
+        assert_eq!(success.unwrap_or(0), 7);
+        assert_eq!(failure.unwrap_or(0), 0);
+
+        let converted: Result<u64, SyscallError> = failure.into();
+        assert_eq!(converted, Err(SyscallError::PermissionDenied));
+        assert_eq!(Result::from(success), Ok(7));
+        serial_println!("[ok]");
+    }
+
+    /// Test that every error has a distinct, stable errno that decodes back
+    #[test_case]
+    fn test_syscall_errno_round_trip() {
+        serial_print!("test_syscall_errno_round_trip...");
+        for (i, err) in SyscallError::ALL.iter().enumerate() {
+            assert!(err.as_errno() < 0);
+            assert_eq!(SyscallError::from_errno(err.as_errno()), Some(*err));
+            assert!(SyscallError::ALL[i + 1..].iter().all(|other| other.as_errno() != err.as_errno()));
+        }
+
+        // Pinned: these numbers are the syscall ABI
+        let codes: Vec<i64> = SyscallError::ALL.iter().map(|err| err.as_errno()).collect();
+        assert_eq!(codes, [-1, -6, -7, -8, -9]);
+        for unknown in [0, 1, -2, -5, -10, i64::MIN] {
+            assert_eq!(SyscallError::from_errno(unknown), None);
+        }
+        serial_println!("[ok]");
+    }
+
+    /// Test that repeated invokes of made-up ids trip the probing warning
+    #[test_case]
+    fn test_capability_probing_detected() {
+        use crate::capability::PROBE_THRESHOLD;
+
+        serial_print!("test_capability_probing_detected...");
+        let mut ctx = SyscallContext::new();
+        let real = ctx.cspace.create(ResourceType::Memory, 0x1000, Rights::READ);
+
+        for guess in 1..PROBE_THRESHOLD as u64 {
+            let result = ctx.syscall(SyscallNumber::CapInvoke as u64, 1000 + guess, 0, 0, 0);
+            assert_eq!(result, SyscallResult::Error(SyscallError::InvalidCapability));
+        }
+        // Valid lookups don't count
+        assert!(ctx.syscall(SyscallNumber::CapInvoke as u64, real.value(), 0, 0, 0).is_ok());
+        assert!(!ctx.cspace.is_probing());
+
+        // The threshold-th miss flags the CSpace and logs once
+        ctx.throttle_probing = true;
+        ctx.syscall(SyscallNumber::CapInvoke as u64, 0xDEAD, 0, 0, 0);
+        assert!(ctx.cspace.is_probing());
+        let warnings = crate::klog::tail(4).into_iter()
+            .filter(|line| line.contains("[SECURITY] capability probing detected"))
+            .count();
+        assert_eq!(warnings, 1);
+
+        // Throttled misses still fail normally
+        ctx.syscall(SyscallNumber::CapInvoke as u64, 0xBEEF, 0, 0, 0);
+        assert_eq!(ctx.cspace.failed_lookups(), PROBE_THRESHOLD + 1);
+        serial_println!("[ok]");
+    }
+}
@@ -2,16 +2,18 @@
 //!
 //! Provides task/thread abstraction for multitasking
 
-use crate::capability::CSpace;
-use alloc::boxed::Box;
+use crate::capability::{CapabilityId, CSpace};
+use crate::stack::TaskStack;
+use alloc::string::String;
 use alloc::vec::Vec;
+use spin::Mutex;
 
 /// Unique task identifier
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(u64);
 
 impl TaskId {
-    pub fn new(id: u64) -> Self {
+    pub const fn new(id: u64) -> Self {
         TaskId(id)
     }
 
@@ -33,6 +35,28 @@ pub enum TaskState {
     Terminated,
 }
 
+/// What a blocked task is waiting for
+///
+/// There's no task join or semaphore to block on yet. sync::CondVar and
+/// sync::Barrier wait in an IPC receive, so they show up as Ipc with the
+/// primitive's endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// Receiving on an IPC endpoint (also CondVar/Barrier waits)
+    Ipc(CapabilityId),
+    /// Sleeping until the given timer tick
+    Sleep(u64),
+}
+
+impl core::fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BlockReason::Ipc(endpoint) => write!(f, "endpoint {}", endpoint.value()),
+            BlockReason::Sleep(deadline) => write!(f, "sleep until tick {}", deadline),
+        }
+    }
+}
+
 /// Task priority (for future priority scheduling)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Priority {
@@ -42,6 +66,28 @@ pub enum Priority {
     Realtime = 3,
 }
 
+impl Priority {
+    /// Convert from u64 (syscall argument)
+    pub fn from_u64(n: u64) -> Option<Self> {
+        match n {
+            0 => Some(Priority::Low),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::High),
+            3 => Some(Priority::Realtime),
+            _ => None,
+        }
+    }
+}
+
+/// Privilege level a task runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    /// Ring 0, shares the kernel's segments
+    Kernel,
+    /// Ring 3, entered through an iret with the user segments
+    User,
+}
+
 /// Saved CPU context for task switching
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -69,6 +115,11 @@ pub struct TaskContext {
     // CPU flags and instruction pointer
     pub rflags: u64,
     pub rip: u64,
+
+    // Segment selectors the task runs with (not touched by switch_context;
+    // user tasks load them through the iret in user_entry_trampoline)
+    pub cs: u64,
+    pub ss: u64,
 }
 
 impl TaskContext {
@@ -93,6 +144,8 @@ impl TaskContext {
             r15: 0,
             rflags: 0x200, // Interrupt enable flag
             rip: 0,
+            cs: 0,
+            ss: 0,
         }
     }
 }
@@ -103,8 +156,8 @@ impl Default for TaskContext {
     }
 }
 
-/// Task stack size (64 KB)
-const TASK_STACK_SIZE: usize = 64 * 1024;
+/// Number of task-local storage slots
+pub const TLS_SLOTS: usize = 4;
 
 /// A task (thread) in the system
 pub struct Task {
@@ -114,11 +167,20 @@ pub struct Task {
     /// Current execution state
     state: TaskState,
 
+    /// Why the task is Blocked (None in any other state)
+    block_reason: Option<BlockReason>,
+
     /// Saved CPU context
     context: TaskContext,
 
-    /// Task's stack
-    stack: Box<[u8; TASK_STACK_SIZE]>,
+    /// Task's stack (pages mapped on demand, see crate::stack)
+    stack: TaskStack,
+
+    /// Ring 0 stack for interrupts taken in ring 3 (user tasks only)
+    ///
+    /// Fully committed up front: the CPU pushes the interrupt frame here
+    /// before any handler runs, so it can't grow on demand.
+    kernel_stack: Option<TaskStack>,
 
     /// Capability Space (security context)
     cspace: CSpace,
@@ -126,40 +188,147 @@ pub struct Task {
     /// Task priority
     priority: Priority,
 
-    /// Task name (for debugging)
-    name: &'static str,
+    /// Task name (for debugging and task listings)
+    name: String,
+
+    /// Ring the task runs in
+    privilege: Privilege,
+
+    /// Preemption-disable nesting depth (0 = preemptible)
+    preempt_count: u32,
+
+    /// Task-local storage slots (error codes, handles, ...)
+    tls: [u64; TLS_SLOTS],
+
+    /// Scheduling group: the scheduler prefers running members back-to-back
+    group: Option<u32>,
+
+    /// Code passed to scheduler::exit (None if it ended any other way)
+    exit_code: Option<i32>,
+}
+
+/// Make the next stack allocation fail (tests only)
+#[cfg(test)]
+static FAIL_NEXT_STACK_ALLOC: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(false);
+
+/// Reserve a task stack without aborting when none is available
+///
+/// Only the top page is committed; the rest is mapped as the task
+/// touches it, and already filled with the sentinel for stack_high_water.
+fn try_alloc_stack() -> Option<TaskStack> {
+    #[cfg(test)]
+    if FAIL_NEXT_STACK_ALLOC.swap(false, core::sync::atomic::Ordering::Relaxed) {
+        return None;
+    }
+
+    TaskStack::try_new()
+}
+
+/// Why a task couldn't be created from the entry table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// No entry point registered at that index
+    InvalidEntry,
+    /// The task stack couldn't be allocated
+    OutOfMemory,
 }
 
 impl Task {
     /// Create a new task with given entry point
-    pub fn new(name: &'static str, entry_point: fn() -> !, priority: Priority) -> Self {
-        use crate::scheduler::task_entry_wrapper;
+    ///
+    /// Returns None if the stack can't be allocated, so callers can reject
+    /// the request instead of hitting the kernel's OOM handler.
+    ///
+    /// User tasks start in ring 3 through `user_entry_trampoline` and get
+    /// a second, fully committed stack for ring 0 (see kernel_stack_top).
+    /// Their code and stack must be mapped USER_ACCESSIBLE, which the memory
+    /// manager doesn't do yet - for now only spawn them from the fixed
+    /// entry table (see `register_entry`).
+    pub fn try_new(
+        name: impl Into<String>,
+        entry_point: fn() -> !,
+        priority: Priority,
+        privilege: Privilege,
+    ) -> Option<Self> {
+        use crate::scheduler::{task_entry_wrapper, user_entry_trampoline};
 
         let mut context = TaskContext::new();
 
-        // Allocate stack
-        let stack = Box::new([0u8; TASK_STACK_SIZE]);
+        let stack = try_alloc_stack()?;
+        let stack_top = stack.top();
+        let kernel_stack = match privilege {
+            Privilege::Kernel => None,
+            Privilege::User => {
+                let mut kernel_stack = try_alloc_stack()?;
+                if !kernel_stack.lock() {
+                    return None;
+                }
+                Some(kernel_stack)
+            }
+        };
 
         // Set up initial context
         // RIP points to wrapper, which expects entry point in RDI
-        context.rip = task_entry_wrapper as *const () as u64;
         context.rdi = entry_point as *const () as u64;  // Entry point in RDI for wrapper
-        context.rsp = stack.as_ptr() as u64 + TASK_STACK_SIZE as u64;
+        context.rsp = stack_top;
         context.rbp = context.rsp;
         context.rflags = 0x200; // Enable interrupts (IF flag)
 
+        match privilege {
+            Privilege::Kernel => {
+                let (code, data) = crate::gdt::kernel_selectors();
+                context.rip = task_entry_wrapper as *const () as u64;
+                context.cs = code.0 as u64;
+                context.ss = data.0 as u64;
+            }
+            Privilege::User => {
+                // Trampoline irets to RDI with RSI/RDX/RCX as rsp/cs/ss
+                let (code, data) = crate::gdt::user_selectors();
+                context.rip = user_entry_trampoline as *const () as u64;
+                context.cs = code.0 as u64;
+                context.ss = data.0 as u64;
+                context.rsi = stack_top;
+                context.rdx = context.cs;
+                context.rcx = context.ss;
+            }
+        }
+
         static NEXT_ID: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(1);
         let id = TaskId::new(NEXT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed));
 
-        Task {
+        Some(Task {
             id,
             state: TaskState::Ready,
+            block_reason: None,
             context,
             stack,
+            kernel_stack,
             cspace: CSpace::new(),
             priority,
-            name,
-        }
+            name: name.into(),
+            privilege,
+            preempt_count: 0,
+            tls: [0; TLS_SLOTS],
+            group: None,
+            exit_code: None,
+        })
+    }
+
+    /// Create a task whose entry point comes from the entry table
+    ///
+    /// The safe constructor for untrusted callers: `entry_index` is
+    /// resolved through `register_entry`'s table, so the task can only
+    /// start at a function the kernel registered. `try_new` still takes
+    /// a raw pointer for kernel-internal tasks.
+    pub fn new_by_index(
+        name: impl Into<String>,
+        entry_index: usize,
+        priority: Priority,
+        privilege: Privilege,
+    ) -> Result<Self, SpawnError> {
+        let entry_point = entry_by_index(entry_index).ok_or(SpawnError::InvalidEntry)?;
+        Self::try_new(name, entry_point, priority, privilege).ok_or(SpawnError::OutOfMemory)
     }
 
     /// Get task ID
@@ -173,8 +342,25 @@ impl Task {
     }
 
     /// Set task state
+    ///
+    /// Leaving Blocked clears the block reason; use `block` to enter it
+    /// with one.
     pub fn set_state(&mut self, state: TaskState) {
         self.state = state;
+        if state != TaskState::Blocked {
+            self.block_reason = None;
+        }
+    }
+
+    /// Mark the task Blocked on `reason`
+    pub fn block(&mut self, reason: BlockReason) {
+        self.state = TaskState::Blocked;
+        self.block_reason = Some(reason);
+    }
+
+    /// What the task is blocked on, if it is Blocked
+    pub fn block_reason(&self) -> Option<BlockReason> {
+        self.block_reason
     }
 
     /// Get mutable reference to context
@@ -192,9 +378,34 @@ impl Task {
         self.priority
     }
 
+    /// Change task priority (use Scheduler::set_priority for queued tasks)
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Get the ring this task runs in
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    /// Code the task exited with through scheduler::exit
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Record the code reported for this task once it terminates
+    pub fn set_exit_code(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
     /// Get task name
     pub fn name(&self) -> &str {
-        self.name
+        &self.name
+    }
+
+    /// Change the name shown in task listings
+    pub fn rename(&mut self, name: &str) {
+        self.name = String::from(name);
     }
 
     /// Get mutable capability space
@@ -206,6 +417,121 @@ impl Task {
     pub fn cspace(&self) -> &CSpace {
         &self.cspace
     }
+
+    /// Get the scheduling group (None = ungrouped)
+    pub fn group(&self) -> Option<u32> {
+        self.group
+    }
+
+    /// Tag the task with a scheduling group (a soft hint, see Scheduler::schedule)
+    pub fn set_group(&mut self, group: Option<u32>) {
+        self.group = group;
+    }
+
+    /// Get preemption-disable nesting depth
+    pub fn preempt_count(&self) -> u32 {
+        self.preempt_count
+    }
+
+    /// Set preemption-disable nesting depth
+    pub fn set_preempt_count(&mut self, count: u32) {
+        self.preempt_count = count;
+    }
+
+    /// Read a task-local storage slot (None if index out of range)
+    pub fn tls(&self, index: usize) -> Option<u64> {
+        self.tls.get(index).copied()
+    }
+
+    /// Write a task-local storage slot, returns false if index out of range
+    pub fn set_tls(&mut self, index: usize, value: u64) -> bool {
+        match self.tls.get_mut(index) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Peak stack usage in bytes (see TaskStack::high_water)
+    ///
+    /// Overflowing hits the guard page below the stack, so this never
+    /// exceeds the stack size.
+    pub fn stack_high_water(&self) -> usize {
+        self.stack.high_water()
+    }
+
+    /// Top of the ring 0 stack loaded into RSP0 while this task runs
+    ///
+    /// None for kernel tasks, which never change privilege level.
+    pub fn kernel_stack_top(&self) -> Option<u64> {
+        self.kernel_stack.as_ref().map(TaskStack::top)
+    }
+
+    /// Pre-fault the whole stack so the task never takes a stack page fault
+    ///
+    /// Meant for realtime tasks, before they first run. Safe to call from
+    /// a task with a lazily committed stack (see TaskStack::lock). Returns
+    /// false if the pages couldn't all be mapped.
+    pub fn lock_stack(&mut self) -> bool {
+        self.stack.lock()
+    }
+}
+
+/// Kernel-registered task entry points
+///
+/// Untrusted callers (syscalls) name an entry by its index in this table
+/// instead of passing a raw function pointer. Entries are never removed,
+/// so an index stays valid for the life of the kernel.
+pub struct TaskEntryTable {
+    entries: Vec<fn() -> !>,
+}
+
+impl TaskEntryTable {
+    /// Create an empty table
+    pub const fn new() -> Self {
+        TaskEntryTable { entries: Vec::new() }
+    }
+
+    /// Register an entry point, returning its index
+    pub fn register(&mut self, entry_point: fn() -> !) -> usize {
+        self.entries.push(entry_point);
+        self.entries.len() - 1
+    }
+
+    /// Look up an entry point (None if the index is out of range)
+    pub fn get(&self, index: usize) -> Option<fn() -> !> {
+        self.entries.get(index).copied()
+    }
+
+    /// Number of registered entry points
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no entry points are registered
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for TaskEntryTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static TASK_ENTRIES: Mutex<TaskEntryTable> = Mutex::new(TaskEntryTable::new());
+
+/// Register a task entry point, returning its index
+pub fn register_entry(entry_point: fn() -> !) -> usize {
+    TASK_ENTRIES.lock().register(entry_point)
+}
+
+/// Look up a registered entry point by index
+pub fn entry_by_index(index: usize) -> Option<fn() -> !> {
+    TASK_ENTRIES.lock().get(index)
 }
 
 /// Task list for scheduler
@@ -273,3 +599,211 @@ impl Default for TaskList {
         Self::new()
     }
 }
+
+/// Test that the stack high-water mark covers a task's deepest call
+#[test_case]
+fn test_stack_high_water() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::scheduler::{self, Scheduler};
+    use crate::wasm_runtime::tests::{yield_until, TestTask};
+
+    static DEEPEST: AtomicUsize = AtomicUsize::new(0);
+
+    /// Recurse `depth` frames of about 1KB each; returns the deepest frame's address
+    #[inline(never)]
+    fn descend(depth: usize) -> usize {
+        let frame = [0u8; 1024];
+        let here = core::hint::black_box(&frame).as_ptr() as usize;
+        if depth == 0 { here } else { core::hint::black_box(descend(depth - 1)) }
+    }
+
+    fn worker_main() -> ! {
+        DEEPEST.store(descend(12), Ordering::SeqCst);
+        loop {
+            scheduler::task_yield();
+        }
+    }
+
+    fn idle_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_stack_high_water...");
+    // A task that hasn't run has touched none of its stack
+    let mut idle = Scheduler::new();
+    let id = idle.add_task(Task::try_new("stack-idle", idle_main, Priority::Normal, Privilege::Kernel).unwrap()).unwrap();
+    assert_eq!(idle.stack_high_water(id), Some(0));
+
+    let task = Task::try_new("stack-test", worker_main, Priority::Normal, Privilege::Kernel).expect("out of memory creating task");
+    let top = task.stack.top() as usize;
+    let task = TestTask::start(task);
+
+    assert!(yield_until(200, || DEEPEST.load(Ordering::SeqCst) != 0));
+    let deepest = DEEPEST.load(Ordering::SeqCst);
+    assert!(deepest < top);
+    let used = top - deepest;
+    assert!(used >= 12 * 1024);
+
+    let high_water = task.with(|t| t.stack_high_water());
+    drop(task);
+    assert!(high_water >= used);
+    assert!(high_water < crate::stack::TASK_STACK_SIZE);
+    serial_println!("[ok]");
+}
+
+/// Test that locking a stack commits every page before the task runs
+#[test_case]
+fn test_lock_stack_commits_all_pages() {
+    use crate::stack::TASK_STACK_SIZE;
+
+    fn realtime_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_lock_stack_commits_all_pages...");
+    let mut task = Task::try_new("rt", realtime_main, Priority::Realtime, Privilege::Kernel).unwrap();
+    assert!(!task.stack.is_locked());
+    assert!(task.lock_stack());
+    assert!(task.stack.is_locked());
+
+    let pages = TASK_STACK_SIZE / 4096;
+    assert_eq!(task.stack.committed_pages(), pages);
+    // The sentinel scan reads every page; untouched, it reports no usage
+    assert_eq!(task.stack_high_water(), 0);
+    for depth in 1..=pages {
+        let addr = task.stack.top() - (depth * 4096) as u64;
+        let page = x86_64::structures::paging::Page::containing_address(x86_64::VirtAddr::new(addr));
+        assert!(crate::memory::is_mapped(page));
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that a failed stack allocation is reported instead of panicking
+#[test_case]
+fn test_try_new_stack_alloc_failure() {
+    use core::sync::atomic::Ordering;
+
+    fn idle_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_try_new_stack_alloc_failure...");
+    FAIL_NEXT_STACK_ALLOC.store(true, Ordering::Relaxed);
+    assert!(Task::try_new("oom", idle_main, Priority::Normal, Privilege::Kernel).is_none());
+
+    // Failure is one-shot; the next allocation succeeds
+    assert!(Task::try_new("ok", idle_main, Priority::Normal, Privilege::Kernel).is_some());
+    serial_println!("[ok]");
+}
+
+/// Test that user tasks get ring 3 selectors and the iret trampoline
+#[test_case]
+fn test_user_task_uses_user_selectors() {
+    use crate::scheduler::{task_entry_wrapper, user_entry_trampoline};
+
+    fn idle_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_user_task_uses_user_selectors...");
+    let (user_code, user_data) = crate::gdt::user_selectors();
+    let user = Task::try_new("user", idle_main, Priority::Normal, Privilege::User).unwrap();
+    assert_eq!(user.privilege(), Privilege::User);
+    assert_eq!(user.context().cs, user_code.0 as u64);
+    assert_eq!(user.context().ss, user_data.0 as u64);
+    assert_eq!(user.context().cs & 3, 3);  // RPL 3
+    assert_eq!(user.context().rip, user_entry_trampoline as *const () as u64);
+
+    // Each user task has its own ring 0 stack, separate from its user stack
+    let other = Task::try_new("user2", idle_main, Priority::Normal, Privilege::User).unwrap();
+    let kernel_stack = user.kernel_stack_top().expect("user task without kernel stack");
+    assert_ne!(kernel_stack, user.stack.top());
+    assert_ne!(Some(kernel_stack), other.kernel_stack_top());
+
+    let (kernel_code, _) = crate::gdt::kernel_selectors();
+    let kernel = Task::try_new("kernel", idle_main, Priority::Normal, Privilege::Kernel).unwrap();
+    assert_eq!(kernel.context().cs, kernel_code.0 as u64);
+    assert_eq!(kernel.context().rip, task_entry_wrapper as *const () as u64);
+    assert_eq!(kernel.kernel_stack_top(), None);
+    serial_println!("[ok]");
+}
+
+/// Test that tasks spawned by index start at the registered entry point
+#[test_case]
+fn test_new_by_index_validates_entry() {
+    fn first_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    fn second_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_new_by_index_validates_entry...");
+    let first = register_entry(first_main);
+    let second = register_entry(second_main);
+    assert_ne!(first, second);
+
+    let task = Task::new_by_index("first", first, Priority::Normal, Privilege::Kernel).unwrap();
+    assert_eq!(task.context().rdi, first_main as *const () as u64);
+    let task = Task::new_by_index("second", second, Priority::Normal, Privilege::Kernel).unwrap();
+    assert_eq!(task.context().rdi, second_main as *const () as u64);
+
+    let past_end = TASK_ENTRIES.lock().len();
+    assert_eq!(
+        Task::new_by_index("bad", past_end, Priority::Normal, Privilege::Kernel).err(),
+        Some(SpawnError::InvalidEntry)
+    );
+    serial_println!("[ok]");
+}
+
+/// Test that a runtime-built task name shows up in list_tasks, and rename updates it
+#[test_case]
+fn test_dynamic_task_name_listed() {
+    use alloc::format;
+    use crate::scheduler::{self, SCHEDULER};
+
+    fn idle_main() -> ! {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    serial_print!("test_dynamic_task_name_listed...");
+    let name = format!("worker-{}", 7);
+    let task = Task::try_new(name.clone(), idle_main, Priority::Low, Privilege::Kernel).unwrap();
+    let id = x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().map(|s| s.add_task(task))
+    }).unwrap().expect("task limit reached");
+    let name_of = |id: TaskId| scheduler::list_tasks().into_iter().find(|info| info.id == id).map(|info| info.name);
+    assert_eq!(name_of(id).as_deref(), Some("worker-7"));
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().unwrap();
+        scheduler.get_task_mut(id).unwrap().rename("worker-7-idle");
+    });
+    assert_eq!(name_of(id).as_deref(), Some("worker-7-idle"));
+
+    // Remove it again so it never gets scheduled
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().unwrap();
+        scheduler.get_task_mut(id).unwrap().set_state(TaskState::Terminated);
+        scheduler.reap_terminated();
+    });
+    assert_eq!(name_of(id), None);
+    serial_println!("[ok]");
+}
@@ -1,22 +1,32 @@
 // wasm runtime (wasmi interpreter)
 // runs wasm modules in sandboxed environment with capability checks
 
-use alloc::vec;
 use alloc::vec::Vec;
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, VecDeque};
 use wasmi::*;
-use crate::capability::{Capability, ResourceType};
+use crate::capability::{Capability, CapabilityId, CSpace, ResourceType, Rights};
 use ::core::str::from_utf8;
-use spin::Mutex;
-
-/// Global message queue for MQTT demo IPC
-/// Stores pending IPC messages to be delivered to subscribers
-static IPC_MESSAGE_QUEUE: Mutex<VecDeque<IpcMessage>> = Mutex::new(VecDeque::new());
+use ::core::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use crate::mpsc::MpscRing;
+use crate::syscall::SyscallError;
 
 // resource limits to prevent dos attacks
 pub const MAX_IPC_MESSAGE_SIZE: usize = 512;  // max message size
 pub const MAX_IPC_QUEUE_DEPTH: usize = 64;    // max queue depth
 
+/// Global message queue for MQTT demo IPC, send side
+/// Senders push here without taking a lock
+static IPC_INBOX: MpscRing<IpcMessage, MAX_IPC_QUEUE_DEPTH> = MpscRing::new();
+
+/// Global message queue for MQTT demo IPC, receive side
+/// Messages moved out of IPC_INBOX in arrival order; only receivers lock
+/// this, and holding it is what makes them the inbox's single consumer
+static IPC_MESSAGE_QUEUE: Mutex<VecDeque<IpcMessage>> = Mutex::new(VecDeque::new());
+
+/// Messages in IPC_INBOX plus IPC_MESSAGE_QUEUE (bounded by MAX_IPC_QUEUE_DEPTH)
+static IPC_QUEUED: AtomicUsize = AtomicUsize::new(0);
+
 /// IPC message for delivery
 #[derive(Clone)]
 pub struct IpcMessage {
@@ -24,27 +34,477 @@ pub struct IpcMessage {
     pub message: Vec<u8>,
 }
 
+/// Max bytes (keys + values) a single client may keep in the kv store
+pub const MAX_KV_BYTES_PER_CLIENT: usize = 1024;
+
+/// A client's (key, value) pairs
+type KvEntries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Per-client key/value store, survives module reloads
+static KV_STORES: Mutex<BTreeMap<u32, KvEntries>> = Mutex::new(BTreeMap::new());
+
+/// Longest service name accepted by sys_register_service
+pub const MAX_SERVICE_NAME: usize = 32;
+
+/// Most services that can be registered at once
+pub const MAX_SERVICES: usize = 32;
+
+/// A named endpoint published by a guest
+struct Service {
+    name: Vec<u8>,
+    endpoint: u32,
+    /// Client id of the registering module; only it may re-register
+    owner: u32,
+}
+
+/// Name → endpoint directory shared by all guests
+static SERVICES: Mutex<Vec<Service>> = Mutex::new(Vec::new());
+
+/// Number of kernel counters guests can share via sys_atomic_*
+pub const MAX_ATOMIC_COUNTERS: usize = 16;
+
+/// Kernel-owned counters shared by all guests, indexed by counter id
+static ATOMIC_COUNTERS: [AtomicI64; MAX_ATOMIC_COUNTERS] = [const { AtomicI64::new(0) }; MAX_ATOMIC_COUNTERS];
+
+/// xorshift64 state behind sys_random for unseeded guests (seeded by init)
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(RANDOM_ZERO_SEED);
+
+/// Most modules sys_load_module can have waiting for take_loaded_module
+pub const MAX_LOADED_MODULES: usize = 16;
+
+/// Modules loaded by guests, by handle, until the kernel takes them
+static LOADED_MODULES: Mutex<BTreeMap<u32, WasmModule>> = Mutex::new(BTreeMap::new());
+
+/// Next sys_load_module handle (handles are never reused)
+static NEXT_MODULE_HANDLE: AtomicU32 = AtomicU32::new(1);
+
+/// Highest supported MQTT QoS level (2 = exactly once)
+pub const MAX_MQTT_QOS: u8 = 2;
+
+/// An MQTT subscription and its delivery guarantee
+#[derive(Clone, Copy)]
+struct Subscription {
+    client_id: u32,
+    /// 0 = fire-and-forget, 1+ = failed deliveries are requeued
+    qos: u8,
+}
+
 /// Global subscriber registry for MQTT demo
 /// Tracks which client IDs are subscribers
-static MQTT_SUBSCRIBERS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+static MQTT_SUBSCRIBERS: Mutex<Vec<Subscription>> = Mutex::new(Vec::new());
+
+/// Get the QoS level a client subscribed with (0 if not subscribed)
+fn subscriber_qos(client_id: u32) -> u8 {
+    MQTT_SUBSCRIBERS.lock()
+        .iter()
+        .find(|sub| sub.client_id == client_id)
+        .map(|sub| sub.qos)
+        .unwrap_or(0)
+}
+
+/// Load-time size limits, checked before wasmi parses a module
+///
+/// Complements the fuel and memory limits, which only apply once a module
+/// runs: an oversized module is turned away before validation can spend
+/// time and heap on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModuleLimits {
+    /// Largest accepted module, in bytes
+    pub max_bytes: usize,
+    /// Most functions the module may define (imports not included)
+    pub max_functions: usize,
+    /// Most imports the module may declare
+    pub max_imports: usize,
+    /// Most exports the module may declare
+    pub max_exports: usize,
+}
+
+impl ModuleLimits {
+    /// Limits in force until set_module_limits is called
+    pub const DEFAULT: ModuleLimits = ModuleLimits {
+        max_bytes: 1024 * 1024,
+        max_functions: 1024,
+        max_imports: 256,
+        max_exports: 256,
+    };
+}
+
+static MODULE_LIMITS: Mutex<ModuleLimits> = Mutex::new(ModuleLimits::DEFAULT);
+
+/// Replace the limits applied to modules loaded from now on
+pub fn set_module_limits(limits: ModuleLimits) {
+    *MODULE_LIMITS.lock() = limits;
+}
+
+/// Limits currently applied by from_bytes
+pub fn module_limits() -> ModuleLimits {
+    *MODULE_LIMITS.lock()
+}
 
 /// Wasm module handle with cached instance for reuse
 pub struct WasmModule {
-    _module: Module,
+    module: Module,
     store: Store<WasmContext>,
     instance: Instance,
+    /// Fuel budget given at load time (None = not metered)
+    initial_fuel: Option<u64>,
+    /// Host functions linked into the instance (kept for reset)
+    exposed: HostFunctions,
+    /// What call_function does when the guest traps
+    trap_policy: TrapPolicy,
+}
+
+/// What happens to the hosting task when a guest traps in call_function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapPolicy {
+    /// Return the error and keep the task running
+    #[default]
+    Report,
+    /// End the hosting task with TRAP_EXIT_CODE (see set_trap_policy)
+    Terminate,
+}
+
+/// Exit code of a task ended by TrapPolicy::Terminate
+pub const TRAP_EXIT_CODE: i32 = 1;
+
+/// Smallest valid module: what a terminated task leaves in place of its guest
+#[cfg(target_arch = "x86_64")]
+const EMPTY_MODULE: &[u8] = &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+/// Which host functions a module's linker provides
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostFunctions {
+    /// Every host function (from_bytes)
+    All,
+    /// Only these `env` imports
+    Only(Vec<&'static str>),
+}
+
+impl HostFunctions {
+    /// Whether host function `name` is linked
+    pub fn allows(&self, name: &str) -> bool {
+        match self {
+            HostFunctions::All => true,
+            HostFunctions::Only(names) => names.contains(&name),
+        }
+    }
+}
+
+/// Loads a module with a chosen set of host functions
+///
+/// Starts with no host functions at all; each `expose` adds one. A module
+/// importing anything else fails to load with HostFunctionNotExposed, so
+/// a guest only gets the syscalls it was meant to have.
+pub struct WasmModuleBuilder<'a> {
+    wasm_bytes: &'a [u8],
+    fuel: Option<u64>,
+    exposed: Vec<&'static str>,
+}
+
+impl<'a> WasmModuleBuilder<'a> {
+    /// Start building a module from `wasm_bytes`
+    pub fn new(wasm_bytes: &'a [u8]) -> Self {
+        WasmModuleBuilder { wasm_bytes, fuel: None, exposed: Vec::new() }
+    }
+
+    /// Link host function `name` (e.g. "sys_print")
+    pub fn expose(mut self, name: &'static str) -> Self {
+        if !self.exposed.contains(&name) {
+            self.exposed.push(name);
+        }
+        self
+    }
+
+    /// Meter the module with an initial fuel budget (see from_bytes_with_fuel)
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Load and instantiate the module
+    pub fn build(self) -> Result<WasmModule, LoadError> {
+        WasmModule::instantiate(self.wasm_bytes, self.fuel, HostFunctions::Only(self.exposed))
+    }
+}
+
+/// Why a module failed to load
+#[derive(Debug)]
+pub enum LoadError {
+    /// Parsing, validation or instantiation failed in wasmi
+    Wasm(Error),
+    /// The module was linked but its start function trapped
+    StartTrapped(Error),
+    /// The module's memory can't be reached by our host functions
+    UnsupportedMemory(&'static str),
+    /// The module imports a host function it wasn't given (see WasmModuleBuilder)
+    HostFunctionNotExposed(alloc::string::String),
+    /// The module is bigger than ModuleLimits allows
+    LimitExceeded {
+        /// What was counted: "bytes", "functions", "imports" or "exports"
+        what: &'static str,
+        count: usize,
+        limit: usize,
+    },
+}
+
+impl From<Error> for LoadError {
+    fn from(error: Error) -> Self {
+        LoadError::Wasm(error)
+    }
+}
+
+/// Why the previous host call failed, read by guests via sys_last_error
+///
+/// Finer-grained than the negative return codes: e.g. QueueFull, Busy
+/// and RateLimited all return -5. Each variant's errno() is that return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum HostError {
+    /// The previous host call succeeded (or has nothing to add)
+    None = 0,
+    /// No capability for the resource (-1)
+    NoCap = 1,
+    /// Capability lacks WRITE rights (-2)
+    NoWrite = 2,
+    /// Bad guest pointer/length or no memory export (-3)
+    Fault = 3,
+    /// Message, buffer or table over its size limit (-4)
+    TooBig = 4,
+    /// IPC queue at MAX_IPC_QUEUE_DEPTH (-5)
+    QueueFull = 5,
+    /// Host call limit for this quantum reached (-5)
+    RateLimited = 6,
+    /// Kernel heap exhausted (-8)
+    NoMemory = 7,
+    /// A kernel lock was contended; retry (-5)
+    Busy = 8,
+    /// Argument out of range, or not supported here (-6)
+    Invalid = 9,
+    /// No such key, service or counter (-7)
+    NotFound = 10,
+}
+
+impl HostError {
+    /// Return value the failing host function gives the guest
+    pub fn errno(self) -> i32 {
+        match self {
+            HostError::None => 0,
+            HostError::NoCap => -1,
+            HostError::NoWrite => -2,
+            HostError::Fault => -3,
+            HostError::TooBig => -4,
+            HostError::QueueFull | HostError::RateLimited | HostError::Busy => -5,
+            HostError::Invalid => -6,
+            HostError::NotFound => -7,
+            HostError::NoMemory => -8,
+        }
+    }
+}
+
+/// What a host function sees of a guest's capability
+///
+/// A copy of the resource and rights, nothing else: no capability ID to
+/// pass on, and changing the returned Rights changes nothing in the
+/// context. Host functions should check rights through this rather than
+/// the full Capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilityView {
+    resource_type: ResourceType,
+    resource_id: u64,
+    rights: Rights,
+}
+
+impl CapabilityView {
+    /// Kind of resource the capability refers to
+    pub fn resource_type(&self) -> ResourceType {
+        self.resource_type
+    }
+
+    /// Which resource of that kind
+    pub fn resource_id(&self) -> u64 {
+        self.resource_id
+    }
+
+    /// Rights the guest holds on it
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+}
+
+impl From<&Capability> for CapabilityView {
+    fn from(cap: &Capability) -> Self {
+        CapabilityView {
+            resource_type: cap.resource_type(),
+            resource_id: cap.resource_id(),
+            rights: cap.rights(),
+        }
+    }
+}
+
+/// Host functions counted against the per-quantum limit
+///
+/// Indexes WasmContext's call counters. sys_last_error isn't here: it
+/// reports on the previous call rather than doing anything itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostCall {
+    Print,
+    SysPrint,
+    SysPrintU32,
+    Syscall,
+    SysMqttSubscribe,
+    SysMqttSubscribeQos,
+    SysMqttPublish,
+    SysMqttListSubs,
+    SysIpcSend,
+    SysIpcSendCap,
+    SysIpcRecv,
+    SysKvSet,
+    SysKvGet,
+    SysRegisterService,
+    SysLookupService,
+    SysRequestFuel,
+    SysFuelUsed,
+    SysSleepTicks,
+    SysUptimeMs,
+    SysHeapFree,
+    SysTaskCount,
+    SysAtomicCas,
+    SysAtomicLoad,
+    SysRandom,
+    SysSeed,
+    SysLoadModule,
+}
+
+impl HostCall {
+    const COUNT: usize = HostCall::SysLoadModule as usize + 1;
+}
+
+/// Import names of the counted host functions, indexed by HostCall
+const HOSTCALL_NAMES: [&str; HostCall::COUNT] = [
+    "print",
+    "sys_print",
+    "sys_print_u32",
+    "syscall",
+    "sys_mqtt_subscribe",
+    "sys_mqtt_subscribe_qos",
+    "sys_mqtt_publish",
+    "sys_mqtt_list_subs",
+    "sys_ipc_send",
+    "sys_ipc_send_cap",
+    "sys_ipc_recv",
+    "sys_kv_set",
+    "sys_kv_get",
+    "sys_register_service",
+    "sys_lookup_service",
+    "sys_request_fuel",
+    "sys_fuel_used",
+    "sys_sleep_ticks",
+    "sys_uptime_ms",
+    "sys_heap_free",
+    "sys_task_count",
+    "sys_atomic_cas",
+    "sys_atomic_load",
+    "sys_random",
+    "sys_seed",
+    "sys_load_module",
+];
+
+/// Host call quantum in progress: changes whenever the CPU switches task
+///
+/// A task that sees a new value has been switched out and back in since
+/// it last looked. ARM64 has no scheduler yet, so there the quantum only
+/// ends through begin_quantum.
+fn current_quantum() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    return crate::scheduler::stats().context_switches;
+
+    #[cfg(not(target_arch = "x86_64"))]
+    0
+}
+
+/// Charge a host call to the guest's quantum, returning `$refused` over the limit
+macro_rules! charge_hostcall {
+    ($caller:ident, $call:ident) => {
+        charge_hostcall!($caller, $call, ())
+    };
+    ($caller:ident, $call:ident, $refused:expr) => {
+        if !$caller.data_mut().charge_hostcall(HostCall::$call) {
+            return $refused; // rate limited until the next quantum
+        }
+    };
 }
 
 /// Wasm execution context with capability access
 pub struct WasmContext {
     /// Capabilities available to this Wasm module (full objects for verification)
     pub capabilities: Vec<Capability>,
+    /// IPC client ID this module receives messages as (None = not a client)
+    pub client_id: Option<u32>,
+    /// Total fuel given to this module (initial budget plus refills)
+    pub fuel_granted: u64,
+    /// Max calls per host function per quantum (None = unlimited)
+    pub hostcall_limit: Option<u32>,
+    /// Host calls made this quantum, indexed by HostCall
+    hostcalls: [u32; HostCall::COUNT],
+    /// Quantum the counters belong to (see current_quantum)
+    quantum: u64,
+    /// Host calls refused this quantum for exceeding the limit
+    hostcalls_rejected: u32,
+    /// Detail of the most recent host call's failure (None if it succeeded)
+    last_error: HostError,
+    /// Private sys_random state after sys_seed (None = draw from the shared one)
+    random_state: Option<u64>,
 }
 
 impl WasmContext {
     /// Create a new Wasm context with given capabilities
     pub fn new(capabilities: Vec<Capability>) -> Self {
-        WasmContext { capabilities }
+        WasmContext {
+            capabilities,
+            client_id: None,
+            fuel_granted: 0,
+            hostcall_limit: None,
+            hostcalls: [0; HostCall::COUNT],
+            quantum: current_quantum(),
+            hostcalls_rejected: 0,
+            last_error: HostError::None,
+            random_state: None,
+        }
+    }
+
+    /// Count a call to host function `call`
+    ///
+    /// Every host call starts here, so this also clears last_error: it
+    /// only ever describes the call in progress or the one before it.
+    /// Returns false once `call` has been made hostcall_limit times this
+    /// quantum; the host function then refuses the call.
+    fn charge_hostcall(&mut self, call: HostCall) -> bool {
+        self.last_error = HostError::None;
+        let quantum = current_quantum();
+        if quantum != self.quantum {
+            self.reset_hostcalls(quantum);
+        }
+        let count = &mut self.hostcalls[call as usize];
+        if self.hostcall_limit.is_some_and(|limit| *count >= limit) {
+            self.hostcalls_rejected += 1;
+            self.last_error = HostError::RateLimited;
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Zero the host call counters and start counting `quantum`
+    fn reset_hostcalls(&mut self, quantum: u64) {
+        self.hostcalls = [0; HostCall::COUNT];
+        self.hostcalls_rejected = 0;
+        self.quantum = quantum;
+    }
+
+    /// Record `error` as the last failure and return its errno
+    fn fail(&mut self, error: HostError) -> i32 {
+        self.last_error = error;
+        error.errno()
     }
 
     /// Find a capability by resource type and resource ID
@@ -56,19 +516,118 @@ impl WasmContext {
         })
     }
 
+    /// find_capability for host functions (see CapabilityView)
+    pub fn find_capability_view(&self, resource_type: ResourceType, resource_id: u64) -> Option<CapabilityView> {
+        self.find_capability(resource_type, resource_id).map(CapabilityView::from)
+    }
+
+    /// View of the capability at `index`, the handle guests pass in
+    pub fn capability_view(&self, index: usize) -> Option<CapabilityView> {
+        self.capabilities.get(index).map(CapabilityView::from)
+    }
+
+    /// Views of every capability, in grant order
+    pub fn capability_views(&self) -> impl Iterator<Item = CapabilityView> + '_ {
+        self.capabilities.iter().map(CapabilityView::from)
+    }
+
     /// Check if this context has any capabilities
     pub fn has_capabilities(&self) -> bool {
         !self.capabilities.is_empty()
     }
 }
 
+/// Attempts a host function makes on a contended lock before giving up
+const LOCK_RETRY_SPINS: usize = 64;
+
+/// Take a lock from a host function without blocking indefinitely
+///
+/// The queue and subscriber locks are also taken on the kernel side
+/// (delivery, timer-driven tasks). Rather than stall a guest call behind
+/// them, host functions spin briefly and then return -5 so the guest can
+/// retry later.
+fn lock_or_retry<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    for _ in 0..LOCK_RETRY_SPINS {
+        if let Some(guard) = mutex.try_lock() {
+            return Some(guard);
+        }
+        ::core::hint::spin_loop();
+    }
+    None
+}
+
+/// Copy guest bytes into a new buffer, returning None on OOM
+///
+/// Host functions use this so a guest can't take the kernel down through
+/// alloc_error_handler by pushing allocations when the heap is low.
+fn try_copy(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(bytes.len()).ok()?;
+    buf.extend_from_slice(bytes);
+    Some(buf)
+}
+
+/// Claim room for one IPC message, false if the queue is full
+///
+/// Senders claim before allocating the message copy. A claimed slot is
+/// either filled with push_reserved or given back with release_ipc_slot.
+fn reserve_ipc_slot() -> bool {
+    IPC_QUEUED
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < MAX_IPC_QUEUE_DEPTH).then_some(n + 1))
+        .is_ok()
+}
+
+/// Give back a slot claimed with reserve_ipc_slot
+fn release_ipc_slot() {
+    IPC_QUEUED.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Queue a message into a claimed slot (lock-free)
+fn push_reserved(msg: IpcMessage) {
+    // Claims never exceed the ring size, so this can't fail in practice
+    if IPC_INBOX.push(msg).is_err() {
+        serial_println!("[IPC] Inbox unexpectedly full, message dropped");
+        release_ipc_slot();
+    }
+}
+
+/// Move everything senders have pushed into the receive-side queue
+fn drain_inbox(queue: &mut VecDeque<IpcMessage>) {
+    // SAFETY: callers hold IPC_MESSAGE_QUEUE, so there is one consumer
+    while let Some(msg) = unsafe { IPC_INBOX.pop() } {
+        queue.push_back(msg);
+    }
+}
+
+/// Lock the receive-side queue, up to date with every completed send
+fn lock_ipc_queue() -> MutexGuard<'static, VecDeque<IpcMessage>> {
+    let mut queue = IPC_MESSAGE_QUEUE.lock();
+    drain_inbox(&mut queue);
+    queue
+}
+
+/// Remove a queued message for good
+fn take_ipc_message(queue: &mut VecDeque<IpcMessage>, pos: usize) -> Option<IpcMessage> {
+    let msg = queue.remove(pos)?;
+    release_ipc_slot();
+    Some(msg)
+}
+
+/// Put a taken message back at the front of the receive-side queue
+fn requeue_ipc_message(queue: &mut VecDeque<IpcMessage>, msg: IpcMessage) {
+    IPC_QUEUED.fetch_add(1, Ordering::AcqRel);
+    queue.push_front(msg);
+}
+
 // simple print for testing
-fn host_print(_caller: Caller<'_, WasmContext>, value: i32) {
+fn host_print(mut caller: Caller<'_, WasmContext>, value: i32) {
+    charge_hostcall!(caller, Print);
     serial_println!("[WASM] Print called: {}", value);
 }
 
 // print string from wasm memory
-fn host_sys_print(caller: Caller<'_, WasmContext>, msg_ptr: i32, msg_len: i32) {
+fn host_sys_print(mut caller: Caller<'_, WasmContext>, msg_ptr: i32, msg_len: i32) {
+    charge_hostcall!(caller, SysPrint);
     let memory = match caller.get_export("memory") {
         Some(Extern::Memory(mem)) => mem,
         _ => {
@@ -98,13 +657,16 @@ fn host_sys_print(caller: Caller<'_, WasmContext>, msg_ptr: i32, msg_len: i32) {
 }
 
 // print u32 - arm64 uart doesn't support format args yet, so just print placeholder
-fn host_sys_print_u32(_caller: Caller<'_, WasmContext>, _value: u32) {
+fn host_sys_print_u32(mut caller: Caller<'_, WasmContext>, _value: u32) {
+    charge_hostcall!(caller, SysPrintU32);
     serial_print!("<u32>");
 }
 
 // generic syscall handler for 03_syscall.wasm demo
 // syscall(syscall_num, arg1, arg2, arg3) -> result
-fn host_syscall(caller: Caller<'_, WasmContext>, syscall_num: i32, arg1: i32, _arg2: i32, _arg3: i32) -> i32 {
+// failures use the SyscallError::as_errno codes
+fn host_syscall(mut caller: Caller<'_, WasmContext>, syscall_num: i32, arg1: i32, _arg2: i32, _arg3: i32) -> i32 {
+    charge_hostcall!(caller, Syscall, -5);
     match syscall_num {
         0 => {
             // SYS_READ - deny access for protected file descriptors
@@ -112,7 +674,7 @@ fn host_syscall(caller: Caller<'_, WasmContext>, syscall_num: i32, arg1: i32, _a
             if arg1 == 99 {
                 // protected fd - deny without capability
                 serial_println!("[SYSCALL] Access denied: protected resource");
-                -1
+                SyscallError::PermissionDenied.as_errno() as i32
             } else {
                 serial_println!("[SYSCALL] Read permitted");
                 0
@@ -137,30 +699,61 @@ fn host_syscall(caller: Caller<'_, WasmContext>, syscall_num: i32, arg1: i32, _a
         }
         _ => {
             serial_println!("[SYSCALL] Unknown syscall");
-            -1
+            SyscallError::InvalidSyscall.as_errno() as i32
         }
     }
 }
 
-/// Host function: MQTT subscribe
+/// Host function: MQTT subscribe (QoS 0)
 fn host_sys_mqtt_subscribe(
-    caller: Caller<'_, WasmContext>,
+    mut caller: Caller<'_, WasmContext>,
+    client_id: u32,
+    topic_ptr: i32,
+    topic_len: i32,
+) -> i32 {
+    charge_hostcall!(caller, SysMqttSubscribe, -5);
+    subscribe_with_qos(caller, client_id, topic_ptr, topic_len, 0)
+}
+
+/// Host function: MQTT subscribe with a QoS level (0/1/2)
+///
+/// QoS 0 is fire-and-forget; at QoS 1 and above, messages whose delivery
+/// fails in `deliver_pending_messages` are requeued instead of dropped.
+fn host_sys_mqtt_subscribe_qos(
+    mut caller: Caller<'_, WasmContext>,
+    client_id: u32,
+    topic_ptr: i32,
+    topic_len: i32,
+    qos: u32,
+) -> i32 {
+    charge_hostcall!(caller, SysMqttSubscribeQos, -5);
+    if qos > MAX_MQTT_QOS as u32 {
+        serial_println!("[MQTT-DENIED] Invalid QoS level {}", qos);
+        return caller.data_mut().fail(HostError::Invalid); // unsupported QoS
+    }
+    subscribe_with_qos(caller, client_id, topic_ptr, topic_len, qos as u8)
+}
+
+/// Shared subscribe path: read the topic and register the client
+fn subscribe_with_qos(
+    mut caller: Caller<'_, WasmContext>,
     client_id: u32,
     topic_ptr: i32,
     topic_len: i32,
+    qos: u8,
 ) -> i32 {
     // Read topic from WASM memory
     let memory = match caller.get_export("memory") {
         Some(Extern::Memory(mem)) => mem,
-        _ => return -1,
+        _ => return caller.data_mut().fail(HostError::Fault),
     };
 
     let data = memory.data(&caller);
     let topic_ptr = topic_ptr as usize;
     let topic_len = topic_len as usize;
 
-    if topic_ptr + topic_len > data.len() {
-        return -1;
+    if topic_ptr.saturating_add(topic_len) > data.len() {
+        return caller.data_mut().fail(HostError::Fault);
     }
 
     let topic = &data[topic_ptr..topic_ptr + topic_len];
@@ -173,10 +766,14 @@ fn host_sys_mqtt_subscribe(
     }
     serial_print!("\n");
 
-    // Register subscriber in global registry
-    let mut subscribers = MQTT_SUBSCRIBERS.lock();
-    if !subscribers.contains(&client_id) {
-        subscribers.push(client_id);
+    // Register subscriber in global registry (re-subscribing updates QoS)
+    let mut subscribers = match lock_or_retry(&MQTT_SUBSCRIBERS) {
+        Some(subs) => subs,
+        None => return caller.data_mut().fail(HostError::Busy), // registry busy, try again
+    };
+    match subscribers.iter_mut().find(|sub| sub.client_id == client_id) {
+        Some(sub) => sub.qos = qos,
+        None => subscribers.push(Subscription { client_id, qos }),
     }
 
     // TODO: route to actual broker module instead of global registry
@@ -185,23 +782,24 @@ fn host_sys_mqtt_subscribe(
 
 // mqtt publish - enforces 512 byte message limit and 64 message queue depth
 fn host_sys_mqtt_publish(
-    caller: Caller<'_, WasmContext>,
+    mut caller: Caller<'_, WasmContext>,
     topic_ptr: i32,
     topic_len: i32,
     msg_ptr: i32,
     msg_len: i32,
 ) -> i32 {
+    charge_hostcall!(caller, SysMqttPublish, -5);
     // reject huge messages (512 byte limit)
     let msg_len_usize = msg_len as usize;
     if msg_len < 0 || msg_len_usize > MAX_IPC_MESSAGE_SIZE {
         serial_println!("[MQTT-DENIED] Message too large: {} > {}", msg_len, MAX_IPC_MESSAGE_SIZE);
-        return -4; // too big
+        return caller.data_mut().fail(HostError::TooBig);
     }
 
     // read topic and message from wasm memory
     let memory = match caller.get_export("memory") {
         Some(Extern::Memory(mem)) => mem,
-        _ => return -1,
+        _ => return caller.data_mut().fail(HostError::Fault),
     };
 
     let data = memory.data(&caller);
@@ -212,7 +810,7 @@ fn host_sys_mqtt_publish(
     // Overflow-safe bounds check
     if topic_ptr.saturating_add(topic_len) > data.len()
         || msg_ptr.saturating_add(msg_len_usize) > data.len() {
-        return -3; // EFAULT
+        return caller.data_mut().fail(HostError::Fault);
     }
 
     let topic = &data[topic_ptr..topic_ptr + topic_len];
@@ -233,27 +831,88 @@ fn host_sys_mqtt_publish(
     let _ = topic; // Used in debug builds
 
     // Simplified broker: directly enqueue to all registered subscribers
-    let subscribers = MQTT_SUBSCRIBERS.lock();
+    let subscribers = match lock_or_retry(&MQTT_SUBSCRIBERS) {
+        Some(subs) => subs,
+        None => return caller.data_mut().fail(HostError::Busy), // registry busy, try again
+    };
     let subscriber_count = subscribers.len();
 
-    for &client_id in subscribers.iter() {
+    for sub in subscribers.iter() {
         // don't let queue grow forever - cap at 64 msgs
-        let mut queue = IPC_MESSAGE_QUEUE.lock();
-        if queue.len() >= MAX_IPC_QUEUE_DEPTH {
-            serial_println!("[MQTT-DENIED] Queue full ({}/{})", queue.len(), MAX_IPC_QUEUE_DEPTH);
+        if !reserve_ipc_slot() {
+            serial_println!("[MQTT-DENIED] Queue full ({} messages)", MAX_IPC_QUEUE_DEPTH);
             break; // Stop enqueueing, return partial count
         }
 
-        let ipc_msg = IpcMessage {
-            dest_client_id: client_id,
-            message: msg.to_vec(),
+        let message = match try_copy(msg) {
+            Some(m) => m,
+            None => {
+                release_ipc_slot();
+                serial_println!("[MQTT-DENIED] Out of memory copying message");
+                break; // Stop enqueueing, return partial count
+            }
         };
-        queue.push_back(ipc_msg);
+        push_reserved(IpcMessage {
+            dest_client_id: sub.client_id,
+            message,
+        });
     }
 
     subscriber_count as i32
 }
 
+/// Host function: list subscribed MQTT client IDs (admin/debug)
+///
+/// Writes up to `max` client IDs as little-endian u32s at `out_ptr` and
+/// returns how many were written, or negative errno.
+///
+/// # Security
+/// - Requires a ResourceType::Admin capability with READ rights (-1 otherwise)
+/// - Output buffer (max * 4 bytes) is bounds-checked against guest memory
+fn host_sys_mqtt_list_subs(mut caller: Caller<'_, WasmContext>, out_ptr: i32, max: i32) -> i32 {
+    charge_hostcall!(caller, SysMqttListSubs, -5);
+    let is_admin = caller.data().capability_views()
+        .any(|cap| cap.resource_type() == ResourceType::Admin && cap.rights().read);
+    if !is_admin {
+        serial_println!("[MQTT-DENIED] Subscription list requires Admin capability");
+        return caller.data_mut().fail(HostError::NoCap);
+    }
+
+    if out_ptr < 0 || max < 0 {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => return caller.data_mut().fail(HostError::Fault),
+    };
+
+    let data = memory.data_mut(&mut caller);
+    let out = out_ptr as usize;
+    let max = max as usize;
+
+    // Overflow-safe bounds check on the whole output buffer
+    let out_len = match max.checked_mul(4) {
+        Some(len) => len,
+        None => return caller.data_mut().fail(HostError::Fault),
+    };
+    if out.saturating_add(out_len) > data.len() {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    let subscribers = match lock_or_retry(&MQTT_SUBSCRIBERS) {
+        Some(subs) => subs,
+        None => return caller.data_mut().fail(HostError::Busy), // registry busy, try again
+    };
+    let count = subscribers.len().min(max);
+    for (i, sub) in subscribers.iter().take(count).enumerate() {
+        let at = out + i * 4;
+        data[at..at + 4].copy_from_slice(&sub.client_id.to_le_bytes());
+    }
+
+    count as i32
+}
+
 /// Host function: IPC send - enqueues message for delivery
 /// Enforces capability-based access control with 4-layer verification
 ///
@@ -267,57 +926,115 @@ fn host_sys_mqtt_publish(
 /// - Message size limited to MAX_IPC_MESSAGE_SIZE (512 bytes)
 /// - Queue depth limited to MAX_IPC_QUEUE_DEPTH (64 messages)
 /// - Queue check happens BEFORE allocation to prevent memory exhaustion
+/// - Zero-length messages are valid (pure notifications)
 ///
 /// # Assumptions
 /// - TRUST: Called from WASM sandbox (untrusted code)
 /// - Destination is treated as endpoint resource_id
 fn host_sys_ipc_send(
-    caller: Caller<'_, WasmContext>,
+    mut caller: Caller<'_, WasmContext>,
     dest: u32,
     msg_ptr: i32,
     msg_len: i32,
 ) -> i32 {
+    charge_hostcall!(caller, SysIpcSend, -5);
     // reject huge messages early (512 byte limit)
     let msg_len_usize = msg_len as usize;
     if msg_len < 0 || msg_len_usize > MAX_IPC_MESSAGE_SIZE {
         serial_println!("[IPC-DENIED] Message too large: {} > {}", msg_len, MAX_IPC_MESSAGE_SIZE);
-        return -4; // too big
+        return caller.data_mut().fail(HostError::TooBig); // -4: too big
     }
 
     // verify caller has the right capability for this endpoint
-    let cap = match caller.data().find_capability(ResourceType::Endpoint, dest as u64) {
-        Some(c) => c,
+    let writable = match caller.data().find_capability_view(ResourceType::Endpoint, dest as u64) {
+        Some(c) => c.rights().write,
         None => {
             serial_println!("[IPC-DENIED] No Endpoint capability for destination {}", dest);
-            return -1; // EACCES: Permission denied
+            return caller.data_mut().fail(HostError::NoCap); // -1: EACCES
         }
     };
 
     // Layer 3: Verify WRITE rights (required for sending)
-    if !cap.rights().write {
+    if !writable {
         serial_println!("[IPC-DENIED] Capability lacks WRITE rights for endpoint {}", dest);
-        return -2; // EPERM: Operation not permitted
+        return caller.data_mut().fail(HostError::NoWrite); // -2: EPERM
+    }
+
+    // Layer 4: Verify resource_id matches destination (already done in find_capability_view)
+    // This is implicit in the find_capability_view call above
+
+    match enqueue_guest_message(&caller, dest, msg_ptr, msg_len_usize) {
+        Ok(()) => 0,
+        Err(error) => caller.data_mut().fail(error),
+    }
+}
+
+/// Host function: IPC send through a granted capability, selected by index
+///
+/// Same as sys_ipc_send, but the guest names the endpoint by its position
+/// in the module's capability list instead of a raw resource_id.
+///
+/// # Security
+/// - cap_index must select an Endpoint capability (-1 otherwise)
+/// - The capability must have WRITE rights (-2 otherwise)
+/// - Same size, bounds and queue limits as sys_ipc_send
+fn host_sys_ipc_send_cap(
+    mut caller: Caller<'_, WasmContext>,
+    cap_index: u32,
+    msg_ptr: i32,
+    msg_len: i32,
+) -> i32 {
+    charge_hostcall!(caller, SysIpcSendCap, -5);
+    let msg_len_usize = msg_len as usize;
+    if msg_len < 0 || msg_len_usize > MAX_IPC_MESSAGE_SIZE {
+        serial_println!("[IPC-DENIED] Message too large: {} > {}", msg_len, MAX_IPC_MESSAGE_SIZE);
+        return caller.data_mut().fail(HostError::TooBig); // -4: too big
+    }
+
+    let (writable, resource_id) = match caller.data().capability_view(cap_index as usize) {
+        Some(c) if c.resource_type() == ResourceType::Endpoint => (c.rights().write, c.resource_id()),
+        _ => {
+            serial_println!("[IPC-DENIED] Capability {} is not an Endpoint", cap_index);
+            return caller.data_mut().fail(HostError::NoCap); // -1: EACCES
+        }
+    };
+    if !writable {
+        serial_println!("[IPC-DENIED] Capability {} lacks WRITE rights", cap_index);
+        return caller.data_mut().fail(HostError::NoWrite); // -2: EPERM
     }
+    let dest = match u32::try_from(resource_id) {
+        Ok(dest) => dest,
+        // not addressable by the guest message queue
+        Err(_) => return caller.data_mut().fail(HostError::NoCap),
+    };
 
-    // Layer 4: Verify resource_id matches destination (already done in find_capability)
-    // This is implicit in the find_capability call above
+    match enqueue_guest_message(&caller, dest, msg_ptr, msg_len_usize) {
+        Ok(()) => 0,
+        Err(error) => caller.data_mut().fail(error),
+    }
+}
 
+/// Copy a message out of guest memory onto the IPC queue for `dest`
+///
+/// Shared tail of the send host functions, run after their capability
+/// checks. `msg_len` must already be checked against MAX_IPC_MESSAGE_SIZE.
+fn enqueue_guest_message(caller: &Caller<'_, WasmContext>, dest: u32, msg_ptr: i32, msg_len: usize) -> Result<(), HostError> {
     // === Memory Access (after capability check passes) ===
     let memory = match caller.get_export("memory") {
         Some(Extern::Memory(mem)) => mem,
-        _ => return -3, // EFAULT: Bad address
+        _ => return Err(HostError::Fault), // EFAULT: Bad address
     };
 
-    let data = memory.data(&caller);
+    let data = memory.data(caller);
     let msg_ptr = msg_ptr as usize;
 
-    // Bounds check with overflow protection (msg_len_usize already validated above)
-    if msg_ptr.saturating_add(msg_len_usize) > data.len() {
-        serial_println!("[IPC-DENIED] Invalid memory access: ptr={}, len={}", msg_ptr, msg_len_usize);
-        return -3; // EFAULT: Bad address
+    // Bounds check with overflow protection (msg_len already validated above)
+    if msg_ptr.saturating_add(msg_len) > data.len() {
+        serial_println!("[IPC-DENIED] Invalid memory access: ptr={}, len={}", msg_ptr, msg_len);
+        return Err(HostError::Fault); // EFAULT: Bad address
     }
 
-    let msg = &data[msg_ptr..msg_ptr + msg_len_usize];
+    let msg = &data[msg_ptr..msg_ptr + msg_len];
 
     #[cfg(debug_assertions)]
     {
@@ -328,108 +1045,901 @@ fn host_sys_ipc_send(
         serial_print!("\n");
     }
 
-    // check queue isn't full before we allocate
-    let mut queue = IPC_MESSAGE_QUEUE.lock();
-    if queue.len() >= MAX_IPC_QUEUE_DEPTH {
-        serial_println!("[IPC-DENIED] Queue full: {} >= {}", queue.len(), MAX_IPC_QUEUE_DEPTH);
-        return -5; // queue full, try again later
+    // check queue isn't full before we allocate (lock-free, never waits on other senders)
+    if !reserve_ipc_slot() {
+        serial_println!("[IPC-DENIED] Queue full: {} messages", MAX_IPC_QUEUE_DEPTH);
+        return Err(HostError::QueueFull); // try again later
     }
 
     // good to go
-    let ipc_msg = IpcMessage {
-        dest_client_id: dest,
-        message: msg.to_vec(),
+    let message = match try_copy(msg) {
+        Some(m) => m,
+        None => {
+            release_ipc_slot();
+            return Err(HostError::NoMemory); // ENOMEM: kernel heap exhausted
+        }
     };
-    queue.push_back(ipc_msg);
+    push_reserved(IpcMessage {
+        dest_client_id: dest,
+        message,
+    });
 
-    0 // Success
+    Ok(())
 }
 
-impl WasmModule {
-    /// Load a Wasm module from bytes and create a reusable instance
-    pub fn from_bytes(wasm_bytes: &[u8]) -> Result<Self, Error> {
-        // Create engine
-        let engine = Engine::default();
+/// Host function: detail of the previous host call's failure (a HostError code)
+///
+/// Returns 0 if that call succeeded, or failed with nothing to add to its
+/// return value. Not charged against the host call limit, and doesn't
+/// clear the error itself, so it can be read more than once.
+fn host_sys_last_error(caller: Caller<'_, WasmContext>) -> i32 {
+    caller.data().last_error as i32
+}
 
-        // Parse and validate module
-        let module = Module::new(&engine, wasm_bytes)?;
+/// Pull one pending IPC message for this guest into its own memory
+///
+/// Pull-model counterpart to `deliver_pending_messages`: the guest
+/// supplies the buffer and decides when to receive.
+/// Returns the message length, 0 if no message is pending, or negative errno.
+///
+/// # Security
+/// - Only messages addressed to the guest's own client_id are returned
+/// - Destination buffer is bounds-checked against guest memory
+/// - A message larger than max_len stays queued (returns -4)
+///
+/// # Assumptions
+/// - TRUST: Called from WASM sandbox (untrusted code)
+fn host_sys_ipc_recv(
+    mut caller: Caller<'_, WasmContext>,
+    dst_ptr: i32,
+    max_len: i32,
+) -> i32 {
+    charge_hostcall!(caller, SysIpcRecv, -5);
+    let client_id = match caller.data().client_id {
+        Some(id) => id,
+        None => {
+            serial_println!("[IPC-DENIED] Receive from module without a client ID");
+            return caller.data_mut().fail(HostError::NoCap); // not an IPC client
+        }
+    };
 
-        // Create store with context
-        let context = WasmContext::new(Vec::new());
-        let mut store = Store::new(&engine, context);
+    if dst_ptr < 0 || max_len < 0 {
+        return caller.data_mut().fail(HostError::Fault);
+    }
 
-        // Create linker with host functions
-        let linker = Self::create_linker(&engine);
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => return caller.data_mut().fail(HostError::Fault),
+    };
 
-        // Instantiate module once and cache it for reuse
-        let instance = linker
-            .instantiate(&mut store, &module)?
-            .start(&mut store)?;
+    let mut queue = match lock_or_retry(&IPC_MESSAGE_QUEUE) {
+        Some(queue) => queue,
+        None => return caller.data_mut().fail(HostError::Busy), // queue busy, try again
+    };
+    drain_inbox(&mut queue);
+    let pos = match queue.iter().position(|m| m.dest_client_id == client_id) {
+        Some(pos) => pos,
+        None => return 0, // nothing pending
+    };
 
-        Ok(WasmModule {
-            _module: module,
-            store,
-            instance,
-        })
+    let msg_len = queue[pos].message.len();
+    if msg_len > max_len as usize {
+        return caller.data_mut().fail(HostError::TooBig); // too big for the guest buffer, leave it queued
     }
 
-    /// Create a linker with host functions
-    fn create_linker(engine: &Engine) -> Linker<WasmContext> {
-        let mut linker = Linker::new(engine);
+    let data = memory.data_mut(&mut caller);
+    let dst = dst_ptr as usize;
 
-        // Add host function: print (original for i32)
-        linker
-            .func_wrap("env", "print", host_print)
-            .expect("Failed to link print function");
+    // Bounds check with overflow protection
+    if dst.saturating_add(msg_len) > data.len() {
+        serial_println!("[IPC-DENIED] Invalid memory access: ptr={}, len={}", dst, msg_len);
+        return caller.data_mut().fail(HostError::Fault);
+    }
 
-        // mqtt syscalls for demos
-        linker
-            .func_wrap("env", "sys_print", host_sys_print)
-            .expect("Failed to link sys_print");
+    // only dequeue once the copy is known to succeed
+    let ipc_msg = match take_ipc_message(&mut queue, pos) {
+        Some(m) => m,
+        None => return 0,
+    };
+    data[dst..dst + msg_len].copy_from_slice(&ipc_msg.message);
 
-        linker
-            .func_wrap("env", "sys_print_u32", host_sys_print_u32)
-            .expect("Failed to link sys_print_u32");
+    msg_len as i32
+}
 
-        linker
-            .func_wrap("env", "sys_mqtt_subscribe", host_sys_mqtt_subscribe)
-            .expect("Failed to link sys_mqtt_subscribe");
+/// Host function: store a value under a key in the guest's kv store
+///
+/// Returns 0 on success or negative errno.
+///
+/// # Security (DoS Prevention)
+/// - Keyed by the module's client_id - guests can't touch each other's state
+/// - Total key + value bytes per client capped at MAX_KV_BYTES_PER_CLIENT
+fn host_sys_kv_set(
+    mut caller: Caller<'_, WasmContext>,
+    key_ptr: i32,
+    key_len: i32,
+    val_ptr: i32,
+    val_len: i32,
+) -> i32 {
+    charge_hostcall!(caller, SysKvSet, -5);
+    let client_id = match caller.data().client_id {
+        Some(id) => id,
+        None => return caller.data_mut().fail(HostError::NoCap), // no client identity to store under
+    };
 
-        linker
-            .func_wrap("env", "sys_mqtt_publish", host_sys_mqtt_publish)
-            .expect("Failed to link sys_mqtt_publish");
+    if key_ptr < 0 || key_len <= 0 || val_ptr < 0 || val_len < 0 {
+        return caller.data_mut().fail(HostError::Fault);
+    }
 
-        linker
-            .func_wrap("env", "sys_ipc_send", host_sys_ipc_send)
-            .expect("Failed to link sys_ipc_send");
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => return caller.data_mut().fail(HostError::Fault),
+    };
+
+    let data = memory.data(&caller);
+    let (key_ptr, key_len) = (key_ptr as usize, key_len as usize);
+    let (val_ptr, val_len) = (val_ptr as usize, val_len as usize);
+
+    // Overflow-safe bounds check
+    if key_ptr.saturating_add(key_len) > data.len()
+        || val_ptr.saturating_add(val_len) > data.len() {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    let key = &data[key_ptr..key_ptr + key_len];
+    let value = &data[val_ptr..val_ptr + val_len];
+
+    let mut stores = KV_STORES.lock();
+    let entries = stores.entry(client_id).or_default();
+
+    // Quota check counts the new size of this key, not the old one
+    let used: usize = entries.iter()
+        .filter(|(k, _)| k.as_slice() != key)
+        .map(|(k, v)| k.len() + v.len())
+        .sum();
+    if used + key_len + val_len > MAX_KV_BYTES_PER_CLIENT {
+        serial_println!("[KV-DENIED] Client {} over quota ({} bytes)", client_id, MAX_KV_BYTES_PER_CLIENT);
+        return caller.data_mut().fail(HostError::TooBig);
+    }
+
+    match entries.iter_mut().find(|(k, _)| k.as_slice() == key) {
+        Some((_, v)) => *v = value.to_vec(),
+        None => entries.push((key.to_vec(), value.to_vec())),
+    }
+
+    0
+}
+
+/// Host function: copy the value stored under a key into guest memory
+///
+/// Returns the value length, or negative errno (-7 if the key isn't set).
+fn host_sys_kv_get(
+    mut caller: Caller<'_, WasmContext>,
+    key_ptr: i32,
+    key_len: i32,
+    dst_ptr: i32,
+    max_len: i32,
+) -> i32 {
+    charge_hostcall!(caller, SysKvGet, -5);
+    let client_id = match caller.data().client_id {
+        Some(id) => id,
+        None => return caller.data_mut().fail(HostError::NoCap),
+    };
+
+    if key_ptr < 0 || key_len <= 0 || dst_ptr < 0 || max_len < 0 {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => return caller.data_mut().fail(HostError::Fault),
+    };
+
+    let data = memory.data_mut(&mut caller);
+    let (key_ptr, key_len) = (key_ptr as usize, key_len as usize);
+    let dst = dst_ptr as usize;
+
+    if key_ptr.saturating_add(key_len) > data.len() {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    let key = &data[key_ptr..key_ptr + key_len];
+    let stores = KV_STORES.lock();
+    let value = match stores.get(&client_id)
+        .and_then(|entries| entries.iter().find(|(k, _)| k.as_slice() == key))
+    {
+        Some((_, v)) => v,
+        None => return caller.data_mut().fail(HostError::NotFound), // key not set
+    };
+
+    if value.len() > max_len as usize {
+        return caller.data_mut().fail(HostError::TooBig); // too big for the guest buffer
+    }
+    if dst.saturating_add(value.len()) > data.len() {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    data[dst..dst + value.len()].copy_from_slice(value);
+    value.len() as i32
+}
+
+/// Copy a service name out of guest memory
+fn read_service_name(caller: &Caller<'_, WasmContext>, name_ptr: i32, name_len: i32) -> Result<Vec<u8>, HostError> {
+    if name_ptr < 0 || name_len <= 0 {
+        return Err(HostError::Fault);
+    }
+    if name_len as usize > MAX_SERVICE_NAME {
+        return Err(HostError::TooBig);
+    }
+
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => return Err(HostError::Fault),
+    };
+
+    let data = memory.data(caller);
+    let (ptr, len) = (name_ptr as usize, name_len as usize);
+    if ptr.saturating_add(len) > data.len() {
+        return Err(HostError::Fault);
+    }
+    try_copy(&data[ptr..ptr + len]).ok_or(HostError::NoMemory)
+}
+
+/// Host function: publish one of the guest's endpoints under a name
+///
+/// `endpoint_cap_index` selects an Endpoint capability in the module's
+/// list, as in sys_ipc_send_cap. Returns 0 on success or negative errno.
+///
+/// # Security
+/// - Only endpoints the guest holds a capability for can be published
+/// - A name belongs to the client that registered it first; only that
+///   client may re-register it (-2 for anyone else)
+/// - Registering grants nothing: senders still need their own WRITE
+///   capability for the endpoint
+fn host_sys_register_service(
+    mut caller: Caller<'_, WasmContext>,
+    name_ptr: i32,
+    name_len: i32,
+    endpoint_cap_index: u32,
+) -> i32 {
+    charge_hostcall!(caller, SysRegisterService, -5);
+    let owner = match caller.data().client_id {
+        Some(id) => id,
+        None => return caller.data_mut().fail(HostError::NoCap), // no client identity to own the name
+    };
+
+    let endpoint = match caller.data().capability_view(endpoint_cap_index as usize) {
+        Some(c) if c.resource_type() == ResourceType::Endpoint => c.resource_id(),
+        _ => {
+            serial_println!("[SVC-DENIED] Capability {} is not an Endpoint", endpoint_cap_index);
+            return caller.data_mut().fail(HostError::NoCap);
+        }
+    };
+    let endpoint = match u32::try_from(endpoint) {
+        Ok(endpoint) => endpoint,
+        // not addressable by the guest message queue
+        Err(_) => return caller.data_mut().fail(HostError::NoCap),
+    };
+
+    let name = match read_service_name(&caller, name_ptr, name_len) {
+        Ok(name) => name,
+        Err(error) => return caller.data_mut().fail(error),
+    };
+
+    let mut services = SERVICES.lock();
+    if let Some(service) = services.iter_mut().find(|s| s.name == name) {
+        if service.owner != owner {
+            serial_println!("[SVC-DENIED] Client {} can't take a name owned by client {}", owner, service.owner);
+            return caller.data_mut().fail(HostError::NoWrite);
+        }
+        service.endpoint = endpoint;
+        return 0;
+    }
+
+    if services.len() >= MAX_SERVICES {
+        return caller.data_mut().fail(HostError::TooBig); // registry full
+    }
+    if services.try_reserve(1).is_err() {
+        return caller.data_mut().fail(HostError::NoMemory);
+    }
+    services.push(Service { name, endpoint, owner });
+    0
+}
+
+/// Host function: resolve a service name to its endpoint
+///
+/// Returns the endpoint id, usable as the destination of sys_ipc_send,
+/// or negative errno (-7 if no service has that name). Sending still
+/// requires an Endpoint capability with WRITE rights for it.
+fn host_sys_lookup_service(mut caller: Caller<'_, WasmContext>, name_ptr: i32, name_len: i32) -> i32 {
+    charge_hostcall!(caller, SysLookupService, -5);
+    let name = match read_service_name(&caller, name_ptr, name_len) {
+        Ok(name) => name,
+        Err(error) => return caller.data_mut().fail(error),
+    };
+
+    // ids past i32::MAX would read as an errno
+    let endpoint = SERVICES.lock().iter()
+        .find(|s| s.name == name)
+        .and_then(|service| i32::try_from(service.endpoint).ok());
+    match endpoint {
+        Some(endpoint) => endpoint,
+        None => caller.data_mut().fail(HostError::NotFound),
+    }
+}
+
+/// Host function: request a fuel refill
+///
+/// Returns the remaining fuel after the refill (saturated to i32::MAX),
+/// or negative errno.
+///
+/// # Security
+/// - Requires a ResourceType::Fuel capability with WRITE rights (-1 otherwise)
+/// - The capability's resource_id caps the amount per request (-2 if exceeded)
+/// - Only works on modules loaded with fuel metering (-6 otherwise)
+fn host_sys_request_fuel(mut caller: Caller<'_, WasmContext>, amount: u32) -> i32 {
+    charge_hostcall!(caller, SysRequestFuel, -5);
+    let limit = caller.data().capability_views()
+        .find(|cap| cap.resource_type() == ResourceType::Fuel && cap.rights().write)
+        .map(|cap| cap.resource_id());
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            serial_println!("[FUEL-DENIED] No Fuel capability");
+            return caller.data_mut().fail(HostError::NoCap);
+        }
+    };
+
+    if amount as u64 > limit {
+        serial_println!("[FUEL-DENIED] Requested {} > per-request limit {}", amount, limit);
+        return caller.data_mut().fail(HostError::NoWrite); // over the per-request limit
+    }
+
+    if caller.add_fuel(amount as u64).is_err() {
+        return caller.data_mut().fail(HostError::Invalid); // fuel metering disabled for this module
+    }
+    caller.data_mut().fuel_granted += amount as u64;
+
+    match caller.consume_fuel(0) {
+        Ok(remaining) => remaining.min(i32::MAX as u64) as i32,
+        Err(_) => caller.data_mut().fail(HostError::Invalid),
+    }
+}
+
+/// Host function: fuel consumed so far by this module
+///
+/// Granted fuel (initial budget plus refills) minus what remains, so a
+/// guest can profile its own hot paths. Returns -1 if the module isn't
+/// fuel-metered.
+fn host_sys_fuel_used(mut caller: Caller<'_, WasmContext>) -> i64 {
+    charge_hostcall!(caller, SysFuelUsed, -5);
+    match caller.consume_fuel(0) {
+        Ok(remaining) => caller.data().fuel_granted.saturating_sub(remaining) as i64,
+        Err(_) => -1,
+    }
+}
+
+/// Host function: park the calling task for `ticks` timer ticks
+///
+/// Runs `scheduler::sleep_ticks` on the task's own kernel stack. No
+/// kernel locks are held across a host call and the module's store is
+/// owned by this task, so other tasks (including other Wasm guests) run
+/// normally meanwhile and nothing can re-enter this instance before it
+/// wakes. Returns 0, or -6 for a negative duration or when the timer
+/// can't wake us (interrupts disabled, or no scheduler on ARM64).
+fn host_sys_sleep_ticks(mut caller: Caller<'_, WasmContext>, ticks: i32) -> i32 {
+    charge_hostcall!(caller, SysSleepTicks, -5);
+    if ticks < 0 {
+        return caller.data_mut().fail(HostError::Invalid);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        // Sleeping with interrupts off would never see the deadline
+        if !x86_64::instructions::interrupts::are_enabled() {
+            return caller.data_mut().fail(HostError::Invalid);
+        }
+        crate::scheduler::sleep_ticks(ticks as u64);
+        0
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        caller.data_mut().fail(HostError::Invalid)
+    }
+}
+
+/// Host function: milliseconds since boot
+///
+/// Timer ticks scaled by the timer frequency on x86_64; the generic
+/// counter via ticks_to_us on ARM64. Returns -6 if the x86 timer hasn't
+/// been started yet (no frequency to scale by).
+fn host_sys_uptime_ms(mut caller: Caller<'_, WasmContext>) -> i64 {
+    charge_hostcall!(caller, SysUptimeMs, -5);
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let hz = crate::interrupts::timer_frequency() as u64;
+        if hz == 0 {
+            return caller.data_mut().fail(HostError::Invalid) as i64; // timer not running
+        }
+        let ms = crate::benchmark::scale_ticks(crate::interrupts::timer_ticks(), hz, 1000);
+        ms.min(i64::MAX as u64) as i64
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        // counter_frequency falls back to a default if CNTFRQ_EL0 reads 0
+        let us = crate::arch::benchmark::ticks_to_us(crate::arch::benchmark::read_counter());
+        (us / 1000).min(i64::MAX as u64) as i64
+    }
+}
+
+/// Host function: free kernel heap in bytes
+///
+/// Informational only - lets a guest check before asking for a large
+/// buffer, nothing is reserved. Returns -1 if the allocator is busy.
+fn host_sys_heap_free(mut caller: Caller<'_, WasmContext>) -> i64 {
+    charge_hostcall!(caller, SysHeapFree, -5);
+
+    #[cfg(target_arch = "x86_64")]
+    let free = crate::allocator::stats().map(|stats| stats.free);
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let free = crate::ALLOCATOR.try_lock().map(|heap| heap.free());
+
+    free.map_or(-1, |bytes| bytes as i64)
+}
+
+/// Host function: number of tasks the scheduler holds
+///
+/// Informational only, for guests that scale back under load. Returns -1
+/// if the scheduler is busy (or not started) rather than waiting for it.
+fn host_sys_task_count(mut caller: Caller<'_, WasmContext>) -> i32 {
+    charge_hostcall!(caller, SysTaskCount, -5);
+
+    #[cfg(target_arch = "x86_64")]
+    let count = crate::scheduler::SCHEDULER.try_lock()
+        .and_then(|guard| guard.as_ref().map(|scheduler| scheduler.task_count()));
+
+    // ARM64: single core and the scheduler is only written during boot
+    #[cfg(not(target_arch = "x86_64"))]
+    let count = Some(unsafe { (*::core::ptr::addr_of!(crate::scheduler::SCHEDULER)).num_tasks });
+
+    count.map_or(-1, |tasks| tasks as i32)
+}
+
+/// Host function: compare-and-swap a shared kernel counter
+///
+/// Sets counter `counter_id` to `new` if it currently holds `expected`.
+/// Returns 1 if swapped, 0 if the value didn't match (reload and retry),
+/// or -6 for a counter_id outside 0..MAX_ATOMIC_COUNTERS.
+fn host_sys_atomic_cas(mut caller: Caller<'_, WasmContext>, counter_id: i32, expected: i64, new: i64) -> i32 {
+    charge_hostcall!(caller, SysAtomicCas, -5);
+    let counter = match usize::try_from(counter_id).ok().and_then(|id| ATOMIC_COUNTERS.get(id)) {
+        Some(counter) => counter,
+        None => return caller.data_mut().fail(HostError::Invalid),
+    };
+    match counter.compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Host function: current value of a shared kernel counter
+///
+/// Returns -6 for an unknown counter_id (or -5 when rate limited); those
+/// are indistinguishable from a counter holding that value, so guests
+/// should keep shared counters non-negative.
+fn host_sys_atomic_load(mut caller: Caller<'_, WasmContext>, counter_id: i32) -> i64 {
+    charge_hostcall!(caller, SysAtomicLoad, -5);
+    match usize::try_from(counter_id).ok().and_then(|id| ATOMIC_COUNTERS.get(id)) {
+        Some(counter) => counter.load(Ordering::SeqCst),
+        None => caller.data_mut().fail(HostError::Invalid) as i64,
+    }
+}
+
+/// Seed used when a guest seeds with 0 (xorshift never leaves state 0)
+const RANDOM_ZERO_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// One xorshift64 step (never leaves a non-zero state)
+fn xorshift64(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Next value of the shared kernel PRNG
+///
+/// xorshift64, seeded from the cycle counter by init. NOT cryptographic:
+/// the output is predictable from a few samples.
+fn next_random() -> u64 {
+    let previous = RANDOM_STATE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(xorshift64(x)))
+        .unwrap_or_else(|x| x);
+    xorshift64(previous)
+}
+
+/// Host function: pseudo-random number in 0..=i64::MAX
+///
+/// Not cryptographic - for simulations and jitter only. Draws from the
+/// shared boot-seeded generator until the guest calls sys_seed, then from
+/// its own. Negative returns are errors (-5 when rate limited).
+fn host_sys_random(mut caller: Caller<'_, WasmContext>) -> i64 {
+    charge_hostcall!(caller, SysRandom, -5);
+    let value = match caller.data().random_state {
+        Some(state) => {
+            let next = xorshift64(state);
+            caller.data_mut().random_state = Some(next);
+            next
+        }
+        None => next_random(),
+    };
+    (value >> 1) as i64
+}
+
+/// Host function: seed a generator private to this module
+///
+/// Later sys_random calls from this module repeat the sequence for `seed`
+/// whatever other guests draw; the shared generator is never touched.
+/// Returns 0.
+fn host_sys_seed(mut caller: Caller<'_, WasmContext>, seed: i64) -> i32 {
+    charge_hostcall!(caller, SysSeed, -5);
+    let seed = if seed == 0 { RANDOM_ZERO_SEED } else { seed as u64 };
+    caller.data_mut().random_state = Some(seed);
+    0
+}
+
+/// Host function: load another Wasm module from guest memory
+///
+/// The bytes go through load_and_validate, so the ModuleLimits apply. The
+/// new module waits under the returned handle (> 0) until the kernel
+/// picks it up with take_loaded_module. Returns negative errno otherwise:
+/// -3 for a bad range, -4 once MAX_LOADED_MODULES are waiting, -6 if the
+/// bytes don't load.
+///
+/// # Security
+/// - Requires a ResourceType::WasmModule capability with EXECUTE rights (-1 otherwise)
+/// - The new module starts with no capabilities; the launcher's aren't
+///   passed on
+fn host_sys_load_module(mut caller: Caller<'_, WasmContext>, bytes_ptr: i32, bytes_len: i32) -> i32 {
+    charge_hostcall!(caller, SysLoadModule, -5);
+    let authorized = caller.data().capability_views()
+        .any(|cap| cap.resource_type() == ResourceType::WasmModule && cap.rights().execute);
+    if !authorized {
+        serial_println!("[LOAD-DENIED] No WasmModule capability with EXECUTE rights");
+        return caller.data_mut().fail(HostError::NoCap);
+    }
+
+    if bytes_ptr < 0 || bytes_len <= 0 {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => return caller.data_mut().fail(HostError::Fault),
+    };
+    let data = memory.data(&caller);
+    let (ptr, len) = (bytes_ptr as usize, bytes_len as usize);
+    if ptr.saturating_add(len) > data.len() {
+        return caller.data_mut().fail(HostError::Fault);
+    }
+
+    // Parse before taking the lock: validation is the slow part, and
+    // nothing else should wait on LOADED_MODULES while it runs
+    let module = match load_and_validate(&data[ptr..ptr + len]) {
+        Ok(module) => module,
+        Err(e) => {
+            serial_println!("[LOAD] Guest-supplied module rejected: {:?}", e);
+            return caller.data_mut().fail(HostError::Invalid);
+        }
+    };
+    let mut modules = match lock_or_retry(&LOADED_MODULES) {
+        Some(modules) => modules,
+        None => return caller.data_mut().fail(HostError::Busy),
+    };
+    if modules.len() >= MAX_LOADED_MODULES {
+        return caller.data_mut().fail(HostError::TooBig); // too many waiting
+    }
+    let handle = NEXT_MODULE_HANDLE.fetch_add(1, Ordering::Relaxed);
+    let Ok(errno_safe) = i32::try_from(handle) else {
+        return caller.data_mut().fail(HostError::TooBig); // handles exhausted
+    };
+    modules.insert(handle, module);
+    errno_safe
+}
+
+/// Decode an unsigned LEB128 u32, returning it and the bytes it took
+fn read_leb_u32(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (i, &byte) in bytes.iter().take(5).enumerate() {
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+impl WasmModule {
+    /// Load a Wasm module from bytes and create a reusable instance
+    ///
+    /// Modules over the current ModuleLimits are rejected with
+    /// LoadError::LimitExceeded before they're parsed.
+    pub fn from_bytes(wasm_bytes: &[u8]) -> Result<Self, LoadError> {
+        Self::instantiate(wasm_bytes, None, HostFunctions::All)
+    }
+
+    /// Load a Wasm module with fuel metering and an initial fuel budget
+    ///
+    /// Calls trap once the budget runs out; guests holding a Fuel
+    /// capability can top up via `sys_request_fuel`.
+    pub fn from_bytes_with_fuel(wasm_bytes: &[u8], fuel: u64) -> Result<Self, LoadError> {
+        Self::instantiate(wasm_bytes, Some(fuel), HostFunctions::All)
+    }
+
+    fn instantiate(wasm_bytes: &[u8], fuel: Option<u64>, exposed: HostFunctions) -> Result<Self, LoadError> {
+        // Create engine (fuel metering only when a budget is given)
+        let engine = match fuel {
+            Some(_) => {
+                let mut config = Config::default();
+                config.consume_fuel(true);
+                Engine::new(&config)
+            }
+            None => Engine::default(),
+        };
+
+        // Parse and validate module, once it's known not to be oversized
+        Self::check_module_limits(wasm_bytes, &module_limits())?;
+        let module = Module::new(&engine, wasm_bytes)?;
+        Self::check_memory_layout(&module)?;
+        Self::check_host_imports(&module, &exposed)?;
+
+        // Instantiate module once and cache it for reuse
+        let (store, instance) = Self::instantiate_in_new_store(&module, WasmContext::new(Vec::new()), fuel, &exposed)?;
+
+        Ok(WasmModule {
+            module,
+            store,
+            instance,
+            initial_fuel: fuel,
+            exposed,
+            trap_policy: TrapPolicy::Report,
+        })
+    }
+
+    /// Create a store holding `context` and instantiate `module` in it
+    ///
+    /// A trapping start function is reported as StartTrapped. The store is
+    /// dropped on any error, taking the half-initialised instance with it.
+    fn instantiate_in_new_store(
+        module: &Module,
+        context: WasmContext,
+        fuel: Option<u64>,
+        exposed: &HostFunctions,
+    ) -> Result<(Store<WasmContext>, Instance), LoadError> {
+        let engine = module.engine();
+        let mut store = Store::new(engine, context);
+
+        // Fuel must be in place before instantiation (start function runs then)
+        if let Some(fuel) = fuel {
+            store.add_fuel(fuel).expect("module engine is fuel-metered");
+            store.data_mut().fuel_granted = fuel;
+        }
+
+        // Create linker with host functions
+        let linker = Self::create_linker(engine, exposed);
+        let instance = linker
+            .instantiate(&mut store, module)?
+            .start(&mut store)
+            .map_err(LoadError::StartTrapped)?;
+        Ok((store, instance))
+    }
+
+    /// Restart the guest from scratch without re-parsing its bytes
+    ///
+    /// Instantiates the already validated module into a fresh store, so
+    /// memory, globals and tables are back to their initial values and the
+    /// start function runs again. Granted capabilities, the client ID and
+    /// the host call limit and counters carry over; fuel is reset to the
+    /// load-time budget. On error the old instance is kept.
+    pub fn reset(&mut self) -> Result<(), LoadError> {
+        let old = self.store.data();
+        let mut context = WasmContext::new(old.capabilities.clone());
+        context.client_id = old.client_id;
+        context.hostcall_limit = old.hostcall_limit;
+        context.hostcalls = old.hostcalls;
+        context.hostcalls_rejected = old.hostcalls_rejected;
+        context.quantum = old.quantum;
+
+        let (store, instance) = Self::instantiate_in_new_store(&self.module, context, self.initial_fuel, &self.exposed)?;
+        self.store = store;
+        self.instance = instance;
+        Ok(())
+    }
+
+    /// Reject modules whose memory our host functions can't reach
+    ///
+    /// Host functions look up a single export named `memory`. Multiple
+    /// memories are already refused by wasmi's validator (multi-memory is
+    /// off); here we catch imported memories (the host provides none) and
+    /// memories exported under another name, which would otherwise load
+    /// and then fail on every host call that touches guest memory.
+    fn check_memory_layout(module: &Module) -> Result<(), LoadError> {
+        if module.imports().any(|import| matches!(import.ty(), ExternType::Memory(_))) {
+            return Err(LoadError::UnsupportedMemory("memory imports are not supported"));
+        }
+        for export in module.exports() {
+            if matches!(export.ty(), ExternType::Memory(_)) && export.name() != "memory" {
+                return Err(LoadError::UnsupportedMemory("memory must be exported as \"memory\""));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject a module that exceeds `limits`
+    ///
+    /// Only walks the section headers and reads the entry count at the
+    /// start of the import, function and export sections, so the cost
+    /// doesn't grow with the size of their contents. Malformed headers
+    /// end the scan; Module::new reports those.
+    fn check_module_limits(wasm_bytes: &[u8], limits: &ModuleLimits) -> Result<(), LoadError> {
+        let check = |what, count: usize, limit| {
+            if count > limit {
+                serial_println!("[WASM-DENIED] Module has {} {} (limit {})", count, what, limit);
+                return Err(LoadError::LimitExceeded { what, count, limit });
+            }
+            Ok(())
+        };
+        check("bytes", wasm_bytes.len(), limits.max_bytes)?;
+
+        // Skip the magic number and version
+        let mut pos = 8;
+        while pos < wasm_bytes.len() {
+            let id = wasm_bytes[pos];
+            let Some((size, used)) = read_leb_u32(&wasm_bytes[pos + 1..]) else { break };
+            let start = pos + 1 + used;
+            let Some(payload) = start.checked_add(size as usize).and_then(|end| wasm_bytes.get(start..end)) else { break };
+
+            let count = || read_leb_u32(payload).map_or(0, |(count, _)| count as usize);
+            match id {
+                2 => check("imports", count(), limits.max_imports)?,
+                3 => check("functions", count(), limits.max_functions)?,
+                7 => check("exports", count(), limits.max_exports)?,
+                _ => {}
+            }
+            pos = start + size as usize;
+        }
+        Ok(())
+    }
+
+    /// Reject a module importing a host function outside `exposed`
+    ///
+    /// wasmi would fail instantiation anyway; this names the import.
+    fn check_host_imports(module: &Module, exposed: &HostFunctions) -> Result<(), LoadError> {
+        for import in module.imports() {
+            if import.module() == "env" && matches!(import.ty(), ExternType::Func(_)) && !exposed.allows(import.name()) {
+                serial_println!("[WASM-DENIED] Host function {} not exposed to this module", import.name());
+                return Err(LoadError::HostFunctionNotExposed(import.name().into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Link `func` as env.`name` if `exposed` includes it
+    fn link_host<Params, Results>(
+        linker: &mut Linker<WasmContext>,
+        exposed: &HostFunctions,
+        name: &'static str,
+        func: impl IntoFunc<WasmContext, Params, Results>,
+    ) {
+        if exposed.allows(name) {
+            linker
+                .func_wrap("env", name, func)
+                .unwrap_or_else(|_| panic!("Failed to link {}", name));
+        }
+    }
+
+    /// Create a linker with host functions
+    ///
+    /// Only the host functions in `exposed` are linked.
+    fn create_linker(engine: &Engine, exposed: &HostFunctions) -> Linker<WasmContext> {
+        let mut linker = Linker::new(engine);
+
+        // Add host function: print (original for i32)
+        Self::link_host(&mut linker, exposed, "print", host_print);
+
+        // mqtt syscalls for demos
+        Self::link_host(&mut linker, exposed, "sys_print", host_sys_print);
+        Self::link_host(&mut linker, exposed, "sys_print_u32", host_sys_print_u32);
+        Self::link_host(&mut linker, exposed, "sys_mqtt_subscribe", host_sys_mqtt_subscribe);
+        Self::link_host(&mut linker, exposed, "sys_mqtt_subscribe_qos", host_sys_mqtt_subscribe_qos);
+        Self::link_host(&mut linker, exposed, "sys_mqtt_list_subs", host_sys_mqtt_list_subs);
+        Self::link_host(&mut linker, exposed, "sys_mqtt_publish", host_sys_mqtt_publish);
+        Self::link_host(&mut linker, exposed, "sys_ipc_send", host_sys_ipc_send);
+        Self::link_host(&mut linker, exposed, "sys_ipc_send_cap", host_sys_ipc_send_cap);
+        Self::link_host(&mut linker, exposed, "sys_ipc_recv", host_sys_ipc_recv);
+        Self::link_host(&mut linker, exposed, "sys_last_error", host_sys_last_error);
+        Self::link_host(&mut linker, exposed, "sys_kv_set", host_sys_kv_set);
+        Self::link_host(&mut linker, exposed, "sys_kv_get", host_sys_kv_get);
+        Self::link_host(&mut linker, exposed, "sys_register_service", host_sys_register_service);
+        Self::link_host(&mut linker, exposed, "sys_lookup_service", host_sys_lookup_service);
+        Self::link_host(&mut linker, exposed, "sys_request_fuel", host_sys_request_fuel);
+        Self::link_host(&mut linker, exposed, "sys_fuel_used", host_sys_fuel_used);
+        Self::link_host(&mut linker, exposed, "sys_sleep_ticks", host_sys_sleep_ticks);
+        Self::link_host(&mut linker, exposed, "sys_uptime_ms", host_sys_uptime_ms);
+        Self::link_host(&mut linker, exposed, "sys_heap_free", host_sys_heap_free);
+        Self::link_host(&mut linker, exposed, "sys_task_count", host_sys_task_count);
+        Self::link_host(&mut linker, exposed, "sys_atomic_cas", host_sys_atomic_cas);
+        Self::link_host(&mut linker, exposed, "sys_atomic_load", host_sys_atomic_load);
+        Self::link_host(&mut linker, exposed, "sys_random", host_sys_random);
+        Self::link_host(&mut linker, exposed, "sys_seed", host_sys_seed);
+        Self::link_host(&mut linker, exposed, "sys_load_module", host_sys_load_module);
 
         // generic syscall interface for 03_syscall.wasm demo
-        linker
-            .func_wrap("env", "syscall", host_syscall)
-            .expect("Failed to link syscall function");
+        Self::link_host(&mut linker, exposed, "syscall", host_syscall);
 
         linker
     }
 
     /// Call a function on the cached instance (no re-instantiation!)
+    ///
+    /// Arguments and results may be any Wasm value type (i32/i64/f32/f64).
     pub fn call_function(&mut self, func_name: &str, args: &[Value]) -> Result<Option<Value>, &'static str> {
         // Get the function from the cached instance
         let func = self.instance
             .get_func(&mut self.store, func_name)
             .ok_or("Function not found")?;
 
-        // Get function type to determine result count
+        // Allocate results buffer typed from the function signature
+        // (wasmi rejects a buffer whose value types don't match)
         let func_type = func.ty(&self.store);
-        let result_count = func_type.results().len();
-
-        // Allocate results buffer based on actual return type
-        let mut results = vec![Value::I32(0); result_count];
-        func.call(&mut self.store, args, &mut results)
-            .map_err(|_| "Failed to call function")?;
+        let mut results: Vec<Value> = func_type
+            .results()
+            .iter()
+            .map(|ty| Value::default(*ty))
+            .collect();
+        if func.call(&mut self.store, args, &mut results).is_err() {
+            self.apply_trap_policy(func_name);
+            return Err("Failed to call function");
+        }
 
         Ok(results.into_iter().next())
     }
 
+    /// Choose what a trap in call_function does to the hosting task
+    ///
+    /// Under Terminate the task exits through scheduler::exit instead of
+    /// carrying on with a module that may be stuck half way through an
+    /// update. Outside a task, and on ARM64 (no task exit yet), the trap
+    /// is reported as under Report.
+    pub fn set_trap_policy(&mut self, policy: TrapPolicy) {
+        self.trap_policy = policy;
+    }
+
+    /// End the hosting task if the trap policy says so
+    ///
+    /// scheduler::exit never returns, so whoever owns this module never
+    /// gets to drop it. The module is swapped for an empty one first and
+    /// the guest's store, linear memory, capabilities and engine are
+    /// released here; only the empty stub stays behind.
+    fn apply_trap_policy(&mut self, func_name: &str) {
+        if self.trap_policy == TrapPolicy::Report {
+            return;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if crate::scheduler::current_task_id().is_some() {
+            serial_println!("[WASM] {} trapped, terminating host task", func_name);
+            if let Ok(stub) = WasmModule::from_bytes(EMPTY_MODULE) {
+                drop(::core::mem::replace(self, stub));
+            }
+            crate::scheduler::exit(TRAP_EXIT_CODE);
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = func_name;
+    }
+
     /// Add a capability to this module's context
     ///
     /// Grants the full capability object (not just ID) to enable
@@ -440,6 +1950,101 @@ impl WasmModule {
         self.store.data_mut().capabilities.push(capability);
     }
 
+    /// Set the IPC client ID this module receives messages as
+    pub fn set_client_id(&mut self, client_id: u32) {
+        self.store.data_mut().client_id = Some(client_id);
+    }
+
+    /// Limit each host function to `limit` calls per quantum
+    ///
+    /// Over the limit, host functions return -5 (void ones like sys_print
+    /// drop the call) until the next quantum, so a guest can't monopolize
+    /// serial output or kernel time within its fuel budget. A quantum is
+    /// the hosting task's time slice: it ends when the task is switched
+    /// out, however many call_function calls it made meanwhile.
+    pub fn set_hostcall_limit(&mut self, limit: u32) {
+        self.store.data_mut().hostcall_limit = Some(limit);
+    }
+
+    /// Reset host call counters before the hosting task's quantum ends
+    pub fn begin_quantum(&mut self) {
+        self.store.data_mut().reset_hostcalls(current_quantum());
+    }
+
+    /// Accepted calls to host function `name` this quantum
+    pub fn hostcall_count(&self, name: &str) -> u32 {
+        HOSTCALL_NAMES.iter()
+            .position(|&counted| counted == name)
+            .map_or(0, |call| self.store.data().hostcalls[call])
+    }
+
+    /// Host calls refused this quantum for exceeding the limit
+    pub fn hostcalls_rejected(&self) -> u32 {
+        self.store.data().hostcalls_rejected
+    }
+
+    /// Remaining fuel (None if the module isn't fuel-metered)
+    pub fn remaining_fuel(&mut self) -> Option<u64> {
+        self.store.consume_fuel(0).ok()
+    }
+
+    /// Largest linear memory snapshot_memory will copy (16 pages)
+    pub const MAX_SNAPSHOT_BYTES: usize = 16 * 64 * 1024;
+
+    /// Copy the guest's linear memory so it can be rolled back later
+    ///
+    /// Fails if the module exports no memory, the memory is larger than
+    /// MAX_SNAPSHOT_BYTES, or the copy can't be allocated.
+    pub fn snapshot_memory(&self) -> Result<Vec<u8>, &'static str> {
+        let memory = self.memory().ok_or("No memory export")?;
+        let data = memory.data(&self.store);
+        if data.len() > Self::MAX_SNAPSHOT_BYTES {
+            return Err("Memory exceeds snapshot limit");
+        }
+        try_copy(data).ok_or("Out of memory")
+    }
+
+    /// Write a snapshot from `snapshot_memory` back into linear memory
+    ///
+    /// Memory can't shrink, so pages grown since the snapshot are zeroed
+    /// (their contents when freshly grown).
+    pub fn restore_memory(&mut self, snapshot: &[u8]) -> Result<(), &'static str> {
+        let memory = self.memory().ok_or("No memory export")?;
+        let data = memory.data_mut(&mut self.store);
+        if snapshot.len() > data.len() {
+            return Err("Snapshot larger than memory");
+        }
+        let (restored, grown) = data.split_at_mut(snapshot.len());
+        restored.copy_from_slice(snapshot);
+        grown.fill(0);
+        Ok(())
+    }
+
+    /// Read an exported global (None if there's no global `name`)
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let global = self.instance.get_global(&self.store, name)?;
+        Some(global.get(&self.store))
+    }
+
+    /// Write an exported global
+    ///
+    /// Fails if there's no global `name`, it is immutable, or `value`
+    /// has the wrong type.
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), &'static str> {
+        let global = self.instance.get_global(&self.store, name).ok_or("Global not found")?;
+        if !global.ty(&self.store).mutability().is_mut() {
+            return Err("Global is immutable");
+        }
+        global.set(&mut self.store, value).map_err(|_| "Global type mismatch")
+    }
+
+    fn memory(&self) -> Option<Memory> {
+        match self.instance.get_export(&self.store, "memory") {
+            Some(Extern::Memory(mem)) => Some(mem),
+            _ => None,
+        }
+    }
+
     /// Get capabilities count
     pub fn capability_count(&self) -> usize {
         self.store.data().capabilities.len()
@@ -448,14 +2053,73 @@ impl WasmModule {
 
 /// Initialize the Wasm runtime
 pub fn init() {
+    RANDOM_STATE.store(crate::benchmark::read_cycles() | 1, Ordering::Relaxed);
     serial_println!("[WASM] Runtime initialized (wasmi interpreter)");
 }
 
 /// Load and validate a WASM module from bytes
-pub fn load_and_validate(wasm_bytes: &[u8]) -> Result<WasmModule, Error> {
+pub fn load_and_validate(wasm_bytes: &[u8]) -> Result<WasmModule, LoadError> {
     WasmModule::from_bytes(wasm_bytes)
 }
 
+/// Take a module a guest loaded with sys_load_module
+///
+/// Each handle can be taken once. Returns None for an unknown handle.
+pub fn take_loaded_module(handle: u32) -> Option<WasmModule> {
+    LOADED_MODULES.lock().remove(&handle)
+}
+
+/// Grant a module a copy of a capability held in a CSpace
+///
+/// Bridges the syscall layer (capabilities live in a task's CSpace,
+/// addressed by id) and the Wasm layer (guests hold full objects).
+pub fn grant_from_cspace(module: &mut WasmModule, cspace: &CSpace, cap_id: CapabilityId) -> Result<(), &'static str> {
+    let capability = cspace.get(cap_id).ok_or("Capability not found")?;
+    module.grant_capability(capability.clone());
+    Ok(())
+}
+
+/// Copy `len` bytes from one guest's memory into another's
+///
+/// Kernel-mediated transfer for pipelines of guest modules: reads
+/// `src[src_off..src_off + len]` and writes it at `dst_off` in `dst`.
+///
+/// # Security
+/// - `src` must hold a READ capability and `dst` a WRITE capability on
+///   the same resource; otherwise nothing is copied
+/// - Both ranges are bounds-checked (overflow-safe) against the guests'
+///   current memory before any byte moves
+pub fn pipe(
+    src: &mut WasmModule,
+    src_off: usize,
+    len: usize,
+    dst: &mut WasmModule,
+    dst_off: usize,
+) -> Result<(), &'static str> {
+    let src_caps = &src.store.data().capabilities;
+    let dst_caps = &dst.store.data().capabilities;
+    let linked = src_caps.iter().filter(|cap| cap.rights().read).any(|src_cap| {
+        dst_caps.iter().any(|dst_cap| dst_cap.rights().write && dst_cap.refers_same_resource(src_cap))
+    });
+    if !linked {
+        serial_println!("[PIPE-DENIED] No shared resource readable by source and writable by destination");
+        return Err("No shared capability");
+    }
+
+    let src_mem = src.memory().ok_or("Source has no memory export")?;
+    let dst_mem = dst.memory().ok_or("Destination has no memory export")?;
+
+    let src_data = src_mem.data(&src.store);
+    let src_end = src_off.checked_add(len).filter(|&end| end <= src_data.len())
+        .ok_or("Source range out of bounds")?;
+    let dst_data = dst_mem.data_mut(&mut dst.store);
+    let dst_end = dst_off.checked_add(len).filter(|&end| end <= dst_data.len())
+        .ok_or("Destination range out of bounds")?;
+
+    dst_data[dst_off..dst_end].copy_from_slice(&src_data[src_off..src_end]);
+    Ok(())
+}
+
 /// Deliver pending IPC messages to a subscriber module
 /// Returns number of messages delivered
 ///
@@ -463,20 +2127,28 @@ pub fn load_and_validate(wasm_bytes: &[u8]) -> Result<WasmModule, Error> {
 /// - Kernel NEVER writes to guest memory at fixed addresses
 /// - Guest must export `allocate_message_buffer(size) -> ptr` to provide buffer
 /// - If guest doesn't export this function, messages are not delivered (safe default)
+/// - Zero-length messages never reach the allocator; the guest gets a null pointer
+///
+/// # Delivery guarantees
+/// - QoS 0 subscribers: a message whose delivery fails is dropped
+/// - QoS 1+ subscribers: failed messages are requeued for the next call
 ///
 /// # Assumptions
 /// - TRUST: Guest code is untrusted
 /// - Guest controls its own memory layout
 pub fn deliver_pending_messages(subscriber: &mut WasmModule, client_id: u32) -> usize {
     let mut delivered = 0;
+    let requeue_failed = subscriber_qos(client_id) >= 1;
+    // Held back until the loop ends so a failing message isn't retried forever
+    let mut failed: Vec<IpcMessage> = Vec::new();
 
     // Drain all messages for this client from the queue
     loop {
         let msg_opt = {
-            let mut queue = IPC_MESSAGE_QUEUE.lock();
+            let mut queue = lock_ipc_queue();
             // Find first message for this client
             if let Some(pos) = queue.iter().position(|m| m.dest_client_id == client_id) {
-                queue.remove(pos)
+                take_ipc_message(&mut queue, pos)
             } else {
                 None
             }
@@ -487,55 +2159,71 @@ pub fn deliver_pending_messages(subscriber: &mut WasmModule, client_id: u32) ->
                 let msg_len = ipc_msg.message.len().min(MAX_IPC_MESSAGE_SIZE);
 
                 // === SECURITY: Request buffer from guest (never use fixed address) ===
-                // Guest must export allocate_message_buffer(size) -> ptr
-                let buffer_ptr = match subscriber.call_function(
-                    "allocate_message_buffer",
-                    &[Value::I32(msg_len as i32)]
-                ) {
-                    Ok(Some(Value::I32(ptr))) if ptr > 0 => ptr,
-                    Ok(Some(Value::I32(ptr))) => {
-                        // Guest returned null/invalid pointer - skip this message
-                        serial_println!("[IPC] Guest returned invalid buffer ptr: {}", ptr);
-                        continue;
-                    }
-                    Ok(_) => {
-                        // Wrong return type
-                        serial_println!("[IPC] allocate_message_buffer returned unexpected type");
-                        continue;
-                    }
-                    Err(_) => {
-                        // Function doesn't exist or failed - safe default is to skip
-                        serial_println!("[IPC] Guest doesn't export allocate_message_buffer - skipping delivery");
-                        // Re-queue the message so it's not lost
-                        let mut queue = IPC_MESSAGE_QUEUE.lock();
-                        queue.push_front(ipc_msg);
-                        break; // Stop trying for this subscriber
+                // Guest must export allocate_message_buffer(size) -> ptr.
+                // Empty messages (valid notifications) skip the allocator
+                // and are delivered as subscriber_receive(0, 0).
+                let buffer_ptr = if msg_len == 0 {
+                    0
+                } else {
+                    match subscriber.call_function(
+                        "allocate_message_buffer",
+                        &[Value::I32(msg_len as i32)]
+                    ) {
+                        Ok(Some(Value::I32(ptr))) if ptr > 0 => ptr,
+                        Ok(Some(Value::I32(ptr))) => {
+                            // Guest returned null/invalid pointer - skip this message
+                            serial_println!("[IPC] Guest returned invalid buffer ptr: {}", ptr);
+                            if requeue_failed {
+                                failed.push(ipc_msg);
+                            }
+                            continue;
+                        }
+                        Ok(_) => {
+                            // Wrong return type
+                            serial_println!("[IPC] allocate_message_buffer returned unexpected type");
+                            if requeue_failed {
+                                failed.push(ipc_msg);
+                            }
+                            continue;
+                        }
+                        Err(_) => {
+                            // Function doesn't exist or failed - safe default is to skip
+                            serial_println!("[IPC] Guest doesn't export allocate_message_buffer - skipping delivery");
+                            // Re-queue the message so it's not lost
+                            requeue_ipc_message(&mut IPC_MESSAGE_QUEUE.lock(), ipc_msg);
+                            break; // Stop trying for this subscriber
+                        }
                     }
                 };
 
-                // Get subscriber's memory
-                let memory = match subscriber.instance.get_export(&mut subscriber.store, "memory") {
-                    Some(Extern::Memory(mem)) => mem,
-                    _ => {
-                        serial_println!("[IPC] Subscriber has no memory export");
-                        break;
-                    }
-                };
-
-                // Write message to guest-provided buffer
-                {
-                    let data = memory.data_mut(&mut subscriber.store);
-                    let buffer_start = buffer_ptr as usize;
-
-                    // Bounds check on guest-provided pointer
-                    if buffer_start.saturating_add(msg_len) > data.len() {
-                        serial_println!("[IPC] Guest buffer out of bounds: ptr={}, len={}, mem_size={}",
-                            buffer_start, msg_len, data.len());
-                        continue;
+                if msg_len > 0 {
+                    // Get subscriber's memory
+                    let memory = match subscriber.instance.get_export(&mut subscriber.store, "memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            serial_println!("[IPC] Subscriber has no memory export");
+                            break;
+                        }
+                    };
+
+                    // Write message to guest-provided buffer
+                    {
+                        let data = memory.data_mut(&mut subscriber.store);
+                        let buffer_start = buffer_ptr as usize;
+
+                        // Bounds check on guest-provided pointer
+                        if buffer_start.saturating_add(msg_len) > data.len() {
+                            serial_println!("[IPC] Guest buffer out of bounds: ptr={}, len={}, mem_size={}",
+                                buffer_start, msg_len, data.len());
+                            if requeue_failed {
+                                failed.push(ipc_msg);
+                            }
+                            continue;
+                        }
+
+                        data[buffer_start..buffer_start + msg_len]
+                            .copy_from_slice(&ipc_msg.message[..msg_len]);
                     }
-
-                    data[buffer_start..buffer_start + msg_len]
-                        .copy_from_slice(&ipc_msg.message[..msg_len]);
                 }
 
                 // Call subscriber_receive(msg_ptr, msg_len)
@@ -551,6 +2239,9 @@ pub fn deliver_pending_messages(subscriber: &mut WasmModule, client_id: u32) ->
                     Err(e) => {
                         serial_print!("[IPC] Failed to deliver message: ");
                         serial_println!("{}", e);
+                        if requeue_failed {
+                            failed.push(ipc_msg);
+                        }
                     }
                 }
             }
@@ -558,17 +2249,1613 @@ pub fn deliver_pending_messages(subscriber: &mut WasmModule, client_id: u32) ->
         }
     }
 
+    // QoS 1+: put failed messages back at the front, in their original order
+    if !failed.is_empty() {
+        serial_println!("[IPC] Requeued {} undelivered message(s) for client {}",
+            failed.len(), client_id);
+        let mut queue = IPC_MESSAGE_QUEUE.lock();
+        for msg in failed.into_iter().rev() {
+            requeue_ipc_message(&mut queue, msg);
+        }
+    }
+
     delivered
 }
 
 /// Get count of pending messages for a client
 pub fn pending_message_count(client_id: u32) -> usize {
-    let queue = IPC_MESSAGE_QUEUE.lock();
+    let queue = lock_ipc_queue();
     queue.iter().filter(|m| m.dest_client_id == client_id).count()
 }
 
 /// Clear all pending messages (for cleanup)
 pub fn clear_ipc_queue() {
-    let mut queue = IPC_MESSAGE_QUEUE.lock();
+    let mut queue = lock_ipc_queue();
+    IPC_QUEUED.fetch_sub(queue.len(), Ordering::AcqRel);
     queue.clear();
 }
+
+/// Reset the MQTT broker: drop all subscriptions and pending messages
+///
+/// Lets demos and tests start from a clean broker. Per-client KV stores
+/// are left alone - they are meant to survive module reloads.
+pub fn reset_broker() {
+    MQTT_SUBSCRIBERS.lock().clear();
+    clear_ipc_queue();
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::scheduler::SCHEDULER;
+    use crate::task::{Priority, Privilege, Task, TaskId};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    /// Queue a message the way a sender would
+    fn queue_message(msg: IpcMessage) {
+        assert!(reserve_ipc_slot(), "IPC queue full");
+        push_reserved(msg);
+    }
+
+    /// Call `func`, which must return an i32 (a status, count or length)
+    fn call_i32(module: &mut WasmModule, func: &str, args: &[Value]) -> i32 {
+        match module.call_function(func, args) {
+            Ok(Some(Value::I32(ret))) => ret,
+            _ => panic!("{} did not return an i32", func),
+        }
+    }
+
+    /// Kernel task a test started, taken out of the scheduler on drop
+    ///
+    /// Test tasks park in a yield loop once done, so they must not be
+    /// left behind to run alongside later tests.
+    pub(crate) struct TestTask(TaskId);
+
+    impl TestTask {
+        /// Start `entry` as a Normal priority kernel task
+        pub(crate) fn spawn(name: &'static str, entry: fn() -> !) -> TestTask {
+            let task = Task::try_new(name, entry, Priority::Normal, Privilege::Kernel).expect("out of memory creating task");
+            TestTask::start(task)
+        }
+
+        /// Add a task the test built itself (e.g. with capabilities)
+        pub(crate) fn start(task: Task) -> TestTask {
+            let id = without_interrupts(|| SCHEDULER.lock().as_mut().map(|s| s.add_task(task)))
+                .expect("scheduler not initialized")
+                .expect("task limit reached");
+            TestTask(id)
+        }
+
+        pub(crate) fn id(&self) -> TaskId {
+            self.0
+        }
+
+        /// Look at the task while it's still in the scheduler
+        pub(crate) fn with<R>(&self, f: impl FnOnce(&Task) -> R) -> R {
+            without_interrupts(|| {
+                let scheduler = SCHEDULER.lock();
+                f(scheduler.as_ref().and_then(|s| s.get_task(self.0)).expect("test task gone"))
+            })
+        }
+
+        /// Take the task out of the scheduler now, for a closer look
+        pub(crate) fn remove(self) -> Task {
+            let id = self.0;
+            ::core::mem::forget(self);
+            without_interrupts(|| SCHEDULER.lock().as_mut().and_then(|s| s.remove_task(id)))
+                .expect("test task gone")
+        }
+    }
+
+    impl Drop for TestTask {
+        fn drop(&mut self) {
+            let removed = without_interrupts(|| SCHEDULER.lock().as_mut().and_then(|s| s.remove_task(self.0)));
+            drop(removed);
+        }
+    }
+
+    /// Yield until `done` holds or `ticks` timer ticks pass; returns `done()`
+    pub(crate) fn yield_until(ticks: u64, mut done: impl FnMut() -> bool) -> bool {
+        let deadline = crate::interrupts::timer_ticks() + ticks;
+        while !done() && crate::interrupts::timer_ticks() < deadline {
+            crate::scheduler::task_yield();
+        }
+        done()
+    }
+
+    /// (module
+    ///   (func (export "id_f64") (param f64) (result f64) local.get 0)
+    ///   (func (export "big_i64") (result i64) i64.const 0x1_0000_0002))
+    const WASM_WIDE_TYPES: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60,
+        0x01, 0x7c, 0x01, 0x7c, 0x60, 0x00, 0x01, 0x7e, 0x03, 0x03, 0x02, 0x00,
+        0x01, 0x07, 0x14, 0x02, 0x06, 0x69, 0x64, 0x5f, 0x66, 0x36, 0x34, 0x00,
+        0x00, 0x07, 0x62, 0x69, 0x67, 0x5f, 0x69, 0x36, 0x34, 0x00, 0x01, 0x0a,
+        0x0f, 0x02, 0x04, 0x00, 0x20, 0x00, 0x0b, 0x08, 0x00, 0x42, 0x82, 0x80,
+        0x80, 0x80, 0x10, 0x0b,
+    ];
+
+    #[test_case]
+    fn test_call_function_wide_types() {
+        serial_print!("test_call_function_wide_types...");
+        let mut module = WasmModule::from_bytes(WASM_WIDE_TYPES).expect("load failed");
+
+        let arg = Value::F64(2.5f64.into());
+        match module.call_function("id_f64", &[arg]) {
+            Ok(Some(Value::F64(v))) => assert_eq!(v.to_float(), 2.5),
+            _ => panic!("id_f64 did not return an f64"),
+        }
+
+        match module.call_function("big_i64", &[]) {
+            Ok(Some(Value::I64(v))) => assert_eq!(v, 0x1_0000_0002),
+            _ => panic!("big_i64 did not return an i64"),
+        }
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_ipc_recv" (func $recv (param i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (func (export "pull") (result i32) i32.const 64 i32.const 128 call $recv))
+    const WASM_IPC_RECV: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0b, 0x02, 0x60,
+        0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x14, 0x01,
+        0x03, 0x65, 0x6e, 0x76, 0x0c, 0x73, 0x79, 0x73, 0x5f, 0x69, 0x70, 0x63,
+        0x5f, 0x72, 0x65, 0x63, 0x76, 0x00, 0x00, 0x03, 0x02, 0x01, 0x01, 0x05,
+        0x03, 0x01, 0x00, 0x01, 0x07, 0x11, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f,
+        0x72, 0x79, 0x02, 0x00, 0x04, 0x70, 0x75, 0x6c, 0x6c, 0x00, 0x01, 0x0a,
+        0x0c, 0x01, 0x0a, 0x00, 0x41, 0xc0, 0x00, 0x41, 0x80, 0x01, 0x10, 0x00,
+        0x0b, 0x00, 0x0e, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07, 0x01, 0x00,
+        0x04, 0x72, 0x65, 0x63, 0x76,
+    ];
+
+    #[test_case]
+    fn test_ipc_recv_pulls_into_guest_memory() {
+        serial_print!("test_ipc_recv_pulls_into_guest_memory...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_IPC_RECV).expect("load failed");
+        module.set_client_id(42);
+
+        queue_message(IpcMessage {
+            dest_client_id: 42,
+            message: b"hello".to_vec(),
+        });
+
+        match module.call_function("pull", &[]) {
+            Ok(Some(Value::I32(len))) => assert_eq!(len, 5),
+            _ => panic!("pull did not return a length"),
+        }
+
+        let memory = match module.instance.get_export(&module.store, "memory") {
+            Some(Extern::Memory(mem)) => mem,
+            _ => panic!("no memory export"),
+        };
+        assert_eq!(&memory.data(&module.store)[64..69], b"hello");
+        assert_eq!(pending_message_count(42), 0);
+
+        // queue now empty for this client
+        match module.call_function("pull", &[]) {
+            Ok(Some(Value::I32(len))) => assert_eq!(len, 0),
+            _ => panic!("pull did not return a length"),
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_memory_snapshot_restore() {
+        serial_print!("test_memory_snapshot_restore...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_IPC_RECV).expect("load failed");
+        module.set_client_id(42);
+
+        let snapshot = module.snapshot_memory().expect("snapshot failed");
+        assert_eq!(&snapshot[64..69], &[0; 5]);
+
+        // host call writes into guest memory
+        queue_message(IpcMessage {
+            dest_client_id: 42,
+            message: b"hello".to_vec(),
+        });
+        module.call_function("pull", &[]).expect("pull failed");
+        let mutated = module.snapshot_memory().expect("snapshot failed");
+        assert_eq!(&mutated[64..69], b"hello");
+
+        module.restore_memory(&snapshot).expect("restore failed");
+        assert!(module.snapshot_memory().expect("snapshot failed") == snapshot);
+
+        let oversized = alloc::vec![0u8; snapshot.len() + 1];
+        assert!(module.restore_memory(&oversized).is_err());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_grant_from_cspace() {
+        use crate::capability::Rights;
+        use crate::syscall::SyscallContext;
+
+        serial_print!("test_grant_from_cspace...");
+        let mut ctx = SyscallContext::new();
+        let cap_id = ctx.cspace.create(ResourceType::Memory, 0x1000, Rights::READ);
+        let mut module = WasmModule::from_bytes(WASM_IPC_RECV).expect("load failed");
+
+        assert!(grant_from_cspace(&mut module, &ctx.cspace, cap_id).is_ok());
+        assert_eq!(module.capability_count(), 1);
+
+        // unknown id: nothing granted
+        assert!(grant_from_cspace(&mut module, &ctx.cspace, CapabilityId::new(9999)).is_err());
+        assert_eq!(module.capability_count(), 1);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (memory (export "heap") 1)
+    ///   (func (export "f") (result i32) i32.const 0))
+    const WASM_MISNAMED_MEMORY: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60,
+        0x00, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01,
+        0x07, 0x0c, 0x02, 0x04, 0x68, 0x65, 0x61, 0x70, 0x02, 0x00, 0x01, 0x66,
+        0x00, 0x00, 0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x00, 0x0b,
+    ];
+
+    #[test_case]
+    fn test_rejects_misnamed_memory() {
+        serial_print!("test_rejects_misnamed_memory...");
+        match WasmModule::from_bytes(WASM_MISNAMED_MEMORY) {
+            Err(LoadError::UnsupportedMemory(reason)) => assert!(reason.contains("memory")),
+            Err(e) => panic!("wrong error: {:?}", e),
+            Ok(_) => panic!("module with misnamed memory loaded"),
+        }
+        // modules without any memory are still fine
+        assert!(WasmModule::from_bytes(WASM_WIDE_TYPES).is_ok());
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (func $init unreachable)
+    ///   (start $init)
+    ///   (func (export "f") (result i32) i32.const 0))
+    const WASM_START_TRAP: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60,
+        0x00, 0x00, 0x60, 0x00, 0x01, 0x7f, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07,
+        0x05, 0x01, 0x01, 0x66, 0x00, 0x01, 0x08, 0x01, 0x00, 0x0a, 0x0a, 0x02,
+        0x03, 0x00, 0x00, 0x0b, 0x04, 0x00, 0x41, 0x00, 0x0b, 0x00, 0x0e, 0x04,
+        0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x69, 0x6e, 0x69,
+        0x74,
+    ];
+
+    /// (module (import "env" "missing" (func)))
+    const WASM_UNKNOWN_IMPORT: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60,
+        0x00, 0x00, 0x02, 0x0f, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x07, 0x6d, 0x69,
+        0x73, 0x73, 0x69, 0x6e, 0x67, 0x00, 0x00,
+    ];
+
+    #[test_case]
+    fn test_start_trap_reported_separately() {
+        serial_print!("test_start_trap_reported_separately...");
+        match WasmModule::from_bytes(WASM_START_TRAP) {
+            Err(LoadError::StartTrapped(_)) => {}
+            Err(e) => panic!("wrong error: {:?}", e),
+            Ok(_) => panic!("module with trapping start function loaded"),
+        }
+        // A link failure is still a plain Wasm error
+        assert!(matches!(WasmModule::from_bytes(WASM_UNKNOWN_IMPORT), Err(LoadError::Wasm(_))));
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_mqtt_subscribe_qos" (func $sub (param i32 i32 i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "test")
+    ///   (global $failed (mut i32) (i32.const 0))
+    ///   (func (export "subscribe") (param i32) (result i32)
+    ///     i32.const 77 i32.const 0 i32.const 4 local.get 0 call $sub)
+    ///   (func (export "allocate_message_buffer") (param i32) (result i32) i32.const 64)
+    ///   ;; traps on the first delivery, succeeds afterwards
+    ///   (func (export "subscriber_receive") (param i32 i32)
+    ///     global.get $failed i32.eqz
+    ///     if i32.const 1 global.set $failed unreachable end))
+    const WASM_QOS_SUBSCRIBER: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x13, 0x03, 0x60,
+        0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+        0x60, 0x02, 0x7f, 0x7f, 0x00, 0x02, 0x1e, 0x01, 0x03, 0x65, 0x6e, 0x76,
+        0x16, 0x73, 0x79, 0x73, 0x5f, 0x6d, 0x71, 0x74, 0x74, 0x5f, 0x73, 0x75,
+        0x62, 0x73, 0x63, 0x72, 0x69, 0x62, 0x65, 0x5f, 0x71, 0x6f, 0x73, 0x00,
+        0x00, 0x03, 0x04, 0x03, 0x01, 0x01, 0x02, 0x05, 0x03, 0x01, 0x00, 0x01,
+        0x06, 0x06, 0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b, 0x07, 0x45, 0x04, 0x06,
+        0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x09, 0x73, 0x75, 0x62,
+        0x73, 0x63, 0x72, 0x69, 0x62, 0x65, 0x00, 0x01, 0x17, 0x61, 0x6c, 0x6c,
+        0x6f, 0x63, 0x61, 0x74, 0x65, 0x5f, 0x6d, 0x65, 0x73, 0x73, 0x61, 0x67,
+        0x65, 0x5f, 0x62, 0x75, 0x66, 0x66, 0x65, 0x72, 0x00, 0x02, 0x12, 0x73,
+        0x75, 0x62, 0x73, 0x63, 0x72, 0x69, 0x62, 0x65, 0x72, 0x5f, 0x72, 0x65,
+        0x63, 0x65, 0x69, 0x76, 0x65, 0x00, 0x03, 0x0a, 0x23, 0x03, 0x0d, 0x00,
+        0x41, 0xcd, 0x00, 0x41, 0x00, 0x41, 0x04, 0x20, 0x00, 0x10, 0x00, 0x0b,
+        0x05, 0x00, 0x41, 0xc0, 0x00, 0x0b, 0x0d, 0x00, 0x23, 0x00, 0x45, 0x04,
+        0x40, 0x41, 0x01, 0x24, 0x00, 0x00, 0x0b, 0x0b, 0x0b, 0x0a, 0x01, 0x00,
+        0x41, 0x00, 0x0b, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x18, 0x04, 0x6e,
+        0x61, 0x6d, 0x65, 0x01, 0x06, 0x01, 0x00, 0x03, 0x73, 0x75, 0x62, 0x07,
+        0x09, 0x01, 0x00, 0x06, 0x66, 0x61, 0x69, 0x6c, 0x65, 0x64,
+    ];
+
+    #[test_case]
+    fn test_qos1_requeues_failed_delivery() {
+        serial_print!("test_qos1_requeues_failed_delivery...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_QOS_SUBSCRIBER).expect("load failed");
+
+        assert_eq!(call_i32(&mut module, "subscribe", &[Value::I32(3)]), -6);
+        assert_eq!(call_i32(&mut module, "subscribe", &[Value::I32(1)]), 0);
+        assert_eq!(subscriber_qos(77), 1);
+
+        queue_message(IpcMessage {
+            dest_client_id: 77,
+            message: b"qos1".to_vec(),
+        });
+
+        // First delivery traps in the guest - message must stay queued
+        assert_eq!(deliver_pending_messages(&mut module, 77), 0);
+        assert_eq!(pending_message_count(77), 1);
+
+        // Retry succeeds
+        assert_eq!(deliver_pending_messages(&mut module, 77), 1);
+        assert_eq!(pending_message_count(77), 0);
+
+        MQTT_SUBSCRIBERS.lock().retain(|sub| sub.client_id != 77);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_host_calls_return_retry_when_locked() {
+        serial_print!("test_host_calls_return_retry_when_locked...");
+        clear_ipc_queue();
+        let mut receiver = WasmModule::from_bytes(WASM_IPC_RECV).expect("load failed");
+        receiver.set_client_id(42);
+        let mut subscriber = WasmModule::from_bytes(WASM_QOS_SUBSCRIBER).expect("load failed");
+
+        // Locks held elsewhere: calls come back with -5 instead of spinning forever
+        {
+            let _queue = IPC_MESSAGE_QUEUE.lock();
+            match receiver.call_function("pull", &[]) {
+                Ok(Some(Value::I32(ret))) => assert_eq!(ret, -5),
+                _ => panic!("pull did not return a status"),
+            }
+        }
+        {
+            let _subs = MQTT_SUBSCRIBERS.lock();
+            match subscriber.call_function("subscribe", &[Value::I32(0)]) {
+                Ok(Some(Value::I32(ret))) => assert_eq!(ret, -5),
+                _ => panic!("subscribe did not return a status"),
+            }
+        }
+        assert_eq!(subscriber_qos(77), 0);
+
+        // Uncontended again: the retry goes through
+        match receiver.call_function("pull", &[]) {
+            Ok(Some(Value::I32(ret))) => assert_eq!(ret, 0),
+            _ => panic!("pull did not return a status"),
+        }
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_kv_set" (func $set (param i32 i32 i32 i32) (result i32)))
+    ///   (import "env" "sys_kv_get" (func $get (param i32 i32 i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "counter")
+    ///   (data (i32.const 16) "42")
+    ///   (func (export "save") (result i32)
+    ///     i32.const 0 i32.const 7 i32.const 16 i32.const 2 call $set)
+    ///   (func (export "load") (result i32)
+    ///     i32.const 0 i32.const 7 i32.const 32 i32.const 16 call $get))
+    const WASM_KV: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0d, 0x02, 0x60,
+        0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, 0x02,
+        0x23, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x0a, 0x73, 0x79, 0x73, 0x5f, 0x6b,
+        0x76, 0x5f, 0x73, 0x65, 0x74, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x0a,
+        0x73, 0x79, 0x73, 0x5f, 0x6b, 0x76, 0x5f, 0x67, 0x65, 0x74, 0x00, 0x00,
+        0x03, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x18,
+        0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x04, 0x73,
+        0x61, 0x76, 0x65, 0x00, 0x02, 0x04, 0x6c, 0x6f, 0x61, 0x64, 0x00, 0x03,
+        0x0a, 0x1b, 0x02, 0x0c, 0x00, 0x41, 0x00, 0x41, 0x07, 0x41, 0x10, 0x41,
+        0x02, 0x10, 0x00, 0x0b, 0x0c, 0x00, 0x41, 0x00, 0x41, 0x07, 0x41, 0x20,
+        0x41, 0x10, 0x10, 0x01, 0x0b, 0x0b, 0x14, 0x02, 0x00, 0x41, 0x00, 0x0b,
+        0x07, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x00, 0x41, 0x10, 0x0b,
+        0x02, 0x34, 0x32, 0x00, 0x12, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x0b,
+        0x02, 0x00, 0x03, 0x73, 0x65, 0x74, 0x01, 0x03, 0x67, 0x65, 0x74,
+    ];
+
+    #[test_case]
+    fn test_kv_store_survives_reload() {
+        serial_print!("test_kv_store_survives_reload...");
+        {
+            let mut module = WasmModule::from_bytes(WASM_KV).expect("load failed");
+            module.set_client_id(55);
+            assert_eq!(call_i32(&mut module, "save", &[]), 0);
+        }
+
+        // Fresh instance, fresh linear memory - value must come from the kernel
+        let mut module = WasmModule::from_bytes(WASM_KV).expect("reload failed");
+        module.set_client_id(55);
+        assert_eq!(call_i32(&mut module, "load", &[]), 2);
+
+        let memory = match module.instance.get_export(&module.store, "memory") {
+            Some(Extern::Memory(mem)) => mem,
+            _ => panic!("no memory export"),
+        };
+        assert_eq!(&memory.data(&module.store)[32..34], b"42");
+
+        KV_STORES.lock().remove(&55);
+        serial_println!("[ok]");
+    }
+
+    /// Test that the demo syscall shim returns decodable SyscallError codes
+    #[test_case]
+    fn test_syscall_shim_returns_errno() {
+        serial_print!("test_syscall_shim_returns_errno...");
+        let mut module = WasmModule::from_bytes(include_bytes!("../demos/wasm/03_syscall.wasm")).expect("load failed");
+        let denied = call_i32(&mut module, "test_unauthorized", &[]);
+        assert_eq!(SyscallError::from_errno(denied as i64), Some(SyscallError::PermissionDenied));
+        serial_println!("[ok]");
+    }
+
+    /// Test that host functions outside IPC also leave their failure detail
+    #[test_case]
+    fn test_last_error_covers_kv_calls() {
+        serial_print!("test_last_error_covers_kv_calls...");
+        let mut module = WasmModule::from_bytes(WASM_KV).expect("load failed");
+        let last_error = |module: &WasmModule| module.store.data().last_error;
+
+        // No client id to look the store up under
+        assert_eq!(call_i32(&mut module, "load", &[]), HostError::NoCap.errno());
+        assert_eq!(last_error(&module), HostError::NoCap);
+
+        module.set_client_id(56);
+        KV_STORES.lock().remove(&56);
+        assert_eq!(call_i32(&mut module, "load", &[]), HostError::NotFound.errno());
+        assert_eq!(last_error(&module), HostError::NotFound);
+
+        assert_eq!(call_i32(&mut module, "save", &[]), 0);
+        assert_eq!(last_error(&module), HostError::None);
+        assert_eq!(call_i32(&mut module, "load", &[]), 2);
+
+        KV_STORES.lock().remove(&56);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_request_fuel" (func $fuel (param i32) (result i32)))
+    ///   (func (export "refill") (param i32) (result i32)
+    ///     local.get 0 call $fuel)
+    ///   ;; tops up, then burns well past a 1000 fuel budget
+    ///   (func (export "work") (result i32) (local i32)
+    ///     i32.const 100000 call $fuel drop
+    ///     loop
+    ///       local.get 0 i32.const 1 i32.add local.tee 0
+    ///       i32.const 2000 i32.lt_u br_if 0
+    ///     end
+    ///     local.get 0))
+    const WASM_FUEL: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60,
+        0x01, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x18, 0x01, 0x03,
+        0x65, 0x6e, 0x76, 0x10, 0x73, 0x79, 0x73, 0x5f, 0x72, 0x65, 0x71, 0x75,
+        0x65, 0x73, 0x74, 0x5f, 0x66, 0x75, 0x65, 0x6c, 0x00, 0x00, 0x03, 0x03,
+        0x02, 0x00, 0x01, 0x07, 0x11, 0x02, 0x06, 0x72, 0x65, 0x66, 0x69, 0x6c,
+        0x6c, 0x00, 0x01, 0x04, 0x77, 0x6f, 0x72, 0x6b, 0x00, 0x02, 0x0a, 0x26,
+        0x02, 0x06, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b, 0x1d, 0x01, 0x01, 0x7f,
+        0x41, 0xa0, 0x8d, 0x06, 0x10, 0x00, 0x1a, 0x03, 0x40, 0x20, 0x00, 0x41,
+        0x01, 0x6a, 0x22, 0x00, 0x41, 0xd0, 0x0f, 0x49, 0x0d, 0x00, 0x0b, 0x20,
+        0x00, 0x0b, 0x00, 0x0e, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07, 0x01,
+        0x00, 0x04, 0x66, 0x75, 0x65, 0x6c,
+    ];
+
+    #[test_case]
+    fn test_request_fuel_extends_budget() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_request_fuel_extends_budget...");
+
+        // Without a Fuel capability the refill is refused and work runs dry
+        let mut denied = WasmModule::from_bytes_with_fuel(WASM_FUEL, 1000).expect("load failed");
+        assert_eq!(call_i32(&mut denied, "refill", &[Value::I32(10)]), -1);
+        assert!(denied.call_function("work", &[]).is_err());
+
+        // Authorized guest tops up mid-execution and finishes
+        let mut module = WasmModule::from_bytes_with_fuel(WASM_FUEL, 1000).expect("load failed");
+        module.grant_capability(Capability::new(
+            CapabilityId::new(1),
+            ResourceType::Fuel,
+            100_000,
+            Rights::READ_WRITE,
+        ));
+        assert_eq!(call_i32(&mut module, "work", &[]), 2000);
+        assert!(module.remaining_fuel().unwrap() < 100_000);
+
+        // Over the per-request limit
+        assert_eq!(call_i32(&mut module, "refill", &[Value::I32(200_000)]), -2);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_fuel_used" (func $used (result i64)))
+    ///   (func (export "used") (result i64) call $used)
+    ///   ;; fuel consumed by a loop of `param 0` iterations
+    ///   (func (export "measure") (param i32) (result i64) (local i64 i32)
+    ///     call $used local.set 1
+    ///     loop
+    ///       local.get 2 i32.const 1 i32.add local.tee 2
+    ///       local.get 0 i32.lt_u br_if 0
+    ///     end
+    ///     call $used local.get 1 i64.sub))
+    const WASM_FUEL_USED: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60,
+        0x00, 0x01, 0x7e, 0x60, 0x01, 0x7f, 0x01, 0x7e, 0x02, 0x15, 0x01, 0x03,
+        0x65, 0x6e, 0x76, 0x0d, 0x73, 0x79, 0x73, 0x5f, 0x66, 0x75, 0x65, 0x6c,
+        0x5f, 0x75, 0x73, 0x65, 0x64, 0x00, 0x00, 0x03, 0x03, 0x02, 0x00, 0x01,
+        0x07, 0x12, 0x02, 0x04, 0x75, 0x73, 0x65, 0x64, 0x00, 0x01, 0x07, 0x6d,
+        0x65, 0x61, 0x73, 0x75, 0x72, 0x65, 0x00, 0x02, 0x0a, 0x25, 0x02, 0x04,
+        0x00, 0x10, 0x00, 0x0b, 0x1e, 0x02, 0x01, 0x7e, 0x01, 0x7f, 0x10, 0x00,
+        0x21, 0x01, 0x03, 0x40, 0x20, 0x02, 0x41, 0x01, 0x6a, 0x22, 0x02, 0x20,
+        0x00, 0x49, 0x0d, 0x00, 0x0b, 0x10, 0x00, 0x20, 0x01, 0x7d, 0x0b, 0x00,
+        0x0e, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x75,
+        0x73, 0x65, 0x64,
+    ];
+
+    #[test_case]
+    fn test_fuel_used_tracks_loop_cost() {
+        serial_print!("test_fuel_used_tracks_loop_cost...");
+        let mut module = WasmModule::from_bytes_with_fuel(WASM_FUEL_USED, 1_000_000).expect("load failed");
+
+        let mut measure = |iterations: i32| match module.call_function("measure", &[Value::I32(iterations)]) {
+            Ok(Some(Value::I64(used))) => used,
+            _ => panic!("measure did not return fuel"),
+        };
+        let short = measure(100);
+        let long = measure(1000);
+        assert!(short > 0);
+        // ~10x the iterations costs ~10x the fuel
+        assert!(long > short * 5 && long < short * 15);
+
+        // Unmetered modules report -1
+        let mut unmetered = WasmModule::from_bytes(WASM_FUEL_USED).expect("load failed");
+        match unmetered.call_function("used", &[]) {
+            Ok(Some(Value::I64(used))) => assert_eq!(used, -1),
+            _ => panic!("measure did not return fuel"),
+        }
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_print" (func $print (param i32 i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "spam")
+    ///   (func (export "spam") (param i32) (local i32)
+    ///     loop
+    ///       i32.const 0 i32.const 4 call $print
+    ///       local.get 1 i32.const 1 i32.add local.tee 1
+    ///       local.get 0 i32.lt_u br_if 0
+    ///     end))
+    const WASM_PRINT_SPAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60,
+        0x02, 0x7f, 0x7f, 0x00, 0x60, 0x01, 0x7f, 0x00, 0x02, 0x11, 0x01, 0x03,
+        0x65, 0x6e, 0x76, 0x09, 0x73, 0x79, 0x73, 0x5f, 0x70, 0x72, 0x69, 0x6e,
+        0x74, 0x00, 0x00, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01,
+        0x07, 0x11, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        0x04, 0x73, 0x70, 0x61, 0x6d, 0x00, 0x01, 0x0a, 0x1b, 0x01, 0x19, 0x01,
+        0x01, 0x7f, 0x03, 0x40, 0x41, 0x00, 0x41, 0x04, 0x10, 0x00, 0x20, 0x01,
+        0x41, 0x01, 0x6a, 0x22, 0x01, 0x20, 0x00, 0x49, 0x0d, 0x00, 0x0b, 0x0b,
+        0x0b, 0x0a, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x04, 0x73, 0x70, 0x61, 0x6d,
+        0x00, 0x0f, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x08, 0x01, 0x00, 0x05,
+        0x70, 0x72, 0x69, 0x6e, 0x74,
+    ];
+
+    #[test_case]
+    fn test_hostcall_rate_limit() {
+        serial_print!("test_hostcall_rate_limit...");
+        let mut module = WasmModule::from_bytes(WASM_PRINT_SPAM).expect("load failed");
+        module.set_hostcall_limit(5);
+
+        // No task switch in between, so the calls share one quantum
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            module.call_function("spam", &[Value::I32(20)]).expect("spam failed");
+            assert_eq!(module.hostcall_count("sys_print"), 5);
+            assert_eq!(module.hostcalls_rejected(), 15);
+
+            // Calling again doesn't buy a fresh allowance
+            module.call_function("spam", &[Value::I32(3)]).expect("spam failed");
+            assert_eq!(module.hostcall_count("sys_print"), 5);
+            assert_eq!(module.hostcalls_rejected(), 18);
+
+            // Next quantum
+            module.begin_quantum();
+            module.call_function("spam", &[Value::I32(3)]).expect("spam failed");
+            assert_eq!(module.hostcall_count("sys_print"), 3);
+            assert_eq!(module.hostcalls_rejected(), 0);
+        });
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_ipc_send_cap" (func $send (param i32 i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "ping")
+    ///   (func (export "send") (param i32) (result i32)
+    ///     local.get 0 i32.const 0 i32.const 4 call $send))
+    const WASM_SEND_CAP: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0d, 0x02, 0x60,
+        0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x02,
+        0x18, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x10, 0x73, 0x79, 0x73, 0x5f, 0x69,
+        0x70, 0x63, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x5f, 0x63, 0x61, 0x70, 0x00,
+        0x00, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x11,
+        0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x04, 0x73,
+        0x65, 0x6e, 0x64, 0x00, 0x01, 0x0a, 0x0c, 0x01, 0x0a, 0x00, 0x20, 0x00,
+        0x41, 0x00, 0x41, 0x04, 0x10, 0x00, 0x0b, 0x0b, 0x0a, 0x01, 0x00, 0x41,
+        0x00, 0x0b, 0x04, 0x70, 0x69, 0x6e, 0x67, 0x00, 0x0e, 0x04, 0x6e, 0x61,
+        0x6d, 0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x73, 0x65, 0x6e, 0x64,
+    ];
+
+    #[test_case]
+    fn test_ipc_send_by_cap_index() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_ipc_send_by_cap_index...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_SEND_CAP).expect("load failed");
+        for (id, endpoint, rights) in [(1, 501, Rights::READ_WRITE), (2, 502, Rights::READ_WRITE), (3, 503, Rights::READ)] {
+            module.grant_capability(Capability::new(CapabilityId::new(id), ResourceType::Endpoint, endpoint, rights));
+        }
+
+        let mut send = |index: i32| match module.call_function("send", &[Value::I32(index)]) {
+            Ok(Some(Value::I32(ret))) => ret,
+            _ => panic!("send did not return a status"),
+        };
+        assert_eq!(send(1), 0);
+        assert_eq!(send(2), -2);  // read-only endpoint
+        assert_eq!(send(7), -1);  // no such capability
+
+        // Index 1 is endpoint 502, not 501
+        assert_eq!(pending_message_count(502), 1);
+        assert_eq!(pending_message_count(501), 0);
+        clear_ipc_queue();
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_ipc_send" (func $send (param i32 i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (global $allocs (export "allocs") (mut i32) (i32.const 0))
+    ///   (global $received (export "received") (mut i32) (i32.const -1))
+    ///   (func (export "notify") (result i32)
+    ///     i32.const 505 i32.const 0 i32.const 0 call $send)
+    ///   (func (export "allocate_message_buffer") (param i32) (result i32)
+    ///     global.get $allocs i32.const 1 i32.add global.set $allocs
+    ///     i32.const 64)
+    ///   (func (export "subscriber_receive") (param i32 i32)
+    ///     local.get 1 global.set $received))
+    const WASM_EMPTY_MESSAGE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x16, 0x04, 0x60,
+        0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, 0x60, 0x01,
+        0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x00, 0x02, 0x14, 0x01, 0x03,
+        0x65, 0x6e, 0x76, 0x0c, 0x73, 0x79, 0x73, 0x5f, 0x69, 0x70, 0x63, 0x5f,
+        0x73, 0x65, 0x6e, 0x64, 0x00, 0x00, 0x03, 0x04, 0x03, 0x01, 0x02, 0x03,
+        0x05, 0x03, 0x01, 0x00, 0x01, 0x06, 0x0b, 0x02, 0x7f, 0x01, 0x41, 0x00,
+        0x0b, 0x7f, 0x01, 0x41, 0x7f, 0x0b, 0x07, 0x56, 0x06, 0x06, 0x6d, 0x65,
+        0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x61, 0x6c, 0x6c, 0x6f, 0x63,
+        0x73, 0x03, 0x00, 0x08, 0x72, 0x65, 0x63, 0x65, 0x69, 0x76, 0x65, 0x64,
+        0x03, 0x01, 0x06, 0x6e, 0x6f, 0x74, 0x69, 0x66, 0x79, 0x00, 0x01, 0x17,
+        0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x61, 0x74, 0x65, 0x5f, 0x6d, 0x65, 0x73,
+        0x73, 0x61, 0x67, 0x65, 0x5f, 0x62, 0x75, 0x66, 0x66, 0x65, 0x72, 0x00,
+        0x02, 0x12, 0x73, 0x75, 0x62, 0x73, 0x63, 0x72, 0x69, 0x62, 0x65, 0x72,
+        0x5f, 0x72, 0x65, 0x63, 0x65, 0x69, 0x76, 0x65, 0x00, 0x03, 0x0a, 0x21,
+        0x03, 0x0b, 0x00, 0x41, 0xf9, 0x03, 0x41, 0x00, 0x41, 0x00, 0x10, 0x00,
+        0x0b, 0x0c, 0x00, 0x23, 0x00, 0x41, 0x01, 0x6a, 0x24, 0x00, 0x41, 0xc0,
+        0x00, 0x0b, 0x06, 0x00, 0x20, 0x01, 0x24, 0x01, 0x0b, 0x00, 0x23, 0x04,
+        0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x73, 0x65, 0x6e,
+        0x64, 0x07, 0x13, 0x02, 0x00, 0x06, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x73,
+        0x01, 0x08, 0x72, 0x65, 0x63, 0x65, 0x69, 0x76, 0x65, 0x64,
+    ];
+
+    #[test_case]
+    fn test_zero_length_message_round_trip() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_zero_length_message_round_trip...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_EMPTY_MESSAGE).expect("load failed");
+        module.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 505, Rights::READ_WRITE));
+
+        match module.call_function("notify", &[]) {
+            Ok(Some(Value::I32(ret))) => assert_eq!(ret, 0),
+            _ => panic!("notify did not return a status"),
+        }
+        assert_eq!(pending_message_count(505), 1);
+
+        // Delivered as (0, 0) without asking the guest for a buffer
+        assert_eq!(deliver_pending_messages(&mut module, 505), 1);
+        assert!(matches!(module.get_global("allocs"), Some(Value::I32(0))));
+        assert!(matches!(module.get_global("received"), Some(Value::I32(0))));
+        assert_eq!(pending_message_count(505), 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_pipe_copies_between_guests() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_pipe_copies_between_guests...");
+        // Source memory starts with "ping", destination is zeroed
+        let mut src = WasmModule::from_bytes(WASM_SEND_CAP).expect("load failed");
+        let mut dst = WasmModule::from_bytes(WASM_EMPTY_MESSAGE).expect("load failed");
+
+        // No shared resource yet: refused
+        assert!(pipe(&mut src, 0, 4, &mut dst, 100).is_err());
+
+        src.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 506, Rights::READ));
+        dst.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 506, Rights::READ));
+        // Destination can't write the shared resource
+        assert!(pipe(&mut src, 0, 4, &mut dst, 100).is_err());
+
+        dst.grant_capability(Capability::new(CapabilityId::new(2), ResourceType::Endpoint, 506, Rights::READ_WRITE));
+        assert_eq!(pipe(&mut src, 0, 4, &mut dst, 100), Ok(()));
+        assert_eq!(&dst.snapshot_memory().unwrap()[100..104], b"ping");
+
+        // Ranges are checked on both sides
+        let page = 64 * 1024;
+        assert!(pipe(&mut src, page - 2, 4, &mut dst, 0).is_err());
+        assert!(pipe(&mut src, 0, 4, &mut dst, page - 2).is_err());
+        assert!(pipe(&mut src, 0, 4, &mut dst, usize::MAX).is_err());
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_ipc_send" (func $send (param i32 i32 i32) (result i32)))
+    ///   (import "env" "sys_last_error" (func $last_error (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "ping")
+    ///   (func (export "send") (result i32)
+    ///     i32.const 507 i32.const 0 i32.const 4 call $send)
+    ///   (func (export "last_error") (result i32) call $last_error))
+    const WASM_LAST_ERROR: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x02, 0x60,
+        0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x29,
+        0x02, 0x03, 0x65, 0x6e, 0x76, 0x0c, 0x73, 0x79, 0x73, 0x5f, 0x69, 0x70,
+        0x63, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76,
+        0x0e, 0x73, 0x79, 0x73, 0x5f, 0x6c, 0x61, 0x73, 0x74, 0x5f, 0x65, 0x72,
+        0x72, 0x6f, 0x72, 0x00, 0x01, 0x03, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03,
+        0x01, 0x00, 0x01, 0x07, 0x1e, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72,
+        0x79, 0x02, 0x00, 0x04, 0x73, 0x65, 0x6e, 0x64, 0x00, 0x02, 0x0a, 0x6c,
+        0x61, 0x73, 0x74, 0x5f, 0x65, 0x72, 0x72, 0x6f, 0x72, 0x00, 0x03, 0x0a,
+        0x12, 0x02, 0x0b, 0x00, 0x41, 0xfb, 0x03, 0x41, 0x00, 0x41, 0x04, 0x10,
+        0x00, 0x0b, 0x04, 0x00, 0x10, 0x01, 0x0b, 0x0b, 0x0a, 0x01, 0x00, 0x41,
+        0x00, 0x0b, 0x04, 0x70, 0x69, 0x6e, 0x67, 0x00, 0x1a, 0x04, 0x6e, 0x61,
+        0x6d, 0x65, 0x01, 0x13, 0x02, 0x00, 0x04, 0x73, 0x65, 0x6e, 0x64, 0x01,
+        0x0a, 0x6c, 0x61, 0x73, 0x74, 0x5f, 0x65, 0x72, 0x72, 0x6f, 0x72,
+    ];
+
+    #[test_case]
+    fn test_last_error_reports_denial_detail() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_last_error_reports_denial_detail...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_LAST_ERROR).expect("load failed");
+        fn call(module: &mut WasmModule, name: &str) -> i32 {
+            match module.call_function(name, &[]) {
+                Ok(Some(Value::I32(ret))) => ret,
+                _ => panic!("{} did not return a status", name),
+            }
+        }
+        assert_eq!(call(&mut module, "last_error"), HostError::None as i32);
+
+        // No capability at all: -1, detail NO_CAP
+        assert_eq!(call(&mut module, "send"), -1);
+        assert_eq!(call(&mut module, "last_error"), HostError::NoCap as i32);
+
+        // Read-only capability: -2, detail NO_WRITE
+        module.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 507, Rights::READ));
+        assert_eq!(call(&mut module, "send"), -2);
+        assert_eq!(call(&mut module, "last_error"), HostError::NoWrite as i32);
+        assert_eq!(call(&mut module, "last_error"), HostError::NoWrite as i32);
+
+        // A later successful call leaves no stale detail behind
+        let mut writer = WasmModule::from_bytes(WASM_LAST_ERROR).expect("load failed");
+        assert_eq!(call(&mut writer, "send"), -1);
+        writer.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 507, Rights::READ_WRITE));
+        assert_eq!(call(&mut writer, "send"), 0);
+        assert_eq!(call(&mut writer, "last_error"), HostError::None as i32);
+        clear_ipc_queue();
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_mqtt_list_subs" (func $list (param i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (func (export "list") (param i32) (result i32)
+    ///     i32.const 64 local.get 0 call $list))
+    const WASM_LIST_SUBS: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x02, 0x60,
+        0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x02, 0x1a,
+        0x01, 0x03, 0x65, 0x6e, 0x76, 0x12, 0x73, 0x79, 0x73, 0x5f, 0x6d, 0x71,
+        0x74, 0x74, 0x5f, 0x6c, 0x69, 0x73, 0x74, 0x5f, 0x73, 0x75, 0x62, 0x73,
+        0x00, 0x00, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+        0x11, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x04,
+        0x6c, 0x69, 0x73, 0x74, 0x00, 0x01, 0x0a, 0x0b, 0x01, 0x09, 0x00, 0x41,
+        0xc0, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b, 0x00, 0x0e, 0x04, 0x6e, 0x61,
+        0x6d, 0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x6c, 0x69, 0x73, 0x74,
+    ];
+
+    #[test_case]
+    fn test_admin_lists_mqtt_subscriptions() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_admin_lists_mqtt_subscriptions...");
+        // Isolate from subscriptions left by the demos
+        let saved = ::core::mem::take(&mut *MQTT_SUBSCRIBERS.lock());
+        MQTT_SUBSCRIBERS.lock().push(Subscription { client_id: 101, qos: 0 });
+        MQTT_SUBSCRIBERS.lock().push(Subscription { client_id: 102, qos: 1 });
+
+        let mut module = WasmModule::from_bytes(WASM_LIST_SUBS).expect("load failed");
+        let denied = call_i32(&mut module, "list", &[Value::I32(8)]);
+
+        module.grant_capability(Capability::new(
+            CapabilityId::new(1),
+            ResourceType::Admin,
+            0,
+            Rights::READ,
+        ));
+        let listed = call_i32(&mut module, "list", &[Value::I32(8)]);
+
+        let memory = match module.instance.get_export(&module.store, "memory") {
+            Some(Extern::Memory(mem)) => mem,
+            _ => panic!("no memory export"),
+        };
+        let ids = &memory.data(&module.store)[64..72];
+
+        *MQTT_SUBSCRIBERS.lock() = saved;
+
+        assert_eq!(denied, -1);
+        assert_eq!(listed, 2);
+        assert_eq!(&ids[..4], &101u32.to_le_bytes());
+        assert_eq!(&ids[4..], &102u32.to_le_bytes());
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (global (export "config_flag") (mut i32) (i32.const 7))
+    ///   (global (export "version") i32 (i32.const 1))
+    ///   (func (export "read_flag") (result i32) global.get 0))
+    const WASM_GLOBALS: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60,
+        0x00, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x06, 0x0b, 0x02, 0x7f, 0x01,
+        0x41, 0x07, 0x0b, 0x7f, 0x00, 0x41, 0x01, 0x0b, 0x07, 0x25, 0x03, 0x0b,
+        0x63, 0x6f, 0x6e, 0x66, 0x69, 0x67, 0x5f, 0x66, 0x6c, 0x61, 0x67, 0x03,
+        0x00, 0x07, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x03, 0x01, 0x09,
+        0x72, 0x65, 0x61, 0x64, 0x5f, 0x66, 0x6c, 0x61, 0x67, 0x00, 0x00, 0x0a,
+        0x06, 0x01, 0x04, 0x00, 0x23, 0x00, 0x0b,
+    ];
+
+    #[test_case]
+    fn test_global_get_set() {
+        serial_print!("test_global_get_set...");
+        let mut module = WasmModule::from_bytes(WASM_GLOBALS).expect("load failed");
+        assert!(matches!(module.get_global("config_flag"), Some(Value::I32(7))));
+
+        module.set_global("config_flag", Value::I32(42)).expect("set failed");
+        assert!(matches!(module.call_function("read_flag", &[]), Ok(Some(Value::I32(42)))));
+
+        assert_eq!(module.set_global("version", Value::I32(2)), Err("Global is immutable"));
+        assert!(matches!(module.get_global("version"), Some(Value::I32(1))));
+        assert_eq!(module.set_global("config_flag", Value::I64(1)), Err("Global type mismatch"));
+        assert!(module.get_global("missing").is_none());
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_ipc_send" (func $send (param i32 i32 i32) (result i32)))
+    ///   (import "env" "sys_sleep_ticks" (func $sleep (param i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "step")
+    ///   (func (export "pace") (param i32) (result i32)
+    ///     i32.const 504 i32.const 0 i32.const 4 call $send drop
+    ///     local.get 0 call $sleep
+    ///     i32.const 504 i32.const 0 i32.const 4 call $send drop))
+    const WASM_SLEEP: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0d, 0x02, 0x60,
+        0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x02,
+        0x2a, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x0c, 0x73, 0x79, 0x73, 0x5f, 0x69,
+        0x70, 0x63, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x00, 0x00, 0x03, 0x65, 0x6e,
+        0x76, 0x0f, 0x73, 0x79, 0x73, 0x5f, 0x73, 0x6c, 0x65, 0x65, 0x70, 0x5f,
+        0x74, 0x69, 0x63, 0x6b, 0x73, 0x00, 0x01, 0x03, 0x02, 0x01, 0x01, 0x05,
+        0x03, 0x01, 0x00, 0x01, 0x07, 0x11, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f,
+        0x72, 0x79, 0x02, 0x00, 0x04, 0x70, 0x61, 0x63, 0x65, 0x00, 0x02, 0x0a,
+        0x1c, 0x01, 0x1a, 0x00, 0x41, 0xf8, 0x03, 0x41, 0x00, 0x41, 0x04, 0x10,
+        0x00, 0x1a, 0x20, 0x00, 0x10, 0x01, 0x41, 0xf8, 0x03, 0x41, 0x00, 0x41,
+        0x04, 0x10, 0x00, 0x1a, 0x0b, 0x0b, 0x0a, 0x01, 0x00, 0x41, 0x00, 0x0b,
+        0x04, 0x73, 0x74, 0x65, 0x70, 0x00, 0x15, 0x04, 0x6e, 0x61, 0x6d, 0x65,
+        0x01, 0x0e, 0x02, 0x00, 0x04, 0x73, 0x65, 0x6e, 0x64, 0x01, 0x05, 0x73,
+        0x6c, 0x65, 0x65, 0x70,
+    ];
+
+    /// Test that a sleeping guest makes no progress until its deadline
+    #[test_case]
+    fn test_sleep_ticks_parks_guest() {
+        use crate::capability::{CapabilityId, Rights};
+        use crate::interrupts::timer_ticks;
+
+        const SLEEP: u64 = 5;
+
+        serial_print!("test_sleep_ticks_parks_guest...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_SLEEP).expect("load failed");
+        module.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 504, Rights::READ_WRITE));
+
+        // The guest runs on this task: its second send only happens once
+        // sys_sleep_ticks has given the CPU away until start + SLEEP
+        let start = timer_ticks();
+        assert!(module.call_function("pace", &[Value::I32(SLEEP as i32)]).is_ok());
+        assert_eq!(pending_message_count(504), 2);
+        assert!(timer_ticks() >= start + SLEEP);
+        clear_ipc_queue();
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_atomic_load" (func $load (param i32) (result i64)))
+    ///   (import "env" "sys_atomic_cas" (func $cas (param i32 i64 i64) (result i32)))
+    ///   (func (export "bump") (param $id i32) (param $n i32)
+    ///     (local $old i64)
+    ///     (block $done
+    ///       (loop $next
+    ///         (br_if $done (i32.eqz (local.get $n)))
+    ///         (loop $retry
+    ///           (local.set $old (call $load (local.get $id)))
+    ///           (br_if $retry (i32.ne (i32.const 1)
+    ///             (call $cas (local.get $id) (local.get $old) (i64.add (local.get $old) (i64.const 1))))))
+    ///         (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+    ///         (br $next)))))
+    const WASM_ATOMIC_BUMP: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x12, 0x03, 0x60,
+        0x01, 0x7f, 0x01, 0x7e, 0x60, 0x03, 0x7f, 0x7e, 0x7e, 0x01, 0x7f, 0x60,
+        0x02, 0x7f, 0x7f, 0x00, 0x02, 0x2c, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x0f,
+        0x73, 0x79, 0x73, 0x5f, 0x61, 0x74, 0x6f, 0x6d, 0x69, 0x63, 0x5f, 0x6c,
+        0x6f, 0x61, 0x64, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x0e, 0x73, 0x79,
+        0x73, 0x5f, 0x61, 0x74, 0x6f, 0x6d, 0x69, 0x63, 0x5f, 0x63, 0x61, 0x73,
+        0x00, 0x01, 0x03, 0x02, 0x01, 0x02, 0x07, 0x08, 0x01, 0x04, 0x62, 0x75,
+        0x6d, 0x70, 0x00, 0x02, 0x0a, 0x33, 0x01, 0x31, 0x01, 0x01, 0x7e, 0x02,
+        0x40, 0x03, 0x40, 0x20, 0x01, 0x45, 0x0d, 0x01, 0x03, 0x40, 0x20, 0x00,
+        0x10, 0x00, 0x21, 0x02, 0x41, 0x01, 0x20, 0x00, 0x20, 0x02, 0x20, 0x02,
+        0x42, 0x01, 0x7c, 0x10, 0x01, 0x47, 0x0d, 0x00, 0x0b, 0x20, 0x01, 0x41,
+        0x01, 0x6b, 0x21, 0x01, 0x0c, 0x00, 0x0b, 0x0b, 0x0b, 0x00, 0x3c, 0x04,
+        0x6e, 0x61, 0x6d, 0x65, 0x01, 0x0c, 0x02, 0x00, 0x04, 0x6c, 0x6f, 0x61,
+        0x64, 0x01, 0x03, 0x63, 0x61, 0x73, 0x02, 0x0f, 0x01, 0x02, 0x03, 0x00,
+        0x02, 0x69, 0x64, 0x01, 0x01, 0x6e, 0x02, 0x03, 0x6f, 0x6c, 0x64, 0x03,
+        0x16, 0x01, 0x02, 0x03, 0x00, 0x04, 0x64, 0x6f, 0x6e, 0x65, 0x01, 0x04,
+        0x6e, 0x65, 0x78, 0x74, 0x02, 0x05, 0x72, 0x65, 0x74, 0x72, 0x79,
+    ];
+
+    /// Test that two guests incrementing one counter via CAS lose no updates
+    #[test_case]
+    fn test_atomic_cas_counter_no_lost_updates() {
+        use ::core::sync::atomic::AtomicUsize;
+
+        const COUNTER: usize = 3;
+        const PER_GUEST: i32 = 500;
+        static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+        fn guest_main() -> ! {
+            let mut module = WasmModule::from_bytes(WASM_ATOMIC_BUMP).expect("load failed");
+            let _ = module.call_function("bump", &[Value::I32(COUNTER as i32), Value::I32(PER_GUEST)]);
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+            loop {
+                crate::scheduler::task_yield();
+            }
+        }
+
+        serial_print!("test_atomic_cas_counter_no_lost_updates...");
+        ATOMIC_COUNTERS[COUNTER].store(0, Ordering::SeqCst);
+        FINISHED.store(0, Ordering::SeqCst);
+
+        let _guests = [TestTask::spawn("wasm-cas", guest_main), TestTask::spawn("wasm-cas", guest_main)];
+        assert!(yield_until(500, || FINISHED.load(Ordering::SeqCst) == 2));
+        assert_eq!(ATOMIC_COUNTERS[COUNTER].load(Ordering::SeqCst), 2 * PER_GUEST as i64);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_random" (func $random (result i64)))
+    ///   (import "env" "sys_seed" (func $seed (param i64) (result i32)))
+    ///   (func (export "seed") (param i64) (result i32) local.get 0 call $seed)
+    ///   (func (export "next") (result i64) call $random))
+    const WASM_RANDOM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60,
+        0x00, 0x01, 0x7e, 0x60, 0x01, 0x7e, 0x01, 0x7f, 0x02, 0x21, 0x02, 0x03,
+        0x65, 0x6e, 0x76, 0x0a, 0x73, 0x79, 0x73, 0x5f, 0x72, 0x61, 0x6e, 0x64,
+        0x6f, 0x6d, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x08, 0x73, 0x79, 0x73,
+        0x5f, 0x73, 0x65, 0x65, 0x64, 0x00, 0x01, 0x03, 0x03, 0x02, 0x01, 0x00,
+        0x07, 0x0f, 0x02, 0x04, 0x73, 0x65, 0x65, 0x64, 0x00, 0x02, 0x04, 0x6e,
+        0x65, 0x78, 0x74, 0x00, 0x03, 0x0a, 0x0d, 0x02, 0x06, 0x00, 0x20, 0x00,
+        0x10, 0x01, 0x0b, 0x04, 0x00, 0x10, 0x00, 0x0b, 0x00, 0x16, 0x04, 0x6e,
+        0x61, 0x6d, 0x65, 0x01, 0x0f, 0x02, 0x00, 0x06, 0x72, 0x61, 0x6e, 0x64,
+        0x6f, 0x6d, 0x01, 0x04, 0x73, 0x65, 0x65, 0x64,
+    ];
+
+    #[test_case]
+    fn test_random_varies_and_reseeds() {
+        serial_print!("test_random_varies_and_reseeds...");
+        let mut module = WasmModule::from_bytes(WASM_RANDOM).expect("load failed");
+        let mut draw = |seed: i64| -> [i64; 4] {
+            assert!(matches!(module.call_function("seed", &[Value::I64(seed)]), Ok(Some(Value::I32(0)))));
+            ::core::array::from_fn(|_| match module.call_function("next", &[]) {
+                Ok(Some(Value::I64(v))) => v,
+                _ => panic!("sys_random did not return a value"),
+            })
+        };
+
+        let first = draw(42);
+        assert!(first.iter().all(|&v| v >= 0));
+        assert!(first.windows(2).any(|pair| pair[0] != pair[1]));
+        assert_eq!(draw(42), first);
+        assert_ne!(draw(43), first);
+        // 0 is remapped rather than sticking the generator at 0
+        assert!(draw(0).iter().any(|&v| v != 0));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_seed_is_private_to_module() {
+        serial_print!("test_seed_is_private_to_module...");
+        let next = |module: &mut WasmModule| match module.call_function("next", &[]) {
+            Ok(Some(Value::I64(v))) => v,
+            _ => panic!("sys_random did not return a value"),
+        };
+        let mut seeded = WasmModule::from_bytes(WASM_RANDOM).expect("load failed");
+        let mut other = WasmModule::from_bytes(WASM_RANDOM).expect("load failed");
+
+        assert_eq!(call_i32(&mut seeded, "seed", &[Value::I64(42)]), 0);
+        let expected: [i64; 4] = ::core::array::from_fn(|_| next(&mut seeded));
+
+        let shared = RANDOM_STATE.load(Ordering::Relaxed);
+        assert_eq!(call_i32(&mut seeded, "seed", &[Value::I64(42)]), 0);
+        assert_eq!(RANDOM_STATE.load(Ordering::Relaxed), shared);
+        let mut interleaved = [0; 4];
+        for value in interleaved.iter_mut() {
+            // Another guest drawing or reseeding can't steer this sequence
+            next(&mut other);
+            assert_eq!(call_i32(&mut other, "seed", &[Value::I64(42)]), 0);
+            *value = next(&mut seeded);
+        }
+        assert_eq!(interleaved, expected);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_reset_broker_clears_state() {
+        serial_print!("test_reset_broker_clears_state...");
+        let mut module = WasmModule::from_bytes(WASM_QOS_SUBSCRIBER).expect("load failed");
+        match module.call_function("subscribe", &[Value::I32(1)]) {
+            Ok(Some(Value::I32(ret))) => assert_eq!(ret, 0),
+            _ => panic!("subscribe did not return a status"),
+        }
+        queue_message(IpcMessage {
+            dest_client_id: 77,
+            message: b"stale".to_vec(),
+        });
+
+        reset_broker();
+        assert!(MQTT_SUBSCRIBERS.lock().is_empty());
+        assert!(lock_ipc_queue().is_empty());
+        assert_eq!(subscriber_qos(77), 0);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_heap_free" (func $free (result i64)))
+    ///   (memory (export "memory") 1)
+    ///   (func (export "heap_free") (result i64) call $free)
+    ///   (func (export "grow") (param i32) (result i32)
+    ///     local.get 0
+    ///     memory.grow))
+    const WASM_HEAP_FREE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60,
+        0x00, 0x01, 0x7e, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x02, 0x15, 0x01, 0x03,
+        0x65, 0x6e, 0x76, 0x0d, 0x73, 0x79, 0x73, 0x5f, 0x68, 0x65, 0x61, 0x70,
+        0x5f, 0x66, 0x72, 0x65, 0x65, 0x00, 0x00, 0x03, 0x03, 0x02, 0x00, 0x01,
+        0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x1d, 0x03, 0x06, 0x6d, 0x65, 0x6d,
+        0x6f, 0x72, 0x79, 0x02, 0x00, 0x09, 0x68, 0x65, 0x61, 0x70, 0x5f, 0x66,
+        0x72, 0x65, 0x65, 0x00, 0x01, 0x04, 0x67, 0x72, 0x6f, 0x77, 0x00, 0x02,
+        0x0a, 0x0d, 0x02, 0x04, 0x00, 0x10, 0x00, 0x0b, 0x06, 0x00, 0x20, 0x00,
+        0x40, 0x00, 0x0b, 0x00, 0x0e, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07,
+        0x01, 0x00, 0x04, 0x66, 0x72, 0x65, 0x65,
+    ];
+
+    #[test_case]
+    fn test_heap_free_tracks_allocations() {
+        const PAGES: i32 = 2;
+
+        fn heap_free(module: &mut WasmModule) -> i64 {
+            match module.call_function("heap_free", &[]) {
+                Ok(Some(Value::I64(free))) => free,
+                _ => panic!("heap_free did not return a value"),
+            }
+        }
+
+        serial_print!("test_heap_free_tracks_allocations...");
+        let mut module = WasmModule::from_bytes(WASM_HEAP_FREE).expect("load failed");
+
+        // Growing the guest's memory takes the pages from the kernel heap
+        let before = heap_free(&mut module);
+        assert!(before > 0);
+        assert!(matches!(module.call_function("grow", &[Value::I32(PAGES)]), Ok(Some(Value::I32(1)))));
+        let during = heap_free(&mut module);
+        assert!(before - during >= PAGES as i64 * 64 * 1024);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_reset_restores_initial_state() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_reset_restores_initial_state...");
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_IPC_RECV).expect("load failed");
+        module.set_client_id(42);
+        module.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 42, Rights::READ));
+        let initial = module.snapshot_memory().expect("snapshot failed");
+
+        queue_message(IpcMessage {
+            dest_client_id: 42,
+            message: b"hello".to_vec(),
+        });
+        module.call_function("pull", &[]).expect("pull failed");
+        assert_ne!(module.snapshot_memory().expect("snapshot failed"), initial);
+
+        module.reset().expect("reset failed");
+        assert_eq!(module.snapshot_memory().expect("snapshot failed"), initial);
+        assert_eq!(module.capability_count(), 1);
+
+        // Still receives as client 42 after the reset
+        queue_message(IpcMessage {
+            dest_client_id: 42,
+            message: b"again".to_vec(),
+        });
+        assert!(matches!(module.call_function("pull", &[]), Ok(Some(Value::I32(5)))));
+
+        // Globals go back to their initializers too
+        let mut globals = WasmModule::from_bytes(WASM_GLOBALS).expect("load failed");
+        globals.set_global("config_flag", Value::I32(42)).expect("set failed");
+        globals.reset().expect("reset failed");
+        assert!(matches!(globals.get_global("config_flag"), Some(Value::I32(7))));
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_register_service" (func $register (param i32 i32 i32) (result i32)))
+    ///   (import "env" "sys_lookup_service" (func $lookup (param i32 i32) (result i32)))
+    ///   (import "env" "sys_ipc_send" (func $send (param i32 i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "echo")
+    ///   (data (i32.const 16) "hi")
+    ///   (func (export "register") (param i32) (result i32)
+    ///     i32.const 0 i32.const 4 local.get 0 call $register)
+    ///   (func (export "lookup") (result i32)
+    ///     i32.const 0 i32.const 4 call $lookup)
+    ///   (func (export "send") (param i32) (result i32)
+    ///     local.get 0 i32.const 16 i32.const 2 call $send))
+    const WASM_SERVICES: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x17, 0x04, 0x60,
+        0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+        0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x48, 0x03,
+        0x03, 0x65, 0x6e, 0x76, 0x14, 0x73, 0x79, 0x73, 0x5f, 0x72, 0x65, 0x67,
+        0x69, 0x73, 0x74, 0x65, 0x72, 0x5f, 0x73, 0x65, 0x72, 0x76, 0x69, 0x63,
+        0x65, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x12, 0x73, 0x79, 0x73, 0x5f,
+        0x6c, 0x6f, 0x6f, 0x6b, 0x75, 0x70, 0x5f, 0x73, 0x65, 0x72, 0x76, 0x69,
+        0x63, 0x65, 0x00, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0c, 0x73, 0x79, 0x73,
+        0x5f, 0x69, 0x70, 0x63, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x00, 0x00, 0x03,
+        0x04, 0x03, 0x02, 0x03, 0x02, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x25,
+        0x04, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x08, 0x72,
+        0x65, 0x67, 0x69, 0x73, 0x74, 0x65, 0x72, 0x00, 0x03, 0x06, 0x6c, 0x6f,
+        0x6f, 0x6b, 0x75, 0x70, 0x00, 0x04, 0x04, 0x73, 0x65, 0x6e, 0x64, 0x00,
+        0x05, 0x0a, 0x20, 0x03, 0x0a, 0x00, 0x41, 0x00, 0x41, 0x04, 0x20, 0x00,
+        0x10, 0x00, 0x0b, 0x08, 0x00, 0x41, 0x00, 0x41, 0x04, 0x10, 0x01, 0x0b,
+        0x0a, 0x00, 0x20, 0x00, 0x41, 0x10, 0x41, 0x02, 0x10, 0x02, 0x0b, 0x0b,
+        0x11, 0x02, 0x00, 0x41, 0x00, 0x0b, 0x04, 0x65, 0x63, 0x68, 0x6f, 0x00,
+        0x41, 0x10, 0x0b, 0x02, 0x68, 0x69, 0x00, 0x20, 0x04, 0x6e, 0x61, 0x6d,
+        0x65, 0x01, 0x19, 0x03, 0x00, 0x08, 0x72, 0x65, 0x67, 0x69, 0x73, 0x74,
+        0x65, 0x72, 0x01, 0x06, 0x6c, 0x6f, 0x6f, 0x6b, 0x75, 0x70, 0x02, 0x04,
+        0x73, 0x65, 0x6e, 0x64,
+    ];
+
+    #[test_case]
+    fn test_service_register_and_lookup() {
+        use crate::capability::{CapabilityId, Rights};
+
+        serial_print!("test_service_register_and_lookup...");
+        clear_ipc_queue();
+        fn call(module: &mut WasmModule, name: &str, args: &[Value]) -> i32 {
+            match module.call_function(name, args) {
+                Ok(Some(Value::I32(ret))) => ret,
+                _ => panic!("{} did not return a status", name),
+            }
+        }
+
+        // The echo server publishes its endpoint
+        let mut server = WasmModule::from_bytes(WASM_SERVICES).expect("load failed");
+        server.set_client_id(508);
+        server.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::Endpoint, 508, Rights::READ));
+        assert_eq!(call(&mut server, "register", &[Value::I32(0)]), 0);
+
+        // A client resolves the name and sends through its own capability
+        let mut client = WasmModule::from_bytes(WASM_SERVICES).expect("load failed");
+        client.set_client_id(509);
+        assert_eq!(call(&mut client, "lookup", &[]), 508);
+        assert_eq!(call(&mut client, "send", &[Value::I32(508)]), -1);
+        client.grant_capability(Capability::new(CapabilityId::new(2), ResourceType::Endpoint, 508, Rights::READ_WRITE));
+        assert_eq!(call(&mut client, "send", &[Value::I32(508)]), 0);
+        assert_eq!(pending_message_count(508), 1);
+
+        // The name stays with its owner
+        assert_eq!(call(&mut client, "register", &[Value::I32(0)]), -2);
+        assert_eq!(call(&mut server, "register", &[Value::I32(0)]), 0);
+        assert_eq!(call(&mut client, "register", &[Value::I32(5)]), -1);
+
+        SERVICES.lock().retain(|s| s.name != b"echo");
+        assert_eq!(call(&mut client, "lookup", &[]), -7);
+        clear_ipc_queue();
+        serial_println!("[ok]");
+    }
+
+    /// Build a module defining `count` empty functions
+    fn module_with_functions(count: u32) -> Vec<u8> {
+        fn leb(out: &mut Vec<u8>, mut value: u32) {
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    out.push(byte);
+                    return;
+                }
+                out.push(byte | 0x80);
+            }
+        }
+        fn section(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+            out.push(id);
+            leb(out, payload.len() as u32);
+            out.extend_from_slice(payload);
+        }
+
+        let mut bytes = alloc::vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        // One type: () -> ()
+        section(&mut bytes, 1, &[0x01, 0x60, 0x00, 0x00]);
+        let mut functions = Vec::new();
+        leb(&mut functions, count);
+        functions.resize(functions.len() + count as usize, 0x00);
+        section(&mut bytes, 3, &functions);
+        // Each body: size 2, no locals, end
+        let mut code = Vec::new();
+        leb(&mut code, count);
+        for _ in 0..count {
+            code.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        }
+        section(&mut bytes, 10, &code);
+        bytes
+    }
+
+    #[test_case]
+    fn test_module_function_limit() {
+        serial_print!("test_module_function_limit...");
+        let limit = module_limits().max_functions;
+        assert!(WasmModule::from_bytes(&module_with_functions(8)).is_ok());
+
+        let huge = module_with_functions(limit as u32 + 1);
+        match WasmModule::from_bytes(&huge) {
+            Err(LoadError::LimitExceeded { what, count, limit: reported }) => {
+                assert_eq!(what, "functions");
+                assert_eq!(count, limit + 1);
+                assert_eq!(reported, limit);
+            }
+            _ => panic!("oversized module was not rejected"),
+        }
+
+        // Limits are configurable, and the byte size is checked too
+        set_module_limits(ModuleLimits { max_bytes: 16, ..ModuleLimits::DEFAULT });
+        let rejected = WasmModule::from_bytes(&module_with_functions(8));
+        set_module_limits(ModuleLimits::DEFAULT);
+        assert!(matches!(rejected, Err(LoadError::LimitExceeded { what: "bytes", .. })));
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_print" (func $print (param i32 i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "hi")
+    ///   (func (export "hello") i32.const 0 i32.const 2 call $print))
+    const WASM_PRINT_ONLY: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60,
+        0x02, 0x7f, 0x7f, 0x00, 0x60, 0x00, 0x00, 0x02, 0x11, 0x01, 0x03, 0x65,
+        0x6e, 0x76, 0x09, 0x73, 0x79, 0x73, 0x5f, 0x70, 0x72, 0x69, 0x6e, 0x74,
+        0x00, 0x00, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+        0x12, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x05,
+        0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x01, 0x0a, 0x0a, 0x01, 0x08, 0x00,
+        0x41, 0x00, 0x41, 0x02, 0x10, 0x00, 0x0b, 0x0b, 0x08, 0x01, 0x00, 0x41,
+        0x00, 0x0b, 0x02, 0x68, 0x69, 0x00, 0x0f, 0x04, 0x6e, 0x61, 0x6d, 0x65,
+        0x01, 0x08, 0x01, 0x00, 0x05, 0x70, 0x72, 0x69, 0x6e, 0x74,
+    ];
+
+    /// (module
+    ///   (import "env" "sys_print" (func $print (param i32 i32)))
+    ///   (import "env" "sys_ipc_send" (func $send (param i32 i32 i32) (result i32)))
+    ///   (memory (export "memory") 1))
+    const WASM_PRINT_AND_SEND: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0d, 0x02, 0x60,
+        0x02, 0x7f, 0x7f, 0x00, 0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x02,
+        0x24, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x09, 0x73, 0x79, 0x73, 0x5f, 0x70,
+        0x72, 0x69, 0x6e, 0x74, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x0c, 0x73,
+        0x79, 0x73, 0x5f, 0x69, 0x70, 0x63, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x00,
+        0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65,
+        0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x00, 0x15, 0x04, 0x6e, 0x61, 0x6d,
+        0x65, 0x01, 0x0e, 0x02, 0x00, 0x05, 0x70, 0x72, 0x69, 0x6e, 0x74, 0x01,
+        0x04, 0x73, 0x65, 0x6e, 0x64,
+    ];
+
+    #[test_case]
+    fn test_builder_exposes_only_chosen_host_functions() {
+        serial_print!("test_builder_exposes_only_chosen_host_functions...");
+        let mut module = WasmModuleBuilder::new(WASM_PRINT_ONLY)
+            .expose("sys_print")
+            .build()
+            .expect("load failed");
+        assert!(module.call_function("hello", &[]).is_ok());
+        assert_eq!(module.hostcall_count("sys_print"), 1);
+
+        // Importing a host function that wasn't exposed fails to load
+        match WasmModuleBuilder::new(WASM_PRINT_AND_SEND).expose("sys_print").build() {
+            Err(LoadError::HostFunctionNotExposed(name)) => assert_eq!(name, "sys_ipc_send"),
+            _ => panic!("sys_ipc_send import was linked"),
+        }
+        // With everything exposed the same module loads
+        assert!(WasmModule::from_bytes(WASM_PRINT_AND_SEND).is_ok());
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_task_count" (func $task_count (result i32)))
+    ///   (func (export "task_count") (result i32)
+    ///     call $task_count))
+    const WASM_TASK_COUNT: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60,
+        0x00, 0x01, 0x7f, 0x02, 0x16, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0e, 0x73,
+        0x79, 0x73, 0x5f, 0x74, 0x61, 0x73, 0x6b, 0x5f, 0x63, 0x6f, 0x75, 0x6e,
+        0x74, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x0e, 0x01, 0x0a, 0x74,
+        0x61, 0x73, 0x6b, 0x5f, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x00, 0x01, 0x0a,
+        0x06, 0x01, 0x04, 0x00, 0x10, 0x00, 0x0b, 0x00, 0x14, 0x04, 0x6e, 0x61,
+        0x6d, 0x65, 0x01, 0x0d, 0x01, 0x00, 0x0a, 0x74, 0x61, 0x73, 0x6b, 0x5f,
+        0x63, 0x6f, 0x75, 0x6e, 0x74,
+    ];
+
+    #[test_case]
+    fn test_task_count_sees_new_task() {
+        fn idle_main() -> ! {
+            loop {
+                crate::scheduler::task_yield();
+            }
+        }
+
+        serial_print!("test_task_count_sees_new_task...");
+        let mut module = WasmModule::from_bytes(WASM_TASK_COUNT).expect("load failed");
+        let before = call_i32(&mut module, "task_count", &[]);
+        assert!(before >= 1);
+
+        let idle = TestTask::spawn("wasm-count-idle", idle_main);
+        assert_eq!(call_i32(&mut module, "task_count", &[]), before + 1);
+
+        // A held scheduler lock reports busy instead of spinning
+        let busy = without_interrupts(|| {
+            let _guard = SCHEDULER.lock();
+            call_i32(&mut module, "task_count", &[])
+        });
+        assert_eq!(busy, -1);
+
+        drop(idle);
+        assert_eq!(call_i32(&mut module, "task_count", &[]), before);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (func (export "boom")
+    ///     unreachable))
+    const WASM_TRAP: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60,
+        0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x08, 0x01, 0x04, 0x62, 0x6f,
+        0x6f, 0x6d, 0x00, 0x00, 0x0a, 0x05, 0x01, 0x03, 0x00, 0x00, 0x0b,
+    ];
+
+    /// (module
+    ///   (memory 8)
+    ///   (func (export "boom")
+    ///     unreachable))
+    const WASM_TRAP_WITH_MEMORY: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60,
+        0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x08, 0x07,
+        0x08, 0x01, 0x04, 0x62, 0x6f, 0x6f, 0x6d, 0x00, 0x00, 0x0a, 0x05, 0x01,
+        0x03, 0x00, 0x00, 0x0b,
+    ];
+
+    #[test_case]
+    fn test_trap_policy_terminates_task() {
+        use ::core::sync::atomic::AtomicBool;
+        use crate::task::TaskState;
+
+        static SURVIVED: AtomicBool = AtomicBool::new(false);
+
+        fn guest_main() -> ! {
+            let mut module = WasmModule::from_bytes(WASM_TRAP_WITH_MEMORY).expect("load failed");
+            module.set_trap_policy(TrapPolicy::Terminate);
+            let _ = module.call_function("boom", &[]);
+            SURVIVED.store(true, Ordering::SeqCst);
+            loop {
+                crate::scheduler::task_yield();
+            }
+        }
+
+        serial_print!("test_trap_policy_terminates_task...");
+        // Report (the default) hands the trap back and keeps going
+        let mut module = WasmModule::from_bytes(WASM_TRAP).expect("load failed");
+        assert!(module.call_function("boom", &[]).is_err());
+
+        // Terminate ends the hosting task, so the guest needs one of its own
+        let used_before = crate::allocator::stats().expect("heap stats").used;
+        let guest = TestTask::spawn("wasm-trapper", guest_main);
+        let exit = || guest.with(|task| (task.state(), task.exit_code()));
+        assert!(yield_until(100, || exit().0 == TaskState::Terminated));
+        assert_eq!(exit(), (TaskState::Terminated, Some(TRAP_EXIT_CODE)));
+        assert!(!SURVIVED.load(Ordering::SeqCst));
+
+        // The guest's 512 KiB of linear memory went back to the heap
+        // even though guest_main never returned to drop its module
+        drop(guest.remove());
+        let used_after = crate::allocator::stats().expect("heap stats").used;
+        assert!(used_after < used_before + 64 * 1024);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_capability_view_is_read_only() {
+        use crate::capability::CapabilityId;
+
+        serial_print!("test_capability_view_is_read_only...");
+        let cap = Capability::new(CapabilityId::new(3), ResourceType::Endpoint, 507, Rights::READ);
+        let context = WasmContext::new(alloc::vec![cap.clone()]);
+        let view = context.find_capability_view(ResourceType::Endpoint, 507).expect("view missing");
+        assert_eq!(view.resource_type(), cap.resource_type());
+        assert_eq!(view.resource_id(), cap.resource_id());
+        assert_eq!(view.rights(), cap.rights());
+        assert!(context.find_capability_view(ResourceType::Endpoint, 508).is_none());
+
+        // Rights come back by value: widening them changes nothing held,
+        // and deriving with the widened set is refused
+        let widened = Rights { write: true, ..view.rights() };
+        assert!(cap.derive(CapabilityId::new(4), widened).is_none());
+        assert_eq!(context.find_capability_view(ResourceType::Endpoint, 507).unwrap().rights(), Rights::READ);
+        assert_eq!(context.find_capability(ResourceType::Endpoint, 507).unwrap().rights(), Rights::READ);
+
+        // The guest can't write through the READ cap either, by resource or by index
+        clear_ipc_queue();
+        let mut module = WasmModule::from_bytes(WASM_LAST_ERROR).expect("load failed");
+        module.grant_capability(cap.clone());
+        assert!(matches!(module.call_function("send", &[]), Ok(Some(Value::I32(-2)))));
+        let mut sender = WasmModule::from_bytes(WASM_SEND_CAP).expect("load failed");
+        sender.grant_capability(cap);
+        assert!(matches!(sender.call_function("send", &[Value::I32(0)]), Ok(Some(Value::I32(-2)))));
+        assert_eq!(pending_message_count(507), 0);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (func (export "answer") (result i32)
+    ///     i32.const 42))
+    const WASM_CHILD: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60,
+        0x00, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x0a, 0x01, 0x06, 0x61,
+        0x6e, 0x73, 0x77, 0x65, 0x72, 0x00, 0x00, 0x0a, 0x06, 0x01, 0x04, 0x00,
+        0x41, 0x2a, 0x0b,
+    ];
+
+    /// WASM_CHILD's bytes sit at address 0.
+    ///
+    /// (module
+    ///   (import "env" "sys_load_module" (func $load (param i32 i32) (result i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "\00\61\73\6d\01\00\00\00\01\05\01\60\00\01\7f\03\02\01\00\07\0a\01\06\61\6e\73\77\65\72\00\00\0a\06\01\04\00\41\2a\0b")
+    ///   (func (export "load") (param i32 i32) (result i32)
+    ///     local.get 0
+    ///     local.get 1
+    ///     call $load))
+    const WASM_LAUNCHER: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60,
+        0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x02, 0x17, 0x01, 0x03, 0x65, 0x6e, 0x76,
+        0x0f, 0x73, 0x79, 0x73, 0x5f, 0x6c, 0x6f, 0x61, 0x64, 0x5f, 0x6d, 0x6f,
+        0x64, 0x75, 0x6c, 0x65, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x05, 0x03,
+        0x01, 0x00, 0x01, 0x07, 0x11, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72,
+        0x79, 0x02, 0x00, 0x04, 0x6c, 0x6f, 0x61, 0x64, 0x00, 0x01, 0x0a, 0x0a,
+        0x01, 0x08, 0x00, 0x20, 0x00, 0x20, 0x01, 0x10, 0x00, 0x0b, 0x0b, 0x2d,
+        0x01, 0x00, 0x41, 0x00, 0x0b, 0x27, 0x00, 0x61, 0x73, 0x6d, 0x01, 0x00,
+        0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f, 0x03, 0x02, 0x01,
+        0x00, 0x07, 0x0a, 0x01, 0x06, 0x61, 0x6e, 0x73, 0x77, 0x65, 0x72, 0x00,
+        0x00, 0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x2a, 0x0b, 0x00, 0x0e, 0x04,
+        0x6e, 0x61, 0x6d, 0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x6c, 0x6f, 0x61,
+        0x64,
+    ];
+
+    #[test_case]
+    fn test_load_module_requires_capability() {
+        use crate::capability::CapabilityId;
+
+        serial_print!("test_load_module_requires_capability...");
+        let load = |module: &mut WasmModule, ptr: i32, len: i32| match module.call_function("load", &[Value::I32(ptr), Value::I32(len)]) {
+            Ok(Some(Value::I32(result))) => result,
+            other => panic!("load did not return a value: {:?}", other),
+        };
+        let child_len = WASM_CHILD.len() as i32;
+
+        // No capability, or one without EXECUTE: denied
+        let mut denied = WasmModule::from_bytes(WASM_LAUNCHER).expect("load failed");
+        assert_eq!(load(&mut denied, 0, child_len), -1);
+        denied.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::WasmModule, 0, Rights::READ_WRITE));
+        assert_eq!(load(&mut denied, 0, child_len), -1);
+
+        let mut launcher = WasmModule::from_bytes(WASM_LAUNCHER).expect("load failed");
+        launcher.grant_capability(Capability::new(CapabilityId::new(1), ResourceType::WasmModule, 0, Rights::ALL));
+        let handle = load(&mut launcher, 0, child_len);
+        assert!(handle > 0);
+
+        // The kernel collects the child once, and it runs
+        let mut child = take_loaded_module(handle as u32).expect("child not loaded");
+        assert!(take_loaded_module(handle as u32).is_none());
+        assert!(matches!(child.call_function("answer", &[]), Ok(Some(Value::I32(42)))));
+        assert_eq!(child.capability_count(), 0);
+
+        // Truncated bytes don't load; a range past memory is a fault
+        assert_eq!(load(&mut launcher, 0, 8), -6);
+        assert_eq!(load(&mut launcher, 65530, child_len), -3);
+        serial_println!("[ok]");
+    }
+
+    /// (module
+    ///   (import "env" "sys_uptime_ms" (func $uptime (result i64)))
+    ///   (func (export "uptime") (result i64)
+    ///     call $uptime))
+    const WASM_UPTIME: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60,
+        0x00, 0x01, 0x7e, 0x02, 0x15, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0d, 0x73,
+        0x79, 0x73, 0x5f, 0x75, 0x70, 0x74, 0x69, 0x6d, 0x65, 0x5f, 0x6d, 0x73,
+        0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x0a, 0x01, 0x06, 0x75, 0x70,
+        0x74, 0x69, 0x6d, 0x65, 0x00, 0x01, 0x0a, 0x06, 0x01, 0x04, 0x00, 0x10,
+        0x00, 0x0b, 0x00, 0x10, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x09, 0x01,
+        0x00, 0x06, 0x75, 0x70, 0x74, 0x69, 0x6d, 0x65,
+    ];
+
+    #[test_case]
+    fn test_uptime_ms_advances() {
+        use crate::benchmark::{advance_mock, set_mock_clock, CYCLES_PER_TICK};
+        use crate::interrupts::timer_frequency;
+
+        serial_print!("test_uptime_ms_advances...");
+        let mut module = WasmModule::from_bytes(WASM_UPTIME).expect("load failed");
+        let mut uptime = || match module.call_function("uptime", &[]) {
+            Ok(Some(Value::I64(ms))) => ms,
+            _ => panic!("uptime did not return a value"),
+        };
+
+        // The kernel starts the timer before the tests run
+        let hz = timer_frequency() as i64;
+        assert!(hz > 0);
+
+        set_mock_clock(true);
+        assert_eq!(uptime(), 0);
+        advance_mock(5 * CYCLES_PER_TICK);
+        assert_eq!(uptime(), 5 * 1000 / hz);
+        advance_mock(CYCLES_PER_TICK - 1);
+        assert_eq!(uptime(), 5 * 1000 / hz);
+        set_mock_clock(false);
+        serial_println!("[ok]");
+    }
+}